@@ -1,172 +1,3435 @@
-use tracing::{error, info};
-use vercel_runtime::{run, Error};
+use locci_scheduler_core::runtime;
+use tracing::info;
+use vercel_runtime::Error;
 
-mod api {
+pub mod api {
+    use futures::stream::{FuturesUnordered, StreamExt};
     use http::StatusCode;
+    use locci_scheduler_core::alerts;
+    use locci_scheduler_core::apikeys::{self, ApiKey};
+    use locci_scheduler_core::auth::{self, Identity, Scope};
+    use locci_scheduler_core::autoresponder::{self, Rule};
+    use locci_scheduler_core::balance::{self, BalanceCheck};
+    use locci_scheduler_core::campaigns::{self, RejectedRow};
+    use locci_scheduler_core::channels::{self, Channel, ChannelKind, Notification};
+    use locci_scheduler_core::config::Config;
+    use locci_scheduler_core::contacts::{self, Contact, ContactGroup};
+    use locci_scheduler_core::continuation;
+    use locci_scheduler_core::cost::{SegmentLimitAction, Segments};
+    use locci_scheduler_core::deadline;
+    use locci_scheduler_core::delivery;
+    use locci_scheduler_core::dlq::{self, DeadLetter};
+    use locci_scheduler_core::error::ApiError;
+    use locci_scheduler_core::export::{self, ExportFormat};
+    use locci_scheduler_core::flags;
+    use locci_scheduler_core::format::{self, Format};
+    use locci_scheduler_core::health;
+    use locci_scheduler_core::heartbeat::{self, Tick};
+    use locci_scheduler_core::i18n::Lang;
+    use locci_scheduler_core::idempotency::{self, Begin, CachedResponse};
+    use locci_scheduler_core::inflight;
+    use locci_scheduler_core::jobs::{self, Job, JobDefinition};
+    use locci_scheduler_core::jwt;
+    use locci_scheduler_core::killswitch;
+    use locci_scheduler_core::lock;
+    use locci_scheduler_core::logfilter;
+    use locci_scheduler_core::maintenance;
+    use locci_scheduler_core::metrics;
+    use locci_scheduler_core::optout;
+    use locci_scheduler_core::phone;
+    use locci_scheduler_core::precheck::PendingSend;
+    use locci_scheduler_core::priority::Priority;
+    use locci_scheduler_core::providers::routing::ProviderRouter;
+    use locci_scheduler_core::providers::{RecipientStatus, SendReport, SmsProvider};
+    use locci_scheduler_core::ratelimit;
+    use locci_scheduler_core::recipients::{self, NumberRules, RuleList, Verdict};
+    use locci_scheduler_core::redact::{self, Redact, Redacted};
+    use locci_scheduler_core::reporting;
+    use locci_scheduler_core::retry::{GiveUpAction, RetryHint, RetryPolicy, RetryPolicyOverride};
+    use locci_scheduler_core::runtime::{
+        check_body_size, config, dry_run_client, error_response, parse_query_params, read_body,
+        respond, response_builder, sms_client,
+    };
+    use locci_scheduler_core::schedule;
+    use locci_scheduler_core::scheduled::{self, ScheduledSend, ScheduledStore, SendState};
+    use locci_scheduler_core::senders;
+    use locci_scheduler_core::storage::{self, MessageQuery, MessageRecord, MessageStatus};
+    use locci_scheduler_core::telemetry;
+    use locci_scheduler_core::templates::{self, TemplateError};
+    use locci_scheduler_core::tracecontext::{self, TraceContext};
+    use locci_scheduler_core::watchdog;
     use serde::{Deserialize, Serialize};
     use serde_json::{json, Value};
-    use tracing::{debug, error, info, instrument, warn, Span};
-    use ujumbe_sms::{UjumbeSmsClient, UjumbeSmsConfig};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::sync::Semaphore;
+    use tokio::task::JoinSet;
+    use tracing::{
+        debug, debug_span, error, field, info, info_span, instrument, warn, Instrument, Span,
+    };
     pub use vercel_runtime::{Body, Error, Request, Response};
 
+    fn current_trace_id() -> String {
+        tracecontext::current().trace_id
+    }
+
     #[derive(Deserialize, Debug)]
     struct RequestData {
         phone: Option<String>,
         message: Option<String>,
         sender_id: Option<String>,
+        // Overrides the global retry policy for this send
+        retry_policy: Option<RetryPolicyOverride>,
+        // Cron expression this send runs on; overrides SMS_SCHEDULE
+        schedule: Option<String>,
+        // Sends to each of these instead of `phone`
+        recipients: Option<Vec<BulkRecipient>>,
+        // Sends to every member of this contact group, as `recipients`
+        // with each member's name and attributes as `vars`
+        group: Option<String>,
+        // Rendered with each recipient's `vars` into its message
+        template: Option<String>,
+        // Persist the send for a later cron tick instead of sending now
+        send_at: Option<String>,
+        // IANA zone `schedule` and an offset-less `send_at` are read in;
+        // overrides SCHEDULE_TIMEZONE
+        timezone: Option<String>,
+        // Lane a `send_at` send is dispatched in; normal when absent
+        priority: Option<Priority>,
+        // Spreads `recipients` out as scheduled sends instead of sending to
+        // them all at once
+        pace: Option<schedule::PaceConfig>,
+        // Runs the send as usual but reports it instead of submitting it
+        #[serde(default, deserialize_with = "flag")]
+        dry_run: Option<bool>,
         // Add other fields as needed
     }
 
+    // A boolean, or from a form `true`, `false`, `1` or `0`
+    fn flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Flag {
+            Bool(bool),
+            Text(String),
+        }
+        match Option::<Flag>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(Flag::Bool(value)) => Ok(Some(value)),
+            Some(Flag::Text(text)) => match text.trim() {
+                "true" | "1" => Ok(Some(true)),
+                "false" | "0" => Ok(Some(false)),
+                _ => Err(serde::de::Error::custom(format!(
+                    "expected true or false, got {:?}",
+                    text
+                ))),
+            },
+        }
+    }
+
+    // A bare number gets the request's `message`; objects may bring their own,
+    // or `vars` for the request's `template`
+    #[derive(Deserialize, Debug)]
+    #[serde(untagged)]
+    enum BulkRecipient {
+        Phone(String),
+        Message {
+            phone: String,
+            message: Option<String>,
+            #[serde(default)]
+            vars: BTreeMap<String, String>,
+        },
+    }
+
+    impl BulkRecipient {
+        fn phone(&self) -> &str {
+            match self {
+                BulkRecipient::Phone(phone) | BulkRecipient::Message { phone, .. } => phone,
+            }
+        }
+
+        fn phone_mut(&mut self) -> &mut String {
+            match self {
+                BulkRecipient::Phone(phone) | BulkRecipient::Message { phone, .. } => phone,
+            }
+        }
+
+        // Own message first, then the rendered template, then the shared message
+        fn message(&self, data: &RequestData) -> Result<Option<String>, TemplateError> {
+            let (message, vars) = match self {
+                BulkRecipient::Phone(_) => (None, None),
+                BulkRecipient::Message { message, vars, .. } => (message.as_deref(), Some(vars)),
+            };
+            if let Some(message) = message {
+                return Ok(Some(message.to_string()));
+            }
+            if let Some(template) = &data.template {
+                let no_vars = BTreeMap::new();
+                return templates::render(template, vars.unwrap_or(&no_vars)).map(Some);
+            }
+            Ok(data.message.clone())
+        }
+    }
+
+    impl Redact for RequestData {
+        fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RequestData")
+                .field("phone", &self.phone.as_deref().map(redact::phone))
+                .field("message", &self.message.as_deref().map(redact::message))
+                .field("sender_id", &self.sender_id)
+                .field("retry_policy", &self.retry_policy)
+                .field("schedule", &self.schedule)
+                .field("template", &self.template.as_deref().map(redact::message))
+                .field("send_at", &self.send_at)
+                .field("timezone", &self.timezone)
+                .field(
+                    "recipients",
+                    &self.recipients.as_ref().map(|recipients| recipients.len()),
+                )
+                .field("group", &self.group)
+                .field("pace", &self.pace)
+                .finish()
+        }
+    }
+
     #[derive(Serialize)]
     struct ApiResponse {
         message: String,
         data: Option<Value>,
         request_info: RequestInfo,
+        // Sender ID used by the send made by this request, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sender_id: Option<String>,
+        // Effective policy of the send made by this request, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_policy: Option<RetryPolicy>,
+        // Cron schedule this invocation was checked against, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        schedule: Option<ScheduleInfo>,
+        // Classification of the number sent to by this request, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        number_type: Option<phone::NumberType>,
+        // Median submit-to-delivered time seen for the recipient's country;
+        // null until enough deliveries have been observed
+        estimated_delivery_seconds: Option<u64>,
+        // Per-recipient statuses of the send made by this request, for
+        // providers that report them on submission
+        #[serde(skip_serializing_if = "Option::is_none")]
+        delivery_status: Option<Vec<RecipientStatus>>,
+        // Provider calls made by the send of this request, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attempts: Option<u32>,
+        // One-off sends and jobs this cron tick found due, and how they went
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dispatched: Option<Value>,
+        // Whether sends were only reported; `data` holds what would have gone out
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        dry_run: bool,
         trace_id: String,
     }
 
+    #[derive(Serialize)]
+    struct ScheduleInfo {
+        expression: String,
+        timezone: String,
+        // Whether this invocation fell in a scheduled window and so sent
+        due: bool,
+        next_runs: Vec<chrono::DateTime<chrono::Utc>>,
+    }
+
     #[derive(Serialize)]
     struct RequestInfo {
         has_body_data: bool,
-        query_params: std::collections::HashMap<String, String>,
+        // Sorted so the serialized output is stable across identical requests
+        query_params: std::collections::BTreeMap<String, String>,
         path: String,
         method: String,
     }
 
-    // Helper function to parse query parameters
-    #[instrument(level = "debug")]
-    fn parse_query_params(query: Option<&str>) -> std::collections::HashMap<String, String> {
-        let mut params = std::collections::HashMap::new();
+    #[derive(Serialize)]
+    struct DeliveryReportResponse {
+        // Whether a recorded message had the report's id
+        matched: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_status: Option<MessageStatus>,
+        report: delivery::DeliveryReport,
+        trace_id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct OptOutRequest {
+        phone: String,
+    }
+
+    #[derive(Serialize)]
+    struct OptOutResponse {
+        phone: String,
+        opted_out: bool,
+        // Whether this request changed the stored state
+        changed: bool,
+        trace_id: String,
+    }
+
+    // Records (or removes) an opt-out for the normalized number
+    fn set_opt_out(
+        phone: &str,
+        opted_out: bool,
+        trace_id: &str,
+    ) -> Result<OptOutResponse, ApiError> {
+        let phone = phone::normalize(phone);
+        if phone.is_empty() {
+            return Err(ApiError::InvalidBody {
+                reason: "phone is required".to_string(),
+            });
+        }
+
+        let changed = optout::set(&phone, opted_out)?;
+
+        info!(
+            "Number {} opted {} (changed: {})",
+            redact::phone(&phone),
+            if opted_out { "out" } else { "back in" },
+            changed
+        );
+        Ok(OptOutResponse {
+            phone,
+            opted_out,
+            changed,
+            trace_id: trace_id.to_string(),
+        })
+    }
+
+    #[derive(Serialize)]
+    struct JobsResponse {
+        jobs: Vec<Job>,
+        trace_id: String,
+    }
+
+    #[derive(Serialize)]
+    struct JobResponse {
+        job: Job,
+        // Runs still to come within the job's repeat bounds
+        next_runs: Vec<chrono::DateTime<chrono::Utc>>,
+        // The global policy with the job's overrides applied
+        retry_policy: RetryPolicy,
+        trace_id: String,
+    }
+
+    impl JobResponse {
+        fn new(job: Job, config: &Config, trace_id: &str) -> Self {
+            let next_runs = job.upcoming(5);
+            let retry_policy = config.retry.for_send(job.retry_policy.as_ref());
+            JobResponse {
+                job,
+                next_runs,
+                retry_policy,
+                trace_id: trace_id.to_string(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct CreateGroupRequest {
+        name: String,
+        #[serde(default)]
+        members: Vec<Contact>,
+    }
+
+    #[derive(Deserialize)]
+    struct AddMembersRequest {
+        members: Vec<Contact>,
+    }
+
+    #[derive(Serialize)]
+    struct GroupsResponse {
+        groups: Vec<ContactGroup>,
+        trace_id: String,
+    }
+
+    #[derive(Serialize)]
+    struct GroupResponse {
+        group: String,
+        members: Vec<Contact>,
+        trace_id: String,
+    }
+
+    fn parse_body<T: serde::de::DeserializeOwned>(
+        body_format: Format,
+        body: &[u8],
+    ) -> Result<T, ApiError> {
+        body_format
+            .deserialize::<T>(body)
+            .map_err(|e| ApiError::InvalidBody {
+                reason: e.to_string(),
+            })
+    }
+
+    // Rewrites a form or multipart body as the JSON the endpoints parse, for
+    // clients that can only post forms; false when it's neither
+    fn form_as_json(req: &mut Request) -> Result<bool, ApiError> {
+        let content_type = req
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        let boundary = format::multipart_boundary(content_type);
+        if !format::is_form(content_type) && boundary.is_none() {
+            return Ok(false);
+        }
+        let invalid = |reason: String| ApiError::InvalidBody { reason };
+        let body = read_body(std::mem::replace(req.body_mut(), Body::Empty));
+        let fields = match boundary {
+            None => format::form_fields(&body).map_err(invalid)?,
+            Some(boundary) => format::parse_multipart(&body, &boundary)
+                .map_err(invalid)?
+                .into_iter()
+                .map(|part| match part.filename {
+                    Some(filename) => Err(invalid(format!(
+                        "file {:?} can only be uploaded to /campaigns/upload",
+                        filename
+                    ))),
+                    None => String::from_utf8(part.data)
+                        .map(|value| (part.name, value))
+                        .map_err(|e| invalid(e.to_string())),
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        let json = serde_json::to_vec(&format::form_request(fields))
+            .map_err(|e| invalid(e.to_string()))?;
+        *req.body_mut() = Body::Binary(json);
+        Ok(true)
+    }
+
+    #[derive(Deserialize)]
+    struct CreateApiKeyRequest {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct NumberRuleRequest {
+        list: RuleList,
+        pattern: String,
+    }
+
+    // Everything but the hash, which stays in storage
+    fn api_key_json(api_key: &ApiKey) -> Value {
+        json!({
+            "name": api_key.name,
+            "created_at": api_key.created_at,
+            "revoked_at": api_key.revoked_at,
+        })
+    }
+
+    #[derive(Deserialize)]
+    struct RuleRequest {
+        reply: String,
+        #[serde(default)]
+        sender_id: Option<String>,
+    }
+
+    // Returns the saved rule and whether the keyword had none before
+    async fn put_rule(keyword: &str, request: RuleRequest) -> Result<(Rule, bool), ApiError> {
+        let rule = Rule::new(keyword, request.reply, request.sender_id)?;
+        let created = autoresponder::store()?
+            .put(rule.clone())
+            .await
+            .map_err(storage::unavailable)?;
+        info!(
+            "{} auto-reply rule for {}",
+            if created { "Added" } else { "Updated" },
+            rule.keyword
+        );
+        Ok((rule, created))
+    }
+
+    async fn delete_rule(keyword: &str) -> Result<(), ApiError> {
+        let keyword = autoresponder::normalize_keyword(keyword)?;
+        if !autoresponder::store()?
+            .delete(&keyword)
+            .await
+            .map_err(storage::unavailable)?
+        {
+            return Err(ApiError::RuleNotFound { keyword });
+        }
+        info!("Deleted auto-reply rule for {}", keyword);
+        Ok(())
+    }
+
+    async fn create_group(request: CreateGroupRequest) -> Result<ContactGroup, ApiError> {
+        contacts::validate_name(&request.name)?;
+        let members = contacts::normalize_members(request.members)?;
+        let store = contacts::store()?;
+        let mut group = ContactGroup::new(&request.name);
+        if !store
+            .create(group.clone())
+            .await
+            .map_err(storage::unavailable)?
+        {
+            return Err(ApiError::GroupExists { name: group.name });
+        }
+        if !members.is_empty() {
+            group.members = members.len();
+            add_members(&group.name, members).await?;
+        }
+        info!(
+            "Created contact group {} with {} members",
+            group.name, group.members
+        );
+        Ok(group)
+    }
+
+    async fn add_members(name: &str, members: Vec<Contact>) -> Result<(), ApiError> {
+        let count = members.len();
+        if !contacts::store()?
+            .add(name, members)
+            .await
+            .map_err(storage::unavailable)?
+        {
+            return Err(ApiError::GroupNotFound {
+                name: name.to_string(),
+            });
+        }
+        info!("Added {} members to contact group {}", count, name);
+        Ok(())
+    }
+
+    async fn remove_member(name: &str, phone: &str) -> Result<bool, ApiError> {
+        let phone = phone::normalize(phone);
+        let removed = contacts::store()?
+            .remove(name, &phone)
+            .await
+            .map_err(storage::unavailable)?
+            .ok_or_else(|| ApiError::GroupNotFound {
+                name: name.to_string(),
+            })?;
+        info!(
+            "Removed {} from contact group {}: {}",
+            redact::phone(&phone),
+            name,
+            removed
+        );
+        Ok(removed)
+    }
+
+    async fn delete_group(name: &str) -> Result<(), ApiError> {
+        if !contacts::store()?
+            .delete(name)
+            .await
+            .map_err(storage::unavailable)?
+        {
+            return Err(ApiError::GroupNotFound {
+                name: name.to_string(),
+            });
+        }
+        info!("Deleted contact group {}", name);
+        Ok(())
+    }
+
+    #[derive(Serialize)]
+    struct UploadResponse {
+        group: String,
+        accepted: usize,
+        rejected: Vec<RejectedRow>,
+        trace_id: String,
+    }
+
+    // Checks each uploaded row as a send to it would be checked, then adds
+    // the rows that pass to the group, creating it if needed
+    async fn upload_recipients(
+        config: &Config,
+        group: &str,
+        body: &[u8],
+        lang: Lang,
+    ) -> Result<(usize, Vec<RejectedRow>), ApiError> {
+        contacts::validate_name(group)?;
+        let invalid = |reason: String| ApiError::InvalidBody { reason };
+        let text =
+            std::str::from_utf8(body).map_err(|_| invalid("CSV must be UTF-8".to_string()))?;
+        let mut upload = campaigns::parse_csv(text).map_err(invalid)?;
+
+        let sender_id = pick_sender(config, None);
+        let rules = recipients::effective(&config.number_rules).await?;
+        let mut members = Vec::new();
+        for row in upload.rows {
+            match validate_send(&rules, &row.contact.phone, &sender_id, false) {
+                Ok(()) => members.push(row.contact),
+                Err(e) => upload.rejected.push(RejectedRow {
+                    row: row.row,
+                    phone: Some(row.contact.phone),
+                    reason: e.message(lang),
+                }),
+            }
+        }
+        upload.rejected.sort_by_key(|rejected| rejected.row);
+
+        let accepted = members.len();
+        if accepted > 0 {
+            contacts::store()?
+                .create(ContactGroup::new(group))
+                .await
+                .map_err(storage::unavailable)?;
+            add_members(group, members).await?;
+        }
+        info!(
+            "Uploaded {} recipients to group {}, rejected {}",
+            accepted,
+            group,
+            upload.rejected.len()
+        );
+        Ok((accepted, upload.rejected))
+    }
+
+    // A `group` send goes through the bulk path to the group's current
+    // members, so clients don't post the numbers themselves
+    async fn expand_group(data: &mut RequestData) -> Result<(), ApiError> {
+        let Some(group) = data.group.as_deref() else {
+            return Ok(());
+        };
+        let invalid = |reason: String| ApiError::InvalidBody { reason };
+        if data.recipients.is_some() {
+            return Err(invalid(
+                "group can't be combined with recipients".to_string(),
+            ));
+        }
+        if data.send_at.is_some() {
+            return Err(invalid("send_at can't be combined with group".to_string()));
+        }
+        let members = contacts::expand(group).await?;
+        if members.is_empty() {
+            return Err(invalid(format!("group {} has no members", group)));
+        }
+        info!("Sending to {} members of group {}", members.len(), group);
+        data.recipients = Some(
+            members
+                .into_iter()
+                .map(|contact| BulkRecipient::Message {
+                    vars: contact.vars(),
+                    phone: contact.phone,
+                    message: None,
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+
+    // Puts every number in the request in E.164 form, so one bad number fails
+    // the request with all of them listed before anything is sent
+    fn parse_numbers(data: &mut RequestData) -> Result<(), ApiError> {
+        let phones = data
+            .phone
+            .iter()
+            .map(String::as_str)
+            .chain(data.recipients.iter().flatten().map(BulkRecipient::phone));
+        let parsed = phone::parse_all(phones)?;
+        let slots = data.phone.iter_mut().chain(
+            data.recipients
+                .iter_mut()
+                .flatten()
+                .map(BulkRecipient::phone_mut),
+        );
+        for (slot, phone) in slots.zip(parsed) {
+            *slot = phone;
+        }
+        Ok(())
+    }
+
+    fn job_store_write(e: std::io::Error) -> ApiError {
+        ApiError::JobStoreUnavailable {
+            reason: e.to_string(),
+        }
+    }
+
+    fn parse_job(body_format: Format, body: &[u8]) -> Result<JobDefinition, ApiError> {
+        body_format
+            .deserialize::<JobDefinition>(body)
+            .map_err(|e| ApiError::InvalidBody {
+                reason: e.to_string(),
+            })?
+            .validate()
+    }
+
+    async fn create_job(definition: JobDefinition) -> Result<Job, ApiError> {
+        let job = Job::new(definition);
+        jobs::store()?
+            .put(job.clone())
+            .await
+            .map_err(job_store_write)?;
+        info!("Created job {}, first run at {:?}", job.id, job.next_run_at);
+        Ok(job)
+    }
+
+    async fn get_job(id: &str) -> Result<Job, ApiError> {
+        jobs::store()?
+            .get(id)
+            .await
+            .map_err(job_store_write)?
+            .ok_or_else(|| ApiError::JobNotFound { id: id.to_string() })
+    }
+
+    async fn update_job(id: &str, definition: JobDefinition) -> Result<Job, ApiError> {
+        let mut job = get_job(id).await?;
+        job.update(definition);
+        jobs::store()?
+            .put(job.clone())
+            .await
+            .map_err(job_store_write)?;
+        info!("Updated job {}", job.id);
+        Ok(job)
+    }
+
+    async fn set_job_paused(id: &str, paused: bool) -> Result<Job, ApiError> {
+        let mut job = get_job(id).await?;
+        let changed = if paused {
+            job.pause()
+        } else {
+            job.resume(chrono::Utc::now())
+        };
+        if !changed {
+            return Err(ApiError::JobFinished { id: id.to_string() });
+        }
+        jobs::store()?
+            .put(job.clone())
+            .await
+            .map_err(job_store_write)?;
+        info!(
+            "{} job {}",
+            if paused { "Paused" } else { "Resumed" },
+            job.id
+        );
+        Ok(job)
+    }
+
+    async fn delete_job(id: &str) -> Result<(), ApiError> {
+        if !jobs::store()?.delete(id).await.map_err(job_store_write)? {
+            return Err(ApiError::JobNotFound { id: id.to_string() });
+        }
+        info!("Deleted job {}", id);
+        Ok(())
+    }
+
+    // Checks the balance once an interval when LOW_BALANCE_THRESHOLD is set,
+    // alerting while it's below. A failed check is only logged, so the tick
+    // still dispatches; a run that empties the account fails as it would.
+    async fn check_balance(client: &ProviderRouter, config: &Config) -> Option<BalanceCheck> {
+        let threshold = config.low_balance_threshold?;
+        if !balance::take_check(config.balance_check_interval).await {
+            return None;
+        }
+        let balance = match client.get_balance().await {
+            Ok(balance) => balance,
+            Err(e) => {
+                warn!("Balance check failed: {}", e);
+                return None;
+            }
+        };
+        let check = BalanceCheck::new(&balance, Some(threshold));
+        if check.low {
+            warn!(
+                "{} balance {:?} is below the threshold of {}",
+                check.provider, check.credits, threshold
+            );
+            if !alerts::low_balance(config, client, &check).await {
+                warn!("No low-balance alert went out; set SLACK_ALERT_WEBHOOK_URL or ALERT_PHONE");
+            }
+        } else {
+            debug!("{} balance is {:?}", check.provider, check.credits);
+        }
+        Some(check)
+    }
+
+    // Sends a dead letter again under the global policy. A failure puts it
+    // back with its attempts and error updated, whatever the policy says.
+    async fn retry_dead_letter(
+        client: &ProviderRouter,
+        config: &Config,
+        id: &str,
+    ) -> Result<(DeadLetter, Result<SendReport, ApiError>), ApiError> {
+        let store = dlq::store()?;
+        let mut entry = store
+            .take(id)
+            .await
+            .map_err(dlq::unavailable)?
+            .ok_or_else(|| ApiError::DeadLetterNotFound { id: id.to_string() })?;
+        info!("Retrying dead letter {}", entry.id);
+
+        let policy = config.retry.with_override(&RetryPolicyOverride {
+            give_up: Some(GiveUpAction::Drop),
+            ..Default::default()
+        });
+        let (result, attempts) = send_sms(
+            client,
+            config,
+            &entry.phone,
+            &entry.message,
+            &entry.sender_id,
+            &policy,
+            false,
+        )
+        .await;
+
+        if let Err(e) = &result {
+            warn!("Dead letter {} failed again: {}", entry.id, e);
+            entry.attempts += attempts;
+            entry.error = e.to_string();
+            store.push(&entry).await.map_err(dlq::unavailable)?;
+        }
+        Ok((entry, result))
+    }
+
+    // The zone a request's times are read in
+    fn request_timezone(config: &Config, data: &RequestData) -> Result<chrono_tz::Tz, ApiError> {
+        match &data.timezone {
+            Some(name) => schedule::parse_timezone(name).map_err(|e| ApiError::InvalidBody {
+                reason: e.to_string(),
+            }),
+            None => Ok(config.timezone),
+        }
+    }
+
+    // Validates a send now so a bad request fails while the client is still
+    // listening, then stores it for the tick at or after `send_at`
+    fn schedule_send(
+        config: &Config,
+        rules: &NumberRules,
+        data: &RequestData,
+        send_at: &str,
+        allow_nonmobile: bool,
+    ) -> Result<ScheduledSend, ApiError> {
+        let invalid = |reason: &str| ApiError::InvalidBody {
+            reason: reason.to_string(),
+        };
+        let timezone = request_timezone(config, data)?;
+        let send_at = scheduled::parse_send_at(send_at, timezone)
+            .map_err(|reason| ApiError::InvalidBody { reason })?;
+        if data.recipients.is_some() {
+            return Err(invalid("send_at can't be combined with recipients"));
+        }
+        if data.pace.is_some() {
+            return Err(invalid("send_at can't be combined with pace"));
+        }
+        let (Some(phone), Some(message)) = (&data.phone, &data.message) else {
+            return Err(invalid("send_at needs a phone and a message"));
+        };
+
+        let phone = phone::validate(phone)?;
+        let sender_id = pick_sender(config, data.sender_id.as_deref());
+        validate_send(rules, &phone, &sender_id, allow_nonmobile)?;
+        check_segments(config, message)?;
+
+        let mut send = ScheduledSend::new(
+            phone,
+            message.clone(),
+            sender_id,
+            send_at,
+            data.priority.unwrap_or_default(),
+        );
+        send.retry_policy = data.retry_policy.clone();
+        scheduled::store()?
+            .put(send.clone())
+            .map_err(job_store_write)?;
+        info!("Scheduled send {} for {}", send.id, send.send_at);
+        Ok(send)
+    }
+
+    // Stores a send the provider's circuit breaker refused, for the first
+    // tick after the breaker's next trial call. It passed validation before
+    // being refused, so it isn't validated again.
+    fn queue_for_later(
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+        priority: Priority,
+        retry_policy: Option<&RetryPolicyOverride>,
+        refused: &ApiError,
+    ) -> Result<ScheduledSend, ApiError> {
+        let wait = refused.retry_after_secs().unwrap_or(60);
+        let mut send = ScheduledSend::new(
+            phone::normalize(phone),
+            message.to_string(),
+            sender_id.to_string(),
+            chrono::Utc::now() + chrono::Duration::seconds(wait as i64),
+            priority,
+        );
+        send.retry_policy = retry_policy.cloned();
+        send.error = Some(refused.to_string());
+        scheduled::store()?
+            .put(send.clone())
+            .map_err(job_store_write)?;
+        info!(
+            "Queued send {} for {} while the provider is failing",
+            send.id, send.send_at
+        );
+        Ok(send)
+    }
+
+    // How long to put off a scheduled send that failed with `error`, when it
+    // failed without the provider seeing it
+    fn deferral(error: &ApiError) -> Option<u64> {
+        match error {
+            ApiError::CircuitOpen {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            ApiError::ProviderTimeout { timeout_ms: 0, .. } => Some(0),
+            _ => None,
+        }
+    }
+
+    // What became of one scheduled send or job run in a tick
+    enum Dispatched {
+        Sent,
+        Failed,
+        /// Another invocation holds its lease
+        Skipped,
+        /// Put back for a later tick without reaching the provider
+        Deferred,
+    }
+
+    // Sends one claimed scheduled send and records how it went
+    async fn dispatch_send(
+        client: &ProviderRouter,
+        config: &Config,
+        store: &dyn ScheduledStore,
+        mut send: ScheduledSend,
+    ) -> Dispatched {
+        let Some(lease) = dispatch_lease(&format!("send:{}", send.id)).await else {
+            return Dispatched::Skipped;
+        };
+        let policy = config.retry.for_send(send.retry_policy.as_ref());
+        let started = Instant::now();
+        let (result, attempts) = lease
+            .hold(send_sms(
+                client,
+                config,
+                &send.phone,
+                &send.message,
+                &send.sender_id,
+                &policy,
+                true,
+            ))
+            .await;
+        record_send(&result, attempts, started);
+        let dispatched = match result {
+            Ok(_) => {
+                send.state = SendState::Sent;
+                Dispatched::Sent
+            }
+            // Left for a tick after the breaker lets calls through again, or
+            // for the next tick when this one ran out of time
+            Err(e) if deferral(&e).is_some() => {
+                let wait = deferral(&e).unwrap_or_default();
+                info!("Deferring scheduled send {}: {}", send.id, e);
+                send.state = SendState::Pending;
+                send.send_at = chrono::Utc::now() + chrono::Duration::seconds(wait as i64);
+                send.error = Some(e.to_string());
+                if let Err(e) = store.put(send.clone()) {
+                    error!("Failed to defer scheduled send {}: {}", send.id, e);
+                }
+                return Dispatched::Deferred;
+            }
+            Err(e) => {
+                warn!("Scheduled send {} failed: {}", send.id, e);
+                send.state = SendState::Failed;
+                send.error = Some(e.to_string());
+                Dispatched::Failed
+            }
+        };
+        send.dispatched_at = Some(chrono::Utc::now());
+        if let Err(e) = store.put(send.clone()) {
+            error!(
+                "Failed to record outcome of scheduled send {}: {}",
+                send.id, e
+            );
+        }
+        dispatched
+    }
+
+    // Sends the one-off messages in `lane` that have come due, as many as
+    // `budget` allows, taking what it claims out of it. Each was validated
+    // when it was scheduled, so number-type checks aren't repeated.
+    #[instrument(level = "info", skip_all, fields(%lane, due = field::Empty))]
+    async fn dispatch_due(
+        client: &ProviderRouter,
+        config: &Config,
+        lane: Priority,
+        due_by: chrono::DateTime<chrono::Utc>,
+        budget: &mut usize,
+    ) -> Result<Value, ApiError> {
+        let store = scheduled::store()?;
+        let due = store
+            .claim_due(due_by, lane, *budget)
+            .map_err(job_store_write)?;
+        *budget = budget.saturating_sub(due.len());
+        Span::current().record("due", due.len());
+
+        // Sent a few at a time, each mostly waiting on its provider call
+        let permits = Semaphore::new(config.dispatch_concurrency);
+        let mut sends: FuturesUnordered<_> = due
+            .iter()
+            .map(|send| async {
+                // The semaphore is never closed
+                let _permit = permits.acquire().await.ok();
+                dispatch_send(client, config, store, send.clone()).await
+            })
+            .collect();
+        let (mut sent, mut failed, mut skipped, mut deferred) = (0, 0, 0, 0);
+        while let Some(outcome) = sends.next().await {
+            match outcome {
+                Dispatched::Sent => sent += 1,
+                Dispatched::Failed => failed += 1,
+                Dispatched::Skipped => skipped += 1,
+                Dispatched::Deferred => deferred += 1,
+            }
+        }
+        if !due.is_empty() {
+            info!(
+                "Dispatched {} {} scheduled sends: {} sent, {} failed, {} deferred",
+                due.len(),
+                lane,
+                sent,
+                failed,
+                deferred
+            );
+        }
+
+        Ok(json!({
+            "due": due.len(),
+            "sent": sent,
+            "failed": failed,
+            "skipped": skipped,
+            "deferred": deferred,
+        }))
+    }
+
+    // Leases one dispatch so an overlapping invocation skips it. The lease
+    // isn't released after the send, leaving it to expire so a duplicate
+    // arriving just behind also sees it held. Without a lease the work is
+    // skipped, since sending twice is worse than late.
+    async fn dispatch_lease(key: &str) -> Option<lock::Lease> {
+        match lock::acquire(key).await {
+            Ok(Some(lease)) => Some(lease),
+            Ok(None) => {
+                info!("Skipping {}: another invocation is dispatching it", key);
+                None
+            }
+            Err(e) => {
+                error!("Skipping {}: lock unavailable: {}", key, e);
+                None
+            }
+        }
+    }
+
+    // Sends the recurring jobs in `lane` that have come due, within `budget`
+    // as `dispatch_due` does. Runs are counted when claimed, so a campaign
+    // never exceeds `repeat.max_runs` even if a send fails or the invocation
+    // dies partway.
+    #[instrument(level = "info", skip_all, fields(%lane, due = field::Empty))]
+    async fn dispatch_jobs(
+        client: &ProviderRouter,
+        config: &Config,
+        lane: Priority,
+        due_by: chrono::DateTime<chrono::Utc>,
+        budget: &mut usize,
+    ) -> Result<Value, ApiError> {
+        let window = chrono::Duration::seconds(config.schedule_window_secs as i64);
+        let jobs::Claimed {
+            runs: due,
+            deferred,
+        } = jobs::store()?
+            .claim_due(due_by, window, config.catch_up, lane, *budget)
+            .await
+            .map_err(job_store_write)?;
+        *budget = budget.saturating_sub(due.len());
+        Span::current().record("due", due.len());
+
+        let finished = due.iter().filter(|job| job.next_run_at.is_none()).count();
+        for job in due.iter().filter(|job| job.next_run_at.is_none()) {
+            info!("Job {} finished after {} runs", job.id, job.runs);
+        }
+        // Sent `DISPATCH_CONCURRENCY` at a time, as scheduled sends are
+        let permits = Semaphore::new(config.dispatch_concurrency);
+        let mut runs: FuturesUnordered<_> = due
+            .iter()
+            .map(|job| async {
+                let _permit = permits.acquire().await.ok();
+                // Keyed by run so instances that both claimed it send it once
+                let key = format!("job:{}:run:{}", job.id, job.runs);
+                let Some(lease) = dispatch_lease(&key).await else {
+                    return Dispatched::Skipped;
+                };
+                let started = Instant::now();
+                let (result, attempts) = lease.hold(send_job(client, config, job)).await;
+                record_send(&result, attempts, started);
+                match result {
+                    Ok(_) => Dispatched::Sent,
+                    Err(e) => {
+                        warn!("Run {} of job {} failed: {}", job.runs, job.id, e);
+                        Dispatched::Failed
+                    }
+                }
+            })
+            .collect();
+        let (mut sent, mut failed, mut skipped) = (0, 0, 0);
+        while let Some(outcome) = runs.next().await {
+            match outcome {
+                Dispatched::Sent => sent += 1,
+                Dispatched::Failed | Dispatched::Deferred => failed += 1,
+                Dispatched::Skipped => skipped += 1,
+            }
+        }
+        if !due.is_empty() || !deferred.is_empty() {
+            info!(
+                "Dispatched {} {} jobs: {} sent, {} failed, {} deferred by quiet hours",
+                due.len(),
+                lane,
+                sent,
+                failed,
+                deferred.len()
+            );
+        }
 
-        if let Some(query_str) = query {
-            debug!("Parsing query string: {}", query_str);
-            for pair in query_str.split('&') {
-                if let Some((key, value)) = pair.split_once('=') {
-                    let decoded_key = urlencoding::decode(key).unwrap_or_default().to_string();
-                    let decoded_value = urlencoding::decode(value).unwrap_or_default().to_string();
+        Ok(json!({
+            "due": due.len(),
+            "sent": sent,
+            "failed": failed,
+            "skipped": skipped,
+            "finished": finished,
+            // Runs held back by quiet hours, and until when
+            "deferred": deferred,
+        }))
+    }
+
+    // Sends one run of a job over its channel. A WhatsApp job with an SMS
+    // fallback texts the phone when WhatsApp fails, unless the number opted
+    // out. Failures of channels other than SMS are alerted here, after any
+    // fallback, since `send_sms` alerts its own.
+    async fn send_job(
+        client: &ProviderRouter,
+        config: &Config,
+        job: &Job,
+    ) -> (Result<SendReport, ApiError>, u32) {
+        let policy = config.retry.for_send(job.retry_policy.as_ref());
+        let sms = || {
+            let sender_id = pick_sender(config, job.sender_id.as_deref());
+            let policy = &policy;
+            async move {
+                send_sms(
+                    client,
+                    config,
+                    &job.phone,
+                    &job.message,
+                    &sender_id,
+                    policy,
+                    false,
+                )
+                .await
+            }
+        };
+        let to = if job.channel.is_phone() {
+            job.phone.as_str()
+        } else {
+            job.to.as_deref().unwrap_or_default()
+        };
+        let notification = Notification {
+            to,
+            subject: job.subject.as_deref(),
+            message: &job.message,
+            sender_id: "",
+            template: job.template.as_ref(),
+        };
+        let (result, attempts) = match job.channel {
+            ChannelKind::Sms => return sms().await,
+            ChannelKind::Email => match channels::email() {
+                Ok(channel) => send_notification(channel, &notification, &policy).await,
+                Err(e) => (Err(e), 0),
+            },
+            ChannelKind::Webhook => match channels::webhook() {
+                Ok(channel) => send_notification(channel, &notification, &policy).await,
+                Err(e) => (Err(e), 0),
+            },
+            ChannelKind::Telegram => match channels::telegram() {
+                Ok(channel) => send_notification(channel, &notification, &policy).await,
+                Err(e) => (Err(e), 0),
+            },
+            ChannelKind::Slack => match channels::slack() {
+                Ok(channel) => send_notification(channel, &notification, &policy).await,
+                Err(e) => (Err(e), 0),
+            },
+            // Switched off, a run fails over to SMS like a failed send would
+            ChannelKind::WhatsApp if !flags::current().await.enable_whatsapp => (
+                Err(ApiError::FeatureDisabled {
+                    feature: "whatsapp",
+                }),
+                0,
+            ),
+            ChannelKind::WhatsApp => {
+                let opted_out = match optout::store() {
+                    Ok(store) => store.is_opted_out(&job.phone),
+                    Err(e) => return (Err(e), 0),
+                };
+                if opted_out {
+                    warn!(
+                        "Number {} has opted out - not sending",
+                        redact::phone(&job.phone)
+                    );
+                    return (
+                        Err(ApiError::OptedOut {
+                            phone: job.phone.clone(),
+                        }),
+                        0,
+                    );
+                }
+                match channels::whatsapp() {
+                    Ok(channel) => send_notification(channel, &notification, &policy).await,
+                    Err(e) => (Err(e), 0),
+                }
+            }
+        };
+        let e = match result {
+            Ok(report) => return (Ok(report), attempts),
+            Err(e) => e,
+        };
+        if job.fallback == Some(ChannelKind::Sms) {
+            info!(
+                "{} failed for job {}, falling back to SMS: {}",
+                job.channel, job.id, e
+            );
+            let (result, sms_attempts) = sms().await;
+            return (result, attempts + sms_attempts);
+        }
+        if let Some(url) = &config.slack_alert_url {
+            let error = e.to_string();
+            let recipient = job.channel.masked_recipient(to);
+            let failure = alerts::FailedSend {
+                channel: job.channel.as_str(),
+                recipient: &recipient,
+                attempts,
+                error: &error,
+                dead_lettered: false,
+                trace_id: &current_trace_id(),
+            };
+            alerts::failed_send(url, &failure).await;
+        }
+        (Err(e), attempts)
+    }
+
+    // Sends through a channel other than SMS under `policy`. The number and
+    // precheck rules are about SMS, so they don't apply, and the message
+    // history only keeps SMS.
+    #[instrument(level = "info", skip_all, fields(channel = channel.name(), attempts = field::Empty))]
+    async fn send_notification<C: Channel>(
+        channel: &C,
+        notification: &Notification<'_>,
+        policy: &RetryPolicy,
+    ) -> (Result<SendReport, ApiError>, u32) {
+        let (result, attempts) = policy
+            .run(|attempt| {
+                debug!(
+                    "{} attempt {}/{}",
+                    channel.name(),
+                    attempt,
+                    policy.max_attempts
+                );
+                channel.send(notification)
+            })
+            .await;
+        Span::current().record("attempts", attempts);
+        match &result {
+            Ok(report) => info!(
+                "Sent {} notification {:?} after {} attempts",
+                channel.name(),
+                report.message_ids,
+                attempts
+            ),
+            Err(e) => warn!(
+                "{} notification failed after {} attempts: {}",
+                channel.name(),
+                attempts,
+                e
+            ),
+        }
+        (result, attempts)
+    }
+
+    #[derive(Deserialize)]
+    struct MaintenanceRequest {
+        enabled: bool,
+    }
+
+    #[derive(Serialize)]
+    struct MaintenanceResponse {
+        maintenance: bool,
+        // Whether this request changed the mode
+        changed: bool,
+        trace_id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct KillSwitchRequest {
+        enabled: bool,
+    }
+
+    #[derive(Serialize)]
+    struct KillSwitchResponse {
+        kill_switch: killswitch::Status,
+        // Whether this request flipped the switch
+        changed: bool,
+        trace_id: String,
+    }
+
+    #[derive(Deserialize)]
+    struct BatchScheduleRequest {
+        // Wall-clock time every job fires at in its own timezone, e.g. 2024-09-01T09:00
+        local_time: String,
+        // Timezone for jobs that don't set one
+        #[serde(default = "default_timezone")]
+        timezone: String,
+        jobs: Vec<schedule::BatchJob>,
+        // Spreads the batch out instead of firing every job at once
+        #[serde(default)]
+        pace: Option<schedule::PaceConfig>,
+        // Sender for every job; the pool or default sender otherwise
+        #[serde(default)]
+        sender_id: Option<String>,
+        #[serde(default)]
+        priority: Option<Priority>,
+    }
+
+    fn default_timezone() -> String {
+        "Africa/Nairobi".to_string()
+    }
+
+    // A planned job with the id it was stored under
+    #[derive(Serialize)]
+    struct ScheduledBatchJob {
+        id: String,
+        #[serde(flatten)]
+        job: schedule::PlannedJob,
+    }
+
+    #[derive(Serialize)]
+    struct BatchScheduleResponse {
+        jobs: Vec<ScheduledBatchJob>,
+        rejected: Vec<Value>,
+        // When the last job fires once pacing is applied
+        #[serde(skip_serializing_if = "Option::is_none")]
+        projected_completion_utc: Option<chrono::DateTime<chrono::Utc>>,
+        trace_id: String,
+    }
+
+    // Resolves every job's fire time in its own timezone and stores it as a
+    // scheduled send for the tick at or after that time. Jobs that can't be
+    // planned or sent are reported and left out; the rest are stored.
+    fn schedule_batch(
+        config: &Config,
+        rules: &NumberRules,
+        request: BatchScheduleRequest,
+        allow_nonmobile: bool,
+        trace_id: &str,
+    ) -> Result<BatchScheduleResponse, ApiError> {
+        let invalid = |e: schedule::ScheduleError| ApiError::InvalidBody {
+            reason: e.to_string(),
+        };
+        let local = schedule::parse_local(&request.local_time).map_err(invalid)?;
+        let default_tz = schedule::parse_timezone(&request.timezone).map_err(invalid)?;
+
+        let phones = phone::parse_all(request.jobs.iter().map(|job| job.phone.as_str()))?;
+        let jobs: Vec<schedule::BatchJob> = request
+            .jobs
+            .into_iter()
+            .zip(phones)
+            .map(|(job, phone)| schedule::BatchJob { phone, ..job })
+            .collect();
+
+        let mut planned = Vec::new();
+        let mut senders = Vec::new();
+        let mut rejected = Vec::new();
+        for (index, (job, result)) in jobs
+            .iter()
+            .zip(schedule::plan_batch(local, default_tz, &jobs))
+            .enumerate()
+        {
+            let result = result.map_err(|e| e.to_string()).and_then(|planned| {
+                let sender_id = pick_sender(config, request.sender_id.as_deref());
+                validate_send(rules, &planned.phone, &sender_id, allow_nonmobile)
+                    .and_then(|()| check_segments(config, &planned.message))
+                    .map(|()| (planned, sender_id))
+                    .map_err(|e| e.to_string())
+            });
+            match result {
+                Ok((job, sender_id)) => {
+                    debug!(
+                        "Planned job for {} at {} ({})",
+                        redact::phone(&job.phone),
+                        job.fire_at_utc,
+                        job.local_time
+                    );
+                    planned.push(job);
+                    senders.push(sender_id);
+                }
+                Err(e) => {
+                    warn!(
+                        "Rejecting batch job {} for {}: {}",
+                        index,
+                        redact::phone(&job.phone),
+                        e
+                    );
+                    rejected.push(json!({
+                        "index": index,
+                        "phone": job.phone,
+                        "error": e,
+                    }));
+                }
+            }
+        }
+
+        let projected_completion_utc = match &request.pace {
+            Some(pace) => {
+                let completion = schedule::apply_pace(&mut planned, pace).map_err(invalid)?;
+                debug!("Paced batch at {:?}, completing at {:?}", pace, completion);
+                completion
+            }
+            None => None,
+        };
+
+        let store = scheduled::store()?;
+        let priority = request.priority.unwrap_or_default();
+        let mut stored = Vec::with_capacity(planned.len());
+        for (job, sender_id) in planned.into_iter().zip(senders) {
+            let send = ScheduledSend::new(
+                job.phone.clone(),
+                job.message.clone(),
+                sender_id,
+                job.fire_at_utc,
+                priority,
+            );
+            let id = send.id.clone();
+            store.put(send).map_err(job_store_write)?;
+            stored.push(ScheduledBatchJob { id, job });
+        }
+
+        info!(
+            "Scheduled {} batch jobs at local time {} ({} rejected)",
+            stored.len(),
+            local,
+            rejected.len()
+        );
+        Ok(BatchScheduleResponse {
+            jobs: stored,
+            rejected,
+            projected_completion_utc,
+            trace_id: trace_id.to_string(),
+        })
+    }
+
+    // `status`, `from` and `to` (RFC 3339) filter the history; `page` counts
+    // from 1 in pages of `per_page`, default 50 and at most 500
+    fn message_query(params: &BTreeMap<String, String>) -> Result<MessageQuery, ApiError> {
+        let invalid = |reason: String| ApiError::InvalidQuery { reason };
+        let time = |key: &str| {
+            params
+                .get(key)
+                .map(|value| {
+                    chrono::DateTime::parse_from_rfc3339(value)
+                        .map(|at| at.with_timezone(&chrono::Utc))
+                        .map_err(|e| invalid(format!("{}: {}", key, e)))
+                })
+                .transpose()
+        };
+        let count = |key: &str, default: usize| match params.get(key) {
+            Some(value) => value
+                .parse::<usize>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| invalid(format!("{} must be a positive number", key))),
+            None => Ok(default),
+        };
+        let status = params
+            .get("status")
+            .map(|status| status.parse::<MessageStatus>().map_err(invalid))
+            .transpose()?;
+        let per_page = count("per_page", 50)?.min(500);
+        let page = count("page", 1)?;
+        Ok(MessageQuery {
+            status,
+            from: time("from")?,
+            to: time("to")?,
+            offset: (page - 1).saturating_mul(per_page),
+            limit: per_page,
+        })
+    }
+
+    // Everything that can reject a send before the provider is contacted
+    #[instrument(level = "debug", skip_all)]
+    fn validate_send(
+        rules: &NumberRules,
+        phone: &str,
+        sender_id: &str,
+        allow_nonmobile: bool,
+    ) -> Result<(), ApiError> {
+        let masked = redact::phone(phone);
+
+        if let Err(reason) = senders::validate_sender_id(sender_id) {
+            warn!("Rejecting sender ID {}: {}", sender_id, reason);
+            return Err(ApiError::InvalidSenderId {
+                sender_id: sender_id.to_string(),
+                reason,
+            });
+        }
+
+        match rules.check(phone) {
+            Verdict::Allowed => debug!("No allow/deny rule configured for: {}", masked),
+            Verdict::AllowedBy(rule) => info!("Number {} allowed by rule: {}", masked, rule),
+            Verdict::BlockedBy(rule) => {
+                warn!("Number {} blocked by rule: {}", masked, rule);
+                return Err(ApiError::NumberBlocked {
+                    phone: phone.to_string(),
+                    rule: rule.to_string(),
+                });
+            }
+            Verdict::NotAllowed => {
+                warn!("Number {} is not on the allowlist", masked);
+                return Err(ApiError::NumberNotAllowed {
+                    phone: phone.to_string(),
+                });
+            }
+        }
+
+        if optout::store()?.is_opted_out(phone) {
+            warn!("Number {} has opted out - not sending", masked);
+            return Err(ApiError::OptedOut {
+                phone: phone.to_string(),
+            });
+        }
+
+        let number_type = phone::classify_number(phone);
+        debug!("Number {} classified as: {:?}", masked, number_type);
+        if !number_type.is_textable() {
+            if !allow_nonmobile {
+                warn!("Rejecting {} number: {}", number_type.label(), masked);
+                return Err(ApiError::NonMobileNumber {
+                    phone: phone.to_string(),
+                    number_type: number_type.label().to_string(),
+                });
+            }
+            warn!(
+                "Sending to {} number {} as allow_nonmobile is set",
+                number_type.label(),
+                masked
+            );
+        }
+
+        Ok(())
+    }
+
+    // Holds messages to MAX_SEGMENTS, so a long or emoji-laden message isn't
+    // quietly sent as an expensive multi-part one
+    fn check_segments(config: &Config, message: &str) -> Result<(), ApiError> {
+        let Some(limit) = &config.segment_limit else {
+            return Ok(());
+        };
+        let segments = Segments::of(message);
+        if segments.segments > limit.max && limit.action == SegmentLimitAction::Warn {
+            warn!(
+                "Sending a {}-segment {} message, over MAX_SEGMENTS of {}",
+                segments.segments,
+                segments.encoding.as_str(),
+                limit.max
+            );
+        }
+        limit.check(&segments)
+    }
+
+    #[instrument(level = "info", skip_all, fields(attempts = field::Empty))]
+    async fn send_sms<P: SmsProvider>(
+        client: &P,
+        config: &Config,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+        policy: &RetryPolicy,
+        allow_nonmobile: bool,
+    ) -> (Result<SendReport, ApiError>, u32) {
+        let phone = match phone::validate(phone) {
+            Ok(phone) => phone,
+            Err(e) => return (Err(e), 0),
+        };
+        let masked = redact::phone(&phone);
+        info!("Attempting to send SMS to: {}", masked);
+
+        // Sends refused before reaching the provider made no attempts
+        if let Err(e) = recipients::effective(&config.number_rules)
+            .await
+            .and_then(|rules| validate_send(&rules, &phone, sender_id, allow_nonmobile))
+            .and_then(|()| check_segments(config, message))
+        {
+            return (Err(e), 0);
+        }
+
+        if let Some(precheck) = &config.precheck {
+            let pending = PendingSend {
+                phone: &phone,
+                message,
+                sender_id,
+            };
+            if let Err(reason) = precheck.check(&pending).await {
+                warn!("Precheck skipped send to {}: {}", masked, reason);
+                return (Err(ApiError::Skipped { reason }), 0);
+            }
+            debug!("Precheck allowed send to: {}", masked);
+        }
+
+        if let Err(e) = ratelimit::take(config.rate_limits.per_number, "number", &phone).await {
+            warn!("Not sending to {}, over RATE_LIMIT_PER_NUMBER", masked);
+            return (Err(e), 0);
+        }
+
+        // Estimated at the primary provider's rate; what's counted is the
+        // rate of the provider that sent it
+        if let Some(budget) = &config.budget {
+            let estimate = config
+                .segment_rates
+                .cost(&config.provider, Segments::of(message).segments);
+            if let Err(e) = budget.check(estimate).await {
+                warn!("Not sending to {}, over DAILY_BUDGET", masked);
+                return (Err(e), 0);
+            }
+        }
+
+        debug!(
+            "SMS details - Sender: {}, Message length: {}",
+            sender_id,
+            message.len()
+        );
+
+        let number = phone.as_str();
+        let (result, attempts) = policy
+            .run(|attempt| {
+                debug!(
+                    "Send attempt {}/{} to: {}",
+                    attempt, policy.max_attempts, masked
+                );
+                let span = info_span!("provider_call", attempt, outcome = field::Empty);
+                async move {
+                    let result = client.send_single(number, message, sender_id).await;
+                    let outcome = match &result {
+                        Ok(_) => "ok",
+                        Err(e) if e.retry_after().is_some() => "rate_limited",
+                        Err(_) => "error",
+                    };
+                    Span::current().record("outcome", outcome);
+                    result
+                }
+                .instrument(span)
+            })
+            .await;
+        Span::current().record("attempts", attempts);
+
+        let mut record = MessageRecord::new(
+            phone.clone(),
+            message.to_string(),
+            sender_id.to_string(),
+            MessageStatus::Sent,
+            attempts,
+        );
+        record.trace_id = Some(current_trace_id());
+        record.dry_run = client.is_dry_run();
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                record.status = MessageStatus::Failed;
+                record.error = Some(e.to_string());
+                storage::record_message(record).await;
+                if let Some(url) = &config.slack_alert_url {
+                    let error = e.to_string();
+                    let recipient = redact::mask_phone(&phone);
+                    let failure = alerts::FailedSend {
+                        channel: "sms",
+                        recipient: &recipient,
+                        attempts,
+                        error: &error,
+                        dead_lettered: policy.give_up == GiveUpAction::DeadLetter,
+                        trace_id: &current_trace_id(),
+                    };
+                    alerts::failed_send(url, &failure).await;
+                }
+                match policy.give_up {
+                    GiveUpAction::Drop => {
+                        warn!("Dropping message to {} after {} attempts", masked, attempts)
+                    }
+                    GiveUpAction::DeadLetter => {
+                        warn!(
+                            "Dead-lettering message to {} after {} attempts",
+                            masked, attempts
+                        );
+                        dlq::push(DeadLetter::new(
+                            phone.clone(),
+                            message.to_string(),
+                            sender_id.to_string(),
+                            attempts,
+                            e.to_string(),
+                        ))
+                        .await;
+                    }
+                }
+                return (Err(e), attempts);
+            }
+        };
+
+        info!(
+            "SMS sent successfully to: {} after {} attempts",
+            masked, attempts
+        );
+
+        info!(
+            "Provider {} - recipients: {:?}, credits deducted: {:?}, available: {:?}",
+            report.provider, report.recipients, report.credits_deducted, report.available_credits
+        );
+        debug!("SMS response: {:#?}", Redacted(&report));
+        // No provider will report on a dry run's messages
+        if !client.is_dry_run() {
+            for message_id in &report.message_ids {
+                delivery::record_submission(message_id, &phone);
+            }
+        }
+        record.provider = Some(report.provider.to_string());
+        record.message_ids = report.message_ids.clone();
+        record.statuses = report.statuses.clone();
+        record.cost = report.credits_deducted;
+        record.estimated_cost = record
+            .segments
+            .map(|segments| config.segment_rates.cost(report.provider, segments));
+        let spend = record.estimated_cost.filter(|_| !client.is_dry_run());
+        if let (Some(budget), Some(cost)) = (&config.budget, spend) {
+            if let Some(spent) = budget.record(cost).await {
+                warn!(
+                    "Sends today have used {:.2} of the daily budget of {}",
+                    spent, budget.daily_cap
+                );
+                if !alerts::budget_used(config, client, budget, spent).await {
+                    warn!("No budget alert went out; set SLACK_ALERT_WEBHOOK_URL or ALERT_PHONE");
+                }
+            }
+        }
+        storage::record_message(record).await;
+
+        (Ok(report), attempts)
+    }
+
+    // Keeps the tick for /status, alerting when it came late
+    async fn record_tick(client: &ProviderRouter, config: &Config, tick: Tick) {
+        if let Some(previous) = heartbeat::record(tick.clone()).await {
+            watchdog::tick_arrived(config, client, &tick, &previous).await;
+        }
+    }
+
+    fn record_send(result: &Result<SendReport, ApiError>, attempts: u32, started: Instant) {
+        let outcome = match result {
+            Ok(_) => "sent",
+            Err(e) => e.code(),
+        };
+        let elapsed = started.elapsed();
+        info!("Send finished ({}) in {:?}", outcome, elapsed);
+        metrics::record_send(result, attempts, elapsed);
+        if let Err(e) = result {
+            reporting::failed_send(e);
+        }
+    }
+
+    #[derive(Serialize)]
+    struct EstimateResponse {
+        recipients: usize,
+        segments: usize,
+        estimated_cost: f64,
+        // Recipients, segments, rate and cost per provider sends would take
+        by_provider: serde_json::Map<String, Value>,
+        messages: Vec<Value>,
+        // Recipients left out, as a send would fail them
+        rejected: Vec<Value>,
+        trace_id: String,
+    }
+
+    // Prices a send without making it. The body is the one POST / takes;
+    // each recipient's message is counted in segments at the rate of the
+    // provider it would be routed to.
+    async fn estimate(
+        client: &ProviderRouter,
+        config: &Config,
+        mut data: RequestData,
+    ) -> Result<EstimateResponse, ApiError> {
+        expand_group(&mut data).await?;
+        let recipients = match (data.recipients.take(), data.phone.take()) {
+            (Some(recipients), _) => recipients,
+            (None, Some(phone)) => vec![BulkRecipient::Phone(phone)],
+            (None, None) => {
+                return Err(ApiError::InvalidBody {
+                    reason: "phone, recipients or group is required".to_string(),
+                })
+            }
+        };
+
+        let mut messages = Vec::new();
+        let mut rejected = Vec::new();
+        let mut by_provider: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for recipient in &recipients {
+            let message = match recipient.message(&data) {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    rejected
+                        .push(json!({ "phone": recipient.phone(), "reason": "has no message" }));
+                    continue;
+                }
+                Err(e) => {
+                    rejected.push(json!({ "phone": recipient.phone(), "reason": e.to_string() }));
+                    continue;
+                }
+            };
+            let segments = Segments::of(&message);
+            if let Some(Err(e)) = config.segment_limit.map(|limit| limit.check(&segments)) {
+                rejected.push(json!({ "phone": recipient.phone(), "reason": e.to_string() }));
+                continue;
+            }
+            let phone = match phone::parse(recipient.phone()) {
+                Ok(phone) => phone,
+                Err(reason) => {
+                    rejected.push(json!({ "phone": recipient.phone(), "reason": reason }));
+                    continue;
+                }
+            };
+            let provider = client.route(&phone).provider;
+            let totals = by_provider.entry(provider).or_default();
+            totals.0 += 1;
+            totals.1 += segments.segments;
+            messages.push(json!({
+                "phone": recipient.phone(),
+                "provider": provider,
+                "encoding": segments.encoding,
+                "characters": segments.characters,
+                "segments": segments.segments,
+                "estimated_cost": config.segment_rates.cost(provider, segments.segments),
+            }));
+        }
+
+        let rates = &config.segment_rates;
+        let providers: serde_json::Map<String, Value> = by_provider
+            .iter()
+            .map(|(&provider, &(recipients, segments))| {
+                let totals = json!({
+                    "recipients": recipients,
+                    "segments": segments,
+                    "rate": rates.rate(provider),
+                    "estimated_cost": rates.cost(provider, segments),
+                });
+                (provider.to_string(), totals)
+            })
+            .collect();
+        Ok(EstimateResponse {
+            recipients: messages.len(),
+            segments: by_provider.values().map(|&(_, segments)| segments).sum(),
+            estimated_cost: by_provider
+                .iter()
+                .map(|(provider, &(_, segments))| rates.cost(provider, segments))
+                .sum(),
+            by_provider: providers,
+            messages,
+            rejected,
+            trace_id: current_trace_id(),
+        })
+    }
+
+    // Sends to every recipient, at most BULK_CONCURRENCY at a time, and
+    // reports each outcome in request order
+    #[instrument(level = "info", skip_all, fields(recipients = field::Empty))]
+    async fn send_bulk(
+        client: &'static ProviderRouter,
+        config: &'static Config,
+        data: &RequestData,
+        policy: &RetryPolicy,
+        allow_nonmobile: bool,
+        lang: Lang,
+    ) -> Value {
+        let recipients = data.recipients.as_deref().unwrap_or_default();
+        Span::current().record("recipients", recipients.len());
+        let permits = Arc::new(Semaphore::new(config.bulk_concurrency));
+        let mut sends = JoinSet::new();
+        let mut results = vec![Value::Null; recipients.len()];
+        let mut suppressed = 0;
+
+        for (index, recipient) in recipients.iter().enumerate() {
+            let phone = recipient.phone().to_string();
+            let rendered = match recipient.message(data) {
+                Ok(Some(message)) => Ok(message),
+                Ok(None) => Err(format!("recipient {} has no message", index)),
+                Err(e) => {
+                    warn!("Template failed for recipient {}: {}", index, e);
+                    Err(format!("recipient {}: {}", index, e))
+                }
+            };
+            let message = match rendered {
+                Ok(message) => message,
+                Err(reason) => {
+                    let e = ApiError::InvalidBody { reason };
+                    results[index] =
+                        json!({ "phone": phone, "status": "failed", "data": error_data(&e, lang) });
+                    continue;
+                }
+            };
+            // Opted-out numbers are left out before using a send slot; the
+            // store being unavailable is reported by the send itself
+            if optout::store().is_ok_and(|store| store.is_opted_out(&phone::normalize(&phone))) {
+                debug!("Suppressing opted-out recipient {}", index);
+                results[index] = json!({ "phone": phone, "status": "suppressed" });
+                suppressed += 1;
+                continue;
+            }
+            let sender_id = pick_sender(config, data.sender_id.as_deref());
+            let policy = policy.clone();
+            let permits = permits.clone();
+            let span = info_span!("bulk_recipient", index);
+            // A panic in the send is reported with the request it came from
+            sends.spawn(reporting::scope(
+                reporting::current(),
+                deadline::scope(
+                    deadline::current(),
+                    tracecontext::scope(
+                        tracecontext::current(),
+                        async move {
+                            // The semaphore is never closed
+                            let _permit = permits.acquire_owned().await.ok();
+                            let started = Instant::now();
+                            let (result, attempts) = send_sms(
+                                client,
+                                config,
+                                &phone,
+                                &message,
+                                &sender_id,
+                                &policy,
+                                allow_nonmobile,
+                            )
+                            .await;
+                            (index, phone, sender_id, result, attempts, started)
+                        }
+                        .instrument(span),
+                    ),
+                ),
+            ));
+        }
+
+        let mut sent = 0;
+        while let Some(joined) = sends.join_next().await {
+            let (index, phone, sender_id, result, attempts, started) = match joined {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    error!("Bulk send task failed: {}", e);
+                    continue;
+                }
+            };
+            record_send(&result, attempts, started);
+            let route = client.route(&phone::normalize(&phone));
+            results[index] = match result {
+                Ok(report) => {
+                    sent += 1;
+                    json!({
+                        "phone": phone,
+                        "status": "sent",
+                        "sender_id": sender_id,
+                        "attempts": attempts,
+                        // Where it was sent first, and who took it after failover
+                        "route": route,
+                        "provider": report.provider,
+                        "delivery_status": report.statuses,
+                        "data": report.raw,
+                    })
+                }
+                Err(e) => {
+                    warn!("Bulk send to {} failed: {}", redact::phone(&phone), e);
+                    json!({
+                        "phone": phone,
+                        "status": "failed",
+                        "sender_id": sender_id,
+                        "attempts": attempts,
+                        "route": route,
+                        "data": error_data(&e, lang),
+                    })
+                }
+            };
+        }
+        let failed = recipients.len() - sent - suppressed;
+        info!(
+            "Bulk send finished: {} sent, {} failed, {} suppressed",
+            sent, failed, suppressed
+        );
+
+        json!({
+            "total": recipients.len(),
+            "sent": sent,
+            "failed": failed,
+            "suppressed": suppressed,
+            "results": results,
+        })
+    }
+
+    // Validates every recipient now, then stores the accepted ones as
+    // scheduled sends spaced out by `pace`, the first due straight away
+    async fn pace_bulk(
+        config: &Config,
+        data: &RequestData,
+        pace: &schedule::PaceConfig,
+        allow_nonmobile: bool,
+        lang: Lang,
+    ) -> Result<Value, ApiError> {
+        let recipients = data.recipients.as_deref().unwrap_or_default();
+        let rules = recipients::effective(&config.number_rules).await?;
+        let mut results = vec![Value::Null; recipients.len()];
+        let mut accepted = Vec::new();
+        let mut suppressed = 0;
+
+        for (index, recipient) in recipients.iter().enumerate() {
+            let phone = recipient.phone().to_string();
+            if optout::store().is_ok_and(|store| store.is_opted_out(&phone::normalize(&phone))) {
+                debug!("Suppressing opted-out recipient {}", index);
+                results[index] = json!({ "phone": phone, "status": "suppressed" });
+                suppressed += 1;
+                continue;
+            }
+            let sender_id = pick_sender(config, data.sender_id.as_deref());
+            let checked = match recipient.message(data) {
+                Ok(Some(message)) => Ok(message),
+                Ok(None) => Err(ApiError::InvalidBody {
+                    reason: format!("recipient {} has no message", index),
+                }),
+                Err(e) => Err(ApiError::InvalidBody {
+                    reason: format!("recipient {}: {}", index, e),
+                }),
+            }
+            .and_then(|message| {
+                let normalized = phone::validate(&phone)?;
+                validate_send(&rules, &normalized, &sender_id, allow_nonmobile)?;
+                check_segments(config, &message)?;
+                Ok((normalized, message))
+            });
+            match checked {
+                Ok((normalized, message)) => accepted.push((index, normalized, message, sender_id)),
+                Err(e) => {
+                    warn!("Not scheduling paced send to recipient {}: {}", index, e);
+                    results[index] = json!({
+                        "phone": phone,
+                        "status": "failed",
+                        "sender_id": sender_id,
+                        "data": error_data(&e, lang),
+                    });
+                }
+            }
+        }
+
+        let times =
+            schedule::paced_times(chrono::Utc::now(), accepted.len(), pace).map_err(|e| {
+                ApiError::InvalidBody {
+                    reason: e.to_string(),
+                }
+            })?;
+        let projected_completion_utc = times.last().copied();
+        let store = scheduled::store()?;
+        for ((index, phone, message, sender_id), send_at) in accepted.into_iter().zip(times) {
+            let mut send = ScheduledSend::new(
+                phone,
+                message,
+                sender_id,
+                send_at,
+                data.priority.unwrap_or_default(),
+            );
+            send.retry_policy = data.retry_policy.clone();
+            store.put(send.clone()).map_err(job_store_write)?;
+            results[index] = json!({
+                "phone": recipients[index].phone(),
+                "status": "scheduled",
+                "id": send.id,
+                "send_at": send.send_at,
+                "sender_id": send.sender_id,
+            });
+        }
+        let scheduled = results
+            .iter()
+            .filter(|result| result["status"] == "scheduled")
+            .count();
+        let failed = recipients.len() - scheduled - suppressed;
+        info!(
+            "Paced bulk send at {:?}: {} scheduled, {} failed, {} suppressed",
+            pace, scheduled, failed, suppressed
+        );
+
+        Ok(json!({
+            "total": recipients.len(),
+            "scheduled": scheduled,
+            "failed": failed,
+            "suppressed": suppressed,
+            "projected_completion_utc": projected_completion_utc,
+            "results": results,
+        }))
+    }
+
+    // The requested sender wins, then the weighted pool, then the default
+    fn pick_sender(config: &Config, requested: Option<&str>) -> String {
+        if let Some(sender_id) = requested {
+            return sender_id.to_string();
+        }
+        match config.sender_pool.next() {
+            Some(sender_id) => {
+                debug!("Picked sender {} from the sender pool", sender_id);
+                sender_id.to_string()
+            }
+            None => config.default_sender.clone(),
+        }
+    }
+
+    // Machine-readable code plus the message in the negotiated language
+    // Errors inside a response, e.g. a bulk recipient's, take the same
+    // problem details shape as error responses
+    fn error_data(error: &ApiError, lang: Lang) -> Value {
+        error.problem(lang)
+    }
+
+    // Strips the function prefix so `/api/handler/config` routes as `/config`
+    fn route(path: &str) -> &str {
+        match path.strip_prefix("/api/handler").unwrap_or(path) {
+            "" => "/",
+            rest => rest,
+        }
+    }
+
+    // The methods each endpoint answers, by path segments, or `None` when no
+    // endpoint is at the path. `/` and `/send` take sends; GET on `/` is
+    // also the cron tick.
+    fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let methods: &'static [&'static str] = match segments.as_slice() {
+            [""] => &["GET", "POST"],
+            ["send"]
+            | ["estimate"]
+            | ["delivery-reports"]
+            | ["optout"]
+            | ["campaigns", "upload"]
+            | ["admin", "maintenance"]
+            | ["schedule", "batch"]
+            | ["jobs", _, "pause" | "resume"]
+            | ["groups", _, "members"]
+            | ["dlq", _, "retry"] => &["POST"],
+            ["health"]
+            | ["status"]
+            | ["healthz"]
+            | ["readyz"]
+            | ["health", "provider"]
+            | ["metrics"]
+            | ["config"]
+            | ["provider", "balance"]
+            | ["messages"]
+            | ["messages", "export"]
+            | ["dlq"]
+            | ["autoresponder", "rules"] => &["GET"],
+            ["jobs"]
+            | ["groups"]
+            | ["admin", "api-keys"]
+            | ["admin", "number-rules"]
+            | ["admin", "kill-switch"] => &["GET", "POST"],
+            ["jobs", _] => &["GET", "PUT", "DELETE"],
+            ["groups", _] => &["GET", "DELETE"],
+            ["autoresponder", "rules", _] => &["PUT", "DELETE"],
+            ["optout", _]
+            | ["groups", _, "members", _]
+            | ["admin", "api-keys", _]
+            | ["admin", "number-rules", _, _] => &["DELETE"],
+            _ => return None,
+        };
+        Some(methods)
+    }
+
+    fn body_bytes(body: &Body) -> &[u8] {
+        match body {
+            Body::Empty => &[],
+            Body::Text(text) => text.as_bytes(),
+            Body::Binary(bytes) => bytes,
+        }
+    }
+
+    // Whether `handle` still has to authenticate the request. One carrying
+    // an Idempotency-Key is authenticated before its key is reserved, and a
+    // second check would find a signed request's nonce already used.
+    enum Caller {
+        Unchecked,
+        Authenticated(Option<Identity>),
+    }
+
+    // Who made the request: a JWT's subject, an API key's name, or `signed`
+    // for a request signed with REQUEST_SIGNING_SECRET. Health checks,
+    // metrics and delivery reports are left open: monitors and providers
+    // carry no key, and delivery reports have DELIVERY_CALLBACK_TOKEN instead.
+    async fn authenticate(req: &Request, config: &Config) -> Result<Option<Identity>, ApiError> {
+        let path = route(req.uri().path());
+        if matches!(
+            path,
+            "/health"
+                | "/status"
+                | "/healthz"
+                | "/readyz"
+                | "/health/provider"
+                | "/metrics"
+                | "/delivery-reports"
+        ) {
+            return Ok(None);
+        }
+        let rejected = |e: &ApiError| warn!("Rejected {} {}: {}", req.method(), path, e);
+        let identity = match (config.signing_secret.as_deref(), &config.jwt) {
+            (Some(secret), _) if req.headers().contains_key(auth::SIGNATURE_HEADER) => {
+                auth::verify_signature(
+                    req.headers(),
+                    body_bytes(req.body()),
+                    secret,
+                    config.signature_window_secs,
+                )
+                .inspect_err(rejected)?;
+                Some(Identity::unrestricted("signed"))
+            }
+            (_, Some(jwt)) if auth::bearer_token(req.headers()).is_some_and(jwt::is_jwt) => {
+                let token = auth::bearer_token(req.headers()).unwrap_or_default();
+                Some(jwt.verify(token).await.inspect_err(rejected)?)
+            }
+            _ => config
+                .api_keys
+                .authenticate(req.headers(), config.admin_api_key.as_deref())
+                .await
+                .inspect_err(rejected)?,
+        };
+        match &identity {
+            Some(identity) => {
+                Span::current().record("caller", identity.name.as_str());
+            }
+            // With JWTs configured every caller needs a token or a key
+            None if config.jwt.is_some() => {
+                let e = ApiError::Unauthorized;
+                rejected(&e);
+                return Err(e);
+            }
+            None => {}
+        }
+        Ok(identity)
+    }
 
-                    debug!("Parsed query param: {} = {}", decoded_key, decoded_value);
-                    params.insert(decoded_key, decoded_value);
-                }
+    // The scope a JWT needs for the endpoint; API keys aren't scoped. Admin
+    // endpoints still check for the admin key unless the JWT has `admin`.
+    fn required_scope(method: &str, path: &str) -> Option<Scope> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let read = method == "GET";
+        match segments.as_slice() {
+            ["health"] | ["metrics"] | ["delivery-reports"] => None,
+            [""] | ["send"] | ["estimate"] | ["schedule", ..] | ["campaigns", ..] => {
+                Some(Scope::SmsSend)
             }
-            info!("Parsed {} query parameters", params.len());
-        } else {
-            debug!("No query string found");
+            ["jobs", ..] if read => Some(Scope::JobsRead),
+            ["jobs", ..] => Some(Scope::JobsWrite),
+            ["groups", ..] if read => Some(Scope::ContactsRead),
+            ["groups", ..] | ["optout", ..] => Some(Scope::ContactsWrite),
+            _ => Some(Scope::Admin),
         }
+    }
 
-        params
+    // ADMIN_API_KEY, or a JWT with the admin scope
+    fn require_admin(
+        headers: &http::HeaderMap,
+        caller: Option<&Identity>,
+        config: &Config,
+    ) -> Result<(), ApiError> {
+        if caller
+            .is_some_and(|identity| identity.scopes.is_some() && identity.has_scope(Scope::Admin))
+        {
+            return Ok(());
+        }
+        auth::require_admin(headers, config.admin_api_key.as_deref())
     }
 
-    async fn send_sms(
-        client: &UjumbeSmsClient,
-        phone: &str,
-        message: &str,
-        sender_id: &str,
-    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
-        info!("Attempting to send SMS to: {}", phone);
-        debug!(
-            "SMS details - Sender: {}, Message length: {}",
-            sender_id,
-            message.len()
+    // Answers a browser's preflight (an OPTIONS carrying
+    // Access-Control-Request-Method) for a known path: 204 with the path's
+    // methods, and the CORS headers only if the origin and requested method
+    // are allowed
+    fn preflight(req: &Request) -> Option<Result<Response<Body>, Error>> {
+        if req.method() != http::Method::OPTIONS {
+            return None;
+        }
+        let requested_method = req
+            .headers()
+            .get(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())?;
+        let methods = allowed_methods(route(req.uri().path()))?;
+        let config = config().ok()?;
+        let origin = req
+            .headers()
+            .get(http::header::ORIGIN)
+            .and_then(|v| v.to_str().ok());
+        let format = Format::from_accept(
+            req.headers()
+                .get(http::header::ACCEPT)
+                .and_then(|v| v.to_str().ok()),
         );
-
-        let response = client
-            .send_single_message(phone, message, sender_id)
-            .await?;
-
-        info!("SMS sent successfully to: {}", phone);
-        debug!("SMS response: {:#?}", response);
-
-        Ok(json!(response))
+        let response = response_builder(
+            StatusCode::NO_CONTENT,
+            format.content_type(),
+            &current_trace_id(),
+        )
+        .body(Body::Empty);
+        Some(response.map_err(Error::from).map(|mut response| {
+            let headers = response.headers_mut();
+            if let Ok(allow) =
+                http::HeaderValue::from_str(&format!("{}, OPTIONS", methods.join(", ")))
+            {
+                headers.insert(http::header::ALLOW, allow);
+            }
+            config
+                .cors
+                .apply_preflight(origin, requested_method, methods, headers);
+            response
+        }))
     }
 
-    #[instrument(level = "info", skip(req))]
+    // A POST carrying an Idempotency-Key runs at most once per key; duplicates
+    // within IDEMPOTENCY_TTL_SECS get the first response back
     pub async fn handler(req: Request) -> Result<Response<Body>, Error> {
-        // Generate trace ID for this request
-        let trace_id = uuid::Uuid::new_v4().to_string();
-        let span = Span::current();
-        span.record("trace_id", &trace_id);
+        // Continues the caller's trace when it sent a traceparent
+        let trace = TraceContext::from_headers(req.headers());
+        let admin_api_key = config()
+            .ok()
+            .and_then(|config| config.admin_api_key.as_deref());
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = req.uri().path(),
+            trace_id = %trace.trace_id,
+            verbose = logfilter::requested(req.headers(), admin_api_key)
+        );
+        telemetry::continue_trace(&span, &trace);
+        let hub = reporting::for_request(&req, &trace);
+        let origin = req
+            .headers()
+            .get(http::header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let handled = tracecontext::scope(
+            trace,
+            async move {
+                if let Some(response) = preflight(&req) {
+                    return response;
+                }
+                let mut response = if req.method() == http::Method::POST
+                    && req.headers().contains_key(idempotency::HEADER)
+                {
+                    handle_idempotent(req).await?
+                } else {
+                    handle(req, Caller::Unchecked).await?
+                };
+                if let Ok(config) = config() {
+                    config.cors.apply(origin.as_deref(), response.headers_mut());
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        );
+        reporting::scope(hub, deadline::scope(Some(deadline::start()), handled)).await
+    }
 
-        info!("Starting request processing with trace_id: {}", trace_id);
+    async fn handle_idempotent(req: Request) -> Result<Response<Body>, Error> {
+        let trace_id = current_trace_id();
+        let query_params = parse_query_params(req.uri().query());
+        let lang = Lang::negotiate(
+            query_params.get("lang").map(String::as_str),
+            req.headers()
+                .get(http::header::ACCEPT_LANGUAGE)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let format = Format::from_accept(
+            req.headers()
+                .get(http::header::ACCEPT)
+                .and_then(|v| v.to_str().ok()),
+        );
 
-        // Load .env variables
-        let api_key = match std::env::var("UJUMBESMS_API_KEY") {
-            Ok(key) => {
-                debug!("Successfully loaded UJUMBESMS_API_KEY");
-                key
-            }
+        let key = match idempotency::key(req.headers()) {
+            Ok(Some(key)) => key,
+            Ok(None) => return handle(req, Caller::Unchecked).await,
             Err(e) => {
-                error!("Failed to load UJUMBESMS_API_KEY: {}", e);
-                return Err(e.into());
+                warn!("Rejecting request with invalid idempotency key: {}", e);
+                return error_response(&e, lang, format, &trace_id);
             }
         };
+        // Refused before it's hashed for the fingerprint
+        if let Err(e) = check_body_size(&req, config()?.max_body_bytes) {
+            return error_response(&e, lang, format, &trace_id);
+        }
+        // A caller without a key mustn't get to reserve one
+        let caller = match authenticate(&req, config()?).await {
+            Ok(caller) => caller,
+            Err(e) => return error_response(&e, lang, format, &trace_id),
+        };
+        let body = body_bytes(req.body());
+        let fingerprint = idempotency::fingerprint(
+            req.method().as_str(),
+            req.uri().path(),
+            req.uri().query(),
+            body,
+        );
 
-        let email = match std::env::var("UJUMBESMS_EMAIL") {
-            Ok(email) => {
-                debug!("Successfully loaded UJUMBESMS_EMAIL: {}", email);
-                email
+        match idempotency::begin(&key, fingerprint).await {
+            Ok(Begin::Fresh) => {}
+            Ok(Begin::Replay(cached)) => {
+                let status = StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK);
+                let content_type = cached
+                    .content_type
+                    .as_deref()
+                    .unwrap_or(Format::Json.content_type());
+                let body = match String::from_utf8(cached.body) {
+                    Ok(text) => Body::Text(text),
+                    Err(e) => Body::Binary(e.into_bytes()),
+                };
+                return Ok(response_builder(status, content_type, &trace_id)
+                    .header("Idempotent-Replayed", "true")
+                    .body(body)?);
             }
+            Err(e) => return error_response(&e, lang, format, &trace_id),
+        }
+
+        let response = match handle(req, Caller::Authenticated(caller)).await {
+            Ok(response) => response,
             Err(e) => {
-                error!("Failed to load UJUMBESMS_EMAIL: {}", e);
-                return Err(e.into());
+                idempotency::abandon(&key).await;
+                return Err(e);
             }
         };
+        // Failures a retry could fix keep the key free for that retry
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            debug!("Releasing idempotency key {} after {}", key, status);
+            idempotency::abandon(&key).await;
+            return Ok(response);
+        }
 
-        info!("Initializing SMS client");
-        let sms_config = UjumbeSmsConfig::new(api_key, email);
-        let sms_client = match UjumbeSmsClient::new(sms_config) {
-            Ok(client) => {
-                debug!("SMS client initialized successfully");
-                client
-            }
-            Err(e) => {
-                error!("Failed to initialize SMS client: {}", e);
-                return Err(Box::new(e));
-            }
+        let (parts, body) = response.into_parts();
+        let bytes = match &body {
+            Body::Empty => Vec::new(),
+            Body::Text(text) => text.as_bytes().to_vec(),
+            Body::Binary(bytes) => bytes.clone(),
         };
+        let cached = CachedResponse {
+            status: status.as_u16(),
+            content_type: parts
+                .headers
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            body: bytes,
+        };
+        idempotency::complete(&key, fingerprint, cached).await;
+        Ok(Response::from_parts(parts, body))
+    }
+
+    // Sub-phase spans (config_load, init_sms_client, parse_body, send_sms,
+    // validate_send, precheck, provider_call) nest under this one and so
+    // carry its trace_id
+    #[instrument(
+        level = "info",
+        name = "handler",
+        skip(req, caller),
+        fields(trace_id = field::Empty, caller = field::Empty)
+    )]
+    async fn handle(mut req: Request, caller: Caller) -> Result<Response<Body>, Error> {
+        let trace_id = current_trace_id();
+        let span = Span::current();
+        span.record("trace_id", trace_id.as_str());
+
+        info!("Starting request processing with trace_id: {}", trace_id);
+        if let Some(parent) = tracecontext::current().parent_id {
+            debug!("Continuing the caller's trace from span {}", parent);
+        }
 
         // Get request info
         let path = req.uri().path().to_string();
         let method = req.method().to_string();
         let query_params = parse_query_params(req.uri().query());
+        let accept_language = req
+            .headers()
+            .get(http::header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok());
+        let lang = Lang::negotiate(
+            query_params.get("lang").map(String::as_str),
+            accept_language,
+        );
+        debug!("Responding in language: {:?}", lang);
+
+        let headers = req.headers().clone();
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let body_format = Format::from_content_type(header(http::header::CONTENT_TYPE));
+        let format = Format::from_accept(header(http::header::ACCEPT));
+        debug!(
+            "Request body format: {:?}, response format: {:?}",
+            body_format, format
+        );
 
         info!("Processing {} request for path: {}", method, path);
         if !query_params.is_empty() {
             debug!("Query parameters: {:?}", query_params);
         }
 
-        // Parse request body
-        info!("Reading request body");
-        let body_bytes = match req.into_body() {
-            Body::Binary(bytes) => {
-                debug!("Received binary body with {} bytes", bytes.len());
-                bytes
+        // Unknown paths and methods are answered before anything else runs;
+        // OPTIONS gets the methods a path allows
+        match allowed_methods(route(&path)) {
+            None => {
+                let e = ApiError::RouteNotFound { path: path.clone() };
+                return error_response(&e, lang, format, &trace_id);
+            }
+            Some(methods) if !methods.contains(&method.as_str()) => {
+                let allow = format!("{}, OPTIONS", methods.join(", "));
+                let mut response = if method == "OPTIONS" {
+                    response_builder(StatusCode::NO_CONTENT, format.content_type(), &trace_id)
+                        .body(Body::Empty)?
+                } else {
+                    warn!("{} is not allowed on {}", method, path);
+                    error_response(&ApiError::MethodNotAllowed, lang, format, &trace_id)?
+                };
+                if let Ok(allow) = http::HeaderValue::from_str(&allow) {
+                    response.headers_mut().insert(http::header::ALLOW, allow);
+                }
+                return Ok(response);
+            }
+            Some(_) => {}
+        }
+
+        // Health and metrics stay available when the instance is saturated
+        match (method.as_str(), route(&path)) {
+            ("GET", "/health") => {
+                if let (Ok(config), Ok(client)) = (config(), sms_client()) {
+                    watchdog::check(config, client).await;
+                }
+                let health = json!({
+                    "status": "ok",
+                    "maintenance": maintenance::is_enabled(),
+                    "flags": flags::current().await,
+                    "kill_switch": killswitch::is_on().await,
+                });
+                return respond(StatusCode::OK, &health, format, &trace_id);
+            }
+            // The process is up and answering
+            ("GET", "/healthz") => {
+                return respond(
+                    StatusCode::OK,
+                    &json!({ "status": "ok" }),
+                    format,
+                    &trace_id,
+                );
+            }
+            // The config loads and the linked storage answers
+            ("GET", "/readyz") => {
+                let config = config().map(|_| ()).map_err(|e| e.to_string());
+                let readiness = health::readiness(config).await;
+                let (status, state) = if readiness.ready {
+                    (StatusCode::OK, "ready")
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+                };
+                let body = json!({ "status": state, "checks": readiness.checks });
+                return respond(status, &body, format, &trace_id);
+            }
+            // The SMS gateway answers, so a monitor can tell its outages
+            // from ours
+            ("GET", "/health/provider") => {
+                let health = health::provider(sms_client()?, &config()?.provider).await;
+                let (status, state) = if health.check.ok {
+                    (StatusCode::OK, "ok")
+                } else {
+                    (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+                };
+                let mut body = serde_json::to_value(&health)?;
+                body["status"] = json!(state);
+                body["circuits"] = json!(sms_client()?.breakers());
+                return respond(status, &body, format, &trace_id);
+            }
+            // A heartbeat to alert on when the cron stops invoking us
+            ("GET", "/status") => {
+                let last_tick = heartbeat::last().await;
+                let dlq_size = match dlq::depth().await {
+                    Ok(depth) => Some(depth),
+                    Err(e) => {
+                        warn!(
+                            "Leaving the dead-letter queue size out of the status: {}",
+                            e
+                        );
+                        None
+                    }
+                };
+                let config = config()?;
+                let watch = watchdog::check(config, sms_client()?).await;
+                let status = json!({
+                    "last_tick": last_tick,
+                    "watchdog": watch,
+                    "seconds_since_last_tick": last_tick
+                        .as_ref()
+                        .map(|tick| (chrono::Utc::now() - tick.at).num_seconds()),
+                    "dlq_size": dlq_size,
+                    "provider": config.provider,
+                    "fallback_providers": config
+                        .fallback_credentials
+                        .iter()
+                        .map(|credentials| credentials.provider())
+                        .collect::<Vec<_>>(),
+                    "circuits": sms_client()?.breakers(),
+                    "kill_switch": killswitch::is_on().await,
+                    "maintenance": maintenance::is_enabled(),
+                    "trace_id": trace_id,
+                });
+                return respond(StatusCode::OK, &status, format, &trace_id);
+            }
+            ("GET", "/metrics") => {
+                return Ok(
+                    response_builder(StatusCode::OK, metrics::CONTENT_TYPE, &trace_id)
+                        .body(metrics::render().await.into())?,
+                );
             }
-            Body::Text(text) => {
-                debug!("Received text body with {} characters", text.len());
-                text.into_bytes()
+            _ => {}
+        }
+
+        let _permit = match inflight::try_acquire() {
+            Ok(permit) => permit,
+            Err(e) => return error_response(&e, lang, format, &trace_id),
+        };
+        debug!("In-flight requests: {}", inflight::in_flight());
+
+        let config = config()?;
+        // Before a signature check or the parse reads it
+        if let Err(e) = check_body_size(&req, config.max_body_bytes) {
+            return error_response(&e, lang, format, &trace_id);
+        }
+        let caller = match caller {
+            Caller::Authenticated(caller) => {
+                if let Some(identity) = &caller {
+                    span.record("caller", identity.name.as_str());
+                }
+                caller
+            }
+            Caller::Unchecked => match authenticate(&req, config).await {
+                Ok(caller) => caller,
+                Err(e) => return error_response(&e, lang, format, &trace_id),
+            },
+        };
+        if let Some(identity) = &caller {
+            info!("Authenticated as {}", identity.name);
+            if let Some(scope) = required_scope(&method, route(&path)) {
+                if let Err(e) = identity.require_scope(scope) {
+                    return error_response(&e, lang, format, &trace_id);
+                }
             }
-            Body::Empty => {
-                debug!("Received empty body");
-                Vec::new()
+            if let Err(e) = ratelimit::take(config.rate_limits.per_key, "key", &identity.name).await
+            {
+                warn!("{} is over RATE_LIMIT_PER_KEY", identity.name);
+                return error_response(&e, lang, format, &trace_id);
             }
+        }
+        // After the signature check, which covers the body as it was sent.
+        // These two read forms and CSV files themselves.
+        let body_format = match route(&path) {
+            "/delivery-reports" | "/campaigns/upload" => body_format,
+            _ => match form_as_json(&mut req) {
+                Ok(true) => {
+                    debug!("Read the form body as JSON");
+                    Format::Json
+                }
+                Ok(false) => body_format,
+                Err(e) => return error_response(&e, lang, format, &trace_id),
+            },
         };
+        let retry_policy = &config.retry;
+        debug!("Global retry policy: {:?}", retry_policy);
+        // Opts in to sending to numbers that don't look like mobiles
+        let allow_nonmobile = query_params
+            .get("allow_nonmobile")
+            .is_some_and(|value| value == "true" || value == "1");
+
+        let number_rules = &config.number_rules;
+        debug!(
+            "Loaded {} allowed and {} blocked number rules",
+            number_rules.allowed.len(),
+            number_rules.blocked.len()
+        );
+
+        match (method.as_str(), route(&path)) {
+            ("GET", "/config") => {
+                info!("Serving redacted configuration");
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected configuration request: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                return respond(StatusCode::OK, &config.redacted(), format, &trace_id);
+            }
+            ("POST", "/estimate") => {
+                let body_bytes = read_body(req.into_body());
+                let data = match parse_body::<RequestData>(body_format, &body_bytes) {
+                    Ok(data) => data,
+                    Err(e) => return error_response(&e, lang, format, &trace_id),
+                };
+                let sms_client = sms_client()?;
+                return match estimate(sms_client, config, data).await {
+                    Ok(estimate) => respond(StatusCode::OK, &estimate, format, &trace_id),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/provider/balance") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected balance request: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let sms_client = sms_client()?;
+                return match sms_client.get_balance().await {
+                    Ok(balance) => {
+                        let check = BalanceCheck::new(&balance, config.low_balance_threshold);
+                        let response = json!({
+                            "provider": check.provider,
+                            "credits": check.credits,
+                            "threshold": check.threshold,
+                            "low": check.low,
+                            "data": balance.raw,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => {
+                        error!("Failed to fetch provider balance: {}", e);
+                        error_response(&e, lang, format, &trace_id)
+                    }
+                };
+            }
+            ("POST", "/delivery-reports") => {
+                if let Some(expected) = config.delivery_callback_token.as_deref() {
+                    let token = query_params.get("token").map(String::as_str);
+                    if !token.is_some_and(|token| {
+                        auth::constant_time_eq(token.as_bytes(), expected.as_bytes())
+                    }) {
+                        warn!("Rejected delivery report without a valid token");
+                        return error_response(&ApiError::Unauthorized, lang, format, &trace_id);
+                    }
+                }
+                let content_type = header(http::header::CONTENT_TYPE).map(str::to_string);
+                let body_bytes = read_body(req.into_body());
+                let report = match delivery::parse_report(content_type.as_deref(), &body_bytes) {
+                    Ok(report) => report,
+                    Err(reason) => {
+                        warn!("Rejected delivery report: {}", reason);
+                        let e = ApiError::InvalidBody { reason };
+                        return error_response(&e, lang, format, &trace_id);
+                    }
+                };
+                return match storage::reconcile_delivery(&report).await {
+                    Ok(record) => {
+                        match &record {
+                            Some(record) => info!(
+                                "Delivery report for {}: {:?}, message {} is {}",
+                                report.message_id,
+                                report.status,
+                                record.id,
+                                record.status.as_str()
+                            ),
+                            // Still answered 200, so the provider doesn't retry
+                            None => {
+                                warn!("Delivery report for unknown message {}", report.message_id)
+                            }
+                        }
+                        let response = DeliveryReportResponse {
+                            matched: record.is_some(),
+                            message_status: record.map(|record| record.status),
+                            report,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/optout") => {
+                let body_bytes = read_body(req.into_body());
+                let result = body_format
+                    .deserialize::<OptOutRequest>(&body_bytes)
+                    .map_err(|e| ApiError::InvalidBody {
+                        reason: e.to_string(),
+                    })
+                    .and_then(|request| set_opt_out(&request.phone, true, &trace_id));
+                return match result {
+                    Ok(response) => respond(StatusCode::OK, &response, format, &trace_id),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("DELETE", subpath) if subpath.starts_with("/optout/") => {
+                let raw_phone = &subpath["/optout/".len()..];
+                let phone = urlencoding::decode(raw_phone).unwrap_or_default();
+                return match set_opt_out(&phone, false, &trace_id) {
+                    Ok(response) => respond(StatusCode::OK, &response, format, &trace_id),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/jobs") => {
+                let body_bytes = read_body(req.into_body());
+                let created = match parse_job(body_format, &body_bytes) {
+                    Ok(definition) => create_job(definition).await,
+                    Err(e) => Err(e),
+                };
+                return match created {
+                    Ok(job) => {
+                        let response = JobResponse::new(job, config, &trace_id);
+                        respond(StatusCode::CREATED, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/jobs") => {
+                let listed = match jobs::store() {
+                    Ok(store) => store.list().await.map_err(job_store_write),
+                    Err(e) => Err(e),
+                };
+                return match listed {
+                    Ok(jobs) => {
+                        let response = JobsResponse {
+                            jobs,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", subpath)
+                if subpath.starts_with("/jobs/")
+                    && (subpath.ends_with("/pause") || subpath.ends_with("/resume")) =>
+            {
+                let (id, action) = subpath["/jobs/".len()..]
+                    .rsplit_once('/')
+                    .unwrap_or_default();
+                return match set_job_paused(id, action == "pause").await {
+                    Ok(job) => {
+                        let response = JobResponse::new(job, config, &trace_id);
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            (method @ ("GET" | "PUT" | "DELETE"), subpath) if subpath.starts_with("/jobs/") => {
+                let id = subpath["/jobs/".len()..].to_string();
+                let result = match method {
+                    "GET" => get_job(&id).await,
+                    "PUT" => {
+                        let body_bytes = read_body(req.into_body());
+                        match parse_job(body_format, &body_bytes) {
+                            Ok(definition) => update_job(&id, definition).await,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    _ => {
+                        return match delete_job(&id).await {
+                            Ok(()) => Ok(response_builder(
+                                StatusCode::NO_CONTENT,
+                                format.content_type(),
+                                &trace_id,
+                            )
+                            .body(Body::Empty)?),
+                            Err(e) => error_response(&e, lang, format, &trace_id),
+                        };
+                    }
+                };
+                return match result {
+                    Ok(job) => {
+                        let response = JobResponse::new(job, config, &trace_id);
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/campaigns/upload") => {
+                let mut group = query_params.get("group").cloned().unwrap_or_default();
+                let content_type = header(http::header::CONTENT_TYPE);
+                let uploaded = if campaigns::is_csv(content_type) {
+                    let body_bytes = read_body(req.into_body());
+                    upload_recipients(config, &group, &body_bytes, lang).await
+                } else if let Some(boundary) = format::multipart_boundary(content_type) {
+                    // A form with the file, and `group` when it isn't in the query
+                    let body_bytes = read_body(req.into_body());
+                    match format::parse_multipart(&body_bytes, &boundary) {
+                        Ok(parts) => {
+                            if group.is_empty() {
+                                if let Some(part) = parts
+                                    .iter()
+                                    .find(|part| part.name == "group" && part.filename.is_none())
+                                {
+                                    group = String::from_utf8_lossy(&part.data).trim().to_string();
+                                }
+                            }
+                            match parts.iter().find(|part| part.filename.is_some()) {
+                                Some(file) => {
+                                    upload_recipients(config, &group, &file.data, lang).await
+                                }
+                                None => Err(ApiError::InvalidBody {
+                                    reason: "the form has no CSV file".to_string(),
+                                }),
+                            }
+                        }
+                        Err(reason) => Err(ApiError::InvalidBody { reason }),
+                    }
+                } else {
+                    Err(ApiError::InvalidBody {
+                        reason: format!(
+                            "expected a {} body or a multipart form with a CSV file",
+                            campaigns::CONTENT_TYPE
+                        ),
+                    })
+                };
+                return match uploaded {
+                    Ok((accepted, rejected)) => {
+                        let response = UploadResponse {
+                            group,
+                            accepted,
+                            rejected,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/groups") => {
+                let body_bytes = read_body(req.into_body());
+                let created = match parse_body::<CreateGroupRequest>(body_format, &body_bytes) {
+                    Ok(request) => create_group(request).await,
+                    Err(e) => Err(e),
+                };
+                return match created {
+                    Ok(group) => {
+                        let response = json!({ "group": group, "trace_id": trace_id });
+                        respond(StatusCode::CREATED, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/autoresponder/rules") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected auto-responder rules request: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let listed = match autoresponder::store() {
+                    Ok(store) => store.list().await.map_err(storage::unavailable),
+                    Err(e) => Err(e),
+                };
+                return match listed {
+                    Ok(rules) => {
+                        let response = json!({ "rules": rules, "trace_id": trace_id });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            (method @ ("PUT" | "DELETE"), subpath)
+                if subpath.starts_with("/autoresponder/rules/") =>
+            {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected auto-responder rule change: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let keyword = subpath["/autoresponder/rules/".len()..].to_string();
+                if method == "DELETE" {
+                    return match delete_rule(&keyword).await {
+                        Ok(()) => Ok(response_builder(
+                            StatusCode::NO_CONTENT,
+                            format.content_type(),
+                            &trace_id,
+                        )
+                        .body(Body::Empty)?),
+                        Err(e) => error_response(&e, lang, format, &trace_id),
+                    };
+                }
+                let body_bytes = read_body(req.into_body());
+                let saved = match parse_body::<RuleRequest>(body_format, &body_bytes) {
+                    Ok(request) => put_rule(&keyword, request).await,
+                    Err(e) => Err(e),
+                };
+                return match saved {
+                    Ok((rule, created)) => {
+                        let status = if created {
+                            StatusCode::CREATED
+                        } else {
+                            StatusCode::OK
+                        };
+                        let response = json!({ "rule": rule, "trace_id": trace_id });
+                        respond(status, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/groups") => {
+                let listed = match contacts::store() {
+                    Ok(store) => store.list().await.map_err(storage::unavailable),
+                    Err(e) => Err(e),
+                };
+                return match listed {
+                    Ok(groups) => {
+                        let response = GroupsResponse {
+                            groups,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", subpath)
+                if subpath.starts_with("/groups/") && subpath.ends_with("/members") =>
+            {
+                let name = &subpath["/groups/".len()..subpath.len() - "/members".len()];
+                let body_bytes = read_body(req.into_body());
+                let added = match parse_body::<AddMembersRequest>(body_format, &body_bytes)
+                    .and_then(|request| contacts::normalize_members(request.members))
+                {
+                    Ok(members) => {
+                        let count = members.len();
+                        add_members(name, members).await.map(|()| count)
+                    }
+                    Err(e) => Err(e),
+                };
+                return match added {
+                    Ok(count) => {
+                        let response = json!({
+                            "group": name,
+                            "added": count,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("DELETE", subpath)
+                if subpath.starts_with("/groups/") && subpath.contains("/members/") =>
+            {
+                let (name, raw_phone) = subpath["/groups/".len()..]
+                    .split_once("/members/")
+                    .unwrap_or_default();
+                let phone = urlencoding::decode(raw_phone).unwrap_or_default();
+                return match remove_member(name, &phone).await {
+                    Ok(removed) => {
+                        let response = json!({
+                            "group": name,
+                            "phone": phone::normalize(&phone),
+                            "removed": removed,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            (method @ ("GET" | "DELETE"), subpath) if subpath.starts_with("/groups/") => {
+                let name = &subpath["/groups/".len()..];
+                if method == "DELETE" {
+                    return match delete_group(name).await {
+                        Ok(()) => Ok(response_builder(
+                            StatusCode::NO_CONTENT,
+                            format.content_type(),
+                            &trace_id,
+                        )
+                        .body(Body::Empty)?),
+                        Err(e) => error_response(&e, lang, format, &trace_id),
+                    };
+                }
+                return match contacts::expand(name).await {
+                    Ok(members) => {
+                        let response = GroupResponse {
+                            group: name.to_string(),
+                            members,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/admin/maintenance") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected maintenance toggle: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let body_bytes = read_body(req.into_body());
+                return match body_format.deserialize::<MaintenanceRequest>(&body_bytes) {
+                    Ok(request) => {
+                        let previous = maintenance::set_enabled(request.enabled);
+                        let response = MaintenanceResponse {
+                            maintenance: request.enabled,
+                            changed: previous != request.enabled,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => {
+                        let e = ApiError::InvalidBody {
+                            reason: e.to_string(),
+                        };
+                        error_response(&e, lang, format, &trace_id)
+                    }
+                };
+            }
+            ("GET", "/admin/kill-switch") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected kill switch lookup: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let response = json!({
+                    "kill_switch": killswitch::status().await,
+                    "trace_id": trace_id,
+                });
+                return respond(StatusCode::OK, &response, format, &trace_id);
+            }
+            ("POST", "/admin/kill-switch") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected kill switch toggle: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let body_bytes = read_body(req.into_body());
+                let request = match body_format.deserialize::<KillSwitchRequest>(&body_bytes) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        let e = ApiError::InvalidBody {
+                            reason: e.to_string(),
+                        };
+                        return error_response(&e, lang, format, &trace_id);
+                    }
+                };
+                return match killswitch::set(request.enabled).await {
+                    Ok(previous) => {
+                        warn!(
+                            "Kill switch set {} by {}",
+                            if request.enabled { "on" } else { "off" },
+                            caller
+                                .as_ref()
+                                .map_or("the admin key", |caller| caller.name.as_str())
+                        );
+                        let response = KillSwitchResponse {
+                            kill_switch: killswitch::status().await,
+                            changed: previous != request.enabled,
+                            trace_id: trace_id.clone(),
+                        };
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/admin/api-keys") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected API key listing: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                return match apikeys::list().await {
+                    Ok(keys) => {
+                        let keys: Vec<Value> = keys.iter().map(api_key_json).collect();
+                        let response = json!({ "api_keys": keys, "trace_id": trace_id });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/admin/api-keys") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected API key creation: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let body_bytes = read_body(req.into_body());
+                let created = match parse_body::<CreateApiKeyRequest>(body_format, &body_bytes) {
+                    Ok(request) => apikeys::create(request.name.trim()).await,
+                    Err(e) => Err(e),
+                };
+                return match created {
+                    Ok((api_key, key)) => {
+                        info!("Issued API key {}", api_key.name);
+                        let mut response = api_key_json(&api_key);
+                        // The only time the key itself is shown
+                        response["key"] = json!(key);
+                        response["trace_id"] = json!(trace_id);
+                        respond(StatusCode::CREATED, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("DELETE", subpath) if subpath.starts_with("/admin/api-keys/") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected API key revocation: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let name = &subpath["/admin/api-keys/".len()..];
+                return match apikeys::revoke(name).await {
+                    Ok(api_key) => {
+                        info!("Revoked API key {}", api_key.name);
+                        let mut response = api_key_json(&api_key);
+                        response["trace_id"] = json!(trace_id);
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/admin/number-rules") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected number rule listing: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let patterns = |patterns: &[recipients::NumberPattern]| -> Vec<String> {
+                    patterns.iter().map(ToString::to_string).collect()
+                };
+                return match recipients::stored().await {
+                    Ok(stored) => {
+                        let response = json!({
+                            "sandbox": number_rules.sandbox,
+                            // From ALLOWED_NUMBERS, BLOCKED_NUMBERS and their files
+                            "environment": {
+                                "allowed": patterns(&number_rules.allowed),
+                                "blocked": patterns(&number_rules.blocked),
+                            },
+                            "stored": stored,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/admin/number-rules") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected number rule creation: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let body_bytes = read_body(req.into_body());
+                let added = match parse_body::<NumberRuleRequest>(body_format, &body_bytes) {
+                    Ok(request) => recipients::add(request.list, &request.pattern).await,
+                    Err(e) => Err(e),
+                };
+                return match added {
+                    Ok(rule) => {
+                        info!("Added number rule {}", rule.id());
+                        let mut response = json!(rule);
+                        response["trace_id"] = json!(trace_id);
+                        respond(StatusCode::CREATED, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("DELETE", subpath) if subpath.starts_with("/admin/number-rules/") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected number rule removal: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let (list, pattern) = subpath["/admin/number-rules/".len()..]
+                    .split_once('/')
+                    .unwrap_or_default();
+                let pattern = urlencoding::decode(pattern).unwrap_or_default();
+                let removed = match list.parse::<RuleList>() {
+                    Ok(list) => recipients::remove(list, &pattern).await,
+                    Err(_) => Err(ApiError::RouteNotFound {
+                        path: subpath.to_string(),
+                    }),
+                };
+                return match removed {
+                    Ok(()) => {
+                        info!("Removed number rule {}:{}", list, pattern);
+                        Ok(response_builder(
+                            StatusCode::NO_CONTENT,
+                            format.content_type(),
+                            &trace_id,
+                        )
+                        .body(Body::Empty)?)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/messages") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected message history listing: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let query = match message_query(&query_params) {
+                    Ok(query) => query,
+                    Err(e) => return error_response(&e, lang, format, &trace_id),
+                };
+                return match storage::find_messages(query).await {
+                    Ok(page) => {
+                        let response = json!({
+                            "messages": page.messages,
+                            "page": query.offset / query.limit + 1,
+                            "per_page": query.limit,
+                            "has_more": page.has_more,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/messages/export") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected message history export: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let exported = async {
+                    let export_format = query_params
+                        .get("format")
+                        .map(|value| value.parse::<ExportFormat>())
+                        .transpose()
+                        .map_err(|reason| ApiError::InvalidQuery { reason })?
+                        .unwrap_or_default();
+                    let query = message_query(&query_params)?;
+                    let body = export::export(query, export_format).await?;
+                    Ok::<_, ApiError>((export_format, body))
+                }
+                .await;
+                return match exported {
+                    Ok((export_format, body)) => {
+                        info!(
+                            "Exported message history as {:?}, {} bytes",
+                            export_format,
+                            body.len()
+                        );
+                        Ok(response_builder(
+                            StatusCode::OK,
+                            export_format.content_type(),
+                            &trace_id,
+                        )
+                        .header(
+                            http::header::CONTENT_DISPOSITION,
+                            format!(
+                                "attachment; filename=\"messages.{}\"",
+                                export_format.extension()
+                            ),
+                        )
+                        .body(body.into())?)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("GET", "/dlq") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected dead-letter listing: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let entries = match dlq::store() {
+                    Ok(store) => store.list().await.map_err(dlq::unavailable),
+                    Err(e) => Err(e),
+                };
+                return match entries {
+                    Ok(entries) => {
+                        let response = json!({
+                            "dead_letters": entries,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", subpath) if subpath.starts_with("/dlq/") && subpath.ends_with("/retry") => {
+                if let Err(e) = require_admin(req.headers(), caller.as_ref(), config) {
+                    warn!("Rejected dead-letter retry: {}", e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                if let Err(e) = maintenance::check() {
+                    warn!("Rejected dead-letter retry during maintenance");
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                if let Err(e) = killswitch::check().await {
+                    warn!("Rejected dead-letter retry: the kill switch is on");
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let id = subpath["/dlq/".len()..subpath.len() - "/retry".len()].to_string();
+                let sms_client = sms_client()?;
+                return match retry_dead_letter(sms_client, config, &id).await {
+                    Ok((entry, Ok(report))) => {
+                        let response = json!({
+                            "id": entry.id,
+                            "status": "sent",
+                            "data": report.raw,
+                            "trace_id": trace_id,
+                        });
+                        respond(StatusCode::OK, &response, format, &trace_id)
+                    }
+                    Ok((entry, Err(e))) => {
+                        let response = json!({
+                            "id": entry.id,
+                            "status": "failed",
+                            "dead_letter": entry,
+                            "data": error_data(&e, lang),
+                            "trace_id": trace_id,
+                        });
+                        respond(e.status(), &response, format, &trace_id)
+                    }
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            ("POST", "/schedule/batch") => {
+                if let Err(e) = maintenance::check() {
+                    warn!("Rejected batch schedule during maintenance");
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let body_bytes = read_body(req.into_body());
+                let result = match recipients::effective(&config.number_rules).await {
+                    Ok(rules) => body_format
+                        .deserialize::<BatchScheduleRequest>(&body_bytes)
+                        .map_err(|e| ApiError::InvalidBody {
+                            reason: e.to_string(),
+                        })
+                        .and_then(|request| {
+                            schedule_batch(config, &rules, request, allow_nonmobile, &trace_id)
+                        }),
+                    Err(e) => Err(e),
+                };
+                return match result {
+                    Ok(response) => respond(StatusCode::ACCEPTED, &response, format, &trace_id),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                };
+            }
+            _ => debug!("No dedicated route matched - handling as a send request"),
+        }
+
+        if let Err(e) = maintenance::check() {
+            warn!("Rejected send request during maintenance");
+            return error_response(&e, lang, format, &trace_id);
+        }
+        let flags = flags::current().await;
+
+        if let Some(window_secs) = config.replay_window_secs {
+            if let Err(e) = auth::check_replay(req.headers(), window_secs) {
+                warn!("Rejected request failing replay protection: {}", e);
+                return error_response(&e, lang, format, &trace_id);
+            }
+        }
 
-        let request_data: Option<RequestData> = if !body_bytes.is_empty() {
-            info!("Attempting to parse request body as JSON");
-            match serde_json::from_slice::<RequestData>(&body_bytes) {
+        let client_started = Instant::now();
+        let sms_client = sms_client()?;
+        debug!("SMS client ready after {:?}", client_started.elapsed());
+
+        // Only needed if this turns out to be a cron tick, but the headers
+        // go with the body
+        let cron_auth = auth::require_cron(req.headers(), config.api_keys.cron_secret.as_deref());
+
+        // Parse request body
+        let body_bytes = read_body(req.into_body());
+
+        let mut request_data: Option<RequestData> = if !body_bytes.is_empty() {
+            info!("Attempting to parse request body as {:?}", body_format);
+            let parse_span =
+                debug_span!("parse_body", format = ?body_format, bytes = body_bytes.len());
+            match parse_span.in_scope(|| body_format.deserialize::<RequestData>(&body_bytes)) {
                 Ok(data) => {
                     info!("Successfully parsed request data");
-                    debug!("Parsed request data: {:?}", data);
+                    debug!("Parsed request data: {:?}", Redacted(&data));
                     Some(data)
                 }
                 Err(e) => {
-                    warn!("Failed to parse JSON body: {}", e);
-                    // Try to parse as raw text if JSON parsing fails
+                    warn!("Failed to parse {:?} body: {}", body_format, e);
+                    // Try to parse as raw text if parsing fails
                     if let Ok(text) = String::from_utf8(body_bytes.clone()) {
                         debug!(
                             "Raw body text (first 200 chars): {}",
-                            text.chars().take(200).collect::<String>()
+                            redact::message(&text.chars().take(200).collect::<String>())
                         );
                     } else {
                         warn!("Body is not valid UTF-8");
@@ -179,45 +3442,396 @@ mod api {
             None
         };
 
+        if let Some(data) = request_data.as_mut() {
+            if let Err(e) = expand_group(data).await {
+                warn!("Rejected group send: {}", e);
+                return error_response(&e, lang, format, &trace_id);
+            }
+            if let Err(e) = parse_numbers(data) {
+                warn!("Rejected send to invalid numbers: {}", e);
+                return error_response(&e, lang, format, &trace_id);
+            }
+            // Checked once here for every send the request makes, now or
+            // scheduled, before any of them is attempted
+            if let Some(Err(reason)) = data.retry_policy.as_ref().map(|policy| policy.validate()) {
+                warn!("Rejected retry policy override: {}", reason);
+                let e = ApiError::InvalidBody { reason };
+                return error_response(&e, lang, format, &trace_id);
+            }
+        }
+
+        let mut status = StatusCode::OK;
+        let mut effective_policy = None;
+        let mut chosen_sender = None;
+        let mut number_type = None;
+        let mut estimated_delivery_seconds = None;
+        let mut delivery_status = None;
+        let mut send_attempts = None;
+        let dry_run = query_params
+            .get("dry_run")
+            .is_some_and(|value| value == "true" || value == "1")
+            || request_data.as_ref().and_then(|data| data.dry_run) == Some(true);
+        let sms_client = if dry_run && !sms_client.is_dry_run() {
+            info!("Dry run requested: sends are reported, not submitted");
+            dry_run_client()?
+        } else {
+            sms_client
+        };
+
+        // A schedule from the body wins over SMS_SCHEDULE; without either
+        // every invocation sends, as it did before schedules existed
+        let cron = match request_data.as_ref().and_then(|data| {
+            data.schedule
+                .as_deref()
+                .map(|expression| (data, expression))
+        }) {
+            Some((data, expression)) => match schedule::CronSchedule::parse(expression)
+                .map_err(|e| ApiError::InvalidBody {
+                    reason: e.to_string(),
+                })
+                .and_then(|cron| Ok(cron.with_timezone(request_timezone(config, data)?)))
+            {
+                Ok(cron) => Some(cron),
+                Err(e) => {
+                    warn!("Rejecting invalid schedule {:?}: {}", expression, e);
+                    return error_response(&e, lang, format, &trace_id);
+                }
+            },
+            None => config.schedule.clone(),
+        };
+        let schedule_info = cron.map(|cron| {
+            let now = chrono::Utc::now();
+            let window = chrono::Duration::seconds(config.schedule_window_secs as i64);
+            let due = cron.is_due(now, window);
+            info!("Schedule {} is {}due", cron, if due { "" } else { "not " });
+            ScheduleInfo {
+                expression: cron.to_string(),
+                timezone: cron.timezone().name().to_string(),
+                due,
+                next_runs: cron.upcoming(now, 5),
+            }
+        });
+        let due = schedule_info.as_ref().is_none_or(|info| info.due);
+
         // Determine response based on whether we have data or not
-        let (response_message, sms_response_data) =
-            if request_data.is_some() || !query_params.is_empty() {
-                // We have data (either in body or query params), send greeting message
-                info!("Data detected - returning greeting message");
-                ("Hello from Locci Scheduler - Data received!", None)
-            } else {
-                // No data, send SMS
-                info!("No data detected - sending default SMS");
-                let phone = "254717135176"; // Default phone or get from somewhere
-                let message = "Scheduled message from Locci Scheduler";
-                let sender_id = "UjumbeSMS";
+        // `lang`, `allow_nonmobile` and `dry_run` only tune the response and
+        // send, they aren't request data
+        let has_query_data = query_params.keys().any(|key| {
+            key != "lang" && key != "allow_nonmobile" && key != "dry_run" && key != "continue"
+        });
 
-                match send_sms(&sms_client, phone, message, sender_id).await {
-                    Ok(response) => {
-                        info!("Default SMS sent successfully");
-                        ("SMS sent successfully", Some(response))
+        // Every cron tick (a request without data) dispatches the one-off
+        // sends and job runs that have come due, whether or not SMS_SCHEDULE
+        // is. Lanes drain in priority order from one budget, so OTPs aren't
+        // stuck behind a marketing blast.
+        let is_tick = request_data.is_none() && !has_query_data;
+        if let (true, Err(e)) = (is_tick, &cron_auth) {
+            warn!("Rejected cron tick without CRON_SECRET: {}", e);
+            return error_response(e, lang, format, &trace_id);
+        }
+        // A tick that left work behind hands it on with `?continue=`
+        let continued = match query_params.get("continue").filter(|_| is_tick) {
+            Some(token) => match continuation::take(token).await {
+                Some(continued) => {
+                    info!("Picking up chunk {} of a tick", continued.chunk);
+                    Some(continued)
+                }
+                None => {
+                    let e = ApiError::InvalidQuery {
+                        reason: "continue is an unknown, expired or used token".to_string(),
+                    };
+                    return error_response(&e, lang, format, &trace_id);
+                }
+            },
+            None => None,
+        };
+        // While the kill switch is on nothing reaches a provider, but
+        // scheduled sends and jobs are still taken and wait for it to go off
+        let killed = !sms_client.is_dry_run() && killswitch::is_on().await;
+        let dispatched = if is_tick && killed {
+            warn!("Kill switch is on - leaving due sends and jobs queued");
+            let tick = Tick {
+                at: chrono::Utc::now(),
+                dispatched: 0,
+                failed: 0,
+                errors: 0,
+                killed: true,
+                duration_ms: 0,
+                gap_secs: None,
+            };
+            record_tick(sms_client, config, tick).await;
+            Some(json!({ "kill_switch": true }))
+        } else if is_tick {
+            let tick_started = Instant::now();
+            let balance = check_balance(sms_client, config).await;
+            // Work due by the tick that started the chain, so a backlog that
+            // keeps growing waits for the next tick instead
+            let due_by = continued
+                .as_ref()
+                .map_or_else(chrono::Utc::now, |continued| continued.due_by);
+            let mut budget = config.dispatch_budget;
+            let mut lanes = serde_json::Map::new();
+            let (mut sent, mut failed, mut errors) = (0, 0, 0);
+            let mut out_of_time = false;
+            for lane in Priority::LANES {
+                // Left for the next chunk rather than cut off midway
+                if deadline::running_out() {
+                    warn!(
+                        "Out of time for the {} lane; continuing in a new invocation",
+                        lane
+                    );
+                    out_of_time = true;
+                    break;
+                }
+                let sends = match dispatch_due(sms_client, config, lane, due_by, &mut budget).await
+                {
+                    Ok(summary) => summary,
+                    Err(e) => {
+                        error!("Failed to dispatch {} scheduled sends: {}", lane, e);
+                        errors += 1;
+                        error_data(&e, lang)
                     }
+                };
+                let jobs = match dispatch_jobs(sms_client, config, lane, due_by, &mut budget).await
+                {
+                    Ok(summary) => summary,
                     Err(e) => {
-                        error!("Failed to send default SMS: {}", e);
-                        ("Failed to send SMS", Some(json!({"error": e.to_string()})))
+                        error!("Failed to dispatch {} jobs: {}", lane, e);
+                        errors += 1;
+                        error_data(&e, lang)
                     }
+                };
+                for summary in [&sends, &jobs] {
+                    sent += summary["sent"].as_u64().unwrap_or(0);
+                    failed += summary["failed"].as_u64().unwrap_or(0);
                 }
+                lanes.insert(lane.to_string(), json!({ "sends": sends, "jobs": jobs }));
+            }
+            // Sends the budget ran out on midway were put back for this
+            out_of_time |= deadline::running_out();
+            let next = if budget == 0 || out_of_time {
+                let chunk = continued
+                    .as_ref()
+                    .map_or(1, |continued| continued.chunk + 1);
+                continuation::issue(due_by, chunk).await
+            } else {
+                None
+            };
+            if let (Some(next), Some(url)) = (&next, &config.dispatch_continue_url) {
+                continuation::invoke(url, config.api_keys.cron_secret.as_deref(), next).await;
+            }
+            let tick = Tick {
+                at: chrono::Utc::now(),
+                dispatched: sent,
+                failed,
+                errors,
+                killed: false,
+                duration_ms: tick_started.elapsed().as_millis() as u64,
+                gap_secs: None,
             };
+            record_tick(sms_client, config, tick).await;
+            Some(json!({
+                "budget": config.dispatch_budget,
+                "budget_left": budget,
+                "lanes": lanes,
+                "balance": balance,
+                "chunk": continued.as_ref().map_or(0, |continued| continued.chunk),
+                // What's left is sent by requesting `path`, or already being
+                // sent when DISPATCH_CONTINUE_URL is set
+                "continuation": next.map(|next| json!({
+                    "token": next.token,
+                    "path": next.path(),
+                    "due_by": next.due_by,
+                    "chunk": next.chunk,
+                    "invoked": config.dispatch_continue_url.is_some(),
+                })),
+            }))
+        } else {
+            None
+        };
+
+        let (response_message, sms_response_data) = if request_data.is_some() || has_query_data {
+            // We have data (either in body or query params), send greeting message
+            info!("Data detected - returning greeting message");
+            (
+                "Hello from Locci Scheduler - Data received!".to_string(),
+                None,
+            )
+        } else if continued.is_some() {
+            // The tick that started the chain sent it
+            (
+                "Continued dispatch - default SMS not resent".to_string(),
+                None,
+            )
+        } else if !due {
+            info!("Not in a scheduled window - skipping default SMS");
+            ("No schedule due - nothing sent".to_string(), None)
+        } else if killed {
+            ("Kill switch is on - nothing sent".to_string(), None)
+        } else {
+            // No data, send SMS
+            info!("No data detected - sending default SMS");
+            let phone = "254717135176"; // Default phone or get from somewhere
+            let message = "Scheduled message from Locci Scheduler";
+            let sender_id = pick_sender(config, None);
+
+            effective_policy = Some(retry_policy.clone());
+            chosen_sender = Some(sender_id.clone());
+            let normalized = phone::normalize(phone);
+            number_type = Some(phone::classify_number(&normalized));
+            estimated_delivery_seconds = delivery::estimate_secs(&normalized);
+            let started = Instant::now();
+            let (result, attempts) = send_sms(
+                sms_client,
+                config,
+                phone,
+                message,
+                &sender_id,
+                retry_policy,
+                allow_nonmobile,
+            )
+            .await;
+            record_send(&result, attempts, started);
+            send_attempts = Some(attempts);
+            match result {
+                Ok(report) => {
+                    info!("Default SMS sent successfully");
+                    delivery_status = Some(report.statuses).filter(|s| !s.is_empty());
+                    ("SMS sent successfully".to_string(), Some(report.raw))
+                }
+                Err(e) => {
+                    error!("Failed to send default SMS: {}", e);
+                    status = e.status();
+                    (e.message(lang), Some(error_data(&e, lang)))
+                }
+            }
+        };
 
         // If we have request data, we can also use it to send SMS with custom values
         let final_sms_data = if let Some(data) = &request_data {
-            if let (Some(phone), Some(msg)) = (&data.phone, &data.message) {
+            if let Some(send_at) = data.send_at.as_deref() {
+                let scheduled = match recipients::effective(&config.number_rules).await {
+                    Ok(rules) => schedule_send(config, &rules, data, send_at, allow_nonmobile),
+                    Err(e) => Err(e),
+                };
+                match scheduled {
+                    Ok(send) => {
+                        status = StatusCode::ACCEPTED;
+                        Some(json!(send))
+                    }
+                    Err(e) => {
+                        warn!("Rejected scheduled send: {}", e);
+                        return error_response(&e, lang, format, &trace_id);
+                    }
+                }
+            } else if killed {
+                warn!("Rejected send request: the kill switch is on");
+                let e = ApiError::FeatureDisabled { feature: "sending" };
+                return error_response(&e, lang, format, &trace_id);
+            } else if !due {
+                info!("Not in a scheduled window - skipping custom SMS");
+                sms_response_data
+            } else if let Some(recipients) = data.recipients.as_deref().filter(|r| !r.is_empty()) {
+                if let Err(e) = flags.check_bulk() {
+                    warn!("Rejected bulk send: bulk sending is switched off");
+                    return error_response(&e, lang, format, &trace_id);
+                }
+                let policy = retry_policy.for_send(data.retry_policy.as_ref());
+                if let Some(pace) = &data.pace {
+                    info!("Pacing bulk SMS to {} recipients", recipients.len());
+                    if dry_run {
+                        let e = ApiError::InvalidBody {
+                            reason: "pace can't be combined with dry_run".to_string(),
+                        };
+                        return error_response(&e, lang, format, &trace_id);
+                    }
+                    match pace_bulk(config, data, pace, allow_nonmobile, lang).await {
+                        Ok(response) => {
+                            status = StatusCode::ACCEPTED;
+                            effective_policy = Some(policy);
+                            Some(response)
+                        }
+                        Err(e) => {
+                            warn!("Rejected paced bulk send: {}", e);
+                            return error_response(&e, lang, format, &trace_id);
+                        }
+                    }
+                } else {
+                    info!("Sending bulk SMS to {} recipients", recipients.len());
+                    let response =
+                        send_bulk(sms_client, config, data, &policy, allow_nonmobile, lang).await;
+                    effective_policy = Some(policy);
+                    Some(response)
+                }
+            } else if data.pace.is_some() {
+                let e = ApiError::InvalidBody {
+                    reason: "pace needs recipients".to_string(),
+                };
+                return error_response(&e, lang, format, &trace_id);
+            } else if let (Some(phone), Some(msg)) = (&data.phone, &data.message) {
                 info!("Sending custom SMS based on request data");
-                let sender = data.sender_id.as_deref().unwrap_or("UjumbeSMS");
+                let sender = pick_sender(config, data.sender_id.as_deref());
+                let policy = retry_policy.for_send(data.retry_policy.as_ref());
+                debug!("Effective retry policy: {:?}", policy);
 
-                match send_sms(&sms_client, phone, msg, sender).await {
-                    Ok(response) => {
-                        info!("Custom SMS sent successfully to: {}", phone);
-                        Some(response)
+                let started = Instant::now();
+                let normalized = phone::normalize(phone);
+                number_type = Some(phone::classify_number(&normalized));
+                estimated_delivery_seconds = delivery::estimate_secs(&normalized);
+                let (result, attempts) = send_sms(
+                    sms_client,
+                    config,
+                    phone,
+                    msg,
+                    &sender,
+                    &policy,
+                    allow_nonmobile,
+                )
+                .await;
+                record_send(&result, attempts, started);
+                send_attempts = Some(attempts);
+                effective_policy = Some(policy);
+                chosen_sender = Some(sender);
+                match result {
+                    Ok(report) => {
+                        info!("Custom SMS sent successfully to: {}", redact::phone(phone));
+                        delivery_status = Some(report.statuses).filter(|s| !s.is_empty());
+                        Some(report.raw)
+                    }
+                    // Fails fast while the provider is down; kept for later
+                    // rather than lost
+                    Err(e @ ApiError::CircuitOpen { .. }) => {
+                        warn!(
+                            "Not sending custom SMS to {} now: {}",
+                            redact::phone(phone),
+                            e
+                        );
+                        let priority = data.priority.unwrap_or_default();
+                        match queue_for_later(
+                            phone,
+                            msg,
+                            chosen_sender.as_deref().unwrap_or_default(),
+                            priority,
+                            data.retry_policy.as_ref(),
+                            &e,
+                        ) {
+                            Ok(send) => {
+                                status = StatusCode::ACCEPTED;
+                                Some(json!(send))
+                            }
+                            Err(store_error) => {
+                                error!("Failed to queue the send for later: {}", store_error);
+                                return error_response(&e, lang, format, &trace_id);
+                            }
+                        }
                     }
                     Err(e) => {
-                        error!("Failed to send custom SMS to {}: {}", phone, e);
-                        Some(json!({"error": e.to_string()}))
+                        error!(
+                            "Failed to send custom SMS to {}: {}",
+                            redact::phone(phone),
+                            e
+                        );
+                        return error_response(&e, lang, format, &trace_id);
                     }
                 }
             } else {
@@ -235,7 +3849,7 @@ mod api {
 
         info!("Building API response");
         let api_response = ApiResponse {
-            message: response_message.to_string(),
+            message: response_message,
             data: final_sms_data,
             request_info: RequestInfo {
                 has_body_data: request_data.is_some(),
@@ -243,6 +3857,15 @@ mod api {
                 path,
                 method,
             },
+            sender_id: chosen_sender,
+            retry_policy: effective_policy,
+            schedule: schedule_info,
+            number_type,
+            estimated_delivery_seconds,
+            delivery_status,
+            attempts: send_attempts,
+            dispatched,
+            dry_run: sms_client.is_dry_run(),
             trace_id: trace_id.clone(),
         };
 
@@ -251,56 +3874,74 @@ mod api {
             trace_id
         );
 
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .header("Access-Control-Allow-Origin", "*") // Enable CORS if needed
-            .header(
-                "Access-Control-Allow-Methods",
-                "GET, POST, PUT, DELETE, OPTIONS",
-            )
-            .header(
-                "Access-Control-Allow-Headers",
-                "Content-Type, Authorization",
-            )
-            .header("X-Trace-Id", &trace_id) // Include trace ID in response headers
-            .body(match serde_json::to_string(&api_response) {
-                Ok(json_str) => {
-                    debug!("Response serialized successfully");
-                    json_str.into()
-                }
-                Err(e) => {
-                    error!("Failed to serialize response: {}", e);
-                    return Err(e.into());
-                }
-            })?)
+        respond(status, &api_response, format, &trace_id)
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
-        )
-        .with_target(true)
-        // .with_thread_ids(true)
-        // .with_file(true)
-        .with_line_number(true)
-        .init();
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-    info!("Locci Scheduler Demo server initiated...");
-    info!("Tracing initialized...");
+        #[test]
+        fn redacted_requests_hide_phones_and_messages() {
+            let data: RequestData = serde_json::from_value(json!({
+                "phone": "254717135176",
+                "message": "Your one-time code is 483920",
+                "template": "Hi {name}, your code is 483920",
+                "recipients": ["254722000111", { "phone": "254733000222" }],
+                "sender_id": "UjumbeSMS",
+            }))
+            .expect("request");
+            let logged = format!("{:?}", Redacted(&data));
 
-    match run(api::handler).await {
-        Ok(_) => {
-            info!("API server shutdown gracefully");
-            Ok(())
+            for secret in [
+                "254717135176",
+                "Your one-time code",
+                "483920",
+                "254722000111",
+                "254733000222",
+            ] {
+                assert!(!logged.contains(secret), "{} in {}", secret, logged);
+            }
+            assert!(logged.contains("2547*****176"), "{}", logged);
+            assert!(logged.contains("UjumbeSMS"), "{}", logged);
         }
-        Err(e) => {
-            error!("API server error: {}", e);
-            Err(e)
+
+        #[test]
+        fn identical_requests_serialize_identical_query_params() {
+            let info = |query| RequestInfo {
+                has_body_data: false,
+                query_params: parse_query_params(Some(query)),
+                path: "/api/handler".to_string(),
+                method: "GET".to_string(),
+            };
+            let query = "sender_id=Ujumbe&lang=sw&dry_run=true&b=2&a=1&zeta=z&mu=m";
+            let first = serde_json::to_vec(&info(query)).expect("JSON");
+            let second = serde_json::to_vec(&info(query)).expect("JSON");
+            assert_eq!(first, second);
+
+            // Keys come out sorted whatever order they were sent in
+            let reordered = serde_json::to_vec(&info(
+                "mu=m&zeta=z&a=1&b=2&dry_run=true&lang=sw&sender_id=Ujumbe",
+            ))
+            .expect("JSON");
+            assert_eq!(first, reordered);
         }
     }
 }
+
+fn main() -> Result<(), Error> {
+    // Before the runtime's threads exist, as it changes the environment
+    runtime::export_sms_proxy();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            runtime::init_tracing();
+            info!("Locci Scheduler Demo server initiated...");
+
+            // Warm up in the background; the first request waits on the same cell
+            tokio::spawn(runtime::warm_up());
+
+            runtime::serve("API server", api::handler).await
+        })
+}
@@ -0,0 +1,311 @@
+use locci_scheduler_core::runtime;
+use tracing::info;
+use vercel_runtime::Error;
+
+mod api {
+    use http::StatusCode;
+    use locci_scheduler_core::auth;
+    use locci_scheduler_core::autoresponder::{self, AutoReply};
+    use locci_scheduler_core::deadline;
+    use locci_scheduler_core::delivery;
+    use locci_scheduler_core::error::ApiError;
+    use locci_scheduler_core::format::Format;
+    use locci_scheduler_core::i18n::Lang;
+    use locci_scheduler_core::inbound::{self, InboundMessage};
+    use locci_scheduler_core::killswitch;
+    use locci_scheduler_core::logfilter;
+    use locci_scheduler_core::optout::{self, Keyword};
+    use locci_scheduler_core::providers::SmsProvider;
+    use locci_scheduler_core::recipients;
+    use locci_scheduler_core::redact;
+    use locci_scheduler_core::reporting;
+    use locci_scheduler_core::runtime::{
+        check_body_size, config, error_response, parse_query_params, read_body, respond, sms_client,
+    };
+    use locci_scheduler_core::storage::{self, MessageRecord, MessageStatus};
+    use locci_scheduler_core::telemetry;
+    use locci_scheduler_core::tracecontext::{self, TraceContext};
+    use serde::Serialize;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use tracing::{debug, error, info, info_span, warn, Instrument};
+    pub use vercel_runtime::{Body, Error, Request, Response};
+
+    /// Messages GET / returns unless `limit` asks for fewer
+    const DEFAULT_LIMIT: usize = 50;
+
+    /// Most messages GET / returns
+    const MAX_LIMIT: usize = 500;
+
+    #[derive(Serialize)]
+    struct ReceivedResponse {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        keyword: Option<Keyword>,
+        // Whether a keyword changed the sender's opt-out
+        #[serde(skip_serializing_if = "Option::is_none")]
+        opt_out_changed: Option<bool>,
+        forwarded: bool,
+        // The auto-responder rule's reply, when one was sent
+        #[serde(skip_serializing_if = "Option::is_none")]
+        replied: Option<AutoReply>,
+        trace_id: String,
+    }
+
+    // Sends the matching rule's reply back to the sender. STOP may still be
+    // confirmed, but nothing else is sent to a number that opted out. A
+    // reply that can't be sent is only logged: the message was received.
+    async fn auto_reply(message: &InboundMessage, trace_id: &str) -> Option<AutoReply> {
+        let masked = redact::phone(&message.from);
+        let reply = match autoresponder::reply_for(message).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("No auto-reply for message {}: {}", message.id, e);
+                return None;
+            }
+        };
+        if reply.keyword != "STOP"
+            && optout::store().is_ok_and(|store| store.is_opted_out(&message.from))
+        {
+            debug!("Not auto-replying to opted-out {}", masked);
+            return None;
+        }
+        let config = config().ok()?;
+        // Replies are real SMS, so the allow/block rules apply as to sends
+        match recipients::effective(&config.number_rules).await {
+            Ok(rules) if rules.check(&message.from).is_permitted() => {}
+            Ok(_) => {
+                info!(
+                    "Not auto-replying to {}: not permitted by the number rules",
+                    masked
+                );
+                return None;
+            }
+            Err(e) => {
+                warn!("Not auto-replying to {}: {}", masked, e);
+                return None;
+            }
+        }
+        if killswitch::is_on().await {
+            info!("Not auto-replying to {}: the kill switch is on", masked);
+            return None;
+        }
+        if !autoresponder::take_cooldown(&message.from).await {
+            info!("Not auto-replying to {} again so soon", masked);
+            return None;
+        }
+        let client = match sms_client() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to initialize SMS client for auto-reply: {}", e);
+                return None;
+            }
+        };
+        let sender_id = reply.sender_id.as_deref().unwrap_or(&config.default_sender);
+
+        let result = client
+            .send_single(&message.from, &reply.message, sender_id)
+            .await;
+        let mut record = MessageRecord::new(
+            message.from.clone(),
+            reply.message.clone(),
+            sender_id.to_string(),
+            MessageStatus::Sent,
+            1,
+        );
+        record.trace_id = Some(trace_id.to_string());
+        let sent = match result {
+            Ok(report) => {
+                info!("Auto-replied {} to {}", reply.keyword, masked);
+                for message_id in &report.message_ids {
+                    delivery::record_submission(message_id, &message.from);
+                }
+                record.provider = Some(report.provider.to_string());
+                record.message_ids = report.message_ids;
+                record.statuses = report.statuses;
+                record.cost = report.credits_deducted;
+                record.estimated_cost = record
+                    .segments
+                    .map(|segments| config.segment_rates.cost(report.provider, segments));
+                true
+            }
+            Err(e) => {
+                error!(
+                    "Failed to auto-reply {} to {}: {}",
+                    reply.keyword, masked, e
+                );
+                reporting::failed_send(&e);
+                record.status = MessageStatus::Failed;
+                record.error = Some(e.to_string());
+                false
+            }
+        };
+        storage::record_message(record).await;
+        sent.then_some(reply)
+    }
+
+    // The opt-out and the stored copy come before forwarding: if either
+    // fails the provider is answered with an error and retries, and a
+    // retry shouldn't reach the downstream webhook twice
+    async fn receive(
+        message: InboundMessage,
+        trace_id: &str,
+    ) -> Result<ReceivedResponse, ApiError> {
+        let masked = redact::phone(&message.from);
+        let opt_out_changed = match message.keyword {
+            Some(keyword) => {
+                let changed = optout::set(&message.from, keyword == Keyword::Stop)?;
+                info!(
+                    "Inbound {:?} from {} (changed: {})",
+                    keyword, masked, changed
+                );
+                Some(changed)
+            }
+            None => None,
+        };
+        inbound::record(message.clone()).await?;
+
+        let forwarded = match inbound::forwarder() {
+            Some(Ok(forwarder)) => match forwarder.forward(&message).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to forward inbound message {}: {}", message.id, e);
+                    false
+                }
+            },
+            Some(Err(e)) => {
+                warn!("Not forwarding inbound message {}: {}", message.id, e);
+                false
+            }
+            None => false,
+        };
+        debug!(
+            "Received inbound message {} from {} (forwarded: {})",
+            message.id, masked, forwarded
+        );
+        let replied = auto_reply(&message, trace_id).await;
+
+        Ok(ReceivedResponse {
+            id: message.id,
+            keyword: message.keyword,
+            opt_out_changed,
+            forwarded,
+            replied,
+            trace_id: trace_id.to_string(),
+        })
+    }
+
+    fn limit(params: &BTreeMap<String, String>) -> Result<usize, ApiError> {
+        match params.get("limit") {
+            None => Ok(DEFAULT_LIMIT),
+            Some(raw) => match raw.parse::<usize>() {
+                Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+                _ => Err(ApiError::InvalidQuery {
+                    reason: format!("limit must be between 1 and {}", MAX_LIMIT),
+                }),
+            },
+        }
+    }
+
+    // Providers call POST with each message they receive; GET lists the
+    // latest ones for admins
+    pub async fn handler(req: Request) -> Result<Response<Body>, Error> {
+        let trace = TraceContext::from_headers(req.headers());
+        let admin_api_key = config()
+            .ok()
+            .and_then(|config| config.admin_api_key.as_deref());
+        let span = info_span!(
+            "inbound",
+            method = %req.method(),
+            trace_id = %trace.trace_id,
+            verbose = logfilter::requested(req.headers(), admin_api_key)
+        );
+        telemetry::continue_trace(&span, &trace);
+        let hub = reporting::for_request(&req, &trace);
+        reporting::scope(
+            hub,
+            deadline::scope(
+                Some(deadline::start()),
+                tracecontext::scope(trace, handle(req).instrument(span)),
+            ),
+        )
+        .await
+    }
+
+    async fn handle(req: Request) -> Result<Response<Body>, Error> {
+        let trace_id = tracecontext::current().trace_id;
+        let params = parse_query_params(req.uri().query());
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let lang = Lang::negotiate(
+            params.get("lang").map(String::as_str),
+            header(http::header::ACCEPT_LANGUAGE),
+        );
+        let format = Format::from_accept(header(http::header::ACCEPT));
+        let config = config()?;
+        if let Err(e) = check_body_size(&req, config.max_body_bytes) {
+            return error_response(&e, lang, format, &trace_id);
+        }
+
+        match req.method().as_str() {
+            "POST" => {
+                let content_type = header(http::header::CONTENT_TYPE).map(str::to_string);
+                let provider = params
+                    .get("provider")
+                    .map(String::as_str)
+                    .unwrap_or(&config.provider);
+                let body = read_body(req.into_body());
+                let result = InboundMessage::parse(content_type.as_deref(), &body, Some(provider))
+                    .map_err(|reason| {
+                        warn!("Rejected inbound callback: {}", reason);
+                        ApiError::InvalidBody { reason }
+                    });
+                let result = match result {
+                    Ok(message) => receive(message, &trace_id).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(response) => respond(StatusCode::OK, &response, format, &trace_id),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                }
+            }
+            "GET" => {
+                let result =
+                    match auth::require_admin(req.headers(), config.admin_api_key.as_deref()) {
+                        Ok(()) => match limit(&params) {
+                            Ok(limit) => inbound::recent(limit).await,
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    };
+                match result {
+                    Ok(messages) => respond(
+                        StatusCode::OK,
+                        &json!({ "messages": messages, "trace_id": trace_id }),
+                        format,
+                        &trace_id,
+                    ),
+                    Err(e) => error_response(&e, lang, format, &trace_id),
+                }
+            }
+            _ => {
+                let e = ApiError::MethodNotAllowed;
+                error_response(&e, lang, format, &trace_id)
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    // Before the runtime's threads exist, as it changes the environment
+    runtime::export_sms_proxy();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            runtime::init_tracing();
+            info!("Inbound SMS receiver initiated...");
+
+            runtime::serve("Inbound receiver", api::handler).await
+        })
+}
@@ -0,0 +1,238 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::balance::BalanceCheck;
+use crate::budget::Budget;
+use crate::channels::{self, Channel, Notification};
+use crate::config::Config;
+use crate::providers::SmsProvider;
+use crate::recipients;
+use crate::redact;
+
+/// Most alerts one instance posts a minute; a burst of failures beyond it is
+/// summed up in the next alert that goes out
+const MAX_ALERTS_PER_MINUTE: u32 = 10;
+
+/// A send that failed for good, after its retries
+#[derive(Debug, Clone)]
+pub struct FailedSend<'a> {
+    /// `sms` or the notification channel
+    pub channel: &'a str,
+    /// Masked as it is in logs
+    pub recipient: &'a str,
+    pub attempts: u32,
+    pub error: &'a str,
+    /// Whether it went to the dead-letter queue
+    pub dead_lettered: bool,
+    pub trace_id: &'a str,
+}
+
+impl FailedSend<'_> {
+    fn text(&self, unposted: u32) -> String {
+        let mut text = format!(
+            ":rotating_light: {} to {} failed after {} attempts: {}",
+            self.channel, self.recipient, self.attempts, self.error
+        );
+        if self.dead_lettered {
+            text.push_str("\nMoved to the dead-letter queue.");
+        }
+        text.push_str(&format!("\nTrace: `{}`", self.trace_id));
+        if unposted > 0 {
+            text.push_str(&format!(
+                "\n({} more failures in the last minute weren't posted)",
+                unposted
+            ));
+        }
+        text
+    }
+}
+
+/// Alerts posted in the current minute and the failures held back
+struct Window {
+    started: Instant,
+    posted: u32,
+    unposted: u32,
+}
+
+static WINDOW: Lazy<Mutex<Window>> = Lazy::new(|| {
+    Mutex::new(Window {
+        started: Instant::now(),
+        posted: 0,
+        unposted: 0,
+    })
+});
+
+/// Takes a slot in this minute's alerts, returning how many failures were
+/// held back before it, or `None` if this one is held back too
+fn take_slot() -> Option<u32> {
+    let mut window = WINDOW.lock().unwrap_or_else(|e| e.into_inner());
+    if window.started.elapsed() >= Duration::from_secs(60) {
+        window.started = Instant::now();
+        window.posted = 0;
+    }
+    if window.posted >= MAX_ALERTS_PER_MINUTE {
+        window.unposted += 1;
+        return None;
+    }
+    window.posted += 1;
+    Some(std::mem::take(&mut window.unposted))
+}
+
+/// Mirrors a failed send to the Slack webhook at `url`. A failure to post is
+/// only logged.
+pub async fn failed_send(url: &str, failure: &FailedSend<'_>) {
+    let Some(unposted) = take_slot() else {
+        debug!(
+            "Not posting failure alert: over {} a minute",
+            MAX_ALERTS_PER_MINUTE
+        );
+        return;
+    };
+    let slack = match channels::slack() {
+        Ok(slack) => slack,
+        Err(e) => {
+            warn!("Failed to post failure alert: {}", e);
+            return;
+        }
+    };
+    let text = failure.text(unposted);
+    let notification = Notification {
+        to: url,
+        subject: None,
+        message: &text,
+        sender_id: "",
+        template: None,
+    };
+    if let Err(e) = slack.send(&notification).await {
+        warn!("Failed to post failure alert: {}", e);
+    }
+}
+
+/// Warns that the provider balance is below the threshold: on Slack when
+/// `SLACK_ALERT_WEBHOOK_URL` is set, and by SMS to `ALERT_PHONE` through
+/// `client`, sent even when that provider is the one running out. Returns
+/// whether any alert went out.
+pub async fn low_balance(config: &Config, client: &impl SmsProvider, check: &BalanceCheck) -> bool {
+    let text = format!(
+        "{} balance is {} credits, below the alert threshold of {}",
+        check.provider,
+        check.credits.unwrap_or_default(),
+        check.threshold.unwrap_or_default()
+    );
+    warn_operators(config, client, "low-balance", &text).await
+}
+
+/// Warns, the way [`low_balance`] does, that today's sends have used
+/// `spent` of the daily budget
+pub async fn budget_used(
+    config: &Config,
+    client: &impl SmsProvider,
+    budget: &Budget,
+    spent: f64,
+) -> bool {
+    let text = format!(
+        "Sends today have cost {:.2}, {:.0}% of the daily budget of {}",
+        spent,
+        spent / budget.daily_cap * 100.0,
+        budget.daily_cap
+    );
+    warn_operators(config, client, "budget", &text).await
+}
+
+/// Warns, the way [`low_balance`] does, that cron ticks have gone more than
+/// `WATCHDOG_MAX_GAP_MINS` apart: none has come since `last`, or one just
+/// came after a gap of `gap` when `resumed`
+pub async fn missed_ticks(
+    config: &Config,
+    client: &impl SmsProvider,
+    last: DateTime<Utc>,
+    gap: chrono::Duration,
+    resumed: bool,
+) -> bool {
+    let minutes = gap.num_minutes();
+    let text = if resumed {
+        format!(
+            "Cron ticks resumed after {} minutes without one, since {}: scheduled sends due in between went out late",
+            minutes,
+            last.to_rfc3339()
+        )
+    } else {
+        format!(
+            "No cron tick for {} minutes, since {}: scheduled sends and jobs aren't going out",
+            minutes,
+            last.to_rfc3339()
+        )
+    };
+    warn_operators(config, client, "watchdog", &text).await
+}
+
+async fn warn_operators(
+    config: &Config,
+    client: &impl SmsProvider,
+    kind: &str,
+    text: &str,
+) -> bool {
+    let mut alerted = false;
+    if let Some(url) = &config.slack_alert_url {
+        let message = format!(":warning: {}", text);
+        let notification = Notification {
+            to: url,
+            subject: None,
+            message: &message,
+            sender_id: "",
+            template: None,
+        };
+        match channels::slack() {
+            Ok(slack) => match slack.send(&notification).await {
+                Ok(_) => alerted = true,
+                Err(e) => warn!("Failed to post {} alert: {}", kind, e),
+            },
+            Err(e) => warn!("Failed to post {} alert: {}", kind, e),
+        }
+    }
+    if let Some(chat) = &config.alert_telegram_chat {
+        let notification = Notification {
+            to: chat,
+            subject: None,
+            message: text,
+            sender_id: "",
+            template: None,
+        };
+        match channels::telegram() {
+            Ok(telegram) => match telegram.send(&notification).await {
+                Ok(_) => alerted = true,
+                Err(e) => warn!("Failed to send {} alert to Telegram: {}", kind, e),
+            },
+            Err(e) => warn!("Failed to send {} alert to Telegram: {}", kind, e),
+        }
+    }
+    if let Some(phone) = &config.alert_phone {
+        let permitted = recipients::effective(&config.number_rules)
+            .await
+            .is_ok_and(|rules| rules.check(phone).is_permitted());
+        if !permitted {
+            warn!(
+                "Not texting {} alert to {}: not permitted by the number rules",
+                kind,
+                redact::phone(phone)
+            );
+            return alerted;
+        }
+        match client
+            .send_single(phone, text, &config.default_sender)
+            .await
+        {
+            Ok(_) => alerted = true,
+            Err(e) => warn!(
+                "Failed to text {} alert to {}: {}",
+                kind,
+                redact::phone(phone),
+                e
+            ),
+        }
+    }
+    alerted
+}
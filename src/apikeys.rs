@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::auth::{self, Identity};
+use crate::error::ApiError;
+use crate::storage::{self, unavailable};
+
+/// Longest name a key can be given
+const MAX_NAME_LEN: usize = 64;
+
+/// An API key issued through the admin endpoints. Only the key's SHA-256 is
+/// kept, so the key itself is shown once, when it's created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Who the key belongs to, recorded with every request it makes
+    pub name: String,
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    /// Set once the key is revoked; revoked keys are answered with 403
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Hex SHA-256 of a key, the form keys are compared and stored in
+pub fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Names are used in URLs and logs, so they're kept to letters, digits,
+/// `-`, `_` and `.`
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("name must be at most {} characters", MAX_NAME_LEN));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err("name may only use letters, digits, '-', '_' and '.'".to_string());
+    }
+    Ok(())
+}
+
+impl ApiKey {
+    /// A new key for `name`, returned with the key itself
+    pub fn generate(name: &str) -> Result<(Self, String), ApiError> {
+        validate_name(name).map_err(|reason| ApiError::InvalidBody { reason })?;
+        let key = format!(
+            "sk_{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let api_key = ApiKey {
+            name: name.to_string(),
+            hash: hash(&key),
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        Ok((api_key, key))
+    }
+}
+
+/// Keys that authenticate API requests, and whether one is required
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    /// From `API_KEYS`, as (name, hash)
+    pub keys: Vec<(String, String)>,
+    /// Vercel invokes the cron tick with `Authorization: Bearer <CRON_SECRET>`;
+    /// when set, ticks must carry it
+    pub cron_secret: Option<String>,
+    /// Whether requests need a key; only `REQUIRE_API_KEY=false` turns it
+    /// off, for local development
+    pub required: bool,
+}
+
+impl ApiKeys {
+    /// Reads `API_KEYS` as comma-separated `name:key` pairs, `CRON_SECRET`
+    /// and `REQUIRE_API_KEY`. Keys are required unless the latter is
+    /// `false`.
+    pub fn from_env() -> Result<Self, (&'static str, String)> {
+        let mut keys: Vec<(String, String)> = Vec::new();
+        let raw = std::env::var("API_KEYS").unwrap_or_default();
+        for pair in raw
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (name, key) = pair
+                .split_once(':')
+                .ok_or(("API_KEYS", "each key must be name:key".to_string()))?;
+            let (name, key) = (name.trim(), key.trim());
+            validate_name(name).map_err(|reason| ("API_KEYS", format!("{}: {}", name, reason)))?;
+            if key.is_empty() {
+                return Err(("API_KEYS", format!("{} has an empty key", name)));
+            }
+            if keys.iter().any(|(listed, _)| listed == name) {
+                return Err(("API_KEYS", format!("{} is listed twice", name)));
+            }
+            keys.push((name.to_string(), hash(key)));
+        }
+        let required = match std::env::var("REQUIRE_API_KEY") {
+            Ok(raw) => match raw.trim() {
+                "" | "1" | "true" => true,
+                "0" | "false" => {
+                    warn!("REQUIRE_API_KEY is off: requests without a key are let through");
+                    false
+                }
+                _ => return Err(("REQUIRE_API_KEY", "must be true or false".to_string())),
+            },
+            Err(_) => true,
+        };
+        Ok(ApiKeys {
+            keys,
+            cron_secret: std::env::var("CRON_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            required,
+        })
+    }
+
+    /// Who the request's bearer token belongs to: an `API_KEYS` name, a key
+    /// issued into storage, `admin` for `ADMIN_API_KEY` or `cron` for
+    /// `CRON_SECRET`. `None` when no key is required and none was sent.
+    pub async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        admin_api_key: Option<&str>,
+    ) -> Result<Option<Identity>, ApiError> {
+        let Some(token) = auth::bearer_token(headers) else {
+            return if self.required {
+                Err(ApiError::Unauthorized)
+            } else {
+                Ok(None)
+            };
+        };
+        let matches =
+            |expected: &str| auth::constant_time_eq(token.as_bytes(), expected.as_bytes());
+        if admin_api_key.is_some_and(matches) {
+            return Ok(Some(Identity::unrestricted("admin")));
+        }
+        if self.cron_secret.as_deref().is_some_and(matches) {
+            return Ok(Some(Identity::unrestricted("cron")));
+        }
+        let hashed = hash(token);
+        let hash_matches =
+            |expected: &str| auth::constant_time_eq(hashed.as_bytes(), expected.as_bytes());
+        if let Some((name, _)) = self.keys.iter().find(|(_, hash)| hash_matches(hash)) {
+            return Ok(Some(Identity::unrestricted(name)));
+        }
+        if let Some(Ok(storage)) = storage::backend() {
+            if let Some(key) = storage.find_api_key(&hashed).await.map_err(unavailable)? {
+                if key.revoked_at.is_some() {
+                    warn!("Rejected request with revoked API key {}", key.name);
+                    return Err(ApiError::ApiKeyRevoked { name: key.name });
+                }
+                return Ok(Some(Identity::unrestricted(&key.name)));
+            }
+        }
+        if self.required {
+            return Err(ApiError::Unauthorized);
+        }
+        // Not required, so a token this deployment doesn't know is ignored
+        Ok(None)
+    }
+}
+
+fn backend() -> Result<&'static dyn storage::Storage, ApiError> {
+    match storage::backend() {
+        Some(Ok(storage)) => Ok(storage),
+        Some(Err(reason)) => Err(ApiError::StorageUnavailable { reason }),
+        None => Err(ApiError::StorageUnavailable {
+            reason: "issuing API keys needs a storage backend".to_string(),
+        }),
+    }
+}
+
+/// Keys issued into storage, by name, revoked ones included
+pub async fn list() -> Result<Vec<ApiKey>, ApiError> {
+    backend()?.list_api_keys().await.map_err(unavailable)
+}
+
+/// Issues a key for `name`, returning it with the key itself
+pub async fn create(name: &str) -> Result<(ApiKey, String), ApiError> {
+    let (api_key, key) = ApiKey::generate(name)?;
+    if !backend()?
+        .create_api_key(api_key.clone())
+        .await
+        .map_err(unavailable)?
+    {
+        return Err(ApiError::ApiKeyExists {
+            name: name.to_string(),
+        });
+    }
+    Ok((api_key, key))
+}
+
+/// Revokes the named key. It's kept, so requests still using it get 403
+/// rather than 401.
+pub async fn revoke(name: &str) -> Result<ApiKey, ApiError> {
+    let storage = backend()?;
+    let mut api_key = storage
+        .list_api_keys()
+        .await
+        .map_err(unavailable)?
+        .into_iter()
+        .find(|key| key.name == name)
+        .ok_or_else(|| ApiError::ApiKeyNotFound {
+            name: name.to_string(),
+        })?;
+    if api_key.revoked_at.is_none() {
+        api_key.revoked_at = Some(Utc::now());
+        storage
+            .put_api_key(api_key.clone())
+            .await
+            .map_err(unavailable)?;
+    }
+    Ok(api_key)
+}
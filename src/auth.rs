@@ -0,0 +1,390 @@
+use hmac::{Hmac, Mac};
+use http::HeaderMap;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::error::ApiError;
+
+/// What a caller is allowed to do, as JWTs grant it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Scope {
+    #[serde(rename = "sms:send")]
+    SmsSend,
+    #[serde(rename = "jobs:read")]
+    JobsRead,
+    #[serde(rename = "jobs:write")]
+    JobsWrite,
+    #[serde(rename = "contacts:read")]
+    ContactsRead,
+    #[serde(rename = "contacts:write")]
+    ContactsWrite,
+    /// Every endpoint, the admin ones included
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SmsSend => "sms:send",
+            Scope::JobsRead => "jobs:read",
+            Scope::JobsWrite => "jobs:write",
+            Scope::ContactsRead => "contacts:read",
+            Scope::ContactsWrite => "contacts:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sms:send" => Ok(Scope::SmsSend),
+            "jobs:read" => Ok(Scope::JobsRead),
+            "jobs:write" => Ok(Scope::JobsWrite),
+            "contacts:read" => Ok(Scope::ContactsRead),
+            "contacts:write" => Ok(Scope::ContactsWrite),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope: {}", other)),
+        }
+    }
+}
+
+/// Who made a request, once authenticated
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// The API key's name, or a JWT's subject
+    pub name: String,
+    /// Granted by a JWT; `None` for API keys, which may use every endpoint
+    /// their route's own checks allow
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl Identity {
+    /// An identity with no scope restrictions
+    pub fn unrestricted(name: &str) -> Self {
+        Identity {
+            name: name.to_string(),
+            scopes: None,
+        }
+    }
+
+    /// Whether the identity may act with `scope`; `admin` grants every scope
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| {
+            scopes
+                .iter()
+                .any(|granted| *granted == scope || *granted == Scope::Admin)
+        })
+    }
+
+    pub fn require_scope(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.has_scope(scope) {
+            return Ok(());
+        }
+        warn!("{} lacks the {} scope", self.name, scope.as_str());
+        Err(ApiError::MissingScope {
+            scope: scope.as_str(),
+        })
+    }
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Checks that the request carries the admin token. Admin endpoints are
+/// disabled entirely when no token is configured.
+pub fn require_admin(headers: &HeaderMap, admin_api_key: Option<&str>) -> Result<(), ApiError> {
+    let expected = admin_api_key.ok_or(ApiError::AdminDisabled)?;
+
+    match bearer_token(headers) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+/// Checks that a cron tick carries `Authorization: Bearer <CRON_SECRET>`,
+/// as Vercel Cron sends it. Any caller may tick when no secret is set.
+pub fn require_cron(headers: &HeaderMap, cron_secret: Option<&str>) -> Result<(), ApiError> {
+    let Some(expected) = cron_secret else {
+        return Ok(());
+    };
+    match bearer_token(headers) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Header carrying a request's HMAC signature
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// `sha256=<hex>`, an HMAC-SHA256 with `secret` over `<timestamp>.<body>`.
+/// Our outgoing webhooks are signed the same way.
+pub fn signature(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Checks `X-Signature` against the body and `X-Timestamp`, rejecting
+/// timestamps more than `window_secs` away from `now` so a captured request
+/// can't be replayed later. Each signature is accepted once: `X-Nonce` isn't
+/// signed, so a captured request with a fresh nonce would otherwise pass the
+/// replay check inside the window.
+pub fn verify_signature_with(
+    headers: &HeaderMap,
+    body: &[u8],
+    secret: &str,
+    window_secs: u64,
+    now: u64,
+    cache: &NonceCache,
+) -> Result<(), ApiError> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let (Some(signed), Some(timestamp)) = (header(SIGNATURE_HEADER), header("x-timestamp")) else {
+        return Err(ApiError::MissingSignature);
+    };
+    let timestamp = timestamp.trim();
+    let parsed: u64 = timestamp.parse().map_err(|_| ApiError::StaleTimestamp)?;
+    if now.abs_diff(parsed) > window_secs {
+        warn!(
+            "Signed request timestamp {} is outside the ±{}s window",
+            parsed, window_secs
+        );
+        return Err(ApiError::StaleTimestamp);
+    }
+
+    let expected = signature(secret, timestamp, body);
+    if !constant_time_eq(signed.trim().as_bytes(), expected.as_bytes()) {
+        return Err(ApiError::InvalidSignature);
+    }
+    if !cache.insert(&expected, parsed, now, window_secs * 2) {
+        warn!("Rejecting reused signature for timestamp {}", parsed);
+        return Err(ApiError::NonceReused);
+    }
+    debug!("Signature check passed for timestamp: {}", timestamp);
+    Ok(())
+}
+
+/// Signature check against the instance-wide signature cache and the
+/// system clock
+pub fn verify_signature(
+    headers: &HeaderMap,
+    body: &[u8],
+    secret: &str,
+    window_secs: u64,
+) -> Result<(), ApiError> {
+    verify_signature_with(headers, body, secret, window_secs, unix_now(), &SIGNATURES)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Nonces (or signatures) seen recently, with the request timestamp they
+/// arrived with.
+/// Entries older than twice the skew window are pruned on every check, since
+/// a request carrying them would be rejected as stale anyway.
+#[derive(Debug, Default)]
+pub struct NonceCache {
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl NonceCache {
+    /// Records the nonce, returning `false` if it was already seen
+    fn insert(&self, nonce: &str, timestamp: u64, now: u64, ttl_secs: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, ts| now.abs_diff(*ts) <= ttl_secs);
+
+        if seen.contains_key(nonce) {
+            return false;
+        }
+        seen.insert(nonce.to_string(), timestamp);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+static NONCES: Lazy<NonceCache> = Lazy::new(NonceCache::default);
+
+/// Signatures already accepted, kept apart from the nonces so a nonce can't
+/// collide with one
+static SIGNATURES: Lazy<NonceCache> = Lazy::new(NonceCache::default);
+
+/// Rejects requests whose `X-Timestamp` (unix seconds) is more than
+/// `window_secs` away from `now`, or whose `X-Nonce` was already used.
+pub fn check_replay_with(
+    headers: &HeaderMap,
+    window_secs: u64,
+    now: u64,
+    cache: &NonceCache,
+) -> Result<(), ApiError> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let (Some(timestamp), Some(nonce)) = (header("x-timestamp"), header("x-nonce")) else {
+        return Err(ApiError::MissingReplayHeaders);
+    };
+    let timestamp: u64 = timestamp
+        .trim()
+        .parse()
+        .map_err(|_| ApiError::StaleTimestamp)?;
+
+    if now.abs_diff(timestamp) > window_secs {
+        warn!(
+            "Request timestamp {} is outside the ±{}s window",
+            timestamp, window_secs
+        );
+        return Err(ApiError::StaleTimestamp);
+    }
+
+    if !cache.insert(nonce.trim(), timestamp, now, window_secs * 2) {
+        warn!("Rejecting reused nonce: {}", nonce);
+        return Err(ApiError::NonceReused);
+    }
+
+    debug!("Replay check passed for nonce: {}", nonce);
+    Ok(())
+}
+
+/// Replay check against the instance-wide nonce cache and the system clock
+pub fn check_replay(headers: &HeaderMap, window_secs: u64) -> Result<(), ApiError> {
+    check_replay_with(headers, window_secs, unix_now(), &NONCES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+    const WINDOW: u64 = 300;
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (http::HeaderName::from_static(name), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn a_signature_is_accepted_once() {
+        let body = br#"{"phone":"254700000001"}"#;
+        let timestamp = NOW.to_string();
+        let signed = |nonce: &str| {
+            headers(&[
+                ("x-signature", signature("secret", &timestamp, body)),
+                ("x-timestamp", timestamp.clone()),
+                ("x-nonce", nonce.to_string()),
+            ])
+        };
+        let cache = NonceCache::default();
+
+        assert!(verify_signature_with(&signed("a"), body, "secret", 300, NOW, &cache).is_ok());
+        // A fresh nonce doesn't make a captured request new
+        assert!(matches!(
+            verify_signature_with(&signed("b"), body, "secret", 300, NOW + 1, &cache),
+            Err(ApiError::NonceReused)
+        ));
+    }
+
+    #[test]
+    fn a_wrong_signature_is_not_recorded() {
+        let body = b"{}";
+        let timestamp = NOW.to_string();
+        let forged = headers(&[
+            ("x-signature", signature("other", &timestamp, body)),
+            ("x-timestamp", timestamp.clone()),
+        ]);
+        let cache = NonceCache::default();
+
+        assert!(matches!(
+            verify_signature_with(&forged, body, "secret", 300, NOW, &cache),
+            Err(ApiError::InvalidSignature)
+        ));
+        assert!(cache.is_empty());
+    }
+
+    fn replay_headers(timestamp: u64, nonce: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-timestamp", timestamp.to_string().parse().unwrap());
+        headers.insert("x-nonce", nonce.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn stale_timestamps_are_rejected() {
+        let cache = NonceCache::default();
+        for timestamp in [NOW - WINDOW - 1, NOW + WINDOW + 1] {
+            assert!(matches!(
+                check_replay_with(&replay_headers(timestamp, "n1"), WINDOW, NOW, &cache),
+                Err(ApiError::StaleTimestamp)
+            ));
+        }
+        // Skew within the window either way is fine
+        assert!(
+            check_replay_with(&replay_headers(NOW - WINDOW, "n2"), WINDOW, NOW, &cache).is_ok()
+        );
+        assert!(
+            check_replay_with(&replay_headers(NOW + WINDOW, "n3"), WINDOW, NOW, &cache).is_ok()
+        );
+    }
+
+    #[test]
+    fn reused_nonces_are_rejected() {
+        let cache = NonceCache::default();
+        assert!(check_replay_with(&replay_headers(NOW, "once"), WINDOW, NOW, &cache).is_ok());
+        assert!(matches!(
+            check_replay_with(&replay_headers(NOW + 1, "once"), WINDOW, NOW + 1, &cache),
+            Err(ApiError::NonceReused)
+        ));
+        assert!(
+            check_replay_with(&replay_headers(NOW + 1, "other"), WINDOW, NOW + 1, &cache).is_ok()
+        );
+    }
+
+    #[test]
+    fn old_nonces_are_pruned() {
+        let cache = NonceCache::default();
+        assert!(check_replay_with(&replay_headers(NOW, "old"), WINDOW, NOW, &cache).is_ok());
+        assert_eq!(cache.len(), 1);
+
+        let later = NOW + 2 * WINDOW + 1;
+        assert!(check_replay_with(&replay_headers(later, "new"), WINDOW, later, &cache).is_ok());
+        assert_eq!(cache.len(), 1, "the entry past 2 * window is evicted");
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::error::ApiError;
+use crate::inbound::InboundMessage;
+use crate::kv;
+use crate::optout::Keyword;
+use crate::senders;
+use crate::storage::{self, Storage};
+use crate::templates;
+
+/// Longest keyword a rule can answer
+const MAX_KEYWORD_LEN: usize = 20;
+
+/// A number gets at most one automatic reply this often, so two
+/// auto-responders can't keep answering each other
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+/// An automatic reply to messages leading with `keyword`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    /// Uppercase, e.g. `HELP`
+    pub keyword: String,
+    /// Template rendered with the message's `from`, `text` and `keyword`,
+    /// and `args`, the words after the keyword
+    pub reply: String,
+    /// Sends with the default sender when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Keywords are matched case-insensitively and stored uppercase; they're
+/// single words of letters and digits
+pub fn normalize_keyword(keyword: &str) -> Result<String, ApiError> {
+    let invalid = |reason: &str| ApiError::InvalidBody {
+        reason: format!("keyword {}", reason),
+    };
+    let keyword = keyword.trim().to_ascii_uppercase();
+    if keyword.is_empty() {
+        return Err(invalid("must not be empty"));
+    }
+    if keyword.len() > MAX_KEYWORD_LEN {
+        return Err(invalid("must be at most 20 characters"));
+    }
+    if !keyword.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(invalid("may only use letters and digits"));
+    }
+    Ok(keyword)
+}
+
+fn vars(from: &str, text: &str, keyword: &str) -> BTreeMap<String, String> {
+    let args = text
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, args)| args.trim());
+    BTreeMap::from([
+        ("from".to_string(), from.to_string()),
+        ("text".to_string(), text.to_string()),
+        ("keyword".to_string(), keyword.to_string()),
+        ("args".to_string(), args.to_string()),
+    ])
+}
+
+impl Rule {
+    /// Checks the reply renders and the sender ID is one providers accept
+    pub fn new(keyword: &str, reply: String, sender_id: Option<String>) -> Result<Self, ApiError> {
+        let keyword = normalize_keyword(keyword)?;
+        if reply.trim().is_empty() {
+            return Err(ApiError::InvalidBody {
+                reason: "reply must not be empty".to_string(),
+            });
+        }
+        templates::render(&reply, &vars("", "", &keyword)).map_err(|e| ApiError::InvalidBody {
+            reason: format!("reply: {}", e),
+        })?;
+        if let Some(sender_id) = &sender_id {
+            senders::validate_sender_id(sender_id).map_err(|reason| ApiError::InvalidSenderId {
+                sender_id: sender_id.clone(),
+                reason,
+            })?;
+        }
+        Ok(Rule {
+            keyword,
+            reply,
+            sender_id,
+            updated_at: Utc::now(),
+        })
+    }
+}
+
+/// Where rules are kept: the storage backend when one is configured, this
+/// instance's memory otherwise
+pub enum RuleStore {
+    Memory(Mutex<BTreeMap<String, Rule>>),
+    Database(&'static dyn Storage),
+}
+
+fn lock(
+    rules: &Mutex<BTreeMap<String, Rule>>,
+) -> std::sync::MutexGuard<'_, BTreeMap<String, Rule>> {
+    rules.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl RuleStore {
+    /// By keyword
+    pub async fn list(&self) -> io::Result<Vec<Rule>> {
+        match self {
+            RuleStore::Memory(rules) => Ok(lock(rules).values().cloned().collect()),
+            RuleStore::Database(storage) => storage.list_autoreplies().await,
+        }
+    }
+
+    /// Adds or replaces the keyword's rule, returning `true` if it's new
+    pub async fn put(&self, rule: Rule) -> io::Result<bool> {
+        match self {
+            RuleStore::Memory(rules) => {
+                Ok(lock(rules).insert(rule.keyword.clone(), rule).is_none())
+            }
+            RuleStore::Database(storage) => storage.put_autoreply(rule).await,
+        }
+    }
+
+    /// Returns `false` if the keyword had no rule
+    pub async fn delete(&self, keyword: &str) -> io::Result<bool> {
+        match self {
+            RuleStore::Memory(rules) => Ok(lock(rules).remove(keyword).is_some()),
+            RuleStore::Database(storage) => storage.delete_autoreply(keyword).await,
+        }
+    }
+}
+
+// As with the other stores, a misconfigured backend is kept as an error
+// rather than replaced by memory other instances can't see
+static STORE: Lazy<Result<RuleStore, String>> = Lazy::new(|| {
+    if let Some(backend) = storage::backend() {
+        return backend.map(RuleStore::Database);
+    }
+    info!("Keeping auto-responder rules in memory");
+    Ok(RuleStore::Memory(Mutex::new(BTreeMap::new())))
+});
+
+pub fn store() -> Result<&'static RuleStore, ApiError> {
+    STORE
+        .as_ref()
+        .map_err(|reason| ApiError::StorageUnavailable {
+            reason: reason.clone(),
+        })
+}
+
+/// The keyword a message is answered by: STOP and START for their
+/// synonyms, otherwise the message's first word
+fn keyword_of(message: &InboundMessage) -> Option<String> {
+    match message.keyword {
+        Some(Keyword::Stop) => Some("STOP".to_string()),
+        Some(Keyword::Start) => Some("START".to_string()),
+        None => normalize_keyword(message.text.split_whitespace().next()?).ok(),
+    }
+}
+
+/// A reply a rule makes to an inbound message
+#[derive(Debug, Clone, Serialize)]
+pub struct AutoReply {
+    pub keyword: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<String>,
+}
+
+/// The reply the matching rule makes to the message, if any rule matches
+pub async fn reply_for(message: &InboundMessage) -> Result<Option<AutoReply>, ApiError> {
+    let Some(keyword) = keyword_of(message) else {
+        return Ok(None);
+    };
+    let rules = store()?.list().await.map_err(storage::unavailable)?;
+    let Some(rule) = rules.into_iter().find(|rule| rule.keyword == keyword) else {
+        return Ok(None);
+    };
+    let text = templates::render(&rule.reply, &vars(&message.from, &message.text, &keyword))
+        .map_err(|e| ApiError::InvalidBody {
+            reason: format!("rule {} reply: {}", keyword, e),
+        })?;
+    Ok(Some(AutoReply {
+        keyword,
+        message: text,
+        sender_id: rule.sender_id,
+    }))
+}
+
+static RECENT: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether the number may get an automatic reply now, counting this one
+/// against its cooldown. Shared through Vercel KV when it's linked, per
+/// instance otherwise.
+pub async fn take_cooldown(phone: &str) -> bool {
+    if let Some(client) = kv::client() {
+        let key = format!("autoreply:{}", phone);
+        match client.map_err(io::Error::other) {
+            Ok(client) => match client.set_nx(&key, "1", COOLDOWN).await {
+                Ok(taken) => return taken,
+                Err(e) => warn!("Auto-reply cooldown check failed, using memory: {}", e),
+            },
+            Err(e) => warn!("Auto-reply cooldown check failed, using memory: {}", e),
+        }
+    }
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent.retain(|_, replied| replied.elapsed() < COOLDOWN);
+    if recent.contains_key(phone) {
+        return false;
+    }
+    recent.insert(phone.to_string(), Instant::now());
+    true
+}
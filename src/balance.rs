@@ -0,0 +1,54 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::kv;
+use crate::providers::Balance;
+
+/// The provider balance and how it compares to `LOW_BALANCE_THRESHOLD`
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceCheck {
+    pub provider: &'static str,
+    /// `None` when the provider's answer carried no amount
+    pub credits: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    pub low: bool,
+}
+
+impl BalanceCheck {
+    pub fn new(balance: &Balance, threshold: Option<f64>) -> Self {
+        BalanceCheck {
+            provider: balance.provider,
+            credits: balance.credits,
+            threshold,
+            low: matches!((balance.credits, threshold), (Some(credits), Some(threshold)) if credits < threshold),
+        }
+    }
+}
+
+static LAST_CHECK: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// Whether a cron tick should check the balance now, counting this check
+/// against the interval. Shared through Vercel KV when it's linked, so a
+/// deployment checks once an interval rather than once per instance.
+pub async fn take_check(interval: Duration) -> bool {
+    if let Some(client) = kv::client() {
+        match client.map_err(io::Error::other) {
+            Ok(client) => match client.set_nx("balance:check", "1", interval).await {
+                Ok(taken) => return taken,
+                Err(e) => warn!("Balance check schedule unavailable, using memory: {}", e),
+            },
+            Err(e) => warn!("Balance check schedule unavailable, using memory: {}", e),
+        }
+    }
+    let mut last = LAST_CHECK.lock().unwrap_or_else(|e| e.into_inner());
+    if last.is_some_and(|checked| checked.elapsed() < interval) {
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
@@ -0,0 +1,182 @@
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::error::ApiError;
+use crate::kv;
+
+/// How long a day's total is kept in KV, long enough to outlast the day
+const COUNTER_TTL: Duration = Duration::from_secs(2 * 86_400);
+
+/// A cap on what sends may cost in a UTC day, in the units of
+/// `SEGMENT_RATES`
+#[derive(Debug, Clone, Serialize)]
+pub struct Budget {
+    /// From `DAILY_BUDGET`
+    pub daily_cap: f64,
+    /// Share of the cap that triggers an alert, from `DAILY_BUDGET_ALERT_PERCENT`
+    pub alert_percent: f64,
+    /// Set by `DAILY_BUDGET_OVERRIDE` in an emergency: sends past the cap go
+    /// out, still counted and alerted on
+    pub overridden: bool,
+}
+
+impl Budget {
+    /// Reads `DAILY_BUDGET`, `DAILY_BUDGET_ALERT_PERCENT` (80 by default) and
+    /// `DAILY_BUDGET_OVERRIDE`; `None` when no cap is set
+    pub fn from_env() -> Result<Option<Self>, (&'static str, String)> {
+        let daily_cap = match std::env::var("DAILY_BUDGET") {
+            Ok(raw) if !raw.trim().is_empty() => match raw.trim().parse::<f64>() {
+                Ok(cap) if cap.is_finite() && cap > 0.0 => cap,
+                _ => return Err(("DAILY_BUDGET", "must be a positive amount".to_string())),
+            },
+            _ => return Ok(None),
+        };
+        let alert_percent = match std::env::var("DAILY_BUDGET_ALERT_PERCENT") {
+            Ok(raw) if !raw.trim().is_empty() => match raw.trim().parse::<f64>() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => percent,
+                _ => {
+                    return Err((
+                        "DAILY_BUDGET_ALERT_PERCENT",
+                        "must be a percentage above 0, up to 100".to_string(),
+                    ))
+                }
+            },
+            _ => 80.0,
+        };
+        Ok(Some(Budget {
+            daily_cap,
+            alert_percent,
+            overridden: matches!(
+                std::env::var("DAILY_BUDGET_OVERRIDE").as_deref(),
+                Ok("1") | Ok("true")
+            ),
+        }))
+    }
+
+    fn alert_at(&self) -> f64 {
+        self.daily_cap * self.alert_percent / 100.0
+    }
+
+    /// Refuses a send costing `estimate` that would take today past the cap,
+    /// unless the budget is overridden
+    pub async fn check(&self, estimate: f64) -> Result<(), ApiError> {
+        let spent = spent_today().await;
+        if spent + estimate <= self.daily_cap {
+            return Ok(());
+        }
+        if self.overridden {
+            warn!(
+                "Sending past the daily budget of {} as DAILY_BUDGET_OVERRIDE is set",
+                self.daily_cap
+            );
+            return Ok(());
+        }
+        Err(ApiError::BudgetExceeded {
+            spent,
+            budget: self.daily_cap,
+            retry_after_secs: secs_until_midnight(),
+        })
+    }
+
+    /// Counts `cost` against today, returning the new total when it's what
+    /// crossed the alert threshold
+    pub async fn record(&self, cost: f64) -> Option<f64> {
+        if cost <= 0.0 {
+            return None;
+        }
+        let spent = add_today(cost).await;
+        let threshold = self.alert_at();
+        (spent - cost < threshold && spent >= threshold).then_some(spent)
+    }
+}
+
+/// Today's total when KV isn't linked or fails
+static MEMORY: Lazy<Mutex<(NaiveDate, f64)>> =
+    Lazy::new(|| Mutex::new((Utc::now().date_naive(), 0.0)));
+
+fn counter_key(day: NaiveDate) -> String {
+    format!("budget:{}", day.format("%Y-%m-%d"))
+}
+
+fn secs_until_midnight() -> u64 {
+    let now = Utc::now();
+    let midnight = (now.date_naive() + chrono::Days::new(1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .and_utc();
+    (midnight - now).num_seconds().max(1) as u64
+}
+
+fn memory(add: f64) -> f64 {
+    let today = Utc::now().date_naive();
+    let mut total = MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    if total.0 != today {
+        *total = (today, 0.0);
+    }
+    total.1 += add;
+    total.1
+}
+
+fn parse_total(value: &serde_json::Value) -> io::Result<f64> {
+    match value {
+        serde_json::Value::Null => Ok(0.0),
+        serde_json::Value::String(raw) => raw.parse().map_err(io::Error::other),
+        other => other
+            .as_f64()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad budget total")),
+    }
+}
+
+/// What sends have cost so far today. Shared through Vercel KV when it's
+/// linked, so every instance counts against the same budget.
+pub async fn spent_today() -> f64 {
+    if let Some(client) = kv::client() {
+        let key = counter_key(Utc::now().date_naive());
+        let result = match client.map_err(io::Error::other) {
+            Ok(client) => match client.command(&["GET", &key]).await {
+                Ok(value) => parse_total(&value),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(spent) => return spent,
+            Err(e) => warn!("Budget counter unavailable, using memory: {}", e),
+        }
+    }
+    memory(0.0)
+}
+
+async fn add_today(cost: f64) -> f64 {
+    if let Some(client) = kv::client() {
+        let key = counter_key(Utc::now().date_naive());
+        let amount = cost.to_string();
+        let ttl = COUNTER_TTL.as_millis().to_string();
+        let result = match client.map_err(io::Error::other) {
+            Ok(client) => match client
+                .pipeline(&[
+                    &["INCRBYFLOAT", &key, &amount],
+                    &["PEXPIRE", &key, &ttl, "NX"],
+                ])
+                .await
+            {
+                Ok(replies) => replies
+                    .first()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no reply"))
+                    .and_then(parse_total),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(spent) => return spent,
+            Err(e) => warn!("Budget counter unavailable, using memory: {}", e),
+        }
+    }
+    memory(cost)
+}
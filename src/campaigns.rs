@@ -0,0 +1,170 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::contacts::Contact;
+use crate::phone;
+
+pub const CONTENT_TYPE: &str = "text/csv";
+
+/// Most recipient rows one upload may carry
+const MAX_ROWS: usize = 10_000;
+
+/// A recipient read from an upload, with the spreadsheet row it came from
+/// (the header is row 1)
+#[derive(Debug, Clone)]
+pub struct UploadRow {
+    pub row: usize,
+    pub contact: Contact,
+}
+
+/// A row left out of the upload, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedRow {
+    pub row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedUpload {
+    pub rows: Vec<UploadRow>,
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// Whether a `Content-Type` is CSV, whatever its parameters
+pub fn is_csv(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(CONTENT_TYPE))
+}
+
+/// Splits CSV text into records of fields: commas separate fields, double
+/// quotes wrap fields holding commas, quotes or line breaks, and `""` is a
+/// quote inside them. Blank lines are skipped.
+fn records(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                let done = std::mem::take(&mut record);
+                if done.iter().any(|field| !field.is_empty()) {
+                    records.push(done);
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    record.push(field);
+    if record.iter().any(|field| !field.is_empty()) {
+        records.push(record);
+    }
+    records
+}
+
+/// Reads recipients from CSV with a header row. A `phone` column is
+/// required and `name` is optional; every other column becomes a template
+/// variable named after its header. Rows without a phone, with the wrong
+/// number of fields, or repeating an earlier row's number are rejected.
+pub fn parse_csv(text: &str) -> Result<ParsedUpload, String> {
+    // Spreadsheet exports often start with a byte order mark
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = records(text).into_iter();
+    let header: Vec<String> = records
+        .next()
+        .ok_or("the upload is empty")?
+        .into_iter()
+        .map(|column| column.trim().to_string())
+        .collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(name))
+    };
+    let phone_column = column("phone").ok_or("the header has no phone column")?;
+    let name_column = column("name");
+
+    let mut upload = ParsedUpload::default();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (index, fields) in records.enumerate() {
+        let row = index + 2;
+        if index >= MAX_ROWS {
+            return Err(format!("an upload may have at most {} rows", MAX_ROWS));
+        }
+        let raw_phone = fields
+            .get(phone_column)
+            .map(|phone| phone.trim().to_string())
+            .filter(|phone| !phone.is_empty());
+        let mut reject = |reason: String| {
+            upload.rejected.push(RejectedRow {
+                row,
+                phone: raw_phone.clone(),
+                reason,
+            })
+        };
+        if fields.len() != header.len() {
+            reject(format!(
+                "has {} fields, the header has {}",
+                fields.len(),
+                header.len()
+            ));
+            continue;
+        }
+        let Some(raw) = raw_phone.as_deref() else {
+            reject("has no phone".to_string());
+            continue;
+        };
+        let phone = match phone::parse(raw) {
+            Ok(phone) if phone::classify_number(&phone) == phone::NumberType::Shortcode => {
+                reject("is a short code, not a phone number".to_string());
+                continue;
+            }
+            Ok(phone) => phone,
+            Err(reason) => {
+                reject(reason);
+                continue;
+            }
+        };
+        if let Some(first) = seen.get(&phone) {
+            reject(format!("repeats the number in row {}", first));
+            continue;
+        }
+        seen.insert(phone.clone(), row);
+
+        let mut name = None;
+        let mut attributes = BTreeMap::new();
+        for (index, value) in fields.into_iter().enumerate() {
+            let value = value.trim().to_string();
+            if index == phone_column || header[index].is_empty() {
+                continue;
+            }
+            if Some(index) == name_column {
+                name = Some(value).filter(|name| !name.is_empty());
+            } else {
+                attributes.insert(header[index].clone(), value);
+            }
+        }
+        upload.rows.push(UploadRow {
+            row,
+            contact: Contact {
+                phone,
+                name,
+                attributes,
+            },
+        });
+    }
+    Ok(upload)
+}
@@ -0,0 +1,120 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Channel, Notification};
+use crate::error::ApiError;
+use crate::providers::SendReport;
+
+const RESEND_URL: &str = "https://api.resend.com";
+
+/// Timeout for one call to Resend
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Email through Resend's HTTP API
+pub struct ResendChannel {
+    http: reqwest::Client,
+    api_key: String,
+    /// e.g. `Acme <alerts@acme.example>`
+    from: String,
+}
+
+/// `POST /emails` response
+#[derive(Debug, Deserialize)]
+struct ResendResponse {
+    id: String,
+}
+
+impl ResendChannel {
+    pub fn new(api_key: String, from: String) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(ResendChannel {
+            http,
+            api_key,
+            from,
+        })
+    }
+}
+
+impl Channel for ResendChannel {
+    fn name(&self) -> &'static str {
+        "resend"
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "resend",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let response = self
+            .http
+            .post(format!("{}/emails", RESEND_URL))
+            .bearer_auth(&self.api_key)
+            .json(&json!({
+                "from": self.from,
+                "to": [notification.to],
+                "subject": notification.subject.unwrap_or_default(),
+                "text": notification.message,
+            }))
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| crate::retry::parse_retry_after(value, chrono::Utc::now()));
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        if !status.is_success() {
+            let reason = format!(
+                "HTTP {}: {}",
+                status.as_u16(),
+                body.chars().take(256).collect::<String>()
+            );
+            let rate_limited = status == StatusCode::TOO_MANY_REQUESTS;
+            let retry_after = retry_after.filter(|_| rate_limited);
+            return Err(failed(
+                reason,
+                retry_after,
+                rate_limited || status.is_server_error(),
+            ));
+        }
+        let raw: Value =
+            serde_json::from_str(&body).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(body.chars().take(1024).collect()),
+                parse_error: format!("{} (HTTP {})", e, status),
+            })?;
+        let parsed: ResendResponse =
+            serde_json::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+        debug!("Resend accepted email {}", parsed.id);
+
+        Ok(SendReport {
+            provider: "resend",
+            message_ids: vec![parsed.id],
+            recipients: Some(1.0),
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw,
+        })
+    }
+}
@@ -0,0 +1,252 @@
+pub mod email;
+pub mod slack;
+pub mod telegram;
+pub mod webhook;
+pub mod whatsapp;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use tracing::{error, info};
+
+use crate::config;
+use crate::error::ApiError;
+use crate::providers::{AnyProvider, SendReport, SmsProvider};
+use crate::redact;
+
+/// How a notification reaches its recipient
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    /// Through the configured SMS provider
+    #[default]
+    Sms,
+    /// Through Resend, from `EMAIL_FROM`
+    Email,
+    /// A signed JSON POST to the recipient URL
+    Webhook,
+    /// From the bot `TELEGRAM_BOT_TOKEN` is for, free where SMS costs money
+    Telegram,
+    /// Posted to the Slack incoming webhook given as the recipient
+    Slack,
+    /// Through Meta's WhatsApp Cloud API, to the job's phone
+    #[serde(rename = "whatsapp")]
+    WhatsApp,
+}
+
+impl ChannelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelKind::Sms => "sms",
+            ChannelKind::Email => "email",
+            ChannelKind::Webhook => "webhook",
+            ChannelKind::Telegram => "telegram",
+            ChannelKind::Slack => "slack",
+            ChannelKind::WhatsApp => "whatsapp",
+        }
+    }
+
+    /// Whether recipients are phone numbers, given as `phone` rather than `to`
+    pub fn is_phone(&self) -> bool {
+        matches!(self, ChannelKind::Sms | ChannelKind::WhatsApp)
+    }
+
+    /// Checks a recipient for this channel, returning it normalized. Phone
+    /// numbers are left to `phone::normalize` and the send checks.
+    pub fn validate_recipient(&self, to: &str) -> Result<String, String> {
+        let to = to.trim();
+        match self {
+            ChannelKind::Sms | ChannelKind::WhatsApp => Ok(to.to_string()),
+            ChannelKind::Email => {
+                let address = to.split_once('@').filter(|(local, domain)| {
+                    !local.is_empty()
+                        && domain.contains('.')
+                        && !domain.starts_with('.')
+                        && !domain.ends_with('.')
+                        && !domain.contains('@')
+                        && !to.chars().any(char::is_whitespace)
+                });
+                match address {
+                    // Only the domain is case-insensitive
+                    Some((local, domain)) => {
+                        Ok(format!("{}@{}", local, domain.to_ascii_lowercase()))
+                    }
+                    None => Err(format!("{} is not an email address", to)),
+                }
+            }
+            ChannelKind::Webhook => {
+                if !to.starts_with("https://") && !to.starts_with("http://") {
+                    return Err("a webhook recipient must be an http(s) URL".to_string());
+                }
+                Ok(to.to_string())
+            }
+            ChannelKind::Slack => {
+                if !to.starts_with("https://") {
+                    return Err("a Slack recipient must be an incoming webhook URL".to_string());
+                }
+                Ok(to.to_string())
+            }
+            ChannelKind::Telegram => {
+                let valid = match to.strip_prefix('@') {
+                    Some(username) => {
+                        username.len() >= 5
+                            && username
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    }
+                    None => to.strip_prefix('-').unwrap_or(to).parse::<u64>().is_ok(),
+                };
+                if !valid {
+                    return Err(format!(
+                        "{} is not a Telegram chat id or @channel username",
+                        to
+                    ));
+                }
+                Ok(to.to_string())
+            }
+        }
+    }
+}
+
+impl ChannelKind {
+    /// The recipient as it may appear in logs and alerts: webhook URLs can
+    /// carry secrets, so only their host is kept
+    pub fn masked_recipient(&self, to: &str) -> String {
+        match self {
+            ChannelKind::Sms | ChannelKind::WhatsApp => redact::mask_phone(to),
+            ChannelKind::Email => config::mask_email(to),
+            ChannelKind::Webhook | ChannelKind::Slack => reqwest::Url::parse(to)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "****".to_string()),
+            ChannelKind::Telegram => to.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One notification for `Channel::send`
+#[derive(Debug, Clone, Copy)]
+pub struct Notification<'a> {
+    /// Phone number, email address or webhook URL, as the channel expects
+    pub to: &'a str,
+    /// Email subject, or a first line on Telegram; SMS has none
+    pub subject: Option<&'a str>,
+    pub message: &'a str,
+    /// SMS sender ID; other channels send from their own configuration
+    pub sender_id: &'a str,
+    /// WhatsApp template to send in place of `message`
+    pub template: Option<&'a whatsapp::Template>,
+}
+
+/// A way of delivering a notification. SMS goes through `SmsProvider`;
+/// other channels report their outcome in a `SendReport` with the
+/// channel's name as its provider.
+pub trait Channel: Send + Sync {
+    /// Short identifier used in logs, metrics and reports
+    fn name(&self) -> &'static str;
+
+    fn send(
+        &self,
+        notification: &Notification<'_>,
+    ) -> impl Future<Output = Result<SendReport, ApiError>> + Send;
+}
+
+impl Channel for AnyProvider {
+    fn name(&self) -> &'static str {
+        SmsProvider::name(self)
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        self.send_single(
+            notification.to,
+            notification.message,
+            notification.sender_id,
+        )
+        .await
+    }
+}
+
+fn unavailable(channel: ChannelKind) -> impl Fn(&String) -> ApiError {
+    move |reason| ApiError::ChannelUnavailable {
+        channel: channel.as_str(),
+        reason: reason.clone(),
+    }
+}
+
+static EMAIL: Lazy<Result<email::ResendChannel, String>> = Lazy::new(|| {
+    let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let (Some(api_key), Some(from)) = (var("RESEND_API_KEY"), var("EMAIL_FROM")) else {
+        return Err("RESEND_API_KEY and EMAIL_FROM must be set".to_string());
+    };
+    email::ResendChannel::new(api_key, from.clone())
+        .inspect(|_| info!("Sending email through Resend from: {}", from))
+        .inspect_err(|e| error!("Invalid email channel configuration: {}", e))
+});
+
+/// The email channel, or why it can't be used
+pub fn email() -> Result<&'static email::ResendChannel, ApiError> {
+    EMAIL.as_ref().map_err(unavailable(ChannelKind::Email))
+}
+
+static WEBHOOK: Lazy<Result<webhook::WebhookChannel, String>> = Lazy::new(|| {
+    match std::env::var("WEBHOOK_SIGNING_SECRET") {
+        Ok(secret) if !secret.is_empty() => webhook::WebhookChannel::new(secret)
+            .inspect_err(|e| error!("Invalid webhook channel configuration: {}", e)),
+        // Receivers couldn't tell our posts from anyone else's
+        _ => Err("WEBHOOK_SIGNING_SECRET must be set".to_string()),
+    }
+});
+
+/// The webhook channel, or why it can't be used
+pub fn webhook() -> Result<&'static webhook::WebhookChannel, ApiError> {
+    WEBHOOK.as_ref().map_err(unavailable(ChannelKind::Webhook))
+}
+
+static TELEGRAM: Lazy<Result<telegram::TelegramChannel, String>> =
+    Lazy::new(|| match std::env::var("TELEGRAM_BOT_TOKEN") {
+        Ok(token) if !token.is_empty() => telegram::TelegramChannel::new(token)
+            .inspect(|_| info!("Sending Telegram messages through the Bot API"))
+            .inspect_err(|e| error!("Invalid Telegram channel configuration: {}", e)),
+        _ => Err("TELEGRAM_BOT_TOKEN must be set".to_string()),
+    });
+
+/// The Telegram channel, or why it can't be used
+pub fn telegram() -> Result<&'static telegram::TelegramChannel, ApiError> {
+    TELEGRAM
+        .as_ref()
+        .map_err(unavailable(ChannelKind::Telegram))
+}
+
+static SLACK: Lazy<Result<slack::SlackChannel, String>> = Lazy::new(|| {
+    slack::SlackChannel::new().inspect_err(|e| error!("Failed to set up Slack channel: {}", e))
+});
+
+/// The Slack channel; it needs no configuration of its own
+pub fn slack() -> Result<&'static slack::SlackChannel, ApiError> {
+    SLACK.as_ref().map_err(unavailable(ChannelKind::Slack))
+}
+
+static WHATSAPP: Lazy<Result<whatsapp::WhatsAppChannel, String>> = Lazy::new(|| {
+    let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let (Some(token), Some(phone_number_id)) = (
+        var("WHATSAPP_ACCESS_TOKEN"),
+        var("WHATSAPP_PHONE_NUMBER_ID"),
+    ) else {
+        return Err("WHATSAPP_ACCESS_TOKEN and WHATSAPP_PHONE_NUMBER_ID must be set".to_string());
+    };
+    whatsapp::WhatsAppChannel::new(token, phone_number_id)
+        .inspect_err(|e| error!("Invalid WhatsApp channel configuration: {}", e))
+});
+
+/// The WhatsApp channel, or why it can't be used
+pub fn whatsapp() -> Result<&'static whatsapp::WhatsAppChannel, ApiError> {
+    WHATSAPP
+        .as_ref()
+        .map_err(unavailable(ChannelKind::WhatsApp))
+}
@@ -0,0 +1,91 @@
+use serde_json::json;
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Channel, Notification};
+use crate::error::ApiError;
+use crate::providers::SendReport;
+
+/// Timeout for one post to Slack
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages posted to Slack incoming webhooks. The recipient is the
+/// webhook URL, which picks the workspace and channel.
+pub struct SlackChannel {
+    http: reqwest::Client,
+}
+
+impl SlackChannel {
+    pub fn new() -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(SlackChannel { http })
+    }
+}
+
+impl Channel for SlackChannel {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "slack",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let text = match notification.subject {
+            Some(subject) => format!("*{}*\n{}", subject, notification.message),
+            None => notification.message.to_string(),
+        };
+        // The webhook URL is a secret, so it's left out of errors
+        let response = self
+            .http
+            .post(notification.to)
+            .json(&json!({ "text": text }))
+            .send()
+            .await
+            .map_err(|e| failed(e.without_url().to_string(), None, true))?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| crate::retry::parse_retry_after(value, chrono::Utc::now()));
+        // Slack answers `ok`, or an error name such as `channel_not_found`
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.without_url().to_string(), None, true))?;
+        if !status.is_success() {
+            let rate_limited = status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+            return Err(failed(
+                format!(
+                    "HTTP {}: {}",
+                    status.as_u16(),
+                    body.chars().take(256).collect::<String>()
+                ),
+                retry_after.filter(|_| rate_limited),
+                rate_limited || status.is_server_error(),
+            ));
+        }
+        debug!("Slack accepted message: {}", body);
+
+        Ok(SendReport {
+            provider: "slack",
+            message_ids: Vec::new(),
+            recipients: Some(1.0),
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw: json!({ "status": status.as_u16(), "body": body }),
+        })
+    }
+}
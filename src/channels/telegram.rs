@@ -0,0 +1,132 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Channel, Notification};
+use crate::error::ApiError;
+use crate::providers::SendReport;
+
+const API_URL: &str = "https://api.telegram.org";
+
+/// Timeout for one call to the Bot API
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages from a Telegram bot through the Bot API's `sendMessage`. The
+/// recipient is a chat id, or `@username` for a public channel.
+pub struct TelegramChannel {
+    http: reqwest::Client,
+    bot_token: String,
+}
+
+/// Every Bot API response: `result` when `ok`, otherwise `description`
+#[derive(Debug, Deserialize)]
+struct TelegramResponse {
+    ok: bool,
+    #[serde(default)]
+    result: Option<TelegramMessage>,
+    #[serde(default)]
+    error_code: Option<u16>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    parameters: Option<TelegramParameters>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramMessage {
+    message_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramParameters {
+    /// Seconds to wait after hitting a flood limit
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+impl TelegramChannel {
+    pub fn new(bot_token: String) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(TelegramChannel { http, bot_token })
+    }
+}
+
+impl Channel for TelegramChannel {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "telegram",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let text = match notification.subject {
+            Some(subject) => format!("{}\n\n{}", subject, notification.message),
+            None => notification.message.to_string(),
+        };
+        // The token is part of the URL, so it's left out of errors
+        let response = self
+            .http
+            .post(format!("{}/bot{}/sendMessage", API_URL, self.bot_token))
+            .json(&json!({ "chat_id": notification.to, "text": text }))
+            .send()
+            .await
+            .map_err(|e| failed(e.without_url().to_string(), None, true))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.without_url().to_string(), None, true))?;
+        let raw: Value =
+            serde_json::from_str(&body).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(body.chars().take(1024).collect()),
+                parse_error: format!("{} (HTTP {})", e, status),
+            })?;
+        let parsed: TelegramResponse =
+            serde_json::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+
+        let message = match parsed.result {
+            Some(message) if parsed.ok => message,
+            _ => {
+                let code = parsed.error_code.unwrap_or(status.as_u16());
+                let retry_after = parsed
+                    .parameters
+                    .and_then(|parameters| parameters.retry_after)
+                    .map(Duration::from_secs);
+                let reason = format!(
+                    "{} {}",
+                    code,
+                    parsed
+                        .description
+                        .unwrap_or_else(|| "request failed".to_string())
+                );
+                return Err(failed(reason, retry_after, code == 429 || code >= 500));
+            }
+        };
+        debug!("Telegram sent message {}", message.message_id);
+
+        Ok(SendReport {
+            provider: "telegram",
+            message_ids: vec![message.message_id.to_string()],
+            recipients: Some(1.0),
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw,
+        })
+    }
+}
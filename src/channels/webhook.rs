@@ -0,0 +1,96 @@
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Channel, Notification};
+use crate::auth;
+use crate::error::ApiError;
+use crate::providers::SendReport;
+
+/// Timeout for one delivery to a webhook
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Notifications POSTed as JSON to the recipient URL. Each carries the
+/// `X-Timestamp` and `X-Nonce` headers our own signed routes expect and
+/// `X-Signature: sha256=<hex>`, an HMAC-SHA256 with `WEBHOOK_SIGNING_SECRET`
+/// over `<timestamp>.<body>`.
+pub struct WebhookChannel {
+    http: reqwest::Client,
+    secret: String,
+}
+
+impl WebhookChannel {
+    pub fn new(secret: String) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(WebhookChannel { http, secret })
+    }
+
+    /// The `X-Signature` value for a body sent at `timestamp`
+    pub fn sign(&self, timestamp: i64, body: &[u8]) -> String {
+        auth::signature(&self.secret, &timestamp.to_string(), body)
+    }
+}
+
+impl Channel for WebhookChannel {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        let failed = |reason: String, transient: bool| ApiError::ProviderFailed {
+            provider: "webhook",
+            reason,
+            retry_after: None,
+            transient,
+        };
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let sent_at = chrono::Utc::now();
+        let mut payload = json!({
+            "id": id,
+            "message": notification.message,
+            "sent_at": sent_at,
+        });
+        if let (Some(subject), Value::Object(fields)) = (notification.subject, &mut payload) {
+            fields.insert("subject".to_string(), Value::from(subject));
+        }
+        let body = serde_json::to_vec(&payload).map_err(|e| failed(e.to_string(), false))?;
+        let timestamp = sent_at.timestamp();
+
+        let response = self
+            .http
+            .post(notification.to)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Nonce", &id)
+            .header("X-Signature", self.sign(timestamp, &body))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string(), true))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            // Receivers that are down or overloaded may take it later; other
+            // refusals will be refused again
+            return Err(failed(
+                format!("webhook returned HTTP {}", status.as_u16()),
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            ));
+        }
+        debug!("Webhook accepted notification {}", id);
+
+        Ok(SendReport {
+            provider: "webhook",
+            message_ids: vec![id],
+            recipients: Some(1.0),
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw: json!({ "status": status.as_u16() }),
+        })
+    }
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Channel, Notification};
+use crate::error::ApiError;
+use crate::providers::SendReport;
+
+const GRAPH_URL: &str = "https://graph.facebook.com/v20.0";
+
+/// Timeout for one call to the Cloud API
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An approved message template, required to start a conversation outside
+/// the 24 hours after the recipient last wrote to us
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    pub name: String,
+    /// e.g. `en_US`
+    pub language: String,
+    /// Values for the body's `{{1}}`, `{{2}}`… in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<String>,
+}
+
+/// WhatsApp messages through Meta's Cloud API, from the business number
+/// `WHATSAPP_PHONE_NUMBER_ID`. Recipients are phone numbers. A template
+/// message is sent when the notification names one, a free-form session
+/// message otherwise.
+pub struct WhatsAppChannel {
+    http: reqwest::Client,
+    access_token: String,
+    phone_number_id: String,
+}
+
+/// `POST /{phone-number-id}/messages` response
+#[derive(Debug, Deserialize)]
+struct CloudResponse {
+    #[serde(default)]
+    messages: Vec<CloudMessage>,
+    #[serde(default)]
+    error: Option<CloudError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudMessage {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudError {
+    #[serde(default)]
+    code: Option<u32>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl CloudError {
+    // Throttling and temporary outages; anything else, such as a session
+    // message outside the 24-hour window, fails the same way again
+    fn is_transient(&self) -> bool {
+        matches!(self.code, Some(2 | 4 | 80007 | 130429 | 131016 | 131056))
+    }
+}
+
+impl WhatsAppChannel {
+    pub fn new(access_token: String, phone_number_id: String) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(WhatsAppChannel {
+            http,
+            access_token,
+            phone_number_id,
+        })
+    }
+
+    fn payload(notification: &Notification<'_>) -> Value {
+        let to = notification.to.trim_start_matches('+');
+        match notification.template {
+            Some(template) => {
+                let parameters: Vec<Value> = template
+                    .params
+                    .iter()
+                    .map(|text| json!({ "type": "text", "text": text }))
+                    .collect();
+                let mut body = json!({
+                    "name": template.name,
+                    "language": { "code": template.language },
+                });
+                if !parameters.is_empty() {
+                    body["components"] = json!([{ "type": "body", "parameters": parameters }]);
+                }
+                json!({
+                    "messaging_product": "whatsapp",
+                    "to": to,
+                    "type": "template",
+                    "template": body,
+                })
+            }
+            None => json!({
+                "messaging_product": "whatsapp",
+                "to": to,
+                "type": "text",
+                "text": { "body": notification.message },
+            }),
+        }
+    }
+}
+
+impl Channel for WhatsAppChannel {
+    fn name(&self) -> &'static str {
+        "whatsapp"
+    }
+
+    async fn send(&self, notification: &Notification<'_>) -> Result<SendReport, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "whatsapp",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let response = self
+            .http
+            .post(format!("{}/{}/messages", GRAPH_URL, self.phone_number_id))
+            .bearer_auth(&self.access_token)
+            .json(&Self::payload(notification))
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| crate::retry::parse_retry_after(value, chrono::Utc::now()));
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+        let raw: Value =
+            serde_json::from_str(&body).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(body.chars().take(1024).collect()),
+                parse_error: format!("{} (HTTP {})", e, status),
+            })?;
+        let parsed: CloudResponse =
+            serde_json::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+
+        if let Some(error) = parsed.error.filter(|_| !status.is_success()) {
+            let reason = format!(
+                "{} {}",
+                error.code.map(|code| code.to_string()).unwrap_or_default(),
+                error.message.as_deref().unwrap_or("request failed")
+            );
+            let transient = error.is_transient() || status.is_server_error();
+            return Err(failed(reason.trim().to_string(), retry_after, transient));
+        }
+        if !status.is_success() {
+            return Err(failed(
+                format!("HTTP {}", status.as_u16()),
+                None,
+                status.is_server_error(),
+            ));
+        }
+        let message_ids: Vec<String> = parsed.messages.into_iter().map(|m| m.id).collect();
+        debug!("WhatsApp accepted messages {:?}", message_ids);
+
+        Ok(SendReport {
+            provider: "whatsapp",
+            message_ids,
+            recipients: Some(1.0),
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw,
+        })
+    }
+}
@@ -0,0 +1,857 @@
+use http::{HeaderName, HeaderValue};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::{debug, error};
+
+use crate::apikeys::ApiKeys;
+use crate::budget::Budget;
+use crate::config_file;
+use crate::cors::Cors;
+use crate::cost::{Rates, SegmentLimit};
+use crate::jobs::CatchUpPolicy;
+use crate::jwt::JwtConfig;
+use crate::precheck::Precheck;
+use crate::providers::breaker::BreakerPolicy;
+use crate::ratelimit::RateLimits;
+use crate::recipients::NumberRules;
+use crate::retry::RetryPolicy;
+use crate::schedule::{self, CronSchedule};
+use crate::senders::{SenderPool, WeightedSender};
+
+/// Effective configuration of a running instance, loaded from the environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub credentials: ProviderCredentials,
+    /// Name of the provider the credentials are for, e.g. `ujumbe`
+    pub provider: String,
+    /// Providers tried in order when `credentials`' fails, from the rest of
+    /// `SMS_PROVIDERS`
+    pub fallback_credentials: Vec<ProviderCredentials>,
+    /// How long each provider call gets before it's abandoned and the next
+    /// in the chain is tried, from `PROVIDER_TIMEOUT_SECS`
+    pub provider_timeout: std::time::Duration,
+    /// When a failing provider's breaker opens and sends to it fail fast,
+    /// from the `CIRCUIT_*` settings; `None` when `CIRCUIT_FAILURE_PERCENT`
+    /// is 0
+    pub circuit_breaker: Option<BreakerPolicy>,
+    /// From `DRY_RUN`: every send goes through the pipeline and is recorded,
+    /// but is only reported, never submitted to a provider
+    pub dry_run: bool,
+    /// Phone prefixes and the provider sends to them go through first, from
+    /// `SMS_ROUTES`; the empty prefix is the `*` catch-all
+    pub routes: Vec<(String, ProviderCredentials)>,
+    pub default_sender: String,
+    pub sender_pool: SenderPool,
+    pub retry: RetryPolicy,
+    pub number_rules: NumberRules,
+    /// Bearer token for the admin endpoints; they are disabled when unset
+    pub admin_api_key: Option<String>,
+    /// Keys the rest of the API accepts, from `API_KEYS`
+    pub api_keys: ApiKeys,
+    /// How bearer JWTs are verified, when `JWT_SECRET` or `JWT_JWKS_URL` is
+    /// set; requests then need a token or key
+    pub jwt: Option<JwtConfig>,
+    /// Token buckets per caller and per destination number
+    pub rate_limits: RateLimits,
+    /// Token delivery report callbacks must carry as `?token=`, from
+    /// `DELIVERY_CALLBACK_TOKEN`; any caller is accepted when unset
+    pub delivery_callback_token: Option<String>,
+    /// Allowed clock skew for `X-Timestamp`; replay protection is off when unset
+    pub replay_window_secs: Option<u64>,
+    /// Shared secret requests may be signed with instead of carrying an API
+    /// key, from `REQUEST_SIGNING_SECRET`
+    pub signing_secret: Option<String>,
+    /// How far a signed request's `X-Timestamp` may be from our clock, from
+    /// `SIGNATURE_WINDOW_SECS`
+    pub signature_window_secs: u64,
+    /// Extra headers added to every response, from `RESPONSE_HEADERS`
+    pub response_headers: Vec<(HeaderName, HeaderValue)>,
+    /// External check every send must pass, when `PRECHECK_URL` is set
+    pub precheck: Option<Precheck>,
+    /// Cron schedule gating the default send, from `SMS_SCHEDULE`
+    pub schedule: Option<CronSchedule>,
+    /// Zone schedules and offset-less `send_at` times are read in unless a
+    /// request names its own, from `SCHEDULE_TIMEZONE`
+    pub timezone: chrono_tz::Tz,
+    /// How late an invocation may arrive and still count as on schedule
+    pub schedule_window_secs: u64,
+    /// What happens to job runs later than the window, from `CATCH_UP_POLICY`
+    pub catch_up: CatchUpPolicy,
+    /// Most scheduled sends and job runs one tick dispatches across all
+    /// priority lanes, from `DISPATCH_BUDGET`
+    pub dispatch_budget: usize,
+    /// Most sends a bulk request runs at once
+    pub bulk_concurrency: usize,
+    /// Largest request body accepted, from `MAX_BODY_BYTES`; bigger ones are
+    /// refused with a 413 before they're parsed
+    pub max_body_bytes: usize,
+    /// Most scheduled sends and job runs a tick sends at once, from
+    /// `DISPATCH_CONCURRENCY`
+    pub dispatch_concurrency: usize,
+    /// This deployment's address, from `DISPATCH_CONTINUE_URL`; a tick that
+    /// leaves due work behind requests it to send the rest straight away
+    pub dispatch_continue_url: Option<String>,
+    /// Slack incoming webhook that failed sends are mirrored to, from
+    /// `SLACK_ALERT_WEBHOOK_URL`
+    pub slack_alert_url: Option<String>,
+    /// Number texted when the provider balance runs low, from `ALERT_PHONE`
+    pub alert_phone: Option<String>,
+    /// Telegram chat the same alerts go to through the `TELEGRAM_BOT_TOKEN`
+    /// bot, from `ALERT_TELEGRAM_CHAT`
+    pub alert_telegram_chat: Option<String>,
+    /// Longest cron ticks may be apart before the watchdog alerts, from
+    /// `WATCHDOG_MAX_GAP_MINS`; unset turns it off
+    pub watchdog_max_gap: Option<std::time::Duration>,
+    /// Credits below which cron ticks raise a low-balance alert, from
+    /// `LOW_BALANCE_THRESHOLD`; the balance isn't checked when unset
+    pub low_balance_threshold: Option<f64>,
+    /// How often the balance is checked, from `BALANCE_CHECK_INTERVAL_MINS`
+    pub balance_check_interval: std::time::Duration,
+    /// Cap on what a day's sends may cost, from `DAILY_BUDGET`; unlimited
+    /// when unset
+    pub budget: Option<Budget>,
+    /// Per-segment prices sends are estimated and recorded at
+    pub segment_rates: Rates,
+    /// From `MAX_SEGMENTS` and `SEGMENT_LIMIT_ACTION`; any length is sent
+    /// when unset
+    pub segment_limit: Option<SegmentLimit>,
+    /// Country numbers without a country code are read in, from
+    /// `DEFAULT_COUNTRY`
+    pub default_country: phonenumber::country::Id,
+    /// Browser origins allowed to call the API
+    pub cors: Cors,
+}
+
+/// Credentials for the provider selected by `SMS_PROVIDER`
+#[derive(Debug, Clone)]
+pub enum ProviderCredentials {
+    Ujumbe {
+        api_key: String,
+        email: String,
+    },
+    Twilio {
+        account_sid: String,
+        auth_token: String,
+        messaging_service_sid: Option<String>,
+    },
+    AfricasTalking {
+        username: String,
+        api_key: String,
+        /// Send through the sandbox simulator instead of live
+        sandbox: bool,
+    },
+    /// Sends nothing, for tests and local runs; see `MockSmsProvider`
+    Mock,
+}
+
+impl ProviderCredentials {
+    /// Reads the credentials of `SMS_PROVIDER`: `ujumbe` by default,
+    /// `twilio`, `africastalking` or `mock`
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let provider = std::env::var("SMS_PROVIDER").unwrap_or_else(|_| "ujumbe".to_string());
+        Self::for_provider(&provider, "SMS_PROVIDER")
+    }
+
+    /// Reads the credentials of each provider in `SMS_PROVIDERS`, e.g.
+    /// `ujumbe,twilio`, in order; just `SMS_PROVIDER`'s when it's unset
+    pub fn chain_from_env() -> Result<Vec<Self>, ConfigError> {
+        let raw = match std::env::var("SMS_PROVIDERS") {
+            Ok(raw) if !raw.trim().is_empty() => raw,
+            _ => return Ok(vec![Self::from_env()?]),
+        };
+        let mut chain: Vec<Self> = Vec::new();
+        let mut problems = Problems::default();
+        for name in raw
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            // Carry on past a provider missing credentials, so they're all
+            // reported together
+            let Some(credentials) = problems.check(Self::for_provider(name, "SMS_PROVIDERS"))
+            else {
+                continue;
+            };
+            if chain
+                .iter()
+                .any(|listed| listed.provider() == credentials.provider())
+            {
+                error!("SMS_PROVIDERS lists {} twice", name);
+                return Err(ConfigError::Invalid {
+                    key: "SMS_PROVIDERS",
+                    reason: format!("{} is listed twice", name),
+                });
+            }
+            chain.push(credentials);
+        }
+        problems.finish()?;
+        Ok(chain)
+    }
+
+    /// Reads `SMS_ROUTES`, e.g. `+254=ujumbe,255=africastalking,*=twilio`,
+    /// with the credentials of each provider named
+    pub fn routes_from_env() -> Result<Vec<(String, Self)>, ConfigError> {
+        let raw = match std::env::var("SMS_ROUTES") {
+            Ok(raw) if !raw.trim().is_empty() => raw,
+            _ => return Ok(Vec::new()),
+        };
+        let invalid = |reason: String| {
+            error!("Invalid SMS_ROUTES: {}", reason);
+            ConfigError::Invalid {
+                key: "SMS_ROUTES",
+                reason,
+            }
+        };
+        let mut routes: Vec<(String, Self)> = Vec::new();
+        let mut problems = Problems::default();
+        for rule in raw
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let (prefix, provider) = rule
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("{} is not prefix=provider", rule)))?;
+            let prefix = match prefix.trim() {
+                "*" => String::new(),
+                prefix => {
+                    let digits = prefix.trim_start_matches('+');
+                    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                        return Err(invalid(format!("{} is not a phone prefix or *", prefix)));
+                    }
+                    digits.to_string()
+                }
+            };
+            if routes.iter().any(|(listed, _)| *listed == prefix) {
+                return Err(invalid(format!("{} is routed twice", rule)));
+            }
+            if let Some(credentials) = problems.check(Self::for_provider(provider, "SMS_ROUTES")) {
+                routes.push((prefix, credentials));
+            }
+        }
+        problems.finish()?;
+        Ok(routes)
+    }
+
+    fn for_provider(provider: &str, key: &'static str) -> Result<Self, ConfigError> {
+        match provider.trim().to_ascii_lowercase().as_str() {
+            "" | "ujumbe" => {
+                let (api_key, email) = required_pair("UJUMBESMS_API_KEY", "UJUMBESMS_EMAIL")?;
+                Ok(ProviderCredentials::Ujumbe { api_key, email })
+            }
+            "twilio" => {
+                let (account_sid, auth_token) =
+                    required_pair("TWILIO_ACCOUNT_SID", "TWILIO_AUTH_TOKEN")?;
+                Ok(ProviderCredentials::Twilio {
+                    account_sid,
+                    auth_token,
+                    messaging_service_sid: std::env::var("TWILIO_MESSAGING_SERVICE_SID")
+                        .ok()
+                        .filter(|sid| !sid.is_empty()),
+                })
+            }
+            "africastalking" => {
+                let (username, api_key) =
+                    required_pair("AFRICASTALKING_USERNAME", "AFRICASTALKING_API_KEY")?;
+                Ok(ProviderCredentials::AfricasTalking {
+                    username,
+                    api_key,
+                    sandbox: matches!(
+                        std::env::var("AFRICASTALKING_SANDBOX").as_deref(),
+                        Ok("1") | Ok("true")
+                    ),
+                })
+            }
+            "mock" => Ok(ProviderCredentials::Mock),
+            other => {
+                error!("Unknown provider in {}: {}", key, other);
+                Err(ConfigError::Invalid {
+                    key,
+                    reason: format!(
+                        "unknown provider {}, expected ujumbe, twilio, africastalking or mock",
+                        other
+                    ),
+                })
+            }
+        }
+    }
+
+    pub fn provider(&self) -> &'static str {
+        match self {
+            ProviderCredentials::Ujumbe { .. } => "ujumbe",
+            ProviderCredentials::Twilio { .. } => "twilio",
+            ProviderCredentials::AfricasTalking { .. } => "africastalking",
+            ProviderCredentials::Mock => "mock",
+        }
+    }
+
+    fn redacted(&self) -> BTreeMap<&'static str, String> {
+        match self {
+            ProviderCredentials::Ujumbe { api_key, email } => BTreeMap::from([
+                ("api_key", mask_secret(api_key)),
+                ("email", mask_email(email)),
+            ]),
+            ProviderCredentials::Twilio {
+                account_sid,
+                auth_token,
+                messaging_service_sid,
+            } => {
+                let mut redacted = BTreeMap::from([
+                    ("account_sid", mask_secret(account_sid)),
+                    ("auth_token", mask_secret(auth_token)),
+                ]);
+                if let Some(sid) = messaging_service_sid {
+                    redacted.insert("messaging_service_sid", mask_secret(sid));
+                }
+                redacted
+            }
+            ProviderCredentials::AfricasTalking {
+                username,
+                api_key,
+                sandbox,
+            } => BTreeMap::from([
+                ("username", username.clone()),
+                ("api_key", mask_secret(api_key)),
+                ("sandbox", sandbox.to_string()),
+            ]),
+            ProviderCredentials::Mock => BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str, std::env::VarError),
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+    /// Everything wrong with the environment, when there's more than one thing
+    Several(Vec<ConfigError>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Missing(key, e) => write!(f, "{}: {}", key, e),
+            ConfigError::Invalid { key, reason } => write!(f, "invalid {}: {}", key, reason),
+            ConfigError::Several(problems) => {
+                write!(f, "{} configuration problems: ", problems.len())?;
+                for (i, problem) in problems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Problems found while loading, kept so the load can carry on and report
+/// them all at once rather than stop at the first
+#[derive(Debug, Default)]
+struct Problems(Vec<ConfigError>);
+
+impl Problems {
+    fn check<T>(&mut self, result: Result<T, ConfigError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(ConfigError::Several(problems)) => {
+                self.0.extend(problems);
+                None
+            }
+            Err(problem) => {
+                self.0.push(problem);
+                None
+            }
+        }
+    }
+
+    /// The value, or `fallback` with the problem noted. Fallbacks never
+    /// escape: `finish` fails the load if anything was noted.
+    fn or<T>(&mut self, result: Result<T, ConfigError>, fallback: T) -> T {
+        self.check(result).unwrap_or(fallback)
+    }
+
+    fn or_default<T: Default>(&mut self, result: Result<T, ConfigError>) -> T {
+        self.check(result).unwrap_or_default()
+    }
+
+    fn finish(self) -> Result<(), ConfigError> {
+        let mut problems = self.0;
+        // A provider both chained and routed to is read twice
+        problems.dedup_by(|a, b| a.to_string() == b.to_string());
+        match problems.len() {
+            0 => Ok(()),
+            1 => Err(problems.remove(0)),
+            _ => Err(ConfigError::Several(problems)),
+        }
+    }
+}
+
+/// Logs a section parser's `(key, reason)` and makes it a `ConfigError`
+fn invalid((key, reason): (&'static str, String)) -> ConfigError {
+    error!("Invalid {}: {}", key, reason);
+    ConfigError::Invalid { key, reason }
+}
+
+/// `key` as a whole number of at least `min`; `None` when unset
+fn number(key: &'static str, min: u64) -> Result<Option<u64>, ConfigError> {
+    match std::env::var(key) {
+        Ok(raw) if !raw.trim().is_empty() => match raw.trim().parse::<u64>() {
+            Ok(n) if n >= min => Ok(Some(n)),
+            _ => Err(invalid((
+                key,
+                format!("{} is not a whole number of at least {}", raw.trim(), min),
+            ))),
+        },
+        _ => Ok(None),
+    }
+}
+
+impl Config {
+    /// Loads and checks the whole environment, with defaults for what it
+    /// leaves unset from `scheduler.toml`. Everything wrong with it is
+    /// reported in one error, so a deployment can be fixed in one go.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut problems = Problems::default();
+        problems.check(config_file::apply().map_err(invalid));
+        let mut chain = problems
+            .or_default(ProviderCredentials::chain_from_env())
+            .into_iter();
+        let credentials = match chain.next() {
+            Some(credentials) => credentials,
+            None => {
+                if problems.0.is_empty() {
+                    error!("SMS_PROVIDERS lists no provider");
+                    problems.0.push(ConfigError::Invalid {
+                        key: "SMS_PROVIDERS",
+                        reason: "lists no provider".to_string(),
+                    });
+                }
+                ProviderCredentials::Mock
+            }
+        };
+        let timezone = match std::env::var("SCHEDULE_TIMEZONE") {
+            Ok(name) if !name.trim().is_empty() => problems.or(
+                schedule::parse_timezone(&name)
+                    .map_err(|e| invalid(("SCHEDULE_TIMEZONE", e.to_string()))),
+                chrono_tz::UTC,
+            ),
+            _ => chrono_tz::UTC,
+        };
+        let schedule = match std::env::var("SMS_SCHEDULE") {
+            Ok(expression) if !expression.trim().is_empty() => problems
+                .check(
+                    CronSchedule::parse(&expression)
+                        .map_err(|e| invalid(("SMS_SCHEDULE", e.to_string()))),
+                )
+                .map(|schedule| schedule.with_timezone(timezone)),
+            _ => None,
+        };
+        let slack_alert_url = match std::env::var("SLACK_ALERT_WEBHOOK_URL") {
+            Ok(url) if !url.trim().is_empty() => {
+                let url = url.trim().to_string();
+                problems.check(if url.starts_with("https://") {
+                    Ok(url)
+                } else {
+                    Err(invalid((
+                        "SLACK_ALERT_WEBHOOK_URL",
+                        "must be an https URL".to_string(),
+                    )))
+                })
+            }
+            _ => None,
+        };
+        let dispatch_continue_url = match std::env::var("DISPATCH_CONTINUE_URL") {
+            Ok(url) if !url.trim().is_empty() => {
+                let url = url.trim().to_string();
+                problems.check(
+                    if url.starts_with("https://") || url.starts_with("http://") {
+                        Ok(url)
+                    } else {
+                        Err(invalid((
+                            "DISPATCH_CONTINUE_URL",
+                            "must be an http or https URL".to_string(),
+                        )))
+                    },
+                )
+            }
+            _ => None,
+        };
+        let alert_phone = match std::env::var("ALERT_PHONE") {
+            Ok(raw) if !raw.trim().is_empty() => {
+                problems.check(crate::phone::parse(&raw).map_err(|reason| {
+                    invalid(("ALERT_PHONE", format!("{} {}", raw.trim(), reason)))
+                }))
+            }
+            _ => None,
+        };
+        let low_balance_threshold = match std::env::var("LOW_BALANCE_THRESHOLD") {
+            Ok(raw) if !raw.trim().is_empty() => problems.check(match raw.trim().parse::<f64>() {
+                Ok(threshold) if threshold.is_finite() && threshold >= 0.0 => Ok(threshold),
+                _ => Err(invalid((
+                    "LOW_BALANCE_THRESHOLD",
+                    "must be a number of credits, 0 or more".to_string(),
+                ))),
+            }),
+            _ => None,
+        };
+        let segment_limit = match problems.or_default(number("MAX_SEGMENTS", 1)) {
+            Some(max) => {
+                let action = match std::env::var("SEGMENT_LIMIT_ACTION") {
+                    Ok(raw) if !raw.trim().is_empty() => problems.or_default(
+                        raw.parse()
+                            .map_err(|reason| invalid(("SEGMENT_LIMIT_ACTION", reason))),
+                    ),
+                    _ => Default::default(),
+                };
+                Some(SegmentLimit {
+                    max: max as usize,
+                    action,
+                })
+            }
+            None => None,
+        };
+        let cors = problems.check(Cors::from_env().map_err(invalid));
+        let circuit_breaker = match problems.or_default(number("CIRCUIT_FAILURE_PERCENT", 0)) {
+            Some(0) => None,
+            Some(percent) if percent > 100 => {
+                problems.0.push(invalid((
+                    "CIRCUIT_FAILURE_PERCENT",
+                    format!("{} is over 100", percent),
+                )));
+                None
+            }
+            percent => {
+                let defaults = BreakerPolicy::default();
+                let secs = |key, default: std::time::Duration| {
+                    number(key, 1).map(|secs| secs.map_or(default, std::time::Duration::from_secs))
+                };
+                Some(BreakerPolicy {
+                    failure_percent: percent.unwrap_or(defaults.failure_percent),
+                    min_calls: problems
+                        .or_default(number("CIRCUIT_MIN_CALLS", 1))
+                        .map_or(defaults.min_calls, |calls| calls as usize),
+                    window: problems.or(
+                        secs("CIRCUIT_WINDOW_SECS", defaults.window),
+                        defaults.window,
+                    ),
+                    open_for: problems.or(
+                        secs("CIRCUIT_OPEN_SECS", defaults.open_for),
+                        defaults.open_for,
+                    ),
+                })
+            }
+        };
+
+        let config = Config {
+            provider: credentials.provider().to_string(),
+            credentials,
+            fallback_credentials: chain.collect(),
+            routes: problems.or_default(ProviderCredentials::routes_from_env()),
+            provider_timeout: std::time::Duration::from_secs(
+                problems
+                    .or_default(number("PROVIDER_TIMEOUT_SECS", 1))
+                    .unwrap_or(10),
+            ),
+            circuit_breaker,
+            dry_run: matches!(std::env::var("DRY_RUN").as_deref(), Ok("1") | Ok("true")),
+            default_sender: std::env::var("DEFAULT_SENDER_ID")
+                .unwrap_or_else(|_| "UjumbeSMS".to_string()),
+            sender_pool: SenderPool::from_env(),
+            retry: RetryPolicy::from_env(),
+            number_rules: problems.or_default(NumberRules::from_env().map_err(invalid)),
+            admin_api_key: std::env::var("ADMIN_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            api_keys: problems.or_default(ApiKeys::from_env().map_err(invalid)),
+            jwt: problems.or_default(JwtConfig::from_env().map_err(invalid)),
+            rate_limits: problems.or_default(RateLimits::from_env().map_err(invalid)),
+            delivery_callback_token: std::env::var("DELIVERY_CALLBACK_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            replay_window_secs: problems.or_default(number("REPLAY_WINDOW_SECS", 1)),
+            signing_secret: std::env::var("REQUEST_SIGNING_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            signature_window_secs: problems
+                .or_default(number("SIGNATURE_WINDOW_SECS", 1))
+                .unwrap_or(300),
+            response_headers: match std::env::var("RESPONSE_HEADERS") {
+                Ok(raw) if !raw.trim().is_empty() => problems.or_default(
+                    parse_response_headers(&raw)
+                        .map_err(|reason| invalid(("RESPONSE_HEADERS", reason))),
+                ),
+                _ => Vec::new(),
+            },
+            precheck: problems.or_default(Precheck::from_env().map_err(invalid)),
+            schedule,
+            timezone,
+            schedule_window_secs: problems
+                .or_default(number("SCHEDULE_WINDOW_SECS", 0))
+                .unwrap_or(60),
+            catch_up: match std::env::var("CATCH_UP_POLICY") {
+                Ok(raw) if !raw.trim().is_empty() => problems.or_default(
+                    raw.parse()
+                        .map_err(|reason| invalid(("CATCH_UP_POLICY", reason))),
+                ),
+                _ => CatchUpPolicy::default(),
+            },
+            dispatch_budget: problems
+                .or_default(number("DISPATCH_BUDGET", 1))
+                .unwrap_or(100) as usize,
+            bulk_concurrency: problems
+                .or_default(number("BULK_CONCURRENCY", 1))
+                .unwrap_or(5) as usize,
+            max_body_bytes: problems
+                .or_default(number("MAX_BODY_BYTES", 1))
+                .unwrap_or(1024 * 1024) as usize,
+            dispatch_concurrency: problems
+                .or_default(number("DISPATCH_CONCURRENCY", 1))
+                .unwrap_or(5) as usize,
+            dispatch_continue_url,
+            slack_alert_url,
+            alert_phone,
+            alert_telegram_chat: std::env::var("ALERT_TELEGRAM_CHAT")
+                .ok()
+                .map(|chat| chat.trim().to_string())
+                .filter(|chat| !chat.is_empty()),
+            watchdog_max_gap: problems
+                .or_default(number("WATCHDOG_MAX_GAP_MINS", 1))
+                .map(|mins| std::time::Duration::from_secs(60 * mins)),
+            low_balance_threshold,
+            balance_check_interval: std::time::Duration::from_secs(
+                60 * problems
+                    .or_default(number("BALANCE_CHECK_INTERVAL_MINS", 1))
+                    .unwrap_or(60),
+            ),
+            budget: problems.or_default(Budget::from_env().map_err(invalid)),
+            segment_rates: match std::env::var("SEGMENT_RATES") {
+                Ok(raw) if !raw.trim().is_empty() => problems.or_default(
+                    Rates::from_json(&raw).map_err(|reason| invalid(("SEGMENT_RATES", reason))),
+                ),
+                _ => Rates::default(),
+            },
+            segment_limit,
+            default_country: problems.or(
+                crate::phone::country_from_env()
+                    .map_err(|reason| invalid(("DEFAULT_COUNTRY", reason))),
+                phonenumber::country::Id::KE,
+            ),
+            // Only a stand-in when CORS is misconfigured, as the load then fails
+            cors: match cors {
+                Some(cors) => cors,
+                None => Cors {
+                    origins: Vec::new(),
+                    credentials: false,
+                    max_age_secs: 0,
+                },
+            },
+        };
+        problems.finish()?;
+        Ok(config)
+    }
+
+    /// A copy safe to show to operators: secrets are masked and numbers omitted
+    pub fn redacted(&self) -> RedactedConfig {
+        RedactedConfig {
+            credentials: self.credentials.redacted(),
+            provider: self.provider.clone(),
+            fallback_providers: self
+                .fallback_credentials
+                .iter()
+                .map(ProviderCredentials::provider)
+                .collect(),
+            fallback_credentials: self
+                .fallback_credentials
+                .iter()
+                .map(ProviderCredentials::redacted)
+                .collect(),
+            provider_timeout_secs: self.provider_timeout.as_secs(),
+            circuit_breaker: self.circuit_breaker,
+            dry_run: self.dry_run,
+            routes: self
+                .routes
+                .iter()
+                .map(|(prefix, credentials)| {
+                    let prefix = if prefix.is_empty() { "*" } else { prefix };
+                    (prefix.to_string(), credentials.provider())
+                })
+                .collect(),
+            default_sender: self.default_sender.clone(),
+            sender_pool: self.sender_pool.senders.clone(),
+            retry: self.retry.clone(),
+            allowed_number_rules: self.number_rules.allowed.len(),
+            blocked_number_rules: self.number_rules.blocked.len(),
+            sandbox: self.number_rules.sandbox,
+            admin_enabled: self.admin_api_key.is_some(),
+            api_key_required: self.api_keys.required,
+            cron_protected: self.api_keys.cron_secret.is_some(),
+            jwt_hs256: self.jwt.as_ref().is_some_and(JwtConfig::hs256_enabled),
+            jwt_jwks_url: self.jwt.as_ref().and_then(|jwt| jwt.jwks_url.clone()),
+            jwt_issuer: self.jwt.as_ref().and_then(|jwt| jwt.issuer.clone()),
+            jwt_audience: self.jwt.as_ref().and_then(|jwt| jwt.audience.clone()),
+            rate_limits: self.rate_limits.clone(),
+            api_keys: self
+                .api_keys
+                .keys
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect(),
+            delivery_callback_protected: self.delivery_callback_token.is_some(),
+            replay_window_secs: self.replay_window_secs,
+            request_signing_enabled: self.signing_secret.is_some(),
+            signature_window_secs: self.signature_window_secs,
+            response_headers: self
+                .response_headers
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            precheck_enabled: self.precheck.is_some(),
+            schedule: self.schedule.as_ref().map(|s| s.expression().to_string()),
+            timezone: self.timezone.name().to_string(),
+            schedule_window_secs: self.schedule_window_secs,
+            catch_up: self.catch_up,
+            dispatch_budget: self.dispatch_budget,
+            bulk_concurrency: self.bulk_concurrency,
+            max_body_bytes: self.max_body_bytes,
+            dispatch_concurrency: self.dispatch_concurrency,
+            dispatch_continue_url: self.dispatch_continue_url.clone(),
+            slack_alerts_enabled: self.slack_alert_url.is_some(),
+            sms_alerts_enabled: self.alert_phone.is_some(),
+            telegram_alerts_enabled: self.alert_telegram_chat.is_some(),
+            watchdog_max_gap_mins: self.watchdog_max_gap.map(|gap| gap.as_secs() / 60),
+            low_balance_threshold: self.low_balance_threshold,
+            balance_check_interval_mins: self.balance_check_interval.as_secs() / 60,
+            budget: self.budget.clone(),
+            segment_rates: self.segment_rates.clone(),
+            segment_limit: self.segment_limit,
+            default_country: self.default_country.as_ref().to_string(),
+            cors: self.cors.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RedactedConfig {
+    pub credentials: BTreeMap<&'static str, String>,
+    pub provider: String,
+    pub fallback_providers: Vec<&'static str>,
+    pub fallback_credentials: Vec<BTreeMap<&'static str, String>>,
+    pub provider_timeout_secs: u64,
+    pub circuit_breaker: Option<BreakerPolicy>,
+    pub dry_run: bool,
+    pub routes: BTreeMap<String, &'static str>,
+    pub default_sender: String,
+    pub sender_pool: Vec<WeightedSender>,
+    pub retry: RetryPolicy,
+    pub allowed_number_rules: usize,
+    pub blocked_number_rules: usize,
+    pub sandbox: bool,
+    pub admin_enabled: bool,
+    pub api_key_required: bool,
+    pub cron_protected: bool,
+    pub jwt_hs256: bool,
+    pub jwt_jwks_url: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub rate_limits: RateLimits,
+    /// Names of the `API_KEYS` keys
+    pub api_keys: Vec<String>,
+    pub delivery_callback_protected: bool,
+    pub replay_window_secs: Option<u64>,
+    pub request_signing_enabled: bool,
+    pub signature_window_secs: u64,
+    /// Names only, in case a deployment puts something sensitive in a value
+    pub response_headers: Vec<String>,
+    pub precheck_enabled: bool,
+    pub schedule: Option<String>,
+    pub timezone: String,
+    pub schedule_window_secs: u64,
+    pub catch_up: CatchUpPolicy,
+    pub dispatch_budget: usize,
+    pub bulk_concurrency: usize,
+    pub max_body_bytes: usize,
+    pub dispatch_concurrency: usize,
+    pub dispatch_continue_url: Option<String>,
+    pub slack_alerts_enabled: bool,
+    pub sms_alerts_enabled: bool,
+    pub telegram_alerts_enabled: bool,
+    pub watchdog_max_gap_mins: Option<u64>,
+    pub low_balance_threshold: Option<f64>,
+    pub balance_check_interval_mins: u64,
+    pub budget: Option<Budget>,
+    pub segment_rates: Rates,
+    pub segment_limit: Option<SegmentLimit>,
+    pub default_country: String,
+    pub cors: Cors,
+}
+
+/// Parses a JSON object of header names to values, e.g.
+/// `{"X-Env": "staging", "Strict-Transport-Security": "max-age=63072000"}`
+pub fn parse_response_headers(raw: &str) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let map: BTreeMap<String, String> =
+        serde_json::from_str(raw).map_err(|e| format!("expected a JSON object: {}", e))?;
+
+    map.into_iter()
+        .map(|(name, value)| {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name: {:?}", name))?;
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+            Ok((header, value))
+        })
+        .collect()
+}
+
+fn required(key: &'static str) -> Result<String, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) if value.trim().is_empty() => {
+            error!("Failed to load {}: it is empty", key);
+            Err(ConfigError::Missing(key, std::env::VarError::NotPresent))
+        }
+        Ok(value) => {
+            debug!("Successfully loaded {}", key);
+            Ok(value)
+        }
+        Err(e) => {
+            error!("Failed to load {}: {}", key, e);
+            Err(ConfigError::Missing(key, e))
+        }
+    }
+}
+
+/// Both keys, reporting either or both as missing
+fn required_pair(
+    first: &'static str,
+    second: &'static str,
+) -> Result<(String, String), ConfigError> {
+    let mut problems = Problems::default();
+    let first = problems.check(required(first));
+    let second = problems.check(required(second));
+    problems.finish()?;
+    Ok((first.unwrap_or_default(), second.unwrap_or_default()))
+}
+
+/// Keeps only the last four characters, and none of a secret that short
+pub fn mask_secret(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() <= 4 {
+        return "****".to_string();
+    }
+    let last4: String = chars[chars.len() - 4..].iter().collect();
+    format!("****{}", last4)
+}
+
+/// Keeps only the domain of an email address
+pub fn mask_email(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((_, domain)) => format!("****@{}", domain),
+        None => "****".to_string(),
+    }
+}
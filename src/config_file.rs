@@ -0,0 +1,104 @@
+use tracing::{debug, info};
+
+/// Read unless `SCHEDULER_CONFIG` names another file
+pub const DEFAULT_PATH: &str = "scheduler.toml";
+
+/// Settings that stay in the environment: secrets, headers (which carry
+/// them), and URLs, which carry credentials or point a deployment at its
+/// own services
+fn env_only(key: &str) -> bool {
+    key == "API_KEYS"
+        || [
+            "_API_KEY", "SECRET", "TOKEN", "PASSWORD", "_URL", "HEADERS", "_DSN",
+        ]
+        .iter()
+        .any(|suffix| key.ends_with(suffix))
+}
+
+/// The file's settings as environment variable names and values. Tables
+/// prefix their keys, so `[retry] max_attempts = 3` is `RETRY_MAX_ATTEMPTS`,
+/// and lists are joined with commas as the variables take them.
+pub fn parse(raw: &str) -> Result<Vec<(String, String)>, String> {
+    let table: toml::Table = raw
+        .parse()
+        .map_err(|e: toml::de::Error| e.message().to_string())?;
+    let mut settings = Vec::new();
+    flatten("", &table, &mut settings)?;
+    Ok(settings)
+}
+
+fn flatten(
+    prefix: &str,
+    table: &toml::Table,
+    settings: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key.to_ascii_uppercase().replace('-', "_"));
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(&format!("{}_", name), table, settings)?;
+                continue;
+            }
+            toml::Value::Array(items) => items
+                .iter()
+                .map(scalar)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|reason| format!("{}: {}", name, reason))?
+                .join(","),
+            other => scalar(other).map_err(|reason| format!("{}: {}", name, reason))?,
+        };
+        if env_only(&name) {
+            return Err(format!(
+                "{} must be set in the environment, not the file",
+                name
+            ));
+        }
+        settings.push((name, value));
+    }
+    Ok(())
+}
+
+fn scalar(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(n) => Ok(n.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            Err("lists may only hold plain values".to_string())
+        }
+    }
+}
+
+/// Reads the defaults file and sets each of its settings the environment
+/// leaves unset or empty, so environment variables always win. It's fine
+/// for `scheduler.toml` not to exist, but not a file `SCHEDULER_CONFIG`
+/// names.
+pub fn apply() -> Result<(), (&'static str, String)> {
+    let (path, named) = match std::env::var("SCHEDULER_CONFIG") {
+        Ok(path) if !path.trim().is_empty() => (path.trim().to_string(), true),
+        _ => (DEFAULT_PATH.to_string(), false),
+    };
+    let raw = match std::fs::read_to_string(&path) {
+        Ok(raw) => raw,
+        Err(e) if !named && e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No {}, using the environment alone", path);
+            return Ok(());
+        }
+        Err(e) => return Err(("SCHEDULER_CONFIG", format!("{}: {}", path, e))),
+    };
+    let settings =
+        parse(&raw).map_err(|reason| ("SCHEDULER_CONFIG", format!("{}: {}", path, reason)))?;
+    let mut applied = 0;
+    for (key, value) in settings {
+        if std::env::var(&key).is_ok_and(|set| !set.trim().is_empty()) {
+            debug!("{} from the environment overrides {}", key, path);
+            continue;
+        }
+        std::env::set_var(&key, value);
+        applied += 1;
+    }
+    info!("Loaded {} settings from {}", applied, path);
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::error::ApiError;
+use crate::phone;
+use crate::storage::{self, Storage};
+
+/// Longest group name accepted
+const MAX_NAME_LEN: usize = 64;
+
+/// A member of a contact group. `name` and `attributes` are the variables
+/// a group send's template is rendered with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub phone: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Contact {
+    /// Template variables for this contact: its attributes, plus `name`
+    /// unless an attribute already uses it
+    pub fn vars(&self) -> BTreeMap<String, String> {
+        let mut vars = self.attributes.clone();
+        if let Some(name) = &self.name {
+            vars.entry("name".to_string())
+                .or_insert_with(|| name.clone());
+        }
+        vars
+    }
+}
+
+/// A named list of contacts that sends can address as one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactGroup {
+    pub name: String,
+    /// How many contacts are in it; filled in when groups are listed
+    #[serde(default)]
+    pub members: usize,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ContactGroup {
+    pub fn new(name: &str) -> Self {
+        ContactGroup {
+            name: name.to_string(),
+            members: 0,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Group names go in URLs and send requests, so they're kept to lowercase
+/// letters, digits, `-` and `_`, e.g. `vip-customers`
+pub fn validate_name(name: &str) -> Result<(), ApiError> {
+    let invalid = |reason: &str| ApiError::InvalidBody {
+        reason: format!("group name {}", reason),
+    };
+    if name.is_empty() {
+        return Err(invalid("must not be empty"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid("must be at most 64 characters"));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(invalid("may only use a-z, 0-9, - and _"));
+    }
+    Ok(())
+}
+
+/// Puts each member's phone in E.164 form, keeping the last entry for a
+/// number listed twice
+pub fn normalize_members(members: Vec<Contact>) -> Result<Vec<Contact>, ApiError> {
+    if let Some(index) = members
+        .iter()
+        .position(|contact| contact.phone.trim().is_empty())
+    {
+        return Err(ApiError::InvalidBody {
+            reason: format!("member {} has no phone", index),
+        });
+    }
+    let phones = phone::parse_all(members.iter().map(|contact| contact.phone.as_str()))?;
+    let mut by_phone = BTreeMap::new();
+    for (mut contact, phone) in members.into_iter().zip(phones) {
+        contact.phone = phone;
+        by_phone.insert(contact.phone.clone(), contact);
+    }
+    Ok(by_phone.into_values().collect())
+}
+
+#[derive(Debug)]
+pub struct MemoryGroup {
+    group: ContactGroup,
+    members: BTreeMap<String, Contact>,
+}
+
+/// Where groups are kept: the storage backend when one is configured, this
+/// instance's memory otherwise
+pub enum ContactStore {
+    Memory(Mutex<BTreeMap<String, MemoryGroup>>),
+    Database(&'static dyn Storage),
+}
+
+fn lock(
+    groups: &Mutex<BTreeMap<String, MemoryGroup>>,
+) -> std::sync::MutexGuard<'_, BTreeMap<String, MemoryGroup>> {
+    groups.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl ContactStore {
+    /// By name, with their member counts
+    pub async fn list(&self) -> io::Result<Vec<ContactGroup>> {
+        match self {
+            ContactStore::Memory(groups) => Ok(lock(groups)
+                .values()
+                .map(|entry| ContactGroup {
+                    members: entry.members.len(),
+                    ..entry.group.clone()
+                })
+                .collect()),
+            ContactStore::Database(storage) => storage.list_groups().await,
+        }
+    }
+
+    /// Adds the group, returning `false` if one by that name exists
+    pub async fn create(&self, group: ContactGroup) -> io::Result<bool> {
+        match self {
+            ContactStore::Memory(groups) => {
+                let mut groups = lock(groups);
+                if groups.contains_key(&group.name) {
+                    return Ok(false);
+                }
+                groups.insert(
+                    group.name.clone(),
+                    MemoryGroup {
+                        group,
+                        members: BTreeMap::new(),
+                    },
+                );
+                Ok(true)
+            }
+            ContactStore::Database(storage) => storage.create_group(group).await,
+        }
+    }
+
+    /// Removes the group and its members, returning `false` if there was
+    /// no such group
+    pub async fn delete(&self, name: &str) -> io::Result<bool> {
+        match self {
+            ContactStore::Memory(groups) => Ok(lock(groups).remove(name).is_some()),
+            ContactStore::Database(storage) => storage.delete_group(name).await,
+        }
+    }
+
+    /// The group's members by phone, or `None` if there's no such group
+    pub async fn members(&self, name: &str) -> io::Result<Option<Vec<Contact>>> {
+        match self {
+            ContactStore::Memory(groups) => Ok(lock(groups)
+                .get(name)
+                .map(|entry| entry.members.values().cloned().collect())),
+            ContactStore::Database(storage) => storage.group_members(name).await,
+        }
+    }
+
+    /// Adds contacts, replacing any with the same phone, or returns `false`
+    /// if there's no such group
+    pub async fn add(&self, name: &str, members: Vec<Contact>) -> io::Result<bool> {
+        match self {
+            ContactStore::Memory(groups) => {
+                let mut groups = lock(groups);
+                let Some(entry) = groups.get_mut(name) else {
+                    return Ok(false);
+                };
+                for contact in members {
+                    entry.members.insert(contact.phone.clone(), contact);
+                }
+                Ok(true)
+            }
+            ContactStore::Database(storage) => storage.add_members(name, members).await,
+        }
+    }
+
+    /// Whether the phone was a member, or `None` if there's no such group
+    pub async fn remove(&self, name: &str, phone: &str) -> io::Result<Option<bool>> {
+        match self {
+            ContactStore::Memory(groups) => Ok(lock(groups)
+                .get_mut(name)
+                .map(|entry| entry.members.remove(phone).is_some())),
+            ContactStore::Database(storage) => storage.remove_member(name, phone).await,
+        }
+    }
+}
+
+// As with the other stores, a misconfigured backend is kept as an error
+// rather than replaced by memory other instances can't see
+static STORE: Lazy<Result<ContactStore, String>> = Lazy::new(|| {
+    if let Some(backend) = storage::backend() {
+        return backend.map(ContactStore::Database);
+    }
+    info!("Keeping contact groups in memory");
+    Ok(ContactStore::Memory(Mutex::new(BTreeMap::new())))
+});
+
+pub fn store() -> Result<&'static ContactStore, ApiError> {
+    STORE
+        .as_ref()
+        .map_err(|reason| ApiError::StorageUnavailable {
+            reason: reason.clone(),
+        })
+}
+
+/// The members a send to `name` goes to
+pub async fn expand(name: &str) -> Result<Vec<Contact>, ApiError> {
+    store()?
+        .members(name)
+        .await
+        .map_err(storage::unavailable)?
+        .ok_or_else(|| ApiError::GroupNotFound {
+            name: name.to_string(),
+        })
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::kv;
+
+/// How long a continuation can be picked up before the next cron tick is
+/// left to carry on
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Most chunks after a tick's own, so a backlog that keeps growing can't
+/// chain invocations forever
+pub const MAX_CHUNKS: u32 = 20;
+
+/// How long handing the rest to the next invocation waits for it to answer;
+/// it keeps running once it has the request
+const INVOKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where a tick that ran out of budget or time left off. The stores hand out
+/// due work oldest first, so the cursor is the tick's own clock: a
+/// continuation sends what was due by then, and what came due since waits
+/// for the next tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Continuation {
+    pub token: String,
+    pub due_by: DateTime<Utc>,
+    /// 1 for the first chunk after the tick's own
+    pub chunk: u32,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl Continuation {
+    /// The tick request that picks it up
+    pub fn path(&self) -> String {
+        format!("/api/handler?continue={}", self.token)
+    }
+}
+
+/// Continuations when KV isn't linked, which only this instance can pick up
+static MEMORY: Lazy<Mutex<BTreeMap<String, Continuation>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+fn key(token: &str) -> String {
+    format!("dispatch:continuation:{}", token)
+}
+
+/// Persists where a tick left off, so the next invocation can pick it up.
+/// `None` once `MAX_CHUNKS` is reached; what's left then waits for the next
+/// cron tick.
+pub async fn issue(due_by: DateTime<Utc>, chunk: u32) -> Option<Continuation> {
+    if chunk > MAX_CHUNKS {
+        warn!(
+            "Not continuing past chunk {}: the rest waits for the next tick",
+            MAX_CHUNKS
+        );
+        return None;
+    }
+    let continuation = Continuation {
+        token: uuid::Uuid::new_v4().simple().to_string(),
+        due_by,
+        chunk,
+        issued_at: Utc::now(),
+    };
+    MEMORY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(continuation.token.clone(), continuation.clone());
+    if let Some(client) = kv::client() {
+        let result = match (
+            client.map_err(io::Error::other),
+            serde_json::to_string(&continuation),
+        ) {
+            (Ok(client), Ok(value)) => client.set(&key(&continuation.token), &value, TTL).await,
+            (Err(e), _) => Err(e),
+            (_, Err(e)) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to store continuation, only this instance can pick it up: {}",
+                e
+            );
+        }
+    }
+    info!(
+        "Continuing dispatch of work due by {} as chunk {}",
+        continuation.due_by, continuation.chunk
+    );
+    Some(continuation)
+}
+
+/// Picks up a continuation, once; `None` when the token is unknown, expired
+/// or already used
+pub async fn take(token: &str) -> Option<Continuation> {
+    let local = MEMORY
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(token);
+    let Some(client) = kv::client() else {
+        return local;
+    };
+    let result = match client.map_err(io::Error::other) {
+        Ok(client) => client.command(&["GETDEL", &key(token)]).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(serde_json::Value::String(value)) => match serde_json::from_str(&value) {
+            Ok(continuation) => Some(continuation),
+            Err(e) => {
+                warn!("Ignoring stored continuation: {}", e);
+                local
+            }
+        },
+        // Picked up already, when this instance issued it
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Continuations unavailable, using this instance's: {}", e);
+            local
+        }
+    }
+}
+
+/// Requests `base_url` plus the continuation's path, so the rest is sent by
+/// a fresh invocation rather than waiting for the next tick. It's waited on
+/// for `INVOKE_TIMEOUT` only; timing out means the invocation is running.
+pub async fn invoke(base_url: &str, cron_secret: Option<&str>, continuation: &Continuation) {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), continuation.path());
+    let mut request = reqwest::Client::new().get(&url).timeout(INVOKE_TIMEOUT);
+    if let Some(secret) = cron_secret {
+        request = request.bearer_auth(secret);
+    }
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            info!("Chunk {} dispatched", continuation.chunk)
+        }
+        Ok(response) => warn!(
+            "Continuing dispatch failed with HTTP {}; the rest waits for the next tick",
+            response.status()
+        ),
+        Err(e) if e.is_timeout() => info!("Chunk {} is dispatching", continuation.chunk),
+        Err(e) => warn!(
+            "Failed to continue dispatch, the rest waits for the next tick: {}",
+            e
+        ),
+    }
+}
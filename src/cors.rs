@@ -0,0 +1,136 @@
+use http::header::{self, HeaderMap, HeaderValue};
+use serde::Serialize;
+
+/// Request headers browsers may send cross-origin
+const ALLOWED_HEADERS: &str =
+    "Content-Type, Authorization, Idempotency-Key, Accept-Language, X-Timestamp, X-Nonce";
+
+/// Response headers scripts on other origins may read
+const EXPOSED_HEADERS: &str = "X-Trace-Id, Retry-After";
+
+/// Which browser origins may call the API, configured with
+/// `CORS_ALLOWED_ORIGINS`
+#[derive(Debug, Clone, Serialize)]
+pub struct Cors {
+    /// Exact origins such as `https://app.example.com`; empty allows any
+    /// origin, without credentials
+    pub origins: Vec<String>,
+    /// Whether browsers may send cookies and auth headers along, from
+    /// `CORS_ALLOW_CREDENTIALS`
+    pub credentials: bool,
+    /// How long browsers may cache a preflight answer, from
+    /// `CORS_MAX_AGE_SECS`
+    pub max_age_secs: u64,
+}
+
+impl Cors {
+    /// Reads `CORS_ALLOWED_ORIGINS` (comma-separated, `*` or unset for any),
+    /// `CORS_ALLOW_CREDENTIALS` and `CORS_MAX_AGE_SECS` (default 600).
+    /// Credentials need the origins listed: browsers refuse them with `*`.
+    pub fn from_env() -> Result<Self, (&'static str, String)> {
+        let origins: Vec<String> = match std::env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(raw) if !raw.trim().is_empty() && raw.trim() != "*" => raw
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Some(origin) = origins
+            .iter()
+            .find(|origin| !origin.starts_with("https://") && !origin.starts_with("http://"))
+        {
+            return Err((
+                "CORS_ALLOWED_ORIGINS",
+                format!("{} is not an http(s) origin", origin),
+            ));
+        }
+        let credentials = matches!(
+            std::env::var("CORS_ALLOW_CREDENTIALS").as_deref(),
+            Ok("1") | Ok("true")
+        );
+        if credentials && origins.is_empty() {
+            return Err((
+                "CORS_ALLOW_CREDENTIALS",
+                "needs CORS_ALLOWED_ORIGINS to list origins".to_string(),
+            ));
+        }
+        let max_age_secs = match std::env::var("CORS_MAX_AGE_SECS") {
+            Ok(raw) if !raw.trim().is_empty() => raw
+                .trim()
+                .parse()
+                .map_err(|_| ("CORS_MAX_AGE_SECS", format!("not a number: {}", raw)))?,
+            _ => 600,
+        };
+        Ok(Cors {
+            origins,
+            credentials,
+            max_age_secs,
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request from `origin`,
+    /// if it's allowed
+    fn allow_origin(&self, origin: Option<&str>) -> Option<HeaderValue> {
+        if self.origins.is_empty() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .then(|| HeaderValue::from_str(origin).ok())
+            .flatten()
+    }
+
+    /// Adds the CORS headers for a request from `origin`, leaving any the
+    /// response already carries, e.g. from `RESPONSE_HEADERS`. A disallowed
+    /// origin gets none, so the browser keeps the response from the page.
+    pub fn apply(&self, origin: Option<&str>, headers: &mut HeaderMap) {
+        if !self.origins.is_empty() {
+            // The answer depends on the origin, so caches must not share it
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return;
+        };
+        headers
+            .entry(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .or_insert(allow_origin);
+        headers
+            .entry(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+            .or_insert(HeaderValue::from_static(EXPOSED_HEADERS));
+        if self.credentials {
+            headers
+                .entry(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .or_insert(HeaderValue::from_static("true"));
+        }
+    }
+
+    /// Adds the headers answering a preflight to a path allowing `methods`.
+    /// They're left out when the origin or requested method isn't allowed,
+    /// which the browser takes as a refusal.
+    pub fn apply_preflight(
+        &self,
+        origin: Option<&str>,
+        requested_method: &str,
+        methods: &[&str],
+        headers: &mut HeaderMap,
+    ) {
+        if !methods.contains(&requested_method) {
+            return;
+        }
+        self.apply(origin, headers);
+        if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+        if let Ok(methods) = HeaderValue::from_str(&methods.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_static(ALLOWED_HEADERS),
+        );
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age_secs.into());
+    }
+}
@@ -0,0 +1,158 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::error::ApiError;
+
+/// The GSM 03.38 default alphabet, one septet each
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Characters of the extension table, two septets each with their escape
+const GSM7_EXTENDED: &str = "^{}\\[~]|€\u{c}";
+
+/// How a message is encoded on the air, which decides how much fits in a
+/// segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Gsm7,
+    /// Anything outside GSM-7, emoji included, turns the whole message UCS-2
+    Ucs2,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gsm7 => "gsm7",
+            Encoding::Ucs2 => "ucs2",
+        }
+    }
+
+    /// Characters in a single-segment message, and in each part of a
+    /// concatenated one, which gives some up to the header joining them
+    fn capacity(self) -> (usize, usize) {
+        match self {
+            Encoding::Gsm7 => (160, 153),
+            Encoding::Ucs2 => (70, 67),
+        }
+    }
+}
+
+/// How many segments a message is billed as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Segments {
+    pub encoding: Encoding,
+    /// Septets for GSM-7, UTF-16 code units for UCS-2
+    pub characters: usize,
+    pub segments: usize,
+}
+
+impl Segments {
+    pub fn of(text: &str) -> Self {
+        let septets: Option<usize> = text
+            .chars()
+            .map(|c| {
+                if GSM7_BASIC.contains(c) {
+                    Some(1)
+                } else if GSM7_EXTENDED.contains(c) {
+                    Some(2)
+                } else {
+                    None
+                }
+            })
+            .sum();
+        let (encoding, characters) = match septets {
+            Some(septets) => (Encoding::Gsm7, septets),
+            None => (Encoding::Ucs2, text.encode_utf16().count()),
+        };
+        let (single, part) = encoding.capacity();
+        let segments = if characters <= single {
+            1
+        } else {
+            characters.div_ceil(part)
+        };
+        Segments {
+            encoding,
+            characters,
+            segments,
+        }
+    }
+}
+
+/// What a send over `MAX_SEGMENTS` does, from `SEGMENT_LIMIT_ACTION`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentLimitAction {
+    /// Fail it before the provider is contacted
+    #[default]
+    Reject,
+    /// Send it anyway and log a warning
+    Warn,
+}
+
+impl std::str::FromStr for SegmentLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(SegmentLimitAction::Reject),
+            "warn" => Ok(SegmentLimitAction::Warn),
+            other => Err(format!("unknown segment limit action: {}", other)),
+        }
+    }
+}
+
+/// Most segments a message may use, and what happens to longer ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SegmentLimit {
+    pub max: usize,
+    pub action: SegmentLimitAction,
+}
+
+impl SegmentLimit {
+    /// Fails a message over the limit, unless the action is to warn
+    pub fn check(&self, segments: &Segments) -> Result<(), ApiError> {
+        if segments.segments <= self.max || self.action == SegmentLimitAction::Warn {
+            return Ok(());
+        }
+        Err(ApiError::TooManySegments {
+            segments: segments.segments,
+            max: self.max,
+            encoding: segments.encoding.as_str(),
+        })
+    }
+}
+
+/// What a segment costs through each provider, from `SEGMENT_RATES`.
+/// Providers it leaves out are counted at one credit a segment, which is
+/// what Ujumbe and Africa's Talking bill in.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Rates(BTreeMap<String, f64>);
+
+impl Rates {
+    /// Reads a JSON object of provider names to per-segment prices, e.g.
+    /// `{"twilio": 0.0079, "africastalking": 0.8}`
+    pub fn from_json(raw: &str) -> Result<Self, String> {
+        let rates: BTreeMap<String, f64> =
+            serde_json::from_str(raw).map_err(|e| format!("expected a JSON object: {}", e))?;
+        rates
+            .into_iter()
+            .map(|(provider, rate)| {
+                if !rate.is_finite() || rate < 0.0 {
+                    return Err(format!("rate for {} must be 0 or more", provider));
+                }
+                Ok((provider.trim().to_ascii_lowercase(), rate))
+            })
+            .collect::<Result<_, _>>()
+            .map(Rates)
+    }
+
+    pub fn rate(&self, provider: &str) -> f64 {
+        self.0.get(provider).copied().unwrap_or(1.0)
+    }
+
+    pub fn cost(&self, provider: &str, segments: usize) -> f64 {
+        self.rate(provider) * segments as f64
+    }
+}
@@ -0,0 +1,74 @@
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Kept back from provider calls at the end of an invocation, so a request
+/// whose calls ran long still answers before the platform cuts it off
+const RESERVE: Duration = Duration::from_secs(1);
+
+/// Least time left that more dispatch work is started with
+const WORTH_STARTING: Duration = Duration::from_secs(2);
+
+/// How long an invocation may run, from `FUNCTION_MAX_DURATION_SECS`; it
+/// should match the function's `maxDuration`
+static BUDGET: Lazy<Duration> = Lazy::new(|| {
+    let secs = match std::env::var("FUNCTION_MAX_DURATION_SECS") {
+        Ok(raw) if !raw.trim().is_empty() => match raw.trim().parse::<u64>() {
+            Ok(secs) if secs >= 1 => secs,
+            _ => {
+                warn!(
+                    "Ignoring FUNCTION_MAX_DURATION_SECS of {}, using 10",
+                    raw.trim()
+                );
+                10
+            }
+        },
+        _ => 10,
+    };
+    Duration::from_secs(secs)
+});
+
+tokio::task_local! {
+    // When the invocation handling this request is cut off
+    static DEADLINE: Instant;
+}
+
+/// The deadline of an invocation starting now
+pub fn start() -> Instant {
+    Instant::now() + *BUDGET
+}
+
+/// Runs `future` against `deadline`, or with no deadline when it's `None`.
+/// Tasks a request spawns pass its `current()` deadline on.
+pub async fn scope<F: Future>(deadline: Option<Instant>, future: F) -> F::Output {
+    match deadline {
+        Some(deadline) => DEADLINE.scope(deadline, future).await,
+        None => future.await,
+    }
+}
+
+/// The deadline of the request being handled
+pub fn current() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// What's left of the request's budget for provider calls, `None` outside
+/// of a request
+pub fn remaining() -> Option<Duration> {
+    current().map(|deadline| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .saturating_sub(RESERVE)
+    })
+}
+
+/// Whether too little of the request's budget is left to start more work
+pub fn running_out() -> bool {
+    remaining().is_some_and(|left| left < WORTH_STARTING)
+}
+
+/// `timeout`, cut short to what's left of the request's budget
+pub fn limit(timeout: Duration) -> Duration {
+    remaining().map_or(timeout, |left| left.min(timeout))
+}
@@ -0,0 +1,211 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::format;
+use crate::providers::{africastalking, twilio, DeliveryStatus};
+
+/// Calling codes we key latency by; longest match wins, so `1` only
+/// catches what nothing longer did
+const CALLING_CODES: &[&str] = &[
+    "254", "255", "256", "257", "250", "251", "252", "211", "243", "234", "233", "237", "225",
+    "221", "260", "263", "265", "27", "20", "44", "1",
+];
+
+/// Country calling code of a normalized number, if it's one we track
+pub fn calling_code(phone: &str) -> Option<&'static str> {
+    CALLING_CODES
+        .iter()
+        .filter(|code| phone.starts_with(**code))
+        .max_by_key(|code| code.len())
+        .copied()
+}
+
+/// Upper bounds, in seconds, of the submit-to-delivered buckets
+const BUCKETS: [u64; 12] = [1, 2, 5, 10, 20, 30, 60, 120, 300, 600, 1800, 3600];
+
+/// Fewer observations than this and we'd rather not guess
+const MIN_SAMPLES: u64 = 20;
+
+/// Once a country has this many observations all counts are halved, so
+/// the distribution follows recent behaviour rather than all of history
+const MAX_SAMPLES: u64 = 1000;
+
+/// Submissions not confirmed within this long are forgotten
+const PENDING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Default)]
+struct LatencyHistogram {
+    /// One count per entry of `BUCKETS`, plus one for anything slower
+    counts: [u64; BUCKETS.len() + 1],
+    total: u64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let index = BUCKETS
+            .iter()
+            .position(|le| secs <= *le as f64)
+            .unwrap_or(BUCKETS.len());
+        self.counts[index] += 1;
+        self.total += 1;
+
+        if self.total >= MAX_SAMPLES {
+            for count in &mut self.counts {
+                *count /= 2;
+            }
+            self.total = self.counts.iter().sum();
+        }
+    }
+
+    /// Upper bound of the bucket holding the median observation
+    fn median_secs(&self) -> Option<u64> {
+        if self.total < MIN_SAMPLES {
+            return None;
+        }
+        let half = self.total.div_ceil(2);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= half {
+                // The overflow bucket has no upper bound; report its floor
+                return Some(*BUCKETS.get(index).unwrap_or(&BUCKETS[BUCKETS.len() - 1]));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    latencies: HashMap<&'static str, LatencyHistogram>,
+    /// Message id to the country and time it was submitted
+    pending: HashMap<String, (&'static str, Instant)>,
+}
+
+static TRACKER: Lazy<Mutex<Tracker>> = Lazy::new(|| Mutex::new(Tracker::default()));
+
+fn tracker() -> std::sync::MutexGuard<'static, Tracker> {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Remembers when a message was handed to the provider, so a later delivery
+/// report for it can be timed
+pub fn record_submission(message_id: &str, phone: &str) {
+    let Some(code) = calling_code(phone) else {
+        return;
+    };
+    let mut tracker = tracker();
+    tracker
+        .pending
+        .retain(|_, (_, submitted)| submitted.elapsed() <= PENDING_TTL);
+    tracker
+        .pending
+        .insert(message_id.to_string(), (code, Instant::now()));
+}
+
+/// Records a delivery report for a submitted message, returning the observed
+/// submit-to-delivered time when the message was known
+pub fn record_delivery(message_id: &str) -> Option<Duration> {
+    let mut tracker = tracker();
+    let (code, submitted) = tracker.pending.remove(message_id)?;
+    let latency = submitted.elapsed();
+    tracker.latencies.entry(code).or_default().observe(latency);
+    debug!("Delivery to +{} took {:?}", code, latency);
+    Some(latency)
+}
+
+/// Median submit-to-delivered time for the number's country, or `None`
+/// until enough deliveries there have been observed
+pub fn estimate_secs(phone: &str) -> Option<u64> {
+    let code = calling_code(phone)?;
+    tracker().latencies.get(code)?.median_secs()
+}
+
+/// A provider's delivery report callback for one message, in neutral form
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryReport {
+    /// The id the provider gave the message on submission
+    pub message_id: String,
+    pub status: DeliveryStatus,
+    /// The provider's own status, e.g. `Success` or `undelivered`
+    pub provider_status: String,
+    /// Why it wasn't delivered, when the provider says
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The form Twilio posts to a message's status callback
+#[derive(Debug, Deserialize)]
+struct TwilioCallback {
+    #[serde(rename = "MessageSid")]
+    sid: String,
+    #[serde(rename = "MessageStatus")]
+    status: String,
+    #[serde(default, rename = "ErrorCode")]
+    error_code: Option<String>,
+}
+
+/// What relays for other providers, e.g. Ujumbe, post: a status named as
+/// in `DeliveryStatus`
+#[derive(Debug, Deserialize)]
+struct NeutralReport {
+    message_id: String,
+    status: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(fields: &Map<String, Value>) -> Result<T, String> {
+    serde_json::from_value(Value::Object(fields.clone())).map_err(|e| e.to_string())
+}
+
+/// Reads a delivery report callback, form-encoded or JSON, telling the
+/// provider by its fields: Twilio's `MessageSid`, Africa's Talking's `id`,
+/// or else `message_id` and `status`
+pub fn parse_report(content_type: Option<&str>, body: &[u8]) -> Result<DeliveryReport, String> {
+    let fields: Map<String, Value> = if format::is_form(content_type) {
+        format::parse_form(body)?
+    } else {
+        serde_json::from_slice(body).map_err(|e| e.to_string())?
+    };
+
+    let report = if fields.contains_key("MessageSid") {
+        let callback: TwilioCallback = decode(&fields)?;
+        DeliveryReport {
+            message_id: callback.sid,
+            status: twilio::delivery_status(&callback.status),
+            reason: callback
+                .error_code
+                .filter(|code| !code.is_empty())
+                .map(|code| format!("Twilio error {}", code)),
+            provider_status: callback.status,
+        }
+    } else if fields.contains_key("id") {
+        let callback: africastalking::DeliveryReport = decode(&fields)?;
+        DeliveryReport {
+            status: callback.delivery_status(),
+            message_id: callback.id,
+            provider_status: callback.status,
+            reason: callback.failure_reason.filter(|reason| !reason.is_empty()),
+        }
+    } else {
+        let report: NeutralReport = decode(&fields)?;
+        DeliveryReport {
+            status: serde_json::from_value(Value::String(report.status.to_ascii_lowercase()))
+                .unwrap_or(DeliveryStatus::Unknown),
+            message_id: report.message_id,
+            provider_status: report.status,
+            reason: report.reason,
+        }
+    };
+    if report.message_id.is_empty() {
+        return Err("the report has no message id".to_string());
+    }
+    Ok(report)
+}
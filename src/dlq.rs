@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::error::ApiError;
+use crate::redis::{RedisClient, Reply};
+use crate::storage::{self, Storage};
+
+/// A message that exhausted its retries under a `dead_letter` policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub phone: String,
+    pub message: String,
+    pub sender_id: String,
+    pub attempts: u32,
+    pub error: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn new(
+        phone: String,
+        message: String,
+        sender_id: String,
+        attempts: u32,
+        error: String,
+    ) -> Self {
+        DeadLetter {
+            id: uuid::Uuid::new_v4().to_string(),
+            phone,
+            message,
+            sender_id,
+            attempts,
+            error,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Where dead letters are kept: the storage backend when one is configured,
+/// a Redis list when `DLQ_REDIS_URL` is set, this instance's memory otherwise
+pub enum DeadLetterStore {
+    Memory(Mutex<Vec<DeadLetter>>),
+    Redis { client: RedisClient, key: String },
+    Database(&'static dyn Storage),
+}
+
+impl DeadLetterStore {
+    pub async fn push(&self, entry: &DeadLetter) -> io::Result<()> {
+        match self {
+            DeadLetterStore::Memory(entries) => {
+                lock(entries).push(entry.clone());
+                Ok(())
+            }
+            DeadLetterStore::Redis { client, key } => {
+                let value = serde_json::to_vec(entry)?;
+                client
+                    .command(&[b"RPUSH", key.as_bytes(), &value])
+                    .await
+                    .map(drop)
+            }
+            DeadLetterStore::Database(storage) => storage.push_dead_letter(entry.clone()).await,
+        }
+    }
+
+    /// Oldest first
+    pub async fn list(&self) -> io::Result<Vec<DeadLetter>> {
+        match self {
+            DeadLetterStore::Memory(entries) => Ok(lock(entries).clone()),
+            DeadLetterStore::Redis { client, key } => Ok(Self::redis_entries(client, key)
+                .await?
+                .into_iter()
+                .map(|(entry, _)| entry)
+                .collect()),
+            DeadLetterStore::Database(storage) => storage.list_dead_letters().await,
+        }
+    }
+
+    /// How many entries are waiting
+    pub async fn len(&self) -> io::Result<usize> {
+        match self {
+            DeadLetterStore::Memory(entries) => Ok(lock(entries).len()),
+            DeadLetterStore::Redis { client, key } => {
+                match client.command(&[b"LLEN", key.as_bytes()]).await? {
+                    Reply::Integer(len) => Ok(len.max(0) as usize),
+                    _ => Ok(0),
+                }
+            }
+            DeadLetterStore::Database(storage) => storage
+                .list_dead_letters()
+                .await
+                .map(|entries| entries.len()),
+        }
+    }
+
+    /// Removes and returns the entry, so only one replay of it can run
+    pub async fn take(&self, id: &str) -> io::Result<Option<DeadLetter>> {
+        match self {
+            DeadLetterStore::Memory(entries) => {
+                let mut entries = lock(entries);
+                Ok(entries
+                    .iter()
+                    .position(|entry| entry.id == id)
+                    .map(|index| entries.remove(index)))
+            }
+            DeadLetterStore::Redis { client, key } => {
+                let found = Self::redis_entries(client, key)
+                    .await?
+                    .into_iter()
+                    .find(|(entry, _)| entry.id == id);
+                let Some((entry, raw)) = found else {
+                    return Ok(None);
+                };
+                // LREM reports 0 when another replay removed it first
+                match client
+                    .command(&[b"LREM", key.as_bytes(), b"1", &raw])
+                    .await?
+                {
+                    Reply::Integer(removed) if removed > 0 => Ok(Some(entry)),
+                    _ => Ok(None),
+                }
+            }
+            DeadLetterStore::Database(storage) => storage.take_dead_letter(id).await,
+        }
+    }
+
+    async fn redis_entries(
+        client: &RedisClient,
+        key: &str,
+    ) -> io::Result<Vec<(DeadLetter, Vec<u8>)>> {
+        let Reply::Array(items) = client
+            .command(&[b"LRANGE", key.as_bytes(), b"0", b"-1"])
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        Ok(items
+            .into_iter()
+            .filter_map(|item| match item {
+                Reply::Bulk(raw) => match serde_json::from_slice(&raw) {
+                    Ok(entry) => Some((entry, raw)),
+                    Err(e) => {
+                        error!("Skipping unreadable dead letter: {}", e);
+                        None
+                    }
+                },
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+fn lock(entries: &Mutex<Vec<DeadLetter>>) -> std::sync::MutexGuard<'_, Vec<DeadLetter>> {
+    entries.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// As with opt-outs, a misconfigured store is kept as an error rather than
+// replaced by memory that would lose messages on the next cold start
+static STORE: Lazy<Result<DeadLetterStore, String>> = Lazy::new(|| {
+    if let Some(backend) = storage::backend() {
+        return backend.map(DeadLetterStore::Database);
+    }
+    match std::env::var("DLQ_REDIS_URL") {
+        Ok(url) if !url.is_empty() => match RedisClient::parse(&url) {
+            Ok(client) => {
+                let key = std::env::var("DLQ_REDIS_KEY")
+                    .ok()
+                    .filter(|key| !key.is_empty())
+                    .unwrap_or_else(|| "scheduler:dlq".to_string());
+                info!(
+                    "Using Redis dead-letter list {} at: {}",
+                    key,
+                    client.address()
+                );
+                Ok(DeadLetterStore::Redis { client, key })
+            }
+            Err(e) => {
+                error!("Invalid DLQ_REDIS_URL: {}", e);
+                Err(e)
+            }
+        },
+        _ => Ok(DeadLetterStore::Memory(Mutex::new(Vec::new()))),
+    }
+});
+
+pub fn store() -> Result<&'static DeadLetterStore, ApiError> {
+    STORE.as_ref().map_err(|reason| ApiError::DlqUnavailable {
+        reason: reason.clone(),
+    })
+}
+
+/// Unavailable-store error for a failed store operation
+pub fn unavailable(e: io::Error) -> ApiError {
+    error!("Dead-letter store failed: {}", e);
+    ApiError::DlqUnavailable {
+        reason: e.to_string(),
+    }
+}
+
+/// How many messages are waiting in the dead-letter queue
+pub async fn depth() -> Result<usize, ApiError> {
+    store()?.len().await.map_err(unavailable)
+}
+
+/// Keeps a message that exhausted its retries. Failing to store it is only
+/// logged, since the send has already failed and is reported as such.
+pub async fn push(entry: DeadLetter) {
+    debug!(
+        "Dead-lettering message {} for: {}",
+        entry.id,
+        crate::redact::phone(&entry.phone)
+    );
+    let result = match store() {
+        Ok(store) => store.push(&entry).await.map_err(unavailable),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Lost dead letter {}: {}", entry.id, e);
+    }
+}
@@ -0,0 +1,627 @@
+use http::StatusCode;
+use serde_json::{json, Value};
+use ujumbe_sms::UjumbeSmsError;
+
+use crate::i18n::{self, Lang};
+use crate::phone::InvalidNumber;
+use crate::providers::SendReport;
+use crate::retry::RetryHint;
+
+/// Media type of error bodies in JSON, from RFC 7807
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Errors surfaced to API clients. Each variant has a stable machine-readable
+/// `code`; only the human message is localized.
+#[derive(Debug)]
+pub enum ApiError {
+    NumberBlocked {
+        phone: String,
+        rule: String,
+    },
+    NumberNotAllowed {
+        phone: String,
+    },
+    InvalidSenderId {
+        sender_id: String,
+        reason: String,
+    },
+    InvalidBody {
+        reason: String,
+    },
+    InvalidQuery {
+        reason: String,
+    },
+    /// The body is over `MAX_BODY_BYTES`, by its length or the
+    /// `Content-Length` it declared
+    PayloadTooLarge {
+        size_bytes: usize,
+        limit_bytes: usize,
+    },
+    OptedOut {
+        phone: String,
+    },
+    /// The number looks like a landline or shortcode
+    NonMobileNumber {
+        phone: String,
+        number_type: String,
+    },
+    OptOutUnavailable {
+        reason: String,
+    },
+    Overloaded {
+        retry_after_secs: u64,
+    },
+    /// The caller, or sends to the number, are over `RATE_LIMIT_PER_KEY` or
+    /// `RATE_LIMIT_PER_NUMBER`
+    RateLimited {
+        limit: &'static str,
+        retry_after_secs: u64,
+    },
+    /// The send would take today's estimated cost past `DAILY_BUDGET`; it
+    /// can be retried after midnight UTC
+    BudgetExceeded {
+        spent: f64,
+        budget: f64,
+        retry_after_secs: u64,
+    },
+    JobNotFound {
+        id: String,
+    },
+    /// The job has made its last run, so it can't be paused or resumed
+    JobFinished {
+        id: String,
+    },
+    /// A natural-language schedule that couldn't be read
+    InvalidSchedule(crate::schedule::natural::NaturalError),
+    JobStoreUnavailable {
+        reason: String,
+    },
+    DeadLetterNotFound {
+        id: String,
+    },
+    DlqUnavailable {
+        reason: String,
+    },
+    GroupNotFound {
+        name: String,
+    },
+    RuleNotFound {
+        keyword: String,
+    },
+    /// No endpoint is at the path
+    RouteNotFound {
+        path: String,
+    },
+    GroupExists {
+        name: String,
+    },
+    ApiKeyNotFound {
+        name: String,
+    },
+    ApiKeyExists {
+        name: String,
+    },
+    /// No stored allow or block rule has the id, e.g. `block:2547*`
+    NumberRuleNotFound {
+        id: String,
+    },
+    NumberRuleExists {
+        id: String,
+    },
+    /// The bearer token is an API key that was revoked
+    ApiKeyRevoked {
+        name: String,
+    },
+    StorageUnavailable {
+        reason: String,
+    },
+    /// Phone numbers that aren't valid for their country
+    InvalidNumbers {
+        numbers: Vec<InvalidNumber>,
+    },
+    /// The message is longer than `MAX_SEGMENTS` allows
+    TooManySegments {
+        segments: usize,
+        max: usize,
+        encoding: &'static str,
+    },
+    /// The notification channel isn't configured
+    ChannelUnavailable {
+        channel: &'static str,
+        reason: String,
+    },
+    /// Sends are paused for provider maintenance
+    Maintenance {
+        retry_after_secs: u64,
+    },
+    /// A feature flag has this path switched off
+    FeatureDisabled {
+        feature: &'static str,
+    },
+    /// The precheck webhook declined the send
+    Skipped {
+        reason: String,
+    },
+    Unauthorized,
+    AdminDisabled,
+    MethodNotAllowed,
+    MissingReplayHeaders,
+    StaleTimestamp,
+    NonceReused,
+    /// `X-Signature` or its `X-Timestamp` is missing
+    MissingSignature,
+    /// `X-Signature` doesn't match the body and timestamp
+    InvalidSignature,
+    /// The bearer JWT failed verification
+    InvalidToken {
+        reason: String,
+    },
+    /// The caller's token doesn't grant the scope the endpoint needs
+    MissingScope {
+        scope: &'static str,
+    },
+    InvalidIdempotencyKey {
+        reason: String,
+    },
+    /// A request with the same idempotency key is still running
+    IdempotencyInProgress {
+        retry_after_secs: u64,
+    },
+    /// The idempotency key was first used for a different request
+    IdempotencyKeyReused,
+    IdempotencyUnavailable {
+        reason: String,
+    },
+    Provider(UjumbeSmsError),
+    /// A provider other than UjumbeSMS failed the call
+    ProviderFailed {
+        provider: &'static str,
+        reason: String,
+        retry_after: Option<std::time::Duration>,
+        /// Whether the same send could succeed if tried again
+        transient: bool,
+    },
+    /// The provider answered with something that isn't its JSON format,
+    /// typically an HTML error page during an incident
+    ProviderBadResponse {
+        raw: Option<String>,
+        parse_error: String,
+    },
+    /// The provider's circuit breaker is open after too many failures, so
+    /// nothing is sent to it until it's tried again
+    CircuitOpen {
+        provider: &'static str,
+        retry_after_secs: u64,
+    },
+    /// The provider didn't answer within `PROVIDER_TIMEOUT_SECS`, or within
+    /// what was left of the invocation, and the call was abandoned
+    ProviderTimeout {
+        provider: &'static str,
+        timeout_ms: u64,
+        /// What was left of the invocation's budget when it gave up, when
+        /// the call was made for a request
+        budget_remaining_ms: Option<u64>,
+    },
+    /// A bulk send split across routes stopped partway: `sent` covers the
+    /// groups that went out before `error` left `unsent` messages behind
+    PartiallySent {
+        sent: Box<SendReport>,
+        unsent: usize,
+        error: Box<ApiError>,
+    },
+}
+
+/// Longest provider body kept in an error response
+const MAX_RAW_LEN: usize = 1024;
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NumberBlocked { .. } => "number_blocked",
+            ApiError::NumberNotAllowed { .. } => "number_not_allowed",
+            ApiError::InvalidSenderId { .. } => "invalid_sender_id",
+            ApiError::InvalidBody { .. } => "invalid_body",
+            ApiError::InvalidQuery { .. } => "invalid_query",
+            ApiError::PayloadTooLarge { .. } => "payload_too_large",
+            ApiError::OptedOut { .. } => "opted_out",
+            ApiError::NonMobileNumber { .. } => "non_mobile_number",
+            ApiError::OptOutUnavailable { .. } => "optout_unavailable",
+            ApiError::Overloaded { .. } => "overloaded",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::BudgetExceeded { .. } => "budget_exceeded",
+            ApiError::JobNotFound { .. } => "job_not_found",
+            ApiError::JobFinished { .. } => "job_finished",
+            ApiError::InvalidSchedule(_) => "invalid_schedule",
+            ApiError::JobStoreUnavailable { .. } => "job_store_unavailable",
+            ApiError::DeadLetterNotFound { .. } => "dead_letter_not_found",
+            ApiError::DlqUnavailable { .. } => "dlq_unavailable",
+            ApiError::GroupNotFound { .. } => "group_not_found",
+            ApiError::RuleNotFound { .. } => "rule_not_found",
+            ApiError::RouteNotFound { .. } => "route_not_found",
+            ApiError::GroupExists { .. } => "group_exists",
+            ApiError::ApiKeyNotFound { .. } => "api_key_not_found",
+            ApiError::ApiKeyExists { .. } => "api_key_exists",
+            ApiError::NumberRuleNotFound { .. } => "number_rule_not_found",
+            ApiError::NumberRuleExists { .. } => "number_rule_exists",
+            ApiError::ApiKeyRevoked { .. } => "api_key_revoked",
+            ApiError::StorageUnavailable { .. } => "storage_unavailable",
+            ApiError::InvalidNumbers { .. } => "invalid_numbers",
+            ApiError::TooManySegments { .. } => "too_many_segments",
+            ApiError::ChannelUnavailable { .. } => "channel_unavailable",
+            ApiError::Maintenance { .. } => "maintenance",
+            ApiError::FeatureDisabled { .. } => "feature_disabled",
+            ApiError::Skipped { .. } => "skipped",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::AdminDisabled => "admin_disabled",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::MissingReplayHeaders => "missing_replay_headers",
+            ApiError::StaleTimestamp => "stale_timestamp",
+            ApiError::NonceReused => "nonce_reused",
+            ApiError::MissingSignature => "missing_signature",
+            ApiError::InvalidSignature => "invalid_signature",
+            ApiError::InvalidToken { .. } => "invalid_token",
+            ApiError::MissingScope { .. } => "missing_scope",
+            ApiError::InvalidIdempotencyKey { .. } => "invalid_idempotency_key",
+            ApiError::IdempotencyInProgress { .. } => "idempotency_in_progress",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::IdempotencyUnavailable { .. } => "idempotency_unavailable",
+            ApiError::Provider(_) | ApiError::ProviderFailed { .. } => "send_failed",
+            ApiError::ProviderBadResponse { .. } => "provider_bad_response",
+            ApiError::CircuitOpen { .. } => "circuit_open",
+            ApiError::ProviderTimeout { .. } => "provider_timeout",
+            ApiError::PartiallySent { .. } => "partially_sent",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NumberBlocked { .. }
+            | ApiError::NumberNotAllowed { .. }
+            | ApiError::OptedOut { .. } => StatusCode::FORBIDDEN,
+            ApiError::InvalidBody { .. }
+            | ApiError::InvalidQuery { .. }
+            | ApiError::InvalidIdempotencyKey { .. }
+            | ApiError::InvalidSchedule(_) => StatusCode::BAD_REQUEST,
+            ApiError::IdempotencyInProgress { .. }
+            | ApiError::JobFinished { .. }
+            | ApiError::GroupExists { .. }
+            | ApiError::ApiKeyExists { .. }
+            | ApiError::NumberRuleExists { .. } => StatusCode::CONFLICT,
+            ApiError::JobNotFound { .. }
+            | ApiError::DeadLetterNotFound { .. }
+            | ApiError::GroupNotFound { .. }
+            | ApiError::RuleNotFound { .. }
+            | ApiError::ApiKeyNotFound { .. }
+            | ApiError::NumberRuleNotFound { .. }
+            | ApiError::RouteNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::OptOutUnavailable { .. }
+            | ApiError::JobStoreUnavailable { .. }
+            | ApiError::DlqUnavailable { .. }
+            | ApiError::StorageUnavailable { .. }
+            | ApiError::ChannelUnavailable { .. }
+            | ApiError::IdempotencyUnavailable { .. }
+            | ApiError::Overloaded { .. }
+            | ApiError::CircuitOpen { .. }
+            | ApiError::Maintenance { .. }
+            | ApiError::FeatureDisabled { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidSenderId { .. }
+            | ApiError::NonMobileNumber { .. }
+            | ApiError::TooManySegments { .. }
+            | ApiError::InvalidNumbers { .. }
+            | ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized
+            | ApiError::MissingReplayHeaders
+            | ApiError::StaleTimestamp
+            | ApiError::NonceReused
+            | ApiError::MissingSignature
+            | ApiError::InvalidSignature
+            | ApiError::InvalidToken { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::AdminDisabled
+            | ApiError::ApiKeyRevoked { .. }
+            | ApiError::MissingScope { .. } => StatusCode::FORBIDDEN,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::RateLimited { .. } | ApiError::BudgetExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            // The precheck declined the send
+            ApiError::Skipped { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Provider(_)
+            | ApiError::ProviderFailed { .. }
+            | ApiError::ProviderBadResponse { .. } => StatusCode::BAD_GATEWAY,
+            ApiError::ProviderTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            // Whatever stopped the rest decides how the request failed
+            ApiError::PartiallySent { error, .. } => error.status(),
+        }
+    }
+
+    /// Values substituted into the catalog message for this error
+    fn params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ApiError::NumberBlocked { phone, rule } => {
+                vec![("phone", phone.clone()), ("rule", rule.clone())]
+            }
+            ApiError::NumberNotAllowed { phone } | ApiError::OptedOut { phone } => {
+                vec![("phone", phone.clone())]
+            }
+            ApiError::JobNotFound { id }
+            | ApiError::JobFinished { id }
+            | ApiError::DeadLetterNotFound { id } => {
+                vec![("id", id.clone())]
+            }
+            ApiError::RuleNotFound { keyword } => vec![("keyword", keyword.clone())],
+            ApiError::RouteNotFound { path } => vec![("path", path.clone())],
+            ApiError::NumberRuleNotFound { id } | ApiError::NumberRuleExists { id } => {
+                vec![("id", id.clone())]
+            }
+            ApiError::MissingScope { scope } => vec![("scope", scope.to_string())],
+            ApiError::GroupNotFound { name }
+            | ApiError::GroupExists { name }
+            | ApiError::ApiKeyNotFound { name }
+            | ApiError::ApiKeyExists { name }
+            | ApiError::ApiKeyRevoked { name } => {
+                vec![("name", name.clone())]
+            }
+            ApiError::InvalidBody { reason }
+            | ApiError::InvalidQuery { reason }
+            | ApiError::OptOutUnavailable { reason }
+            | ApiError::JobStoreUnavailable { reason }
+            | ApiError::DlqUnavailable { reason }
+            | ApiError::StorageUnavailable { reason }
+            | ApiError::InvalidIdempotencyKey { reason }
+            | ApiError::IdempotencyUnavailable { reason }
+            | ApiError::InvalidToken { reason }
+            | ApiError::Skipped { reason } => {
+                vec![("reason", reason.clone())]
+            }
+            ApiError::InvalidNumbers { numbers } => {
+                let phones: Vec<&str> =
+                    numbers.iter().map(|number| number.phone.as_str()).collect();
+                vec![("numbers", phones.join(", "))]
+            }
+            ApiError::TooManySegments {
+                segments,
+                max,
+                encoding,
+            } => vec![
+                ("segments", segments.to_string()),
+                ("max", max.to_string()),
+                ("encoding", encoding.to_string()),
+            ],
+            ApiError::ChannelUnavailable { channel, reason } => {
+                vec![("channel", channel.to_string()), ("reason", reason.clone())]
+            }
+            ApiError::FeatureDisabled { feature } => vec![("feature", feature.to_string())],
+            ApiError::InvalidSchedule(e) => {
+                vec![("schedule", e.input.clone()), ("reason", e.to_string())]
+            }
+            ApiError::NonMobileNumber { phone, number_type } => {
+                vec![
+                    ("phone", phone.clone()),
+                    ("number_type", number_type.clone()),
+                ]
+            }
+            ApiError::InvalidSenderId { sender_id, reason } => {
+                vec![("sender_id", sender_id.clone()), ("reason", reason.clone())]
+            }
+            ApiError::Overloaded { retry_after_secs }
+            | ApiError::Maintenance { retry_after_secs }
+            | ApiError::IdempotencyInProgress { retry_after_secs } => {
+                vec![("retry_after", retry_after_secs.to_string())]
+            }
+            ApiError::RateLimited {
+                limit,
+                retry_after_secs,
+            } => vec![
+                ("limit", limit.to_string()),
+                ("retry_after", retry_after_secs.to_string()),
+            ],
+            ApiError::BudgetExceeded {
+                spent,
+                budget,
+                retry_after_secs,
+            } => vec![
+                ("spent", format!("{:.2}", spent)),
+                ("budget", budget.to_string()),
+                ("retry_after", retry_after_secs.to_string()),
+            ],
+            ApiError::Unauthorized
+            | ApiError::AdminDisabled
+            | ApiError::MethodNotAllowed
+            | ApiError::MissingReplayHeaders
+            | ApiError::StaleTimestamp
+            | ApiError::NonceReused
+            | ApiError::MissingSignature
+            | ApiError::InvalidSignature
+            | ApiError::IdempotencyKeyReused => Vec::new(),
+            ApiError::Provider(e) => vec![("reason", e.to_string())],
+            ApiError::ProviderFailed {
+                provider, reason, ..
+            } => vec![("reason", format!("{}: {}", provider, reason))],
+            ApiError::ProviderBadResponse { parse_error, .. } => {
+                vec![("reason", parse_error.clone())]
+            }
+            ApiError::PayloadTooLarge {
+                size_bytes,
+                limit_bytes,
+            } => vec![
+                ("size", size_bytes.to_string()),
+                ("limit", limit_bytes.to_string()),
+            ],
+            ApiError::ProviderTimeout {
+                provider,
+                timeout_ms,
+                ..
+            } => vec![
+                ("provider", provider.to_string()),
+                ("timeout_ms", timeout_ms.to_string()),
+            ],
+            ApiError::CircuitOpen {
+                provider,
+                retry_after_secs,
+            } => vec![
+                ("provider", provider.to_string()),
+                ("retry_after", retry_after_secs.to_string()),
+            ],
+            ApiError::PartiallySent { unsent, error, .. } => vec![
+                ("unsent", unsent.to_string()),
+                ("reason", error.to_string()),
+            ],
+        }
+    }
+
+    /// Seconds a client should wait before retrying, sent as `Retry-After`
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            ApiError::Overloaded { retry_after_secs }
+            | ApiError::Maintenance { retry_after_secs }
+            | ApiError::IdempotencyInProgress { retry_after_secs }
+            | ApiError::RateLimited {
+                retry_after_secs, ..
+            }
+            | ApiError::BudgetExceeded {
+                retry_after_secs, ..
+            }
+            | ApiError::CircuitOpen {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            ApiError::PartiallySent { error, .. } => error.retry_after_secs(),
+            _ => None,
+        }
+    }
+
+    /// Extra fields reported alongside the code and message
+    pub fn details(&self) -> Option<Value> {
+        match self {
+            ApiError::ProviderBadResponse { raw, parse_error } => Some(json!({
+                "raw": raw,
+                "parse_error": parse_error,
+            })),
+            ApiError::ProviderTimeout {
+                provider,
+                timeout_ms,
+                budget_remaining_ms,
+            } => Some(json!({
+                "provider": provider,
+                "timeout_ms": timeout_ms,
+                "budget_remaining_ms": budget_remaining_ms,
+            })),
+            ApiError::PayloadTooLarge {
+                size_bytes,
+                limit_bytes,
+            } => Some(json!({
+                "size_bytes": size_bytes,
+                "limit_bytes": limit_bytes,
+            })),
+            ApiError::InvalidNumbers { numbers } => Some(json!({
+                "invalid_numbers": numbers,
+            })),
+            ApiError::InvalidSchedule(e) => Some(json!({
+                "understood": e.understood,
+                "unparsed": e.unparsed,
+                "expected": e.expected,
+            })),
+            ApiError::PartiallySent {
+                sent,
+                unsent,
+                error,
+            } => Some(json!({
+                "sent": {
+                    "provider": sent.provider,
+                    "message_ids": sent.message_ids,
+                    "recipients": sent.recipients,
+                    "credits_deducted": sent.credits_deducted,
+                    "raw": sent.raw,
+                },
+                "unsent": unsent,
+                "error": error.code(),
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn message(&self, lang: Lang) -> String {
+        i18n::render(i18n::message(self.code(), lang), &self.params())
+    }
+
+    /// The error as an RFC 7807 problem details object. `type` names the
+    /// code, `title` is the status text and `detail` the localized message;
+    /// `code` and the error's `details` are extension members.
+    pub fn problem(&self, lang: Lang) -> Value {
+        let status = self.status();
+        let mut problem = json!({
+            "type": format!("urn:scheduler:error:{}", self.code()),
+            "title": status.canonical_reason().unwrap_or("Error"),
+            "status": status.as_u16(),
+            "detail": self.message(lang),
+            "code": self.code(),
+        });
+        if let (Some(Value::Object(details)), Value::Object(fields)) =
+            (self.details(), &mut problem)
+        {
+            fields.extend(details);
+        }
+        problem
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message(Lang::En))
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<UjumbeSmsError> for ApiError {
+    fn from(error: UjumbeSmsError) -> Self {
+        match error {
+            // A non-2xx answer: keep it as a provider error if it's the usual
+            // JSON error body, otherwise report what came back
+            UjumbeSmsError::ApiError(status, body) => match serde_json::from_str::<Value>(&body) {
+                Ok(_) => ApiError::Provider(UjumbeSmsError::ApiError(status, body)),
+                Err(e) => ApiError::ProviderBadResponse {
+                    raw: Some(truncate(&body, MAX_RAW_LEN)),
+                    parse_error: format!("{} (HTTP {})", e, status),
+                },
+            },
+            // A 2xx answer that failed to decode; the body was consumed by the client
+            UjumbeSmsError::NetworkError(e) if e.is_decode() => ApiError::ProviderBadResponse {
+                raw: None,
+                parse_error: e.to_string(),
+            },
+            other => ApiError::Provider(other),
+        }
+    }
+}
+
+// Only provider failures carry a wait hint or are worth retrying
+impl RetryHint for ApiError {
+    fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            ApiError::Provider(e) => e.retry_after(),
+            ApiError::ProviderFailed { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Provider(e) => e.is_transient(),
+            ApiError::ProviderFailed { transient, .. } => *transient,
+            // Usually an error page served during an incident
+            ApiError::ProviderBadResponse { .. } => true,
+            ApiError::ProviderTimeout { .. } => true,
+            // Trying again would repeat the messages that did go out
+            ApiError::PartiallySent { .. } => false,
+            _ => false,
+        }
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => format!("{}…", &text[..index]),
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::Utc;
+use serde_json::json;
+
+use crate::error::ApiError;
+use crate::storage::{self, MessageQuery, MessageRecord};
+
+/// Records read from storage per round trip while exporting
+const PAGE_SIZE: usize = 500;
+
+/// Formats the message history can be exported in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// One JSON record per line
+    JsonLines,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "ndjson" | "json_lines" | "json-lines" => Ok(ExportFormat::JsonLines),
+            other => Err(format!("unknown export format: {}", other)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "created_at",
+    "status",
+    "phone",
+    "sender_id",
+    "message",
+    "attempts",
+    "provider",
+    "message_ids",
+    "cost",
+    "segments",
+    "estimated_cost",
+    "error",
+    "trace_id",
+];
+
+fn csv_field(value: &str) -> String {
+    // Spreadsheets evaluate cells starting with these as formulas, and a
+    // message body is whatever the caller sent
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_row(record: &MessageRecord) -> String {
+    let fields = [
+        record.id.clone(),
+        record.created_at.to_rfc3339(),
+        record.status.as_str().to_string(),
+        record.phone.clone(),
+        record.sender_id.clone(),
+        record.message.clone(),
+        record.attempts.to_string(),
+        record.provider.clone().unwrap_or_default(),
+        record.message_ids.join(";"),
+        record.cost.map(|cost| cost.to_string()).unwrap_or_default(),
+        record
+            .segments
+            .map(|segments| segments.to_string())
+            .unwrap_or_default(),
+        record
+            .estimated_cost
+            .map(|cost| cost.to_string())
+            .unwrap_or_default(),
+        record.error.clone().unwrap_or_default(),
+        record.trace_id.clone().unwrap_or_default(),
+    ];
+    let mut row = fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push_str("\r\n");
+    row
+}
+
+/// All recorded messages passing `query`'s filters, newest first, in
+/// `format`. The query's paging is ignored: every page is read in turn.
+pub async fn export(query: MessageQuery, format: ExportFormat) -> Result<String, ApiError> {
+    // Sends recorded while exporting would shift later pages, so only those
+    // recorded before the export began are read
+    let now = Utc::now();
+    let to = query.to.map_or(now, |to| to.min(now));
+
+    let mut out = String::new();
+    if format == ExportFormat::Csv {
+        out.push_str(&CSV_COLUMNS.join(","));
+        out.push_str("\r\n");
+    }
+    let mut offset = 0;
+    loop {
+        let page = storage::find_messages(MessageQuery {
+            to: Some(to),
+            offset,
+            limit: PAGE_SIZE,
+            ..query
+        })
+        .await?;
+        for record in &page.messages {
+            match format {
+                ExportFormat::Csv => out.push_str(&csv_row(record)),
+                ExportFormat::JsonLines => {
+                    out.push_str(&json!(record).to_string());
+                    out.push('\n');
+                }
+            }
+        }
+        if !page.has_more {
+            return Ok(out);
+        }
+        offset += PAGE_SIZE;
+    }
+}
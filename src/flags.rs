@@ -0,0 +1,145 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::error::ApiError;
+
+/// How long flags read from Edge Config are used before it's read again
+const CACHE_TTL: Duration = Duration::from_secs(15);
+
+/// Timeout for reading Edge Config
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Switches for risky paths, read from the Vercel Edge Config linked as
+/// `EDGE_CONFIG` so they can be flipped without a redeploy. Flags the store
+/// doesn't set keep their defaults, and its other items are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Flags {
+    /// Sends to `recipients` lists and contact groups
+    pub enable_bulk: bool,
+    /// WhatsApp job runs; jobs with an SMS fallback text instead
+    pub enable_whatsapp: bool,
+    /// Holds every provider call, as `POST /admin/kill-switch` does; see
+    /// `killswitch`
+    pub kill_switch: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            enable_bulk: true,
+            enable_whatsapp: true,
+            kill_switch: false,
+        }
+    }
+}
+
+impl Flags {
+    pub fn check_bulk(&self) -> Result<(), ApiError> {
+        if !self.enable_bulk {
+            return Err(ApiError::FeatureDisabled { feature: "bulk" });
+        }
+        Ok(())
+    }
+
+    pub fn check_whatsapp(&self) -> Result<(), ApiError> {
+        if !self.enable_whatsapp {
+            return Err(ApiError::FeatureDisabled {
+                feature: "whatsapp",
+            });
+        }
+        Ok(())
+    }
+}
+
+struct EdgeConfig {
+    http: reqwest::Client,
+    /// Carries the read token, so it's never logged
+    items_url: String,
+}
+
+impl EdgeConfig {
+    /// From a connection string such as
+    /// `https://edge-config.vercel.com/ecfg_abc?token=…`
+    fn parse(connection: &str) -> Result<Self, String> {
+        let (base, query) = connection
+            .trim()
+            .split_once('?')
+            .ok_or("EDGE_CONFIG has no token")?;
+        if !base.starts_with("https://") {
+            return Err("EDGE_CONFIG must be an https URL".to_string());
+        }
+        if !query.split('&').any(|pair| pair.starts_with("token=")) {
+            return Err("EDGE_CONFIG has no token".to_string());
+        }
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(EdgeConfig {
+            http,
+            items_url: format!("{}/items?{}", base.trim_end_matches('/'), query),
+        })
+    }
+
+    async fn fetch(&self) -> Result<Flags, String> {
+        let response = self
+            .http
+            .get(&self.items_url)
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Edge Config returned {}", response.status()));
+        }
+        response
+            .json::<Flags>()
+            .await
+            .map_err(|e| e.without_url().to_string())
+    }
+}
+
+static EDGE_CONFIG: Lazy<Option<Result<EdgeConfig, String>>> =
+    Lazy::new(|| match std::env::var("EDGE_CONFIG") {
+        Ok(connection) if !connection.trim().is_empty() => Some(
+            EdgeConfig::parse(&connection)
+                .inspect_err(|e| error!("Invalid EDGE_CONFIG, using default flags: {}", e)),
+        ),
+        _ => None,
+    });
+
+/// The last flags read, and when
+static CACHE: Lazy<Mutex<Option<(Instant, Flags)>>> = Lazy::new(|| Mutex::new(None));
+
+/// The flags now: read at most every few seconds, the last ones read while
+/// Edge Config can't be reached, and the defaults when it isn't linked
+pub async fn current() -> Flags {
+    let Some(Ok(edge)) = EDGE_CONFIG.as_ref() else {
+        return Flags::default();
+    };
+    let cached = *CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((fetched, flags)) = cached {
+        if fetched.elapsed() < CACHE_TTL {
+            return flags;
+        }
+    }
+    let last = cached.map(|(_, flags)| flags);
+    let flags = match edge.fetch().await {
+        Ok(flags) => {
+            if last != Some(flags) {
+                info!("Feature flags are now {:?}", flags);
+            }
+            flags
+        }
+        Err(e) => {
+            warn!("Couldn't read feature flags, keeping the last ones: {}", e);
+            last.unwrap_or_default()
+        }
+    };
+    // A failed read is cached too, so Edge Config isn't asked on every request
+    *CACHE.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), flags));
+    flags
+}
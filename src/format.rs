@@ -0,0 +1,362 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Wire formats the API accepts and responds with; JSON unless negotiated otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next()?.trim().to_ascii_lowercase();
+        match essence.as_str() {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    /// Format of a request body, from its `Content-Type`
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        content_type
+            .and_then(Format::from_media_type)
+            .unwrap_or_default()
+    }
+
+    /// Response format from the `Accept` header: the supported format with
+    /// the highest `q`, the first listed on a tie. `q=0` rules a format out.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let mut best: Option<(Format, f32)> = None;
+        for range in accept.into_iter().flat_map(|header| header.split(',')) {
+            let Some(format) = Format::from_media_type(range) else {
+                continue;
+            };
+            let q = range
+                .split(';')
+                .skip(1)
+                .find_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("q")
+                        .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+                })
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+        best.map(|(format, _)| format).unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, FormatError> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(FormatError::Json),
+            // Named fields so non-Rust clients get maps rather than positional arrays
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(FormatError::Encode),
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FormatError> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(FormatError::Json),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(FormatError::Decode),
+        }
+    }
+}
+
+/// Whether a `Content-Type` is an HTML form, as providers post callbacks
+pub fn is_form(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|essence| {
+            essence
+                .trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+}
+
+/// A form-encoded body's fields, decoded, in the order they came
+pub fn form_fields(body: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+    let decode = |value: &str| {
+        urlencoding::decode(&value.replace('+', " "))
+            .map(|value| value.into_owned())
+            .map_err(|e| e.to_string())
+    };
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+/// A form-encoded body as a JSON object of string fields, so it can be
+/// deserialized like a JSON body. A repeated field keeps its last value.
+pub fn parse_form(body: &[u8]) -> Result<Map<String, Value>, String> {
+    Ok(form_fields(body)?
+        .into_iter()
+        .map(|(key, value)| (key, Value::String(value)))
+        .collect())
+}
+
+/// Form fields as the JSON object an API request is parsed from, for clients
+/// that can only post forms. A field that's repeated or named with `[]`
+/// becomes a list, e.g. `recipients[]=254700000001&recipients[]=...`, and a
+/// value holding a JSON object or list is read as one, e.g.
+/// `retry_policy={"max_attempts":3}`.
+pub fn form_request(fields: Vec<(String, String)>) -> Value {
+    let mut object = Map::new();
+    for (key, value) in fields {
+        let (key, listed) = match key.strip_suffix("[]") {
+            Some(key) => (key.to_string(), true),
+            None => (key, false),
+        };
+        let trimmed = value.trim_start();
+        let value = if trimmed.starts_with('{') || trimmed.starts_with('[') {
+            serde_json::from_str(&value).unwrap_or(Value::String(value))
+        } else {
+            Value::String(value)
+        };
+        match object.get_mut(&key) {
+            Some(Value::Array(values)) if listed => values.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None if listed => {
+                object.insert(key, Value::Array(vec![value]));
+            }
+            None => {
+                object.insert(key, value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// The boundary of a `multipart/form-data` body, from its `Content-Type`
+pub fn multipart_boundary(content_type: Option<&str>) -> Option<String> {
+    let mut params = content_type?.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("boundary")
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// One part of a multipart form: a field, or a file when it has a filename
+#[derive(Debug, Clone)]
+pub struct FormPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn strip_line_end(bytes: &[u8]) -> Option<&[u8]> {
+    bytes.strip_prefix(b"\r\n").or(bytes.strip_prefix(b"\n"))
+}
+
+/// `name="value"` parameters of a header, splitting on `;` outside quotes
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut quoted = false;
+    for c in value.chars().chain(std::iter::once(';')) {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                param.push(c);
+            }
+            ';' if !quoted => {
+                if let Some((name, value)) = param.split_once('=') {
+                    params.push((
+                        name.trim().to_ascii_lowercase(),
+                        value.trim().trim_matches('"').to_string(),
+                    ));
+                }
+                param.clear();
+            }
+            _ => param.push(c),
+        }
+    }
+    params
+}
+
+fn parse_part(part: &[u8]) -> Result<FormPart, String> {
+    let (head, data) = match strip_line_end(part) {
+        // A part with no headers
+        Some(data) => (&b""[..], data),
+        None => match find(part, b"\r\n\r\n") {
+            Some(end) => (&part[..end], &part[end + 4..]),
+            None => match find(part, b"\n\n") {
+                Some(end) => (&part[..end], &part[end + 2..]),
+                None => return Err("a multipart part has no blank line after its headers".into()),
+            },
+        },
+    };
+    let head = std::str::from_utf8(head).map_err(|e| e.to_string())?;
+    let (mut name, mut filename, mut content_type) = (None, None, None);
+    for line in head.lines() {
+        let Some((header, value)) = line.split_once(':') else {
+            continue;
+        };
+        if header.trim().eq_ignore_ascii_case("content-disposition") {
+            for (param, value) in header_params(value) {
+                match param.as_str() {
+                    "name" => name = Some(value),
+                    "filename" => filename = Some(value),
+                    _ => {}
+                }
+            }
+        } else if header.trim().eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+    Ok(FormPart {
+        name: name.ok_or("a multipart part has no name")?,
+        filename,
+        content_type,
+        data: data.to_vec(),
+    })
+}
+
+/// The parts of a `multipart/form-data` body, in order
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<FormPart>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let start = find(body, &delimiter).ok_or("the body has no multipart boundary")?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+    // The closing delimiter has `--` after it
+    while !rest.starts_with(b"--") {
+        rest = strip_line_end(rest).ok_or("a multipart boundary isn't on a line of its own")?;
+        let end = find(rest, &delimiter).ok_or("the multipart body isn't closed")?;
+        let part = &rest[..end];
+        // The line break before a boundary belongs to it
+        let part = part
+            .strip_suffix(b"\r\n")
+            .or(part.strip_suffix(b"\n"))
+            .unwrap_or(part);
+        parts.push(parse_part(part)?);
+        rest = &rest[end + delimiter.len()..];
+    }
+    Ok(parts)
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Json(e) => write!(f, "JSON error: {}", e),
+            FormatError::Encode(e) => write!(f, "MessagePack encode error: {}", e),
+            FormatError::Decode(e) => write!(f, "MessagePack decode error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn accept_honors_q_values() {
+        let accept = |header: &str| Format::from_accept(Some(header));
+        assert_eq!(accept("application/msgpack"), Format::MessagePack);
+        assert_eq!(accept("application/msgpack;q=0"), Format::Json);
+        assert_eq!(
+            accept("application/msgpack; q=0, application/json"),
+            Format::Json
+        );
+        assert_eq!(
+            accept("application/json;q=0.5, application/x-msgpack;q=0.9"),
+            Format::MessagePack
+        );
+        assert_eq!(
+            accept("application/msgpack, application/json"),
+            Format::MessagePack
+        );
+        assert_eq!(accept("text/html, */*;q=0.8"), Format::Json);
+        assert_eq!(Format::from_accept(None), Format::Json);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Recipient {
+        Phone(String),
+        Message {
+            phone: String,
+            #[serde(default)]
+            vars: BTreeMap<String, String>,
+        },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Request {
+        phone: Option<String>,
+        recipients: Vec<Recipient>,
+        retry: Option<u32>,
+    }
+
+    #[test]
+    fn untagged_values_round_trip_through_messagepack() {
+        let request = Request {
+            phone: None,
+            recipients: vec![
+                Recipient::Phone("254700000001".to_string()),
+                Recipient::Message {
+                    phone: "254700000002".to_string(),
+                    vars: BTreeMap::from([("name".to_string(), "Amina".to_string())]),
+                },
+            ],
+            retry: Some(3),
+        };
+        let bytes = Format::MessagePack.serialize(&request).unwrap();
+        let decoded: Request = Format::MessagePack.deserialize(&bytes).unwrap();
+        assert_eq!(decoded, request);
+
+        // Named fields, so other clients see a map
+        let value: Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(value["recipients"][1]["vars"]["name"], "Amina");
+    }
+}
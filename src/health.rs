@@ -0,0 +1,205 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::kv;
+use crate::providers::SmsProvider;
+use crate::storage;
+
+/// Longest a dependency gets to answer before it counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a provider check is reused, so monitors polling
+/// `/health/provider` don't each cost a gateway call
+const PROVIDER_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// One dependency's state. Errors are kept to a short reason, since the
+/// endpoints are open; the details are logged.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<&'static str>,
+}
+
+impl Check {
+    fn passed(started: Instant) -> Self {
+        Check {
+            ok: true,
+            latency_ms: started.elapsed().as_millis() as u64,
+            error: None,
+            note: None,
+        }
+    }
+
+    fn failed(started: Instant, error: impl Into<String>) -> Self {
+        Check {
+            ok: false,
+            latency_ms: started.elapsed().as_millis() as u64,
+            error: Some(error.into()),
+            note: None,
+        }
+    }
+}
+
+/// Runs a round trip to a dependency, logging why it failed
+async fn probe<F>(name: &str, call: F) -> Check
+where
+    F: Future<Output = io::Result<()>>,
+{
+    let started = Instant::now();
+    match tokio::time::timeout(CHECK_TIMEOUT, call).await {
+        Ok(Ok(())) => Check::passed(started),
+        Ok(Err(e)) => {
+            warn!("Readiness: {} is unavailable: {}", name, e);
+            Check::failed(started, "unavailable")
+        }
+        Err(_) => {
+            warn!(
+                "Readiness: {} didn't answer within {:?}",
+                name, CHECK_TIMEOUT
+            );
+            Check::failed(started, "timed out")
+        }
+    }
+}
+
+/// Whether the instance can serve requests: its config loads and the
+/// storage it's set up with answers
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Checks the config and each storage the deployment links: the
+/// `STORAGE_BACKEND` store and Vercel KV. Unlinked ones pass, as the
+/// instance keeps that state in memory.
+pub async fn readiness(config: Result<(), String>) -> Readiness {
+    let mut checks = BTreeMap::new();
+
+    let started = Instant::now();
+    checks.insert(
+        "config",
+        match config {
+            Ok(()) => Check::passed(started),
+            Err(e) => {
+                warn!("Readiness: the config is invalid: {}", e);
+                Check::failed(started, "invalid")
+            }
+        },
+    );
+
+    let started = Instant::now();
+    let check = match storage::backend() {
+        Some(Ok(backend)) => probe("storage", backend.ping()).await,
+        Some(Err(e)) => {
+            warn!("Readiness: storage is misconfigured: {}", e);
+            Check::failed(started, "misconfigured")
+        }
+        None => Check {
+            note: Some("in memory"),
+            ..Check::passed(started)
+        },
+    };
+    checks.insert("storage", check);
+
+    let started = Instant::now();
+    let check = match kv::client() {
+        Some(Ok(client)) => {
+            probe("kv", async {
+                client.command(&["PING"]).await?;
+                Ok(())
+            })
+            .await
+        }
+        Some(Err(e)) => {
+            warn!("Readiness: Vercel KV is misconfigured: {}", e);
+            Check::failed(started, "misconfigured")
+        }
+        None => Check {
+            note: Some("not linked"),
+            ..Check::passed(started)
+        },
+    };
+    checks.insert("kv", check);
+
+    Readiness {
+        ready: checks.values().all(|check| check.ok),
+        checks,
+    }
+}
+
+/// Whether the SMS gateway answers, as seen by a balance call
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub provider: String,
+    #[serde(flatten)]
+    pub check: Check,
+    /// Whether this is an earlier check, reused
+    pub cached: bool,
+}
+
+static PROVIDER_CHECK: Lazy<Mutex<Option<(Instant, ProviderHealth)>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Asks the primary provider for its balance, the cheapest call every
+/// gateway has, reusing the answer for `PROVIDER_CACHE_TTL`. `provider`
+/// names it should the call fail.
+pub async fn provider(client: &impl SmsProvider, provider: &str) -> ProviderHealth {
+    let cached = PROVIDER_CHECK
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some((checked, health)) = cached {
+        if checked.elapsed() < PROVIDER_CACHE_TTL {
+            debug!(
+                "Reusing the provider check from {:?} ago",
+                checked.elapsed()
+            );
+            return ProviderHealth {
+                cached: true,
+                ..health
+            };
+        }
+    }
+
+    let started = Instant::now();
+    let health = match tokio::time::timeout(CHECK_TIMEOUT, client.get_balance()).await {
+        Ok(Ok(balance)) => ProviderHealth {
+            provider: balance.provider.to_string(),
+            check: Check::passed(started),
+            cached: false,
+        },
+        Ok(Err(e)) => {
+            warn!("Provider check: {} failed: {}", provider, e);
+            ProviderHealth {
+                provider: provider.to_string(),
+                check: Check::failed(started, e.code()),
+                cached: false,
+            }
+        }
+        Err(_) => {
+            warn!(
+                "Provider check: {} didn't answer within {:?}",
+                provider, CHECK_TIMEOUT
+            );
+            ProviderHealth {
+                provider: provider.to_string(),
+                check: Check::failed(started, "timed out"),
+                cached: false,
+            }
+        }
+    };
+    *PROVIDER_CHECK.lock().unwrap_or_else(|e| e.into_inner()) =
+        Some((Instant::now(), health.clone()));
+    health
+}
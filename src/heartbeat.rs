@@ -0,0 +1,95 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::kv;
+
+/// KV key the last tick is kept under
+const KEY: &str = "status:last_tick";
+
+/// How long KV keeps the last tick; a deployment that hasn't ticked in that
+/// long reports none at all
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How the last cron tick went
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tick {
+    pub at: DateTime<Utc>,
+    /// Scheduled sends and job runs sent
+    pub dispatched: u64,
+    /// Scheduled sends and job runs that failed
+    pub failed: u64,
+    /// Lanes whose scheduled sends or jobs couldn't be read
+    pub errors: u64,
+    /// Whether the kill switch held everything
+    pub killed: bool,
+    pub duration_ms: u64,
+    /// Seconds since the tick before, when there was one; filled in by
+    /// `record`
+    #[serde(default)]
+    pub gap_secs: Option<i64>,
+}
+
+/// The last tick when KV isn't linked, for this instance only
+static MEMORY: Lazy<Mutex<Option<Tick>>> = Lazy::new(|| Mutex::new(None));
+
+/// Records a tick, returning the one before it. Kept in Vercel KV when it's
+/// linked, so `/status` sees ticks that ran on other instances; failing to
+/// store it is only logged.
+pub async fn record(mut tick: Tick) -> Option<Tick> {
+    let previous = last().await;
+    tick.gap_secs = previous
+        .as_ref()
+        .map(|previous| (tick.at - previous.at).num_seconds());
+    debug!(
+        "Tick dispatched {} and failed {} in {}ms",
+        tick.dispatched, tick.failed, tick.duration_ms
+    );
+    *MEMORY.lock().unwrap_or_else(|e| e.into_inner()) = Some(tick.clone());
+    let Some(client) = kv::client() else {
+        return previous;
+    };
+    let result = match (
+        client.map_err(io::Error::other),
+        serde_json::to_string(&tick),
+    ) {
+        (Ok(client), Ok(value)) => client.set(KEY, &value, RETENTION).await,
+        (Err(e), _) => Err(e),
+        (_, Err(e)) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        warn!("Failed to store the last tick: {}", e);
+    }
+    previous
+}
+
+/// The last tick any instance recorded, or this instance's when KV can't
+/// be read
+pub async fn last() -> Option<Tick> {
+    let local = MEMORY.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let Some(client) = kv::client() else {
+        return local;
+    };
+    let result = match client.map_err(io::Error::other) {
+        Ok(client) => client.get(KEY).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(Some(value)) => match serde_json::from_str(&value) {
+            Ok(tick) => Some(tick),
+            Err(e) => {
+                warn!("Ignoring the stored last tick: {}", e);
+                local
+            }
+        },
+        Ok(None) => local,
+        Err(e) => {
+            warn!("Last tick unavailable, using this instance's: {}", e);
+            local
+        }
+    }
+}
@@ -0,0 +1,395 @@
+/// Languages client-facing messages are available in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Sw,
+}
+
+impl Lang {
+    /// Maps a language tag such as `sw`, `sw-KE` or `en-US` to a supported language
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            "sw" => Some(Lang::Sw),
+            _ => None,
+        }
+    }
+
+    /// Picks the response language: an explicit `?lang=` wins, then the
+    /// highest-weighted supported `Accept-Language` entry, then English.
+    pub fn negotiate(query_lang: Option<&str>, accept_language: Option<&str>) -> Self {
+        if let Some(lang) = query_lang.and_then(Lang::from_tag) {
+            return lang;
+        }
+
+        let Some(header) = accept_language else {
+            return Lang::default();
+        };
+
+        let mut candidates: Vec<(f32, &str)> = header
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((quality, tag))
+            })
+            .collect();
+        // Stable sort keeps header order for equal weights
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        candidates
+            .into_iter()
+            .filter(|(quality, _)| *quality > 0.0)
+            .find_map(|(_, tag)| Lang::from_tag(tag))
+            .unwrap_or_default()
+    }
+}
+
+// (code, English, Swahili)
+const CATALOG: &[(&str, &str, &str)] = &[
+    (
+        "number_blocked",
+        "Number {phone} is blocked by rule {rule}",
+        "Nambari {phone} imezuiwa na sheria {rule}",
+    ),
+    (
+        "number_not_allowed",
+        "Number {phone} is not on the allowlist",
+        "Nambari {phone} haipo kwenye orodha ya nambari zinazoruhusiwa",
+    ),
+    (
+        "invalid_sender_id",
+        "Sender ID {sender_id} is invalid: {reason}",
+        "Kitambulisho cha mtumaji {sender_id} si sahihi: {reason}",
+    ),
+    (
+        "invalid_body",
+        "The request body is invalid: {reason}",
+        "Maudhui ya ombi si sahihi: {reason}",
+    ),
+    (
+        "invalid_query",
+        "The query string is invalid: {reason}",
+        "Vigezo vya ombi si sahihi: {reason}",
+    ),
+    (
+        "payload_too_large",
+        "The request body is {size} bytes, over the limit of {limit}",
+        "Maudhui ya ombi ni baiti {size}, zaidi ya kikomo cha {limit}",
+    ),
+    (
+        "opted_out",
+        "Number {phone} has opted out of messages",
+        "Nambari {phone} imejiondoa kupokea ujumbe",
+    ),
+    (
+        "optout_unavailable",
+        "The opt-out list is unavailable: {reason}",
+        "Orodha ya waliojiondoa haipatikani: {reason}",
+    ),
+    (
+        "overloaded",
+        "The service is busy, retry in {retry_after} seconds",
+        "Huduma ina shughuli nyingi, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "rate_limited",
+        "Too many requests for this {limit}, retry in {retry_after} seconds",
+        "Maombi mengi mno kwa {limit} hii, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "budget_exceeded",
+        "Sends today have cost {spent} of the daily budget of {budget}, retry in {retry_after} seconds",
+        "Ujumbe wa leo umegharimu {spent} kati ya bajeti ya siku ya {budget}, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "non_mobile_number",
+        "{phone} looks like a {number_type} number, not a mobile; set allow_nonmobile=true to send anyway",
+        "{phone} inaonekana kuwa nambari ya {number_type}, si ya simu ya mkononi; weka allow_nonmobile=true kutuma hata hivyo",
+    ),
+    (
+        "skipped",
+        "Send skipped: {reason}",
+        "Utumaji umerukwa: {reason}",
+    ),
+    (
+        "job_not_found",
+        "No job with id {id}",
+        "Hakuna kazi yenye kitambulisho {id}",
+    ),
+    (
+        "invalid_schedule",
+        "Couldn't understand schedule {schedule}: {reason}",
+        "Ratiba {schedule} haikueleweka: {reason}",
+    ),
+    (
+        "job_finished",
+        "Job {id} has finished and can't be paused or resumed",
+        "Kazi {id} imekamilika na haiwezi kusitishwa wala kuendelezwa",
+    ),
+    (
+        "group_not_found",
+        "No contact group named {name}",
+        "Hakuna kikundi cha anwani kiitwacho {name}",
+    ),
+    (
+        "rule_not_found",
+        "No auto-reply rule for keyword {keyword}",
+        "Hakuna kanuni ya jibu la moja kwa moja kwa neno {keyword}",
+    ),
+    (
+        "group_exists",
+        "A contact group named {name} already exists",
+        "Kikundi cha anwani kiitwacho {name} tayari kipo",
+    ),
+    (
+        "api_key_not_found",
+        "No API key named {name}",
+        "Hakuna ufunguo wa API unaoitwa {name}",
+    ),
+    (
+        "api_key_exists",
+        "An API key named {name} already exists",
+        "Ufunguo wa API unaoitwa {name} tayari upo",
+    ),
+    (
+        "number_rule_not_found",
+        "No stored number rule {id}",
+        "Hakuna kanuni ya namba {id} iliyohifadhiwa",
+    ),
+    (
+        "number_rule_exists",
+        "The number rule {id} already exists",
+        "Kanuni ya namba {id} tayari ipo",
+    ),
+    (
+        "api_key_revoked",
+        "API key {name} has been revoked",
+        "Ufunguo wa API {name} umebatilishwa",
+    ),
+    (
+        "job_store_unavailable",
+        "Jobs are unavailable: {reason}",
+        "Kazi hazipatikani: {reason}",
+    ),
+    (
+        "dead_letter_not_found",
+        "No dead-lettered message with id {id}",
+        "Hakuna ujumbe ulioshindwa wenye kitambulisho {id}",
+    ),
+    (
+        "dlq_unavailable",
+        "Dead-lettered messages are unavailable: {reason}",
+        "Jumbe zilizoshindwa hazipatikani: {reason}",
+    ),
+    (
+        "storage_unavailable",
+        "Storage is unavailable: {reason}",
+        "Hifadhi haipatikani: {reason}",
+    ),
+    (
+        "invalid_numbers",
+        "These phone numbers aren't valid: {numbers}",
+        "Nambari hizi za simu si sahihi: {numbers}",
+    ),
+    (
+        "too_many_segments",
+        "The message needs {segments} {encoding} segments, more than the limit of {max}",
+        "Ujumbe unahitaji sehemu {segments} za {encoding}, zaidi ya kikomo cha {max}",
+    ),
+    (
+        "channel_unavailable",
+        "The {channel} channel is unavailable: {reason}",
+        "Njia ya {channel} haipatikani: {reason}",
+    ),
+    (
+        "maintenance",
+        "Sending is paused for maintenance, retry in {retry_after} seconds",
+        "Utumaji umesitishwa kwa ajili ya matengenezo, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "feature_disabled",
+        "{feature} is switched off for now",
+        "{feature} imezimwa kwa sasa",
+    ),
+    (
+        "unauthorized",
+        "A valid bearer token is required",
+        "Tokeni halali ya bearer inahitajika",
+    ),
+    (
+        "admin_disabled",
+        "Admin endpoints are disabled on this deployment",
+        "Huduma za msimamizi zimezimwa kwenye usambazaji huu",
+    ),
+    (
+        "route_not_found",
+        "There is no endpoint at {path}",
+        "Hakuna huduma katika {path}",
+    ),
+    (
+        "method_not_allowed",
+        "This endpoint does not support that method",
+        "Huduma hii haikubali njia hiyo ya ombi",
+    ),
+    (
+        "missing_replay_headers",
+        "X-Timestamp and X-Nonce headers are required",
+        "Vichwa vya X-Timestamp na X-Nonce vinahitajika",
+    ),
+    (
+        "stale_timestamp",
+        "Request timestamp is missing or outside the allowed window",
+        "Muda wa ombi haupo au uko nje ya kipindi kinachoruhusiwa",
+    ),
+    (
+        "nonce_reused",
+        "This request has already been received",
+        "Ombi hili tayari limepokelewa",
+    ),
+    (
+        "missing_signature",
+        "Signed requests need X-Signature and X-Timestamp headers",
+        "Maombi yaliyosainiwa yanahitaji vichwa vya X-Signature na X-Timestamp",
+    ),
+    (
+        "invalid_signature",
+        "The request signature does not match",
+        "Sahihi ya ombi hailingani",
+    ),
+    (
+        "invalid_token",
+        "The bearer token is not valid: {reason}",
+        "Tokeni ya bearer si halali: {reason}",
+    ),
+    (
+        "missing_scope",
+        "This token does not grant the {scope} scope",
+        "Tokeni hii haitoi ruhusa ya {scope}",
+    ),
+    (
+        "invalid_idempotency_key",
+        "Invalid Idempotency-Key header: {reason}",
+        "Kichwa cha Idempotency-Key si sahihi: {reason}",
+    ),
+    (
+        "idempotency_in_progress",
+        "A request with this Idempotency-Key is still being processed, retry in {retry_after} seconds",
+        "Ombi lenye Idempotency-Key hii bado linashughulikiwa, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "idempotency_key_reused",
+        "This Idempotency-Key was already used for a different request",
+        "Idempotency-Key hii tayari imetumika kwa ombi tofauti",
+    ),
+    (
+        "idempotency_unavailable",
+        "Idempotency keys are unavailable: {reason}",
+        "Funguo za idempotency hazipatikani: {reason}",
+    ),
+    (
+        "send_failed",
+        "Failed to send SMS: {reason}",
+        "Imeshindwa kutuma SMS: {reason}",
+    ),
+    (
+        "circuit_open",
+        "Sends to {provider} are paused after repeated failures, retry in {retry_after} seconds",
+        "Utumaji kupitia {provider} umesitishwa baada ya kushindwa mara kwa mara, jaribu tena baada ya sekunde {retry_after}",
+    ),
+    (
+        "provider_timeout",
+        "{provider} didn't answer within {timeout_ms} ms, so the send was abandoned",
+        "{provider} haikujibu ndani ya milisekunde {timeout_ms}, kwa hivyo utumaji ulisitishwa",
+    ),
+    (
+        "provider_bad_response",
+        "The SMS provider returned an unexpected response: {reason}",
+        "Mtoa huduma wa SMS alirudisha jibu lisilotarajiwa: {reason}",
+    ),
+    (
+        "partially_sent",
+        "Some messages were sent, but {unsent} were not: {reason}",
+        "Baadhi ya jumbe zilitumwa, lakini {unsent} hazikutumwa: {reason}",
+    ),
+];
+
+/// Looks up the message template for an error code, in English when it has
+/// no translation yet, or the code itself when it isn't in the catalog.
+pub fn message(code: &'static str, lang: Lang) -> &'static str {
+    lookup(CATALOG, code, lang)
+}
+
+fn lookup(
+    catalog: &[(&'static str, &'static str, &'static str)],
+    code: &'static str,
+    lang: Lang,
+) -> &'static str {
+    catalog
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map(|(_, en, sw)| match lang {
+            Lang::Sw if !sw.is_empty() => *sw,
+            _ => *en,
+        })
+        .unwrap_or(code)
+}
+
+/// Replaces `{name}` placeholders in a template with the given values
+pub fn render(template: &str, params: &[(&str, String)]) -> String {
+    params
+        .iter()
+        .fold(template.to_string(), |acc, (name, value)| {
+            acc.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swahili_messages_are_looked_up_and_filled_in() {
+        let template = message("opted_out", Lang::Sw);
+        assert_eq!(template, "Nambari {phone} imejiondoa kupokea ujumbe");
+        assert_eq!(
+            render(template, &[("phone", "2547****0001".to_string())]),
+            "Nambari 2547****0001 imejiondoa kupokea ujumbe"
+        );
+        assert_eq!(
+            message("opted_out", Lang::En),
+            "Number {phone} has opted out of messages"
+        );
+    }
+
+    #[test]
+    fn languages_are_negotiated() {
+        assert_eq!(Lang::negotiate(Some("sw"), Some("en")), Lang::Sw);
+        assert_eq!(Lang::negotiate(None, Some("sw-KE")), Lang::Sw);
+        assert_eq!(Lang::negotiate(None, Some("fr-FR, sw;q=0.5")), Lang::Sw);
+        assert_eq!(Lang::negotiate(None, Some("en;q=0.4, sw;q=0.9")), Lang::Sw);
+        assert_eq!(Lang::negotiate(None, Some("sw;q=0")), Lang::En);
+    }
+
+    #[test]
+    fn unknown_languages_fall_back_to_english() {
+        assert_eq!(Lang::negotiate(Some("fr"), None), Lang::En);
+        assert_eq!(Lang::negotiate(Some("xx"), Some("de, fr")), Lang::En);
+        assert_eq!(Lang::negotiate(None, None), Lang::En);
+    }
+
+    #[test]
+    fn missing_translations_fall_back_to_english() {
+        const CATALOG: &[(&str, &str, &str)] = &[("half_done", "In English only", "")];
+        assert_eq!(lookup(CATALOG, "half_done", Lang::Sw), "In English only");
+        // Not in the catalog at all, the code is all there is to show
+        assert_eq!(lookup(CATALOG, "no_such_code", Lang::Sw), "no_such_code");
+        assert_eq!(message("no_such_code", Lang::En), "no_such_code");
+    }
+}
@@ -0,0 +1,377 @@
+use http::HeaderMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::error::ApiError;
+use crate::kv::{self, KvClient};
+use crate::redis::{RedisClient, Reply};
+use crate::storage::{self, Storage};
+
+pub const HEADER: &str = "idempotency-key";
+
+/// Longest key accepted, matching common client libraries
+const MAX_KEY_LEN: usize = 255;
+
+/// How long a key stays reserved while its first request is still running
+const PENDING_TTL: Duration = Duration::from_secs(300);
+
+/// Seconds a duplicate is told to wait while the first request runs
+const IN_PROGRESS_RETRY_AFTER_SECS: u64 = 1;
+
+/// A response kept for replaying to duplicates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Entry {
+    Pending {
+        fingerprint: u64,
+    },
+    Done {
+        fingerprint: u64,
+        response: CachedResponse,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Entry::Pending { fingerprint } | Entry::Done { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+/// Outcome of `begin` for a key
+#[derive(Debug)]
+pub enum Begin {
+    /// First time this key is seen; the request should run and `complete`
+    Fresh,
+    /// Seen and finished before; send this instead of running again
+    Replay(CachedResponse),
+}
+
+/// The `Idempotency-Key` header, if sent and well-formed
+pub fn key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(value) = headers.get(HEADER) else {
+        return Ok(None);
+    };
+    let invalid = |reason: &str| ApiError::InvalidIdempotencyKey {
+        reason: reason.to_string(),
+    };
+    let key = value
+        .to_str()
+        .map_err(|_| invalid("must be visible ASCII"))?
+        .trim();
+    if key.is_empty() {
+        return Err(invalid("must not be empty"));
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(invalid("must be at most 255 characters"));
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// Identifies what a key was first used for, so reusing it for a different
+/// request can be refused rather than answered with the wrong response
+pub fn fingerprint(method: &str, path: &str, query: Option<&str>, body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (method, path, query.unwrap_or_default(), body).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keys kept for the lifetime of the instance
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (Entry, Instant)>>,
+}
+
+impl MemoryStore {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Entry, Instant)>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reserve(&self, key: &str, entry: Entry, ttl: Duration) -> Option<Entry> {
+        let mut entries = self.lock();
+        let now = Instant::now();
+        entries.retain(|_, (_, expires)| *expires > now);
+        if let Some((existing, _)) = entries.get(key) {
+            return Some(existing.clone());
+        }
+        entries.insert(key.to_string(), (entry, now + ttl));
+        None
+    }
+
+    fn set(&self, key: &str, entry: Entry, ttl: Duration) {
+        self.lock()
+            .insert(key.to_string(), (entry, Instant::now() + ttl));
+    }
+
+    fn delete(&self, key: &str) {
+        self.lock().remove(key);
+    }
+}
+
+/// Keys shared by every instance through Redis
+#[derive(Debug)]
+pub struct RedisStore {
+    client: RedisClient,
+}
+
+impl RedisStore {
+    async fn reserve(&self, key: &str, entry: &Entry, ttl: Duration) -> io::Result<Option<Entry>> {
+        let value = encode(entry)?;
+        let ttl = ttl.as_secs().max(1).to_string();
+        for _ in 0..2 {
+            let reply = self
+                .client
+                .command(&[b"SET", key.as_bytes(), &value, b"NX", b"EX", ttl.as_bytes()])
+                .await?;
+            if let Reply::Ok = reply {
+                return Ok(None);
+            }
+            if let Reply::Bulk(existing) = self.client.command(&[b"GET", key.as_bytes()]).await? {
+                return decode(&existing).map(Some);
+            }
+            // Expired between the two commands; try to reserve it again
+        }
+        Err(io::Error::other(
+            "idempotency key expired while being reserved",
+        ))
+    }
+
+    async fn set(&self, key: &str, entry: &Entry, ttl: Duration) -> io::Result<()> {
+        let value = encode(entry)?;
+        let ttl = ttl.as_secs().max(1).to_string();
+        self.client
+            .command(&[b"SET", key.as_bytes(), &value, b"EX", ttl.as_bytes()])
+            .await
+            .map(drop)
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.client
+            .command(&[b"DEL", key.as_bytes()])
+            .await
+            .map(drop)
+    }
+}
+
+/// Keys shared by every instance through Vercel KV, kept as JSON so they
+/// read plainly in the KV dashboard
+#[derive(Debug)]
+pub struct KvStore {
+    client: &'static KvClient,
+}
+
+impl KvStore {
+    fn key(key: &str) -> String {
+        format!("idempotency:{}", key)
+    }
+
+    async fn reserve(&self, key: &str, entry: &Entry, ttl: Duration) -> io::Result<Option<Entry>> {
+        let key = Self::key(key);
+        let value = serde_json::to_string(entry)?;
+        for _ in 0..2 {
+            if self.client.set_nx(&key, &value, ttl).await? {
+                return Ok(None);
+            }
+            if let Some(existing) = self.client.get(&key).await? {
+                return serde_json::from_str(&existing)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            // Expired between the two commands; try to reserve it again
+        }
+        Err(io::Error::other(
+            "idempotency key expired while being reserved",
+        ))
+    }
+
+    async fn set(&self, key: &str, entry: &Entry, ttl: Duration) -> io::Result<()> {
+        let value = serde_json::to_string(entry)?;
+        self.client.set(&Self::key(key), &value, ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.client.delete(&Self::key(key)).await
+    }
+}
+
+fn encode(entry: &Entry) -> io::Result<Vec<u8>> {
+    rmp_serde::to_vec(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode(value: &[u8]) -> io::Result<Entry> {
+    rmp_serde::from_slice(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Where seen keys are kept: the storage backend when one is configured,
+/// Redis when `IDEMPOTENCY_REDIS_URL` is set, Vercel KV when it's linked,
+/// this instance's memory otherwise
+pub enum IdempotencyStore {
+    Memory(MemoryStore),
+    Redis(RedisStore),
+    Kv(KvStore),
+    Database(&'static dyn Storage),
+}
+
+impl IdempotencyStore {
+    async fn reserve(&self, key: &str, entry: Entry, ttl: Duration) -> io::Result<Option<Entry>> {
+        match self {
+            IdempotencyStore::Memory(store) => Ok(store.reserve(key, entry, ttl)),
+            IdempotencyStore::Redis(store) => store.reserve(key, &entry, ttl).await,
+            IdempotencyStore::Kv(store) => store.reserve(key, &entry, ttl).await,
+            IdempotencyStore::Database(storage) => storage
+                .reserve_key(key, encode(&entry)?, ttl)
+                .await?
+                .map(|existing| decode(&existing))
+                .transpose(),
+        }
+    }
+
+    async fn set(&self, key: &str, entry: Entry, ttl: Duration) -> io::Result<()> {
+        match self {
+            IdempotencyStore::Memory(store) => {
+                store.set(key, entry, ttl);
+                Ok(())
+            }
+            IdempotencyStore::Redis(store) => store.set(key, &entry, ttl).await,
+            IdempotencyStore::Kv(store) => store.set(key, &entry, ttl).await,
+            IdempotencyStore::Database(storage) => storage.set_key(key, encode(&entry)?, ttl).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self {
+            IdempotencyStore::Memory(store) => {
+                store.delete(key);
+                Ok(())
+            }
+            IdempotencyStore::Redis(store) => store.delete(key).await,
+            IdempotencyStore::Kv(store) => store.delete(key).await,
+            IdempotencyStore::Database(storage) => storage.delete_key(key).await,
+        }
+    }
+}
+
+// As with opt-outs, a misconfigured store is kept as an error so requests
+// carrying a key fail rather than silently lose their protection
+static STORE: Lazy<Result<IdempotencyStore, String>> = Lazy::new(|| {
+    if let Some(backend) = storage::backend() {
+        return backend.map(IdempotencyStore::Database);
+    }
+    match std::env::var("IDEMPOTENCY_REDIS_URL") {
+        Ok(url) if !url.is_empty() => match RedisClient::parse(&url) {
+            Ok(client) => {
+                info!("Using Redis idempotency store at: {}", client.address());
+                Ok(IdempotencyStore::Redis(RedisStore { client }))
+            }
+            Err(e) => {
+                error!("Invalid IDEMPOTENCY_REDIS_URL: {}", e);
+                Err(e)
+            }
+        },
+        _ => match kv::client() {
+            Some(client) => client.map(|client| IdempotencyStore::Kv(KvStore { client })),
+            None => Ok(IdempotencyStore::Memory(MemoryStore::default())),
+        },
+    }
+});
+
+/// How long a finished response is replayed, from `IDEMPOTENCY_TTL_SECS`
+static TTL: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(24 * 60 * 60),
+    )
+});
+
+fn store() -> Result<&'static IdempotencyStore, ApiError> {
+    STORE
+        .as_ref()
+        .map_err(|reason| ApiError::IdempotencyUnavailable {
+            reason: reason.clone(),
+        })
+}
+
+fn unavailable(e: io::Error) -> ApiError {
+    error!("Idempotency store failed: {}", e);
+    ApiError::IdempotencyUnavailable {
+        reason: e.to_string(),
+    }
+}
+
+/// Reserves the key for this request, or explains why it can't run
+pub async fn begin(key: &str, fingerprint: u64) -> Result<Begin, ApiError> {
+    let existing = store()?
+        .reserve(key, Entry::Pending { fingerprint }, PENDING_TTL)
+        .await
+        .map_err(unavailable)?;
+
+    match existing {
+        None => {
+            debug!("Reserved idempotency key: {}", key);
+            Ok(Begin::Fresh)
+        }
+        Some(entry) if entry.fingerprint() != fingerprint => {
+            warn!("Idempotency key reused for a different request: {}", key);
+            Err(ApiError::IdempotencyKeyReused)
+        }
+        Some(Entry::Pending { .. }) => {
+            info!("Request with idempotency key {} is still running", key);
+            Err(ApiError::IdempotencyInProgress {
+                retry_after_secs: IN_PROGRESS_RETRY_AFTER_SECS,
+            })
+        }
+        Some(Entry::Done { response, .. }) => {
+            info!("Replaying response for idempotency key: {}", key);
+            Ok(Begin::Replay(response))
+        }
+    }
+}
+
+/// Keeps the response for duplicates of the request that reserved the key
+pub async fn complete(key: &str, fingerprint: u64, response: CachedResponse) {
+    let entry = Entry::Done {
+        fingerprint,
+        response,
+    };
+    match store() {
+        Ok(store) => {
+            if let Err(e) = store.set(key, entry, *TTL).await {
+                error!(
+                    "Failed to store response for idempotency key {}: {}",
+                    key, e
+                );
+            }
+        }
+        Err(e) => error!(
+            "Failed to store response for idempotency key {}: {}",
+            key, e
+        ),
+    }
+}
+
+/// Releases the key so the request can be retried, e.g. after a failure
+/// that's worth retrying
+pub async fn abandon(key: &str) {
+    if let Ok(store) = store() {
+        if let Err(e) = store.delete(key).await {
+            warn!("Failed to release idempotency key {}: {}", key, e);
+        }
+    }
+}
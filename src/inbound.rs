@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::error::ApiError;
+use crate::format;
+use crate::optout::Keyword;
+use crate::phone;
+use crate::redact;
+use crate::storage::{self, unavailable};
+use crate::tracecontext;
+
+/// Timeout for handing a message to the downstream webhook
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message someone sent to one of our numbers, as a provider's inbound
+/// callback reported it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboundMessage {
+    /// The provider's id for the message, or one of ours if it sent none
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// Normalized sender number
+    pub from: String,
+    /// The number or short code it was sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub text: String,
+    /// Set when the text leads with STOP, START or the like
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<Keyword>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// The fields providers name differently: Africa's Talking posts `from`,
+/// `to`, `text`, `id` and `linkId`, Twilio `From`, `To`, `Body` and
+/// `MessageSid`. Anything else in the callback is ignored.
+#[derive(Debug, Deserialize)]
+struct Payload {
+    #[serde(alias = "From", alias = "sender")]
+    from: String,
+    #[serde(default, alias = "To", alias = "shortCode")]
+    to: Option<String>,
+    #[serde(alias = "Body", alias = "message")]
+    text: String,
+    #[serde(default, alias = "messageId", alias = "message_id")]
+    id: Option<String>,
+    #[serde(default, rename = "MessageSid")]
+    message_sid: Option<String>,
+    #[serde(default, rename = "linkId")]
+    link_id: Option<String>,
+}
+
+impl InboundMessage {
+    /// Reads a callback body, form-encoded as providers post them or JSON.
+    /// `provider` names the sender when the payload doesn't give it away.
+    pub fn parse(
+        content_type: Option<&str>,
+        body: &[u8],
+        provider: Option<&str>,
+    ) -> Result<Self, String> {
+        let payload: Payload = if format::is_form(content_type) {
+            serde_json::from_value(Value::Object(format::parse_form(body)?))
+        } else {
+            serde_json::from_slice(body)
+        }
+        .map_err(|e| e.to_string())?;
+
+        let from = phone::normalize(&payload.from);
+        if from.is_empty() {
+            return Err("the sender's number is empty".to_string());
+        }
+        let provider = if payload.message_sid.is_some() {
+            Some("twilio")
+        } else if payload.link_id.is_some() {
+            Some("africastalking")
+        } else {
+            provider
+        };
+        Ok(InboundMessage {
+            id: payload
+                .message_sid
+                .or(payload.id)
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            provider: provider.map(str::to_string),
+            from,
+            to: payload.to.filter(|to| !to.is_empty()),
+            keyword: Keyword::parse(&payload.text),
+            text: payload.text,
+            received_at: Utc::now(),
+        })
+    }
+}
+
+/// Most messages kept in memory without a backend; older ones are dropped
+const MEMORY_LIMIT: usize = 1000;
+
+static MEMORY: Lazy<Mutex<VecDeque<InboundMessage>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+fn memory() -> std::sync::MutexGuard<'static, VecDeque<InboundMessage>> {
+    MEMORY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the message with the ones received before it, unless a retried
+/// callback already stored its id. As in the message history, the sender's
+/// number is stored masked.
+pub async fn record(mut message: InboundMessage) -> Result<(), ApiError> {
+    message.from = redact::mask_phone(&message.from);
+    match storage::backend() {
+        Some(Ok(storage)) => storage.record_inbound(message).await.map_err(unavailable),
+        Some(Err(reason)) => Err(ApiError::StorageUnavailable { reason }),
+        None => {
+            let mut messages = memory();
+            if messages.iter().any(|stored| stored.id == message.id) {
+                return Ok(());
+            }
+            messages.push_front(message);
+            messages.truncate(MEMORY_LIMIT);
+            Ok(())
+        }
+    }
+}
+
+/// The latest received messages, newest first
+pub async fn recent(limit: usize) -> Result<Vec<InboundMessage>, ApiError> {
+    match storage::backend() {
+        Some(Ok(storage)) => storage.recent_inbound(limit).await.map_err(unavailable),
+        Some(Err(reason)) => Err(ApiError::StorageUnavailable { reason }),
+        None => Ok(memory().iter().take(limit).cloned().collect()),
+    }
+}
+
+/// Where received messages are passed on, from `INBOUND_FORWARD_URL`
+#[derive(Debug)]
+pub struct Forwarder {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Forwarder {
+    pub fn new(url: &str, token: Option<String>) -> Result<Self, String> {
+        let url = url.trim();
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err("INBOUND_FORWARD_URL must be an http(s) URL".to_string());
+        }
+        let http = reqwest::Client::builder()
+            .timeout(FORWARD_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Forwarder {
+            http,
+            url: url.to_string(),
+            token,
+        })
+    }
+
+    /// Posts the message as JSON, with `INBOUND_FORWARD_TOKEN` as a bearer
+    /// token when it's set
+    pub async fn forward(&self, message: &InboundMessage) -> io::Result<()> {
+        let mut request = tracecontext::propagate(self.http.post(&self.url)).json(message);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(io::Error::other)?;
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "forward webhook returned {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+static FORWARDER: Lazy<Option<Result<Forwarder, String>>> =
+    Lazy::new(|| match std::env::var("INBOUND_FORWARD_URL") {
+        Ok(url) if !url.trim().is_empty() => {
+            let token = std::env::var("INBOUND_FORWARD_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty());
+            Some(
+                Forwarder::new(&url, token)
+                    .inspect(|_| info!("Forwarding inbound messages to: {}", url.trim()))
+                    .inspect_err(|e| error!("Invalid inbound forwarding configuration: {}", e)),
+            )
+        }
+        _ => None,
+    });
+
+/// The downstream webhook, or `None` when `INBOUND_FORWARD_URL` is unset
+pub fn forwarder() -> Option<Result<&'static Forwarder, String>> {
+    FORWARDER
+        .as_ref()
+        .map(|forwarder| forwarder.as_ref().map_err(Clone::clone))
+}
@@ -0,0 +1,61 @@
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::error::ApiError;
+
+/// Seconds clients are told to wait when every permit is taken
+const RETRY_AFTER_SECS: u64 = 1;
+
+// Sized by `MAX_INFLIGHT_REQUESTS`; unlimited when unset or zero
+static PERMITS: Lazy<Option<(usize, Arc<Semaphore>)>> = Lazy::new(|| {
+    let limit = std::env::var("MAX_INFLIGHT_REQUESTS")
+        .ok()
+        .and_then(|raw| raw.parse::<usize>().ok())
+        .filter(|limit| *limit > 0)?;
+    debug!("Limiting in-flight requests to {}", limit);
+    Some((limit, Arc::new(Semaphore::new(limit))))
+});
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Held for the duration of a request; releases its slot on drop
+#[derive(Debug)]
+pub struct InflightPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InflightPermit {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes a request slot without waiting, failing fast when saturated
+pub fn try_acquire() -> Result<InflightPermit, ApiError> {
+    let permit = match PERMITS.as_ref() {
+        Some((_, permits)) => match permits.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                warn!("In-flight request limit reached - rejecting request");
+                return Err(ApiError::Overloaded {
+                    retry_after_secs: RETRY_AFTER_SECS,
+                });
+            }
+        },
+        None => None,
+    };
+
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    Ok(InflightPermit { _permit: permit })
+}
+
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+pub fn limit() -> Option<usize> {
+    PERMITS.as_ref().map(|(limit, _)| *limit)
+}
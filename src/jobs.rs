@@ -0,0 +1,883 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::channels::whatsapp::Template;
+use crate::channels::ChannelKind;
+use crate::error::ApiError;
+use crate::phone;
+use crate::priority::Priority;
+use crate::retry::RetryPolicyOverride;
+use crate::schedule::natural::{self, Natural};
+use crate::schedule::rrule::RRule;
+use crate::schedule::{self, CronSchedule, QuietHours};
+use crate::senders;
+use crate::storage::{self, Storage};
+
+/// What the dispatcher does with runs that fell due while no tick arrived,
+/// e.g. during an outage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// Drop missed runs and wait for the next scheduled time
+    Skip,
+    /// Send once for all missed runs together
+    #[default]
+    RunOnce,
+    /// Send every missed run, up to `MAX_CATCH_UP_RUNS`
+    RunAllMissed,
+}
+
+impl std::str::FromStr for CatchUpPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "skip" => Ok(CatchUpPolicy::Skip),
+            "run_once" | "run-once" => Ok(CatchUpPolicy::RunOnce),
+            "run_all_missed" | "run-all-missed" => Ok(CatchUpPolicy::RunAllMissed),
+            other => Err(format!("unknown catch-up policy: {}", other)),
+        }
+    }
+}
+
+/// Whether a job is being dispatched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    #[default]
+    Active,
+    /// Kept but not sent until resumed
+    Paused,
+    /// `repeat` allows no more runs
+    Finished,
+}
+
+/// A run held back by the job's quiet hours
+#[derive(Debug, Clone, Serialize)]
+pub struct Deferral {
+    pub job_id: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub deferred_until: DateTime<Utc>,
+}
+
+/// What a tick claimed: runs to send now and runs moved out of quiet hours
+#[derive(Debug, Default)]
+pub struct Claimed {
+    pub runs: Vec<Job>,
+    pub deferred: Vec<Deferral>,
+}
+
+/// Most missed runs `run_all_missed` sends in one tick; later ones are
+/// dropped so an outage doesn't end in a flood
+pub const MAX_CATCH_UP_RUNS: usize = 50;
+
+/// Bounds how often a job runs. With `every` it runs on that interval from
+/// when it was defined, in place of a cron schedule.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Repeat {
+    /// Interval such as `30m`, `12h` or `1d`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every: Option<String>,
+    /// No run starts after this instant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+    /// Most runs the job makes over its lifetime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_runs: Option<u32>,
+}
+
+/// What a client submits to create or replace a job
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobDefinition {
+    /// `sms` when absent
+    #[serde(default)]
+    pub channel: ChannelKind,
+    /// The recipient of SMS and WhatsApp jobs
+    #[serde(default)]
+    pub phone: String,
+    /// The email address, webhook URL or Telegram chat jobs on those
+    /// channels send to
+    #[serde(default)]
+    pub to: Option<String>,
+    /// Required for email jobs; webhook and Telegram jobs pass it along
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// The text sent, or with a WhatsApp `template`, the text an SMS
+    /// fallback sends
+    pub message: String,
+    /// An approved template WhatsApp jobs send instead of `message`
+    #[serde(default)]
+    pub template: Option<Template>,
+    /// `sms` to text the phone when a WhatsApp job's message fails
+    #[serde(default)]
+    pub fallback: Option<ChannelKind>,
+    /// Five-field cron expression, evaluated in `timezone`. A job needs
+    /// exactly one of `schedule`, `rrule` or `repeat.every`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// iCalendar recurrence rule such as `FREQ=MONTHLY;BYDAY=2TU;BYHOUR=9`
+    #[serde(default)]
+    pub rrule: Option<String>,
+    #[serde(default)]
+    pub sender_id: Option<String>,
+    /// IANA name such as `Africa/Lagos`; UTC when absent
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub repeat: Option<Repeat>,
+    /// Overrides `CATCH_UP_POLICY` for this job
+    #[serde(default)]
+    pub catch_up: Option<CatchUpPolicy>,
+    /// Runs falling due in this window, read in `timezone`, wait for its end
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Overrides the global retry policy for every run of the job
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicyOverride>,
+}
+
+impl JobDefinition {
+    /// Checks the definition and normalizes its recipient
+    pub fn validate(mut self) -> Result<Self, ApiError> {
+        let invalid = |reason: String| ApiError::InvalidBody { reason };
+
+        match self.channel {
+            channel if channel.is_phone() => {
+                if self.phone.trim().is_empty() {
+                    return Err(invalid("phone is required".to_string()));
+                }
+                self.phone = phone::validate(&self.phone)?;
+                if self.to.is_some() || self.subject.is_some() {
+                    return Err(invalid(format!(
+                        "to and subject don't apply to {} jobs",
+                        channel
+                    )));
+                }
+            }
+            channel => {
+                if !self.phone.is_empty() || self.sender_id.is_some() {
+                    return Err(invalid(
+                        "phone and sender_id only apply to sms and whatsapp jobs".to_string(),
+                    ));
+                }
+                let to = self
+                    .to
+                    .as_deref()
+                    .ok_or_else(|| invalid(format!("to is required for {} jobs", channel)))?;
+                self.to = Some(channel.validate_recipient(to).map_err(invalid)?);
+            }
+        }
+        if self.channel != ChannelKind::WhatsApp {
+            if self.template.is_some() || self.fallback.is_some() {
+                return Err(invalid(
+                    "template and fallback only apply to whatsapp jobs".to_string(),
+                ));
+            }
+        } else if self
+            .fallback
+            .is_some_and(|fallback| fallback != ChannelKind::Sms)
+        {
+            return Err(invalid(
+                "a whatsapp job can only fall back to sms".to_string(),
+            ));
+        }
+        if let Some(template) = &self.template {
+            if template.name.trim().is_empty() || template.language.trim().is_empty() {
+                return Err(invalid("template needs a name and a language".to_string()));
+            }
+        }
+        if self.channel == ChannelKind::Email
+            && self
+                .subject
+                .as_deref()
+                .is_none_or(|subject| subject.trim().is_empty())
+        {
+            return Err(invalid("subject is required for email jobs".to_string()));
+        }
+        if self.message.trim().is_empty() {
+            return Err(invalid("message is required".to_string()));
+        }
+        if let Some(retry_policy) = &self.retry_policy {
+            retry_policy.validate().map_err(invalid)?;
+        }
+        if let Some(timezone) = &self.timezone {
+            self.timezone = Some(
+                schedule::parse_timezone(timezone)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .name()
+                    .to_string(),
+            );
+        }
+        self.resolve_natural_schedule()?;
+
+        let every = self
+            .repeat
+            .as_ref()
+            .and_then(|repeat| repeat.every.as_deref());
+        match (&self.schedule, &self.rrule, every) {
+            (Some(expression), None, None) => {
+                CronSchedule::parse(expression).map_err(|e| invalid(e.to_string()))?;
+            }
+            (None, Some(rule), None) => {
+                RRule::parse(rule).map_err(|e| invalid(e.to_string()))?;
+            }
+            (None, None, Some(every)) => {
+                schedule::parse_every(every).map_err(|e| invalid(e.to_string()))?;
+            }
+            (None, None, None) => {
+                return Err(invalid(
+                    "schedule, rrule or repeat.every is required".to_string(),
+                ))
+            }
+            _ => {
+                return Err(invalid(
+                    "only one of schedule, rrule and repeat.every can be given".to_string(),
+                ))
+            }
+        }
+        if let Some(repeat) = &self.repeat {
+            if repeat.max_runs == Some(0) {
+                return Err(invalid("repeat.max_runs must be positive".to_string()));
+            }
+            if repeat.until.is_some_and(|until| until <= Utc::now()) {
+                return Err(invalid("repeat.until is in the past".to_string()));
+            }
+        }
+        if let Some(quiet_hours) = &self.quiet_hours {
+            quiet_hours
+                .validate()
+                .map_err(|e| invalid(format!("quiet_hours: {}", e)))?;
+        }
+        if let Some(sender_id) = &self.sender_id {
+            senders::validate_sender_id(sender_id).map_err(|reason| ApiError::InvalidSenderId {
+                sender_id: sender_id.clone(),
+                reason,
+            })?;
+        }
+        Ok(self)
+    }
+
+    // A schedule with words in it, e.g. `every weekday at 9am`, is read as
+    // natural language and replaced by the cron expression it means. A
+    // single run such as `in 2 hours` becomes that minute's cron expression
+    // limited to one run.
+    fn resolve_natural_schedule(&mut self) -> Result<(), ApiError> {
+        let Some(text) = self
+            .schedule
+            .as_ref()
+            .filter(|text| text.chars().any(|c| c.is_ascii_alphabetic()))
+        else {
+            return Ok(());
+        };
+        let tz = self
+            .timezone
+            .as_deref()
+            .and_then(|timezone| schedule::parse_timezone(timezone).ok())
+            .unwrap_or(chrono_tz::UTC);
+        let expression =
+            match natural::parse(text, Utc::now(), tz).map_err(ApiError::InvalidSchedule)? {
+                Natural::Cron(expression) => expression,
+                Natural::Once(at) => {
+                    self.repeat.get_or_insert_with(Repeat::default).max_runs = Some(1);
+                    natural::once_as_cron(at, tz)
+                }
+            };
+        debug!("Read schedule {:?} as {}", text, expression);
+        self.schedule = Some(expression);
+        Ok(())
+    }
+}
+
+/// A stored job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(default)]
+    pub channel: ChannelKind,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub phone: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<Template>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<ChannelKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rrule: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<Repeat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub catch_up: Option<CatchUpPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_policy: Option<RetryPolicyOverride>,
+    /// Scheduled time of a run quiet hours moved to `next_run_at`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deferred_from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub status: JobStatus,
+    /// Runs started so far, counted against `repeat.max_runs`
+    #[serde(default)]
+    pub runs: u32,
+    /// Scheduled time of the latest run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// When the dispatcher next sends it; `None` while paused or once finished
+    #[serde(default)]
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Job {
+    pub fn new(definition: JobDefinition) -> Self {
+        let now = Utc::now();
+        let mut job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            channel: definition.channel,
+            phone: definition.phone,
+            to: definition.to,
+            subject: definition.subject,
+            message: definition.message,
+            template: definition.template,
+            fallback: definition.fallback,
+            schedule: definition.schedule,
+            rrule: definition.rrule,
+            sender_id: definition.sender_id,
+            timezone: definition.timezone,
+            repeat: definition.repeat,
+            catch_up: definition.catch_up,
+            quiet_hours: definition.quiet_hours,
+            priority: definition.priority,
+            retry_policy: definition.retry_policy,
+            deferred_from: None,
+            status: JobStatus::Active,
+            runs: 0,
+            last_run_at: None,
+            next_run_at: None,
+            created_at: now,
+            updated_at: now,
+        };
+        job.next_run_at = job.first_run();
+        job.refresh_status();
+        job
+    }
+
+    /// Replaces the definition, keeping the id, creation time and run count.
+    /// A paused job stays paused.
+    pub fn update(&mut self, definition: JobDefinition) {
+        self.channel = definition.channel;
+        self.phone = definition.phone;
+        self.to = definition.to;
+        self.subject = definition.subject;
+        self.message = definition.message;
+        self.template = definition.template;
+        self.fallback = definition.fallback;
+        self.schedule = definition.schedule;
+        self.rrule = definition.rrule;
+        self.sender_id = definition.sender_id;
+        self.timezone = definition.timezone;
+        self.repeat = definition.repeat;
+        self.catch_up = definition.catch_up;
+        self.quiet_hours = definition.quiet_hours;
+        self.priority = definition.priority;
+        self.retry_policy = definition.retry_policy;
+        self.deferred_from = None;
+        self.updated_at = Utc::now();
+        if self.status == JobStatus::Paused {
+            return;
+        }
+        self.next_run_at = self.first_run();
+        self.refresh_status();
+    }
+
+    fn refresh_status(&mut self) {
+        self.status = match self.next_run_at {
+            Some(_) => JobStatus::Active,
+            None => JobStatus::Finished,
+        };
+    }
+
+    /// Stops dispatching the job until it's resumed; `false` if it has
+    /// finished
+    pub fn pause(&mut self) -> bool {
+        if self.status == JobStatus::Finished {
+            return false;
+        }
+        self.status = JobStatus::Paused;
+        self.next_run_at = None;
+        self.deferred_from = None;
+        true
+    }
+
+    /// Dispatches the job again from its next scheduled time after `now`, so
+    /// runs that fell due while paused aren't sent. Intervals keep their
+    /// phase. `false` if it has finished.
+    pub fn resume(&mut self, now: DateTime<Utc>) -> bool {
+        match self.status {
+            JobStatus::Finished => false,
+            JobStatus::Active => true,
+            JobStatus::Paused => {
+                self.skip_to(now);
+                true
+            }
+        }
+    }
+
+    /// The job's cron schedule in its own timezone, if it has one
+    pub fn cron(&self) -> Result<Option<CronSchedule>, schedule::ScheduleError> {
+        let Some(expression) = &self.schedule else {
+            return Ok(None);
+        };
+        let cron = CronSchedule::parse(expression)?;
+        Ok(Some(match &self.timezone {
+            Some(timezone) => cron.with_timezone(schedule::parse_timezone(timezone)?),
+            None => cron,
+        }))
+    }
+
+    fn tz(&self) -> chrono_tz::Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|timezone| schedule::parse_timezone(timezone).ok())
+            .unwrap_or(chrono_tz::UTC)
+    }
+
+    fn every(&self) -> Option<chrono::Duration> {
+        let every = self.repeat.as_ref()?.every.as_deref()?;
+        schedule::parse_every(every).ok()
+    }
+
+    /// First scheduled time strictly after `after`, ignoring the repeat bounds.
+    /// Intervals, and rules without a `DTSTART`, count from `updated_at`, so
+    /// late ticks don't make them drift.
+    fn scheduled_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if let Some(every) = self.every() {
+            let elapsed = (after - self.updated_at).num_seconds().max(-1);
+            let periods = elapsed.div_euclid(every.num_seconds()) + 1;
+            return self
+                .updated_at
+                .checked_add_signed(every.checked_mul(periods as i32)?);
+        }
+        if let Some(rule) = &self.rrule {
+            return RRule::parse(rule)
+                .ok()?
+                .next_after(after, self.updated_at, self.tz());
+        }
+        self.cron().ok()??.next_after(after)
+    }
+
+    fn within_bounds(&self, at: DateTime<Utc>, runs: u32) -> bool {
+        let Some(repeat) = &self.repeat else {
+            return true;
+        };
+        repeat.until.is_none_or(|until| at <= until)
+            && repeat.max_runs.is_none_or(|max_runs| runs < max_runs)
+    }
+
+    // An interval job first runs on the tick after it's defined
+    fn first_run(&self) -> Option<DateTime<Utc>> {
+        let first = match self.every() {
+            Some(_) => Some(self.updated_at),
+            None => self.scheduled_after(self.updated_at),
+        };
+        first.filter(|at| self.within_bounds(*at, self.runs))
+    }
+
+    /// Counts the run scheduled for `at` and moves `next_run_at` past it, to
+    /// `None` once `repeat` allows no more
+    pub fn record_run(&mut self, at: DateTime<Utc>) {
+        self.runs += 1;
+        self.last_run_at = Some(at);
+        self.skip_to(at);
+    }
+
+    fn skip_to(&mut self, after: DateTime<Utc>) {
+        self.next_run_at = self
+            .scheduled_after(after)
+            .filter(|next| self.within_bounds(*next, self.runs));
+        self.refresh_status();
+    }
+
+    /// Records every run due at `now` and returns the job as it was after
+    /// each, one entry per send. Runs more than `window` late were missed and
+    /// are handled by the job's catch-up policy, or `default` without one.
+    /// During quiet hours a due run is instead moved to the window's end.
+    pub fn claim_runs(
+        &mut self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        default: CatchUpPolicy,
+    ) -> Claimed {
+        let mut claimed = Claimed::default();
+        if let Some(at) = self.next_run_at.filter(|at| *at <= now) {
+            let end = self
+                .quiet_hours
+                .as_ref()
+                .and_then(|quiet_hours| quiet_hours.end_after(now, self.tz()));
+            if let Some(end) = end {
+                let scheduled_at = self.deferred_from.unwrap_or(at);
+                if !self.within_bounds(end, self.runs) {
+                    info!(
+                        "Dropping run of job {} scheduled for {}: quiet hours outlast the job",
+                        self.id, scheduled_at
+                    );
+                    self.deferred_from = None;
+                    self.skip_to(end);
+                    return claimed;
+                }
+                info!(
+                    "Deferring run of job {} scheduled for {} to {}: quiet hours",
+                    self.id, scheduled_at, end
+                );
+                self.deferred_from = Some(scheduled_at);
+                self.next_run_at = Some(end);
+                claimed.deferred.push(Deferral {
+                    job_id: self.id.clone(),
+                    scheduled_at,
+                    deferred_until: end,
+                });
+                return claimed;
+            }
+            // A deferred run goes out however late its tick, like one on time
+            if self.deferred_from.take().is_some() {
+                self.record_run(at);
+                claimed.runs.push(self.clone());
+            }
+        }
+
+        let claimed_runs = &mut claimed.runs;
+        let policy = self.catch_up.unwrap_or(default);
+        while let Some(at) = self.next_run_at.filter(|at| *at <= now) {
+            if at >= now - window {
+                self.record_run(at);
+                claimed_runs.push(self.clone());
+                continue;
+            }
+            match policy {
+                CatchUpPolicy::Skip => {
+                    warn!("Skipping missed runs of job {} since {}", self.id, at);
+                    self.skip_to(now);
+                }
+                CatchUpPolicy::RunOnce => {
+                    info!("Catching up missed runs of job {} since {}", self.id, at);
+                    self.record_run(at);
+                    self.skip_to(now);
+                    claimed_runs.push(self.clone());
+                }
+                CatchUpPolicy::RunAllMissed if claimed_runs.len() >= MAX_CATCH_UP_RUNS => {
+                    warn!(
+                        "Dropping missed runs of job {} beyond {} since {}",
+                        self.id, MAX_CATCH_UP_RUNS, at
+                    );
+                    self.skip_to(now);
+                }
+                CatchUpPolicy::RunAllMissed => {
+                    self.record_run(at);
+                    claimed_runs.push(self.clone());
+                }
+            }
+        }
+        claimed
+    }
+
+    /// The next `count` runs still allowed
+    pub fn upcoming(&self, count: usize) -> Vec<DateTime<Utc>> {
+        let mut runs = Vec::with_capacity(count);
+        let mut next = self.next_run_at;
+        while let Some(at) = next.filter(|_| runs.len() < count) {
+            runs.push(at);
+            next = self
+                .scheduled_after(at)
+                .filter(|next| self.within_bounds(*next, self.runs + runs.len() as u32));
+        }
+        runs
+    }
+}
+
+/// Where scheduled jobs are kept. Definitions are expected to be validated
+/// by the caller.
+pub trait JobStore: Send + Sync {
+    fn list(&self) -> Vec<Job>;
+    fn get(&self, id: &str) -> Option<Job>;
+    /// Inserts or replaces the job with the same id
+    fn put(&self, job: Job) -> io::Result<()>;
+    /// Removes a job, returning `false` if there was no such job
+    fn delete(&self, id: &str) -> io::Result<bool>;
+    /// Records the runs of jobs in `lane` due at `now` (see `Job::claim_runs`)
+    /// and returns them, so a run counts even if its send never finishes.
+    /// Jobs are claimed longest-waiting first until there are `limit` runs;
+    /// one job's catch-up runs are never split, so that may run over.
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Claimed>;
+}
+
+/// Jobs kept for the lifetime of the instance
+#[derive(Debug, Default)]
+pub struct InMemoryJobStore {
+    jobs: Mutex<BTreeMap<String, Job>>,
+}
+
+impl JobStore for InMemoryJobStore {
+    fn list(&self) -> Vec<Job> {
+        lock(&self.jobs).values().cloned().collect()
+    }
+
+    fn get(&self, id: &str) -> Option<Job> {
+        lock(&self.jobs).get(id).cloned()
+    }
+
+    fn put(&self, job: Job) -> io::Result<()> {
+        lock(&self.jobs).insert(job.id.clone(), job);
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        Ok(lock(&self.jobs).remove(id).is_some())
+    }
+
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Claimed> {
+        Ok(claim(
+            &mut lock(&self.jobs),
+            now,
+            window,
+            catch_up,
+            lane,
+            limit,
+        ))
+    }
+}
+
+/// Jobs persisted to a JSON file, rewritten on every change
+#[derive(Debug)]
+pub struct FileJobStore {
+    path: PathBuf,
+    jobs: Mutex<BTreeMap<String, Job>>,
+}
+
+impl FileJobStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let jobs: Vec<Job> = match std::fs::read_to_string(&path) {
+            Ok(contents) if contents.trim().is_empty() => Vec::new(),
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(FileJobStore {
+            path,
+            jobs: Mutex::new(jobs.into_iter().map(|job| (job.id.clone(), job)).collect()),
+        })
+    }
+
+    fn persist(&self, jobs: &BTreeMap<String, Job>) -> io::Result<()> {
+        let jobs: Vec<&Job> = jobs.values().collect();
+        let contents = serde_json::to_string_pretty(&jobs)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(&self.path, contents)
+    }
+}
+
+impl JobStore for FileJobStore {
+    fn list(&self) -> Vec<Job> {
+        lock(&self.jobs).values().cloned().collect()
+    }
+
+    fn get(&self, id: &str) -> Option<Job> {
+        lock(&self.jobs).get(id).cloned()
+    }
+
+    fn put(&self, job: Job) -> io::Result<()> {
+        let mut jobs = lock(&self.jobs);
+        jobs.insert(job.id.clone(), job);
+        self.persist(&jobs)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        let mut jobs = lock(&self.jobs);
+        if jobs.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.persist(&jobs)?;
+        Ok(true)
+    }
+
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Claimed> {
+        let mut jobs = lock(&self.jobs);
+        let before: Vec<Option<DateTime<Utc>>> = jobs.values().map(|job| job.next_run_at).collect();
+        let claimed = claim(&mut jobs, now, window, catch_up, lane, limit);
+        // Skipped and deferred runs move `next_run_at` without claiming
+        // anything
+        if jobs.values().map(|job| job.next_run_at).ne(before) {
+            self.persist(&jobs)?;
+        }
+        Ok(claimed)
+    }
+}
+
+fn claim(
+    jobs: &mut BTreeMap<String, Job>,
+    now: DateTime<Utc>,
+    window: chrono::Duration,
+    catch_up: CatchUpPolicy,
+    lane: Priority,
+    limit: usize,
+) -> Claimed {
+    let mut due: Vec<&mut Job> = jobs
+        .values_mut()
+        .filter(|job| job.priority == lane && job.next_run_at.is_some_and(|at| at <= now))
+        .collect();
+    due.sort_by_key(|job| job.next_run_at);
+    claim_in_order(due, now, window, catch_up, limit)
+}
+
+/// Claims the runs of `due`, already in the order they should go, until
+/// there are `limit` of them
+pub(crate) fn claim_in_order<'a>(
+    due: impl IntoIterator<Item = &'a mut Job>,
+    now: DateTime<Utc>,
+    window: chrono::Duration,
+    catch_up: CatchUpPolicy,
+    limit: usize,
+) -> Claimed {
+    let mut claimed = Claimed::default();
+    for job in due {
+        if claimed.runs.len() >= limit {
+            break;
+        }
+        let job_claimed = job.claim_runs(now, window, catch_up);
+        claimed.runs.extend(job_claimed.runs);
+        claimed.deferred.extend(job_claimed.deferred);
+    }
+    claimed
+}
+
+fn lock(jobs: &Mutex<BTreeMap<String, Job>>) -> std::sync::MutexGuard<'_, BTreeMap<String, Job>> {
+    jobs.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Where jobs are kept: the storage backend when one is configured,
+/// otherwise a `JobStore` local to this instance
+pub enum JobStorage {
+    Local(Box<dyn JobStore>),
+    Database(&'static dyn Storage),
+}
+
+impl JobStorage {
+    pub async fn list(&self) -> io::Result<Vec<Job>> {
+        match self {
+            JobStorage::Local(store) => Ok(store.list()),
+            JobStorage::Database(storage) => storage.list_jobs().await,
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> io::Result<Option<Job>> {
+        match self {
+            JobStorage::Local(store) => Ok(store.get(id)),
+            JobStorage::Database(storage) => storage.get_job(id).await,
+        }
+    }
+
+    pub async fn put(&self, job: Job) -> io::Result<()> {
+        match self {
+            JobStorage::Local(store) => store.put(job),
+            JobStorage::Database(storage) => storage.put_job(job).await,
+        }
+    }
+
+    pub async fn delete(&self, id: &str) -> io::Result<bool> {
+        match self {
+            JobStorage::Local(store) => store.delete(id),
+            JobStorage::Database(storage) => storage.delete_job(id).await,
+        }
+    }
+
+    pub async fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Claimed> {
+        match self {
+            JobStorage::Local(store) => store.claim_due(now, window, catch_up, lane, limit),
+            JobStorage::Database(storage) => {
+                storage
+                    .claim_due_jobs(now, window, catch_up, lane, limit)
+                    .await
+            }
+        }
+    }
+}
+
+// As with opt-outs, an unreadable jobs file is kept as an error rather than
+// replaced by an empty store that would overwrite it on the next change
+static STORE: Lazy<Result<JobStorage, String>> = Lazy::new(|| match storage::backend() {
+    Some(backend) => backend.map(JobStorage::Database),
+    None => match std::env::var("JOBS_FILE") {
+        Ok(path) if !path.is_empty() => match FileJobStore::open(&path) {
+            Ok(store) => {
+                info!("Using file job store at: {}", path);
+                Ok(JobStorage::Local(Box::new(store)))
+            }
+            Err(e) => {
+                error!("Failed to open jobs file {}: {}", path, e);
+                Err(format!("failed to open {}: {}", path, e))
+            }
+        },
+        _ => Ok(JobStorage::Local(Box::new(InMemoryJobStore::default()))),
+    },
+});
+
+/// The instance-wide job store: the storage backend when one is
+/// configured, file-backed when `JOBS_FILE` is set, in-memory otherwise
+pub fn store() -> Result<&'static JobStorage, ApiError> {
+    STORE
+        .as_ref()
+        .map_err(|reason| ApiError::JobStoreUnavailable {
+            reason: reason.clone(),
+        })
+}
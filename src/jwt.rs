@@ -0,0 +1,207 @@
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::auth::{Identity, Scope};
+use crate::error::ApiError;
+
+/// How long fetched signing keys are used before `JWT_JWKS_URL` is read again
+const JWKS_TTL: Duration = Duration::from_secs(600);
+
+/// A token signed with a key we haven't seen refetches the set, but at most
+/// this often, so junk tokens can't hammer the identity provider
+const JWKS_REFRESH_MIN: Duration = Duration::from_secs(60);
+
+/// Timeout for fetching the key set
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The last key set fetched from `JWT_JWKS_URL`, and when
+static JWKS: Lazy<Mutex<Option<(Instant, JwkSet)>>> = Lazy::new(|| Mutex::new(None));
+
+/// How bearer JWTs are verified: HS256 with `JWT_SECRET`, RS256 with the
+/// keys published at `JWT_JWKS_URL`, or both
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    secret: Option<String>,
+    pub jwks_url: Option<String>,
+    /// Required `iss`, from `JWT_ISSUER`
+    pub issuer: Option<String>,
+    /// Required `aud`, from `JWT_AUDIENCE`
+    pub audience: Option<String>,
+    http: reqwest::Client,
+}
+
+/// The claims read from a token. Scopes come as an OAuth-style
+/// space-separated `scope` or a `scopes` array.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+/// Whether a bearer token is shaped like a JWT rather than an API key
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3 && token.starts_with("ey")
+}
+
+impl JwtConfig {
+    pub fn hs256_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Reads `JWT_SECRET`, `JWT_JWKS_URL`, `JWT_ISSUER` and `JWT_AUDIENCE`;
+    /// `None` when neither a secret nor a key set is configured
+    pub fn from_env() -> Result<Option<Self>, (&'static str, String)> {
+        let var = |key| {
+            std::env::var(key)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        let secret = var("JWT_SECRET");
+        let jwks_url = var("JWT_JWKS_URL").map(|url| url.trim().to_string());
+        if secret.is_none() && jwks_url.is_none() {
+            return Ok(None);
+        }
+        if let Some(url) = &jwks_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(("JWT_JWKS_URL", "must be an http(s) URL".to_string()));
+            }
+        }
+        let http = reqwest::Client::builder()
+            .timeout(JWKS_TIMEOUT)
+            .build()
+            .map_err(|e| ("JWT_JWKS_URL", e.to_string()))?;
+        Ok(Some(JwtConfig {
+            secret,
+            jwks_url,
+            issuer: var("JWT_ISSUER"),
+            audience: var("JWT_AUDIENCE"),
+            http,
+        }))
+    }
+
+    async fn fetch_jwks(&self, url: &str) -> Result<JwkSet, ApiError> {
+        let unavailable = |reason: String| ApiError::InvalidToken {
+            reason: format!("signing keys unavailable: {}", reason),
+        };
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| unavailable(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(unavailable(format!(
+                "{} returned {}",
+                url,
+                response.status()
+            )));
+        }
+        response
+            .json::<JwkSet>()
+            .await
+            .map_err(|e| unavailable(e.to_string()))
+    }
+
+    /// The RS256 key with id `kid`, from the cached set when it has it
+    async fn rsa_key(&self, kid: &str) -> Result<DecodingKey, ApiError> {
+        let url = self.jwks_url.as_deref().ok_or(ApiError::InvalidToken {
+            reason: "RS256 tokens need JWT_JWKS_URL".to_string(),
+        })?;
+        let cached_key = |max_age: Duration| {
+            let jwks = JWKS.lock().unwrap_or_else(|e| e.into_inner());
+            let (fetched, set) = jwks.as_ref()?;
+            if fetched.elapsed() >= max_age {
+                return None;
+            }
+            Some(set.find(kid).map(DecodingKey::from_jwk))
+        };
+        match cached_key(JWKS_TTL) {
+            Some(Some(key)) => return key.map_err(invalid),
+            // An unknown key may have been rotated in since the last fetch
+            Some(None) if cached_key(JWKS_REFRESH_MIN).is_some() => {
+                return Err(ApiError::InvalidToken {
+                    reason: format!("unknown signing key {}", kid),
+                })
+            }
+            _ => {}
+        }
+        debug!("Fetching JWT signing keys from {}", url);
+        let set = self.fetch_jwks(url).await?;
+        let key = set.find(kid).map(DecodingKey::from_jwk);
+        *JWKS.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), set));
+        key.ok_or_else(|| ApiError::InvalidToken {
+            reason: format!("unknown signing key {}", kid),
+        })?
+        .map_err(invalid)
+    }
+
+    /// Verifies the token's signature, expiry and any configured issuer and
+    /// audience, returning its subject and the scopes it grants. Scopes we
+    /// don't know are ignored.
+    pub async fn verify(&self, token: &str) -> Result<Identity, ApiError> {
+        let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+        let key = match header.alg {
+            Algorithm::HS256 => match &self.secret {
+                Some(secret) => DecodingKey::from_secret(secret.as_bytes()),
+                None => {
+                    return Err(ApiError::InvalidToken {
+                        reason: "HS256 tokens need JWT_SECRET".to_string(),
+                    })
+                }
+            },
+            Algorithm::RS256 => {
+                let kid = header.kid.as_deref().ok_or(ApiError::InvalidToken {
+                    reason: "the token names no signing key".to_string(),
+                })?;
+                self.rsa_key(kid).await?
+            }
+            other => {
+                return Err(ApiError::InvalidToken {
+                    reason: format!("{:?} tokens are not accepted", other),
+                })
+            }
+        };
+        let mut validation = Validation::new(header.alg);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(invalid)?
+            .claims;
+
+        let scopes = claims
+            .scope
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .chain(claims.scopes.iter().map(String::as_str))
+            .filter_map(|scope| {
+                scope
+                    .parse::<Scope>()
+                    .inspect_err(|e| warn!("Ignoring scope in token for {}: {}", claims.sub, e))
+                    .ok()
+            })
+            .collect();
+        Ok(Identity {
+            name: claims.sub,
+            scopes: Some(scopes),
+        })
+    }
+}
+
+fn invalid(e: jsonwebtoken::errors::Error) -> ApiError {
+    ApiError::InvalidToken {
+        reason: e.to_string(),
+    }
+}
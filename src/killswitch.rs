@@ -0,0 +1,121 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+use crate::error::ApiError;
+use crate::flags;
+use crate::kv;
+
+/// KV key the switch is kept under
+const KEY: &str = "killswitch";
+
+/// How long other instances may take to see the switch flipped
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// The switch when KV isn't linked, for this instance only
+static MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// The switch as last read from KV, and when
+static CACHE: Lazy<Mutex<Option<(Instant, bool)>>> = Lazy::new(|| Mutex::new(None));
+
+/// Where the switch stands and what set it
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    /// Set through `POST /admin/kill-switch`
+    pub switched: bool,
+    /// The `kill_switch` feature flag
+    pub flag: bool,
+}
+
+fn cache(on: bool) {
+    *CACHE.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), on));
+}
+
+/// Whether `POST /admin/kill-switch` has it on. Kept in Vercel KV when it's
+/// linked, so every instance stops; a read that fails keeps the last value.
+async fn switched() -> bool {
+    let Some(client) = kv::client() else {
+        return MEMORY.load(Ordering::Relaxed);
+    };
+    let cached = *CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((fetched, on)) = cached {
+        if fetched.elapsed() < CACHE_TTL {
+            return on;
+        }
+    }
+    let last = cached.map(|(_, on)| on).unwrap_or(false);
+    let result = match client.map_err(io::Error::other) {
+        Ok(client) => client.command(&["GET", KEY]).await,
+        Err(e) => Err(e),
+    };
+    let on = match result {
+        Ok(value) => value.as_str() == Some("1"),
+        Err(e) => {
+            warn!("Kill switch unavailable, keeping it {}: {}", state(last), e);
+            last
+        }
+    };
+    cache(on);
+    on
+}
+
+fn state(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+pub async fn status() -> Status {
+    let flag = flags::current().await.kill_switch;
+    let switched = switched().await;
+    Status {
+        enabled: flag || switched,
+        switched,
+        flag,
+    }
+}
+
+pub async fn is_on() -> bool {
+    status().await.enabled
+}
+
+/// Refuses a provider call while the switch is on
+pub async fn check() -> Result<(), ApiError> {
+    if is_on().await {
+        return Err(ApiError::FeatureDisabled { feature: "sending" });
+    }
+    Ok(())
+}
+
+/// Turns the switch on or off for every instance, returning whether it was
+/// on. The `kill_switch` flag holds sends whatever this says.
+pub async fn set(on: bool) -> Result<bool, ApiError> {
+    let previous = switched().await;
+    if let Some(client) = kv::client() {
+        let client = client.map_err(|reason| ApiError::StorageUnavailable { reason })?;
+        let command: &[&str] = if on {
+            &["SET", KEY, "1"]
+        } else {
+            &["DEL", KEY]
+        };
+        client.command(command).await.map_err(|e| {
+            error!("Failed to store the kill switch: {}", e);
+            ApiError::StorageUnavailable {
+                reason: e.to_string(),
+            }
+        })?;
+        cache(on);
+    }
+    MEMORY.store(on, Ordering::Relaxed);
+    if previous != on {
+        warn!("Kill switch turned {}", state(on));
+    }
+    Ok(previous)
+}
@@ -0,0 +1,166 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::Value;
+use std::io;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Timeout for each KV round trip
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A thin client for Vercel KV's REST API, which speaks Redis commands as
+/// JSON arrays over HTTPS. Vercel sets `KV_REST_API_URL` and
+/// `KV_REST_API_TOKEN` when a KV store is linked to the project.
+#[derive(Debug)]
+pub struct KvClient {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvReply {
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl KvReply {
+    fn into_result(self) -> io::Result<Value> {
+        match self.error {
+            Some(error) => Err(io::Error::other(format!("KV error: {}", error))),
+            None => Ok(self.result),
+        }
+    }
+}
+
+impl KvClient {
+    pub fn new(url: &str, token: &str) -> Result<Self, String> {
+        let url = url.trim().trim_end_matches('/');
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err("KV_REST_API_URL must be an http(s) URL".to_string());
+        }
+        if token.trim().is_empty() {
+            return Err("KV_REST_API_TOKEN is required".to_string());
+        }
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(KvClient {
+            http,
+            url: url.to_string(),
+            token: token.trim().to_string(),
+        })
+    }
+
+    /// The REST endpoint, safe to log
+    pub fn address(&self) -> &str {
+        &self.url
+    }
+
+    async fn post<T: for<'de> Deserialize<'de>>(&self, path: &str, body: &Value) -> io::Result<T> {
+        let response = self
+            .http
+            .post(format!("{}{}", self.url, path))
+            .bearer_auth(&self.token)
+            .json(body)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        let status = response.status();
+        if status.is_server_error() || status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(io::Error::other(format!("KV returned {}", status)));
+        }
+        response.json().await.map_err(io::Error::other)
+    }
+
+    /// Runs one command, e.g. `["SET", "key", "value"]`
+    pub async fn command(&self, args: &[&str]) -> io::Result<Value> {
+        self.post::<KvReply>("", &Value::from(args.to_vec()))
+            .await?
+            .into_result()
+    }
+
+    /// Runs commands in order in one round trip, not atomically
+    pub async fn pipeline(&self, commands: &[&[&str]]) -> io::Result<Vec<Value>> {
+        let body = Value::from(
+            commands
+                .iter()
+                .map(|args| Value::from(args.to_vec()))
+                .collect::<Vec<_>>(),
+        );
+        self.post::<Vec<KvReply>>("/pipeline", &body)
+            .await?
+            .into_iter()
+            .map(KvReply::into_result)
+            .collect()
+    }
+
+    pub async fn get(&self, key: &str) -> io::Result<Option<String>> {
+        match self.command(&["GET", key]).await? {
+            Value::String(value) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str, ttl: Duration) -> io::Result<()> {
+        let ttl = ttl.as_millis().max(1).to_string();
+        self.command(&["SET", key, value, "PX", &ttl])
+            .await
+            .map(drop)
+    }
+
+    /// Sets the key only if it's absent, returning whether it was set
+    pub async fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> io::Result<bool> {
+        let ttl = ttl.as_millis().max(1).to_string();
+        let reply = self.command(&["SET", key, value, "NX", "PX", &ttl]).await?;
+        Ok(reply.as_str() == Some("OK"))
+    }
+
+    pub async fn delete(&self, key: &str) -> io::Result<()> {
+        self.command(&["DEL", key]).await.map(drop)
+    }
+
+    /// Counts one more hit against `key` and returns the count so far. The
+    /// first hit starts a `window` after which the counter resets, as a
+    /// fixed-window rate limit needs.
+    pub async fn increment(&self, key: &str, window: Duration) -> io::Result<i64> {
+        let window = window.as_millis().max(1).to_string();
+        let replies = self
+            .pipeline(&[&["INCR", key], &["PEXPIRE", key, &window, "NX"]])
+            .await?;
+        replies
+            .first()
+            .and_then(Value::as_i64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad INCR reply"))
+    }
+}
+
+// A half-configured store is kept as an error so its users fail loudly
+// rather than fall back to state only this instance can see
+static CLIENT: Lazy<Option<Result<KvClient, String>>> =
+    Lazy::new(|| match std::env::var("KV_REST_API_URL") {
+        Ok(url) if !url.trim().is_empty() => {
+            let token = std::env::var("KV_REST_API_TOKEN").unwrap_or_default();
+            Some(match KvClient::new(&url, &token) {
+                Ok(client) => {
+                    info!("Using Vercel KV at: {}", client.address());
+                    Ok(client)
+                }
+                Err(e) => {
+                    error!("Invalid Vercel KV configuration: {}", e);
+                    Err(e)
+                }
+            })
+        }
+        _ => None,
+    });
+
+/// The linked KV store, or `None` when `KV_REST_API_URL` is unset
+pub fn client() -> Option<Result<&'static KvClient, String>> {
+    CLIENT
+        .as_ref()
+        .map(|client| client.as_ref().map_err(Clone::clone))
+}
@@ -0,0 +1,215 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::redis::{RedisClient, Reply};
+
+// Only touch the key while it still holds our token, so a holder whose lease
+// expired can't extend or drop someone else's
+const RENEW_SCRIPT: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then \
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2]) else return 0 end";
+const RELEASE_SCRIPT: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then \
+    return redis.call('DEL', KEYS[1]) else return 0 end";
+
+/// Where leases are kept: Redis when `LOCK_REDIS_URL` is set, so every
+/// instance sees them, this instance's memory otherwise
+#[derive(Debug)]
+pub enum LockStore {
+    Memory(Mutex<HashMap<String, (String, Instant)>>),
+    Redis { client: RedisClient, prefix: String },
+}
+
+impl LockStore {
+    async fn acquire(&self, key: &str, token: &str, ttl: Duration) -> io::Result<bool> {
+        match self {
+            LockStore::Memory(leases) => {
+                let mut leases = lock(leases);
+                let now = Instant::now();
+                leases.retain(|_, (_, expires)| *expires > now);
+                if leases.contains_key(key) {
+                    return Ok(false);
+                }
+                leases.insert(key.to_string(), (token.to_string(), now + ttl));
+                Ok(true)
+            }
+            LockStore::Redis { client, prefix } => {
+                let key = format!("{}{}", prefix, key);
+                let ttl = ttl.as_millis().to_string();
+                let reply = client
+                    .command(&[
+                        b"SET",
+                        key.as_bytes(),
+                        token.as_bytes(),
+                        b"NX",
+                        b"PX",
+                        ttl.as_bytes(),
+                    ])
+                    .await?;
+                Ok(matches!(reply, Reply::Ok))
+            }
+        }
+    }
+
+    async fn renew(&self, key: &str, token: &str, ttl: Duration) -> io::Result<bool> {
+        match self {
+            LockStore::Memory(leases) => match lock(leases).get_mut(key) {
+                Some((held, expires)) if held == token && *expires > Instant::now() => {
+                    *expires = Instant::now() + ttl;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            },
+            LockStore::Redis { client, prefix } => {
+                let key = format!("{}{}", prefix, key);
+                let ttl = ttl.as_millis().to_string();
+                let reply = client
+                    .command(&[
+                        b"EVAL",
+                        RENEW_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        token.as_bytes(),
+                        ttl.as_bytes(),
+                    ])
+                    .await?;
+                Ok(matches!(reply, Reply::Integer(1)))
+            }
+        }
+    }
+
+    async fn release(&self, key: &str, token: &str) -> io::Result<()> {
+        match self {
+            LockStore::Memory(leases) => {
+                let mut leases = lock(leases);
+                if leases.get(key).is_some_and(|(held, _)| held == token) {
+                    leases.remove(key);
+                }
+                Ok(())
+            }
+            LockStore::Redis { client, prefix } => {
+                let key = format!("{}{}", prefix, key);
+                client
+                    .command(&[
+                        b"EVAL",
+                        RELEASE_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        token.as_bytes(),
+                    ])
+                    .await
+                    .map(drop)
+            }
+        }
+    }
+}
+
+fn lock(
+    leases: &Mutex<HashMap<String, (String, Instant)>>,
+) -> std::sync::MutexGuard<'_, HashMap<String, (String, Instant)>> {
+    leases.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// As with dead letters, a misconfigured store is kept as an error so
+// dispatchers skip work rather than fall back to locks only they can see
+static STORE: Lazy<Result<LockStore, String>> =
+    Lazy::new(|| match std::env::var("LOCK_REDIS_URL") {
+        Ok(url) if !url.is_empty() => match RedisClient::parse(&url) {
+            Ok(client) => {
+                info!("Using Redis locks at: {}", client.address());
+                Ok(LockStore::Redis {
+                    client,
+                    prefix: "scheduler:lock:".to_string(),
+                })
+            }
+            Err(e) => {
+                error!("Invalid LOCK_REDIS_URL: {}", e);
+                Err(e)
+            }
+        },
+        _ => Ok(LockStore::Memory(Mutex::new(HashMap::new()))),
+    });
+
+/// How long a lease lasts without renewal, from `LOCK_TTL_MS`
+static TTL: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_millis(
+        std::env::var("LOCK_TTL_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .filter(|ms| *ms >= 1000)
+            .unwrap_or(30_000),
+    )
+});
+
+fn store() -> io::Result<&'static LockStore> {
+    STORE
+        .as_ref()
+        .map_err(|reason| io::Error::other(reason.clone()))
+}
+
+/// Exclusive hold on a key until it's released or its lease expires
+#[derive(Debug)]
+pub struct Lease {
+    key: String,
+    token: String,
+    ttl: Duration,
+}
+
+/// Takes the lease on `key`, or `None` if someone else holds it
+pub async fn acquire(key: &str) -> io::Result<Option<Lease>> {
+    let token = uuid::Uuid::new_v4().to_string();
+    if !store()?.acquire(key, &token, *TTL).await? {
+        debug!("Lock {} is held elsewhere", key);
+        return Ok(None);
+    }
+    debug!("Acquired lock {}", key);
+    Ok(Some(Lease {
+        key: key.to_string(),
+        token,
+        ttl: *TTL,
+    }))
+}
+
+impl Lease {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Extends the lease; `false` means it expired and may have been taken
+    pub async fn renew(&self) -> io::Result<bool> {
+        store()?.renew(&self.key, &self.token, self.ttl).await
+    }
+
+    /// Runs `work` while renewing the lease, so slow work such as a send
+    /// backing off between retries doesn't outlive it
+    pub async fn hold<F: Future>(&self, work: F) -> F::Output {
+        tokio::pin!(work);
+        let mut renewals = tokio::time::interval(self.ttl / 3);
+        // The first tick completes immediately and the lease is fresh
+        renewals.tick().await;
+        loop {
+            tokio::select! {
+                output = &mut work => return output,
+                _ = renewals.tick() => match self.renew().await {
+                    Ok(true) => debug!("Renewed lock {}", self.key),
+                    Ok(false) => warn!("Lost lock {} while holding it", self.key),
+                    Err(e) => warn!("Failed to renew lock {}: {}", self.key, e),
+                },
+            }
+        }
+    }
+
+    /// Gives the key up before the lease expires
+    pub async fn release(self) {
+        let result = match store() {
+            Ok(store) => store.release(&self.key, &self.token).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to release lock {}: {}", self.key, e);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing::{span, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Filter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+use crate::auth;
+
+/// Asks for this request to be logged at debug level
+pub const HEADER: &str = "X-Debug";
+
+/// Field a request's root span sets to `true` to log everything under it at
+/// debug level
+pub const FIELD: &str = "verbose";
+
+/// Whether the request asks for debug logs and may have them: `X-Debug:
+/// true` only counts with the admin key, so clients can't flood the logs
+pub fn requested(headers: &http::HeaderMap, admin_api_key: Option<&str>) -> bool {
+    let asked = headers
+        .get(HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| matches!(v.trim(), "1" | "true"));
+    if !asked {
+        return false;
+    }
+    match auth::require_admin(headers, admin_api_key) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("Ignoring {} without the admin key: {}", HEADER, e);
+            false
+        }
+    }
+}
+
+/// Marks a span, and through it every span and event under it, as verbose
+struct Verbose;
+
+struct VerboseVisitor(bool);
+
+impl Visit for VerboseVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == FIELD {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+}
+
+/// `RUST_LOG`, plus debug level under spans whose `verbose` field is set
+pub struct RequestFilter {
+    env: EnvFilter,
+}
+
+impl RequestFilter {
+    pub fn from_env() -> Self {
+        RequestFilter {
+            env: EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        }
+    }
+
+    fn verbose<S>(cx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        cx.lookup_current().is_some_and(|span| {
+            span.scope()
+                .any(|span| span.extensions().get::<Verbose>().is_some())
+        })
+    }
+}
+
+impl<S> Filter<S> for RequestFilter
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        Filter::<S>::enabled(&self.env, meta, cx)
+            || (*meta.level() <= Level::DEBUG && Self::verbose(cx))
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let interest = Filter::<S>::callsite_enabled(&self.env, meta);
+        // Debug callsites are asked about each time, in case they're under
+        // a verbose request
+        if interest.is_never() && *meta.level() <= Level::DEBUG {
+            return Interest::sometimes();
+        }
+        interest
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let env = Filter::<S>::max_level_hint(&self.env)?;
+        Some(env.max(LevelFilter::DEBUG))
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = VerboseVisitor(false);
+        attrs.record(&mut visitor);
+        if visitor.0 {
+            if let Some(span) = ctx.span(id) {
+                let mut extensions = span.extensions_mut();
+                // Each layer's filter sees the span, so it may be marked already
+                if extensions.get_mut::<Verbose>().is_none() {
+                    extensions.insert(Verbose);
+                }
+            }
+        }
+        Filter::<S>::on_new_span(&self.env, attrs, id, ctx)
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        Filter::<S>::on_record(&self.env, id, values, ctx)
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        Filter::<S>::on_enter(&self.env, id, ctx)
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        Filter::<S>::on_exit(&self.env, id, ctx)
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        Filter::<S>::on_close(&self.env, id, ctx)
+    }
+}
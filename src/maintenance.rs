@@ -0,0 +1,47 @@
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+
+use crate::error::ApiError;
+
+// Starts from `MAINTENANCE_MODE` and can be flipped at runtime through
+// `POST /admin/maintenance`; the runtime value is per instance
+static ENABLED: Lazy<AtomicBool> = Lazy::new(|| {
+    AtomicBool::new(matches!(
+        std::env::var("MAINTENANCE_MODE").as_deref(),
+        Ok("1") | Ok("true")
+    ))
+});
+
+static RETRY_AFTER_SECS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("MAINTENANCE_RETRY_AFTER_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(300)
+});
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns maintenance mode on or off, returning the previous state
+pub fn set_enabled(enabled: bool) -> bool {
+    let previous = ENABLED.swap(enabled, Ordering::Relaxed);
+    if previous != enabled {
+        warn!(
+            "Maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+    previous
+}
+
+/// Rejects sends and schedules while maintenance mode is on
+pub fn check() -> Result<(), ApiError> {
+    if is_enabled() {
+        return Err(ApiError::Maintenance {
+            retry_after_secs: *RETRY_AFTER_SECS,
+        });
+    }
+    Ok(())
+}
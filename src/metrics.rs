@@ -0,0 +1,360 @@
+use ::metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+
+use crate::dlq;
+use crate::error::ApiError;
+use crate::inflight;
+use crate::providers::SendReport;
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 7] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Timeout for pushing to `METRICS_PUSH_URL`
+const PUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Shortest time between two pushes from an instance. Counters are
+/// cumulative, so what a skipped push would have sent goes with the next.
+const PUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The instance registry every `metrics` macro call feeds, installed the
+/// first time anything is recorded or rendered
+static HANDLE: Lazy<Option<PrometheusHandle>> = Lazy::new(|| {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(&LATENCY_BUCKETS)
+        .and_then(PrometheusBuilder::install_recorder)
+        .inspect_err(|e| error!("Metrics are off, the recorder failed: {}", e))
+        .ok()?;
+    describe_counter!(
+        "scheduler_requests_total",
+        "Requests handled, by method and status"
+    );
+    describe_histogram!(
+        "scheduler_request_duration_seconds",
+        "Time spent handling a request"
+    );
+    describe_counter!(
+        "scheduler_sms_sends_total",
+        "SMS sends by outcome and provider"
+    );
+    describe_histogram!(
+        "scheduler_sms_send_duration_seconds",
+        "Time spent sending an SMS, retries included"
+    );
+    describe_counter!(
+        "scheduler_sms_retries_total",
+        "Provider attempts beyond the first, by provider"
+    );
+    describe_gauge!(
+        "scheduler_inflight_requests",
+        "Requests currently being handled"
+    );
+    describe_gauge!(
+        "scheduler_inflight_limit",
+        "Maximum concurrent requests before shedding load"
+    );
+    describe_gauge!("scheduler_dlq_depth", "Messages in the dead-letter queue");
+    Some(handle)
+});
+
+/// Installs the registry; anything recorded before it is lost
+pub fn install() {
+    Lazy::force(&HANDLE);
+}
+
+/// Counts a handled request
+pub fn record_request(method: &str, status: u16, elapsed: Duration) {
+    install();
+    counter!(
+        "scheduler_requests_total",
+        "method" => method.to_string(),
+        "status" => status.to_string()
+    )
+    .increment(1);
+    histogram!("scheduler_request_duration_seconds").record(elapsed);
+    // Folds the latencies recorded so far into their buckets, so they don't
+    // pile up on an instance that's never scraped
+    if let Some(handle) = HANDLE.as_ref() {
+        handle.run_upkeep();
+    }
+}
+
+/// Counts a finished send: its outcome (`sent` or the error code), the
+/// provider that took or last refused it, and its retries
+pub fn record_send(result: &Result<SendReport, ApiError>, attempts: u32, elapsed: Duration) {
+    install();
+    let (outcome, provider) = match result {
+        Ok(report) => ("sent", report.provider),
+        Err(ApiError::ProviderFailed { provider, .. }) => ("send_failed", *provider),
+        Err(e) => (e.code(), "none"),
+    };
+    counter!("scheduler_sms_sends_total", "outcome" => outcome, "provider" => provider)
+        .increment(1);
+    histogram!("scheduler_sms_send_duration_seconds", "provider" => provider).record(elapsed);
+    if attempts > 1 {
+        counter!("scheduler_sms_retries_total", "provider" => provider)
+            .increment(u64::from(attempts - 1));
+    }
+}
+
+/// Renders the instance metrics in Prometheus text format. Gauges are read
+/// now, the dead-letter queue from its store.
+pub async fn render() -> String {
+    install();
+    gauge!("scheduler_inflight_requests").set(inflight::in_flight() as f64);
+    if let Some(limit) = inflight::limit() {
+        gauge!("scheduler_inflight_limit").set(limit as f64);
+    }
+    match dlq::depth().await {
+        Ok(depth) => gauge!("scheduler_dlq_depth").set(depth as f64),
+        Err(e) => warn!("Leaving the dead-letter queue depth out: {}", e),
+    }
+    HANDLE
+        .as_ref()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
+
+/// A Pushgateway group URL, e.g. `https://push.example.com/metrics/job/scheduler`,
+/// for deployments Prometheus can't scrape, extended with the `instance`
+/// label.
+static PUSH_URL: Lazy<Option<String>> = Lazy::new(|| {
+    let url = std::env::var("METRICS_PUSH_URL").ok()?;
+    let instance = std::env::var("METRICS_PUSH_INSTANCE")
+        .or_else(|_| std::env::var("VERCEL_REGION"))
+        .ok();
+    push_url(&url, instance.as_deref())
+});
+
+/// `url` with an `instance` label of `METRICS_PUSH_INSTANCE`, else the Vercel
+/// region, else `default`. The Pushgateway never expires a group, so the
+/// label has to stay the same across cold starts: a label per instance
+/// would leave a stale group behind each one. Instances sharing a label
+/// replace each other's push, so the group holds whichever pushed last.
+fn push_url(url: &str, instance: Option<&str>) -> Option<String> {
+    let url = url.trim().trim_end_matches('/');
+    if url.is_empty() {
+        return None;
+    }
+    let instance = instance
+        .map(str::trim)
+        .filter(|instance| !instance.is_empty())
+        .unwrap_or("default");
+    Some(format!(
+        "{}/instance/{}",
+        url,
+        urlencoding::encode(instance)
+    ))
+}
+
+static LAST_PUSH: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// Whether a push is due, counting this one against `PUSH_INTERVAL`
+fn take_push() -> bool {
+    let mut last = LAST_PUSH.lock().unwrap_or_else(|e| e.into_inner());
+    if last.is_some_and(|pushed| pushed.elapsed() < PUSH_INTERVAL) {
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
+
+static PUSH_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(PUSH_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// Pushes the metrics to `METRICS_PUSH_URL`, when it's set. Called after
+/// each request, since an idle serverless instance may never be scraped,
+/// but pushes at most once a `PUSH_INTERVAL` as rendering reads the
+/// dead-letter queue from storage.
+pub async fn push() {
+    let Some(url) = PUSH_URL.as_deref() else {
+        return;
+    };
+    if !take_push() {
+        return;
+    }
+    let body = render().await;
+    let result = PUSH_CLIENT
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE)
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match result {
+        Ok(_) => debug!("Pushed metrics"),
+        Err(e) => warn!("Failed to push metrics: {}", e.without_url()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn report(provider: &'static str) -> SendReport {
+        SendReport {
+            provider,
+            message_ids: Vec::new(),
+            recipients: None,
+            credits_deducted: None,
+            available_credits: None,
+            statuses: Vec::new(),
+            raw: Value::Null,
+        }
+    }
+
+    // The rendered sample of `series`, e.g. `name{label="value"}`
+    fn sample(text: &str, series: &str) -> Option<f64> {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .and_then(|value| value.trim().parse().ok())
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_by_method_and_status() {
+        record_request("PATCH", 418, Duration::from_millis(20));
+        let text = render().await;
+        assert_eq!(
+            sample(
+                &text,
+                r#"scheduler_requests_total{method="PATCH",status="418"}"#
+            ),
+            Some(1.0),
+            "{}",
+            text
+        );
+        assert!(text.contains("scheduler_request_duration_seconds_bucket{le=\"0.1\"}"));
+        assert!(text.contains("# TYPE scheduler_requests_total counter"));
+    }
+
+    #[tokio::test]
+    async fn sends_are_counted_by_outcome_and_provider() {
+        record_send(&Ok(report("metrics-ok")), 3, Duration::from_millis(5));
+        let failed = ApiError::ProviderFailed {
+            provider: "metrics-failed",
+            reason: "refused".to_string(),
+            retry_after: None,
+            transient: false,
+        };
+        record_send(&Err(failed), 1, Duration::from_millis(5));
+        record_send(
+            &Err(ApiError::OptedOut {
+                phone: "254700000001".to_string(),
+            }),
+            0,
+            Duration::ZERO,
+        );
+
+        let text = render().await;
+        let series = [
+            (
+                r#"scheduler_sms_sends_total{outcome="sent",provider="metrics-ok"}"#,
+                1.0,
+            ),
+            (r#"scheduler_sms_retries_total{provider="metrics-ok"}"#, 2.0),
+            (
+                r#"scheduler_sms_send_duration_seconds_count{provider="metrics-ok"}"#,
+                1.0,
+            ),
+            (
+                r#"scheduler_sms_sends_total{outcome="send_failed",provider="metrics-failed"}"#,
+                1.0,
+            ),
+        ];
+        for (series, value) in series {
+            assert_eq!(sample(&text, series), Some(value), "{}\n{}", series, text);
+        }
+        // Refused before any provider saw it
+        assert!(text.contains(r#"scheduler_sms_sends_total{outcome="opted_out",provider="none"}"#));
+        // A send without retries adds none
+        assert!(!text.contains(r#"scheduler_sms_retries_total{provider="metrics-failed"}"#));
+    }
+
+    // Concurrent requests record into the one registry without losing counts
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_sends_are_all_counted() {
+        const TASKS: usize = 32;
+        const SENDS_PER_TASK: usize = 250;
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                tokio::spawn(async {
+                    for _ in 0..SENDS_PER_TASK {
+                        record_send(&Ok(report("metrics-concurrent")), 2, Duration::ZERO);
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.expect("task");
+        }
+
+        let text = render().await;
+        let sends = (TASKS * SENDS_PER_TASK) as f64;
+        assert_eq!(
+            sample(
+                &text,
+                r#"scheduler_sms_sends_total{outcome="sent",provider="metrics-concurrent"}"#
+            ),
+            Some(sends),
+            "{}",
+            text
+        );
+        assert_eq!(
+            sample(
+                &text,
+                r#"scheduler_sms_retries_total{provider="metrics-concurrent"}"#
+            ),
+            Some(sends)
+        );
+        assert_eq!(
+            sample(
+                &text,
+                r#"scheduler_sms_send_duration_seconds_count{provider="metrics-concurrent"}"#
+            ),
+            Some(sends)
+        );
+    }
+
+    #[tokio::test]
+    async fn gauges_are_read_on_render() {
+        let text = render().await;
+        assert!(
+            sample(&text, "scheduler_inflight_requests").is_some(),
+            "{}",
+            text
+        );
+        assert_eq!(sample(&text, "scheduler_dlq_depth"), Some(0.0), "{}", text);
+    }
+
+    #[test]
+    fn pushes_go_to_a_stable_instance_group() {
+        let url = "https://push.example.com/metrics/job/scheduler/";
+        assert_eq!(
+            push_url(url, Some("fra1")).as_deref(),
+            Some("https://push.example.com/metrics/job/scheduler/instance/fra1")
+        );
+        assert_eq!(
+            push_url(url, Some(" ")).as_deref(),
+            Some("https://push.example.com/metrics/job/scheduler/instance/default")
+        );
+        assert_eq!(
+            push_url(url, Some("eu west")).as_deref(),
+            Some("https://push.example.com/metrics/job/scheduler/instance/eu%20west")
+        );
+        assert_eq!(push_url(url, None), push_url(url, None));
+        assert_eq!(push_url("  ", Some("fra1")), None);
+    }
+}
@@ -0,0 +1,170 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{error, info};
+
+use crate::error::ApiError;
+
+/// Numbers that replied STOP and must not be messaged again. Numbers are
+/// expected to be normalized by the caller.
+pub trait OptOutStore: Send + Sync {
+    /// Records an opt-out, returning `false` if the number was already opted out
+    fn opt_out(&self, phone: &str) -> io::Result<bool>;
+    /// Removes an opt-out, returning `false` if the number wasn't opted out
+    fn opt_in(&self, phone: &str) -> io::Result<bool>;
+    fn is_opted_out(&self, phone: &str) -> bool;
+}
+
+/// Opt-outs kept for the lifetime of the instance
+#[derive(Debug, Default)]
+pub struct InMemoryOptOutStore {
+    numbers: Mutex<HashSet<String>>,
+}
+
+impl OptOutStore for InMemoryOptOutStore {
+    fn opt_out(&self, phone: &str) -> io::Result<bool> {
+        Ok(lock(&self.numbers).insert(phone.to_string()))
+    }
+
+    fn opt_in(&self, phone: &str) -> io::Result<bool> {
+        Ok(lock(&self.numbers).remove(phone))
+    }
+
+    fn is_opted_out(&self, phone: &str) -> bool {
+        lock(&self.numbers).contains(phone)
+    }
+}
+
+/// Opt-outs persisted to a file with one number per line, rewritten on
+/// every change
+#[derive(Debug)]
+pub struct FileOptOutStore {
+    path: PathBuf,
+    numbers: Mutex<HashSet<String>>,
+}
+
+impl FileOptOutStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let numbers = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(FileOptOutStore {
+            path,
+            numbers: Mutex::new(numbers),
+        })
+    }
+
+    fn persist(&self, numbers: &HashSet<String>) -> io::Result<()> {
+        let mut sorted: Vec<&str> = numbers.iter().map(String::as_str).collect();
+        sorted.sort_unstable();
+        std::fs::write(&self.path, sorted.join("\n"))
+    }
+}
+
+impl OptOutStore for FileOptOutStore {
+    fn opt_out(&self, phone: &str) -> io::Result<bool> {
+        let mut numbers = lock(&self.numbers);
+        if !numbers.insert(phone.to_string()) {
+            return Ok(false);
+        }
+        self.persist(&numbers)?;
+        Ok(true)
+    }
+
+    fn opt_in(&self, phone: &str) -> io::Result<bool> {
+        let mut numbers = lock(&self.numbers);
+        if !numbers.remove(phone) {
+            return Ok(false);
+        }
+        self.persist(&numbers)?;
+        Ok(true)
+    }
+
+    fn is_opted_out(&self, phone: &str) -> bool {
+        lock(&self.numbers).contains(phone)
+    }
+}
+
+/// What a reply asks for when its first word is a carrier keyword
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Keyword {
+    Stop,
+    Start,
+}
+
+impl Keyword {
+    /// The keyword a reply leads with, if any, e.g. `Stop please` is a STOP
+    pub fn parse(text: &str) -> Option<Self> {
+        let word = text
+            .split_whitespace()
+            .next()?
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .to_ascii_uppercase();
+        match word.as_str() {
+            "STOP" | "STOPALL" | "UNSUBSCRIBE" | "CANCEL" | "END" | "QUIT" | "OPTOUT" => {
+                Some(Keyword::Stop)
+            }
+            "START" | "UNSTOP" | "SUBSCRIBE" => Some(Keyword::Start),
+            _ => None,
+        }
+    }
+}
+
+fn lock(numbers: &Mutex<HashSet<String>>) -> std::sync::MutexGuard<'_, HashSet<String>> {
+    numbers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// An unreadable opt-out file is kept as an error rather than silently
+// replaced, since an empty store would let opted-out numbers through
+static STORE: Lazy<Result<Box<dyn OptOutStore>, String>> =
+    Lazy::new(|| match std::env::var("OPTOUT_FILE") {
+        Ok(path) if !path.is_empty() => match FileOptOutStore::open(&path) {
+            Ok(store) => {
+                info!("Using file opt-out store at: {}", path);
+                Ok(Box::new(store) as Box<dyn OptOutStore>)
+            }
+            Err(e) => {
+                error!("Failed to open opt-out file {}: {}", path, e);
+                Err(format!("failed to open {}: {}", path, e))
+            }
+        },
+        _ => Ok(Box::new(InMemoryOptOutStore::default())),
+    });
+
+/// The instance-wide opt-out store: file-backed when `OPTOUT_FILE` is set,
+/// in-memory otherwise
+pub fn store() -> Result<&'static dyn OptOutStore, ApiError> {
+    match STORE.as_ref() {
+        Ok(store) => Ok(store.as_ref()),
+        Err(reason) => Err(ApiError::OptOutUnavailable {
+            reason: reason.clone(),
+        }),
+    }
+}
+
+/// Opts a normalized number out, or back in, returning whether that
+/// changed anything
+pub fn set(phone: &str, opted_out: bool) -> Result<bool, ApiError> {
+    let store = store()?;
+    let result = if opted_out {
+        store.opt_out(phone)
+    } else {
+        store.opt_in(phone)
+    };
+    result.map_err(|e| ApiError::OptOutUnavailable {
+        reason: e.to_string(),
+    })
+}
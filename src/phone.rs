@@ -0,0 +1,221 @@
+use once_cell::sync::Lazy;
+use phonenumber::country::Id;
+use phonenumber::Mode;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Longest number kept as dialled instead of parsed, e.g. 22141 or 40404
+const MAX_SHORTCODE_LEN: usize = 6;
+
+/// The country national-format numbers are read in, from `DEFAULT_COUNTRY`:
+/// an ISO 3166 code, `KE` when unset
+pub fn country_from_env() -> Result<Id, String> {
+    match std::env::var("DEFAULT_COUNTRY") {
+        Ok(raw) if !raw.trim().is_empty() => raw
+            .trim()
+            .to_ascii_uppercase()
+            .parse()
+            .map_err(|_| format!("unknown country code: {}", raw.trim())),
+        _ => Ok(Id::KE),
+    }
+}
+
+// An invalid DEFAULT_COUNTRY fails the configuration before any number is
+// read, so falling back here is only for code running without one
+static DEFAULT_COUNTRY: Lazy<Id> = Lazy::new(|| country_from_env().unwrap_or(Id::KE));
+
+/// Reads a number written in international form, with or without its `+`,
+/// or in national form for `DEFAULT_COUNTRY`, and checks it against that
+/// country's numbering plan. Returns the E.164 number without the `+`,
+/// e.g. `254712345678`, which is the shape stored numbers are keyed by.
+/// Short codes are kept as dialled.
+pub fn parse(phone: &str) -> Result<String, String> {
+    let phone = phone.trim();
+    let digits: String = phone.chars().filter(|c| c.is_ascii_digit()).collect();
+    if phone.is_empty() {
+        return Err("is empty".to_string());
+    }
+    if digits.len() == phone.len() && digits.len() <= MAX_SHORTCODE_LEN {
+        return Ok(digits);
+    }
+    let e164 = |number: phonenumber::PhoneNumber| {
+        phonenumber::is_valid(&number).then(|| number.format().mode(Mode::E164).to_string())
+    };
+    let parsed = phonenumber::parse(Some(*DEFAULT_COUNTRY), phone)
+        .ok()
+        .and_then(e164)
+        // Numbers from other countries are often written without the `+`
+        .or_else(|| {
+            (!phone.starts_with('+'))
+                .then(|| phonenumber::parse(None, format!("+{}", phone)).ok())
+                .flatten()
+                .and_then(e164)
+        });
+    match parsed {
+        Some(number) => Ok(number.trim_start_matches('+').to_string()),
+        None => Err("is not a valid phone number".to_string()),
+    }
+}
+
+/// A number `parse` refused, as it was given, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidNumber {
+    pub phone: String,
+    pub reason: String,
+}
+
+/// `parse`, failing with the number reported as invalid
+pub fn validate(phone: &str) -> Result<String, ApiError> {
+    parse(phone).map_err(|reason| ApiError::InvalidNumbers {
+        numbers: vec![InvalidNumber {
+            phone: phone.to_string(),
+            reason,
+        }],
+    })
+}
+
+/// Parses every number, or reports each one that isn't valid
+pub fn parse_all<'a>(phones: impl IntoIterator<Item = &'a str>) -> Result<Vec<String>, ApiError> {
+    let mut parsed = Vec::new();
+    let mut invalid = Vec::new();
+    for phone in phones {
+        match parse(phone) {
+            Ok(number) => parsed.push(number),
+            Err(reason) => invalid.push(InvalidNumber {
+                phone: phone.to_string(),
+                reason,
+            }),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(ApiError::InvalidNumbers { numbers: invalid })
+    }
+}
+
+/// The number `parse` reads, or for lookups of one it refuses, its digits,
+/// with a local `07...`/`01...` number given the Kenyan country code
+pub fn normalize(phone: &str) -> String {
+    if let Ok(number) = parse(phone) {
+        return number;
+    }
+    let digits: String = phone.chars().filter(|c| c.is_ascii_digit()).collect();
+
+    match digits.strip_prefix('0') {
+        Some(rest) if digits.len() == 10 => format!("254{}", rest),
+        _ => digits,
+    }
+}
+
+/// Kenyan mobile network operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    Safaricom,
+    Airtel,
+    Telkom,
+    Equitel,
+}
+
+/// What kind of line a normalized number appears to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NumberType {
+    Mobile {
+        operator: Option<Operator>,
+    },
+    Landline,
+    Shortcode,
+    /// A country we have no prefix rules for
+    Unknown,
+}
+
+impl NumberType {
+    /// Whether an SMS to this number can be expected to arrive; numbers we
+    /// can't classify get the benefit of the doubt
+    pub fn is_textable(&self) -> bool {
+        matches!(self, NumberType::Mobile { .. } | NumberType::Unknown)
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            NumberType::Mobile { .. } => "mobile",
+            NumberType::Landline => "landline",
+            NumberType::Shortcode => "shortcode",
+            NumberType::Unknown => "unknown",
+        }
+    }
+}
+
+// Three-digit national prefixes after 254, as allocated by the
+// Communications Authority of Kenya
+const KENYA_MOBILE_PREFIXES: &[(std::ops::RangeInclusive<u16>, Operator)] = &[
+    (700..=729, Operator::Safaricom),
+    (730..=739, Operator::Airtel),
+    (740..=743, Operator::Safaricom),
+    (745..=746, Operator::Safaricom),
+    (748..=748, Operator::Safaricom),
+    (750..=756, Operator::Airtel),
+    (757..=759, Operator::Safaricom),
+    (762..=762, Operator::Airtel),
+    (763..=766, Operator::Equitel),
+    (768..=769, Operator::Safaricom),
+    (770..=779, Operator::Telkom),
+    (780..=789, Operator::Airtel),
+    (790..=799, Operator::Safaricom),
+    (100..=102, Operator::Airtel),
+    (110..=115, Operator::Safaricom),
+];
+
+/// Classifies a number as returned by `normalize`
+pub fn classify_number(phone: &str) -> NumberType {
+    // Shortcodes are dialled as-is, e.g. 22141 or 40404
+    if phone.len() <= 6 {
+        return NumberType::Shortcode;
+    }
+
+    match phone.strip_prefix("254") {
+        Some(national) if national.len() == 9 => {
+            let prefix: u16 = national[..3].parse().unwrap_or_default();
+            match national.as_bytes()[0] {
+                b'7' | b'1' => NumberType::Mobile {
+                    operator: KENYA_MOBILE_PREFIXES
+                        .iter()
+                        .find(|(range, _)| range.contains(&prefix))
+                        .map(|(_, operator)| *operator),
+                },
+                _ => NumberType::Landline,
+            }
+        }
+        _ => NumberType::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_kenyan_numbers_by_prefix() {
+        let mobile = |operator| NumberType::Mobile {
+            operator: Some(operator),
+        };
+        for (phone, expected) in [
+            ("254712345678", mobile(Operator::Safaricom)),
+            ("254110345678", mobile(Operator::Safaricom)),
+            ("254733345678", mobile(Operator::Airtel)),
+            ("254101345678", mobile(Operator::Airtel)),
+            ("254771345678", mobile(Operator::Telkom)),
+            ("254764345678", mobile(Operator::Equitel)),
+            // Mobile range, but not allocated to an operator we know
+            ("254744345678", NumberType::Mobile { operator: None }),
+            ("254202345678", NumberType::Landline),
+            ("22141", NumberType::Shortcode),
+            ("255712345678", NumberType::Unknown),
+        ] {
+            assert_eq!(classify_number(phone), expected, "{}", phone);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, instrument, warn};
+
+use crate::tracecontext;
+
+/// An external "may we send this?" check run before every send, configured
+/// with `PRECHECK_URL`
+#[derive(Debug, Clone)]
+pub struct Precheck {
+    pub url: String,
+    pub timeout: Duration,
+    /// Decision used when the precheck can't be reached in time
+    pub allow_on_timeout: bool,
+}
+
+/// What the precheck endpoint is POSTed
+#[derive(Debug, Serialize)]
+pub struct PendingSend<'a> {
+    pub phone: &'a str,
+    pub message: &'a str,
+    pub sender_id: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct Decision {
+    allow: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+impl Precheck {
+    /// Reads `PRECHECK_URL`, `PRECHECK_TIMEOUT_MS` (default 2000) and
+    /// `PRECHECK_ON_TIMEOUT` (`allow` or `deny`, default `deny`)
+    pub fn from_env() -> Result<Option<Self>, (&'static str, String)> {
+        let url = match std::env::var("PRECHECK_URL") {
+            Ok(url) if !url.trim().is_empty() => url.trim().to_string(),
+            _ => return Ok(None),
+        };
+        reqwest::Url::parse(&url).map_err(|e| ("PRECHECK_URL", e.to_string()))?;
+
+        let timeout_ms = match std::env::var("PRECHECK_TIMEOUT_MS") {
+            Ok(raw) => raw
+                .trim()
+                .parse()
+                .map_err(|_| ("PRECHECK_TIMEOUT_MS", format!("not a number: {}", raw)))?,
+            Err(_) => 2000,
+        };
+        let allow_on_timeout = match std::env::var("PRECHECK_ON_TIMEOUT").as_deref() {
+            Ok("allow") => true,
+            Ok("deny") | Err(_) => false,
+            Ok(other) => {
+                return Err((
+                    "PRECHECK_ON_TIMEOUT",
+                    format!("expected allow or deny, got {}", other),
+                ))
+            }
+        };
+
+        Ok(Some(Precheck {
+            url,
+            timeout: Duration::from_millis(timeout_ms),
+            allow_on_timeout,
+        }))
+    }
+
+    /// Asks the precheck endpoint about a pending send. `Err` carries the
+    /// reason the send should be skipped.
+    #[instrument(name = "precheck", level = "debug", skip_all, fields(url = %self.url))]
+    pub async fn check(&self, send: &PendingSend<'_>) -> Result<(), String> {
+        let response = match tracecontext::propagate(CLIENT.post(&self.url))
+            .timeout(self.timeout)
+            .json(send)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            // Unreachable and slow prechecks both fall back to the default
+            Err(e) => {
+                warn!("Precheck unavailable: {}", e);
+                return if self.allow_on_timeout {
+                    debug!("Allowing send by default after precheck failure");
+                    Ok(())
+                } else {
+                    Err(format!("precheck unavailable: {}", e))
+                };
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("precheck returned HTTP {}", status.as_u16()));
+        }
+
+        match response.json::<Decision>().await {
+            Ok(Decision { allow: true, .. }) => Ok(()),
+            Ok(Decision {
+                allow: false,
+                reason,
+            }) => Err(reason.unwrap_or_else(|| "denied by precheck".to_string())),
+            Err(e) => Err(format!("unreadable precheck response: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use super::{Balance, DeliveryStatus, OutboundMessage, RecipientStatus, SendReport, SmsProvider};
+use crate::error::ApiError;
+use crate::proxy::ProxyUrl;
+use crate::tracecontext;
+
+const LIVE_URL: &str = "https://api.africastalking.com/version1";
+const SANDBOX_URL: &str = "https://api.sandbox.africastalking.com/version1";
+
+/// Africa's Talking bulk SMS behind the `SmsProvider` trait
+pub struct AfricasTalkingProvider {
+    http: reqwest::Client,
+    username: String,
+    api_key: String,
+    base_url: &'static str,
+}
+
+/// `POST /messaging` response
+#[derive(Debug, Deserialize)]
+struct AtSendResponse {
+    #[serde(rename = "SMSMessageData")]
+    data: AtMessageData,
+}
+
+#[derive(Debug, Deserialize)]
+struct AtMessageData {
+    /// Summary such as `Sent to 1/1 Total Cost: KES 0.8000`
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "Recipients", default)]
+    recipients: Vec<AtRecipient>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AtRecipient {
+    status_code: u16,
+    /// e.g. `Success` or `InvalidPhoneNumber`
+    status: String,
+    #[serde(default)]
+    cost: Option<String>,
+    #[serde(default)]
+    message_id: Option<String>,
+}
+
+impl AtRecipient {
+    fn delivery_status(&self) -> RecipientStatus {
+        RecipientStatus {
+            // Rejected submissions come back with the id `None`
+            message_id: self.message_id.clone().filter(|id| id != "None"),
+            status: send_status(self.status_code),
+            provider_status: format!("{} {}", self.status_code, self.status),
+        }
+    }
+}
+
+/// Maps the `statusCode` of a submission to a `DeliveryStatus`
+pub fn send_status(status_code: u16) -> DeliveryStatus {
+    match status_code {
+        // Processed, Queued
+        100 | 102 => DeliveryStatus::Queued,
+        // Sent
+        101 => DeliveryStatus::Sent,
+        // RiskHold, InvalidSenderId, InvalidPhoneNumber, UnsupportedNumberType,
+        // UserInBlacklist, DoNotDisturbRejection, RejectedByGateway
+        401..=404 | 406 | 409 | 502 => DeliveryStatus::Rejected,
+        // InsufficientBalance, CouldNotRoute, InternalServerError, GatewayError
+        405 | 407 | 500 | 501 => DeliveryStatus::Failed,
+        _ => DeliveryStatus::Unknown,
+    }
+}
+
+/// The form Africa's Talking posts to the delivery report callback
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryReport {
+    /// Message id from the submission
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub phone_number: Option<String>,
+    /// Set on `Rejected` and `Failed`, e.g. `AbsentSubscriber`
+    #[serde(default)]
+    pub failure_reason: Option<String>,
+}
+
+impl DeliveryReport {
+    pub fn delivery_status(&self) -> DeliveryStatus {
+        match self.status.as_str() {
+            "Success" => DeliveryStatus::Delivered,
+            "Sent" | "Submitted" => DeliveryStatus::Sent,
+            "Buffered" => DeliveryStatus::Queued,
+            "Rejected" => DeliveryStatus::Rejected,
+            "Failed" => DeliveryStatus::Failed,
+            "Expired" => DeliveryStatus::Expired,
+            _ => DeliveryStatus::Unknown,
+        }
+    }
+}
+
+/// Amount out of a money string such as `KES 0.8000`
+fn parse_amount(value: &str) -> Option<f64> {
+    value.split_whitespace().last()?.parse().ok()
+}
+
+impl AfricasTalkingProvider {
+    /// `sandbox` sends through the Africa's Talking simulator instead of live
+    pub fn new(
+        username: String,
+        api_key: String,
+        sandbox: bool,
+        proxy: Option<&ProxyUrl>,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(AfricasTalkingProvider {
+            http: super::http_client(proxy)?,
+            username,
+            api_key,
+            base_url: if sandbox { SANDBOX_URL } else { LIVE_URL },
+        })
+    }
+
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<Value, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "africastalking",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let response = tracecontext::propagate(request)
+            .header("apiKey", &self.api_key)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| crate::retry::parse_retry_after(value, chrono::Utc::now()));
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        // Errors come back as plain text, e.g. an invalid API key
+        if !status.is_success() {
+            let reason = format!(
+                "HTTP {}: {}",
+                status.as_u16(),
+                body.chars().take(256).collect::<String>()
+            );
+            let rate_limited = status == StatusCode::TOO_MANY_REQUESTS;
+            let retry_after = retry_after.filter(|_| rate_limited);
+            return Err(failed(
+                reason,
+                retry_after,
+                rate_limited || status.is_server_error(),
+            ));
+        }
+        serde_json::from_str(&body).map_err(|e| ApiError::ProviderBadResponse {
+            raw: Some(body.chars().take(1024).collect()),
+            parse_error: format!("{} (HTTP {})", e, status),
+        })
+    }
+
+    /// Sends one message to one or more numbers
+    async fn send_message(
+        &self,
+        phones: &[&str],
+        message: &str,
+        sender_id: &str,
+    ) -> Result<(AtMessageData, Value), ApiError> {
+        let to = phones
+            .iter()
+            .map(|phone| format!("+{}", phone.trim_start_matches('+')))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut form = vec![
+            ("username", self.username.clone()),
+            ("to", to),
+            ("message", message.to_string()),
+        ];
+        // Without `from` the account's default sender is used
+        if !sender_id.is_empty() {
+            form.push(("from", sender_id.to_string()));
+        }
+
+        let raw = self
+            .call(
+                self.http
+                    .post(format!("{}/messaging", self.base_url))
+                    .form(&form),
+            )
+            .await?;
+        let parsed: AtSendResponse =
+            serde_json::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+        debug!("Africa's Talking: {}", parsed.data.message);
+        Ok((parsed.data, raw))
+    }
+
+    fn report(data: &AtMessageData, raw: Value) -> SendReport {
+        let statuses: Vec<RecipientStatus> = data
+            .recipients
+            .iter()
+            .map(AtRecipient::delivery_status)
+            .collect();
+        let cost: Option<f64> = data
+            .recipients
+            .iter()
+            .map(|recipient| recipient.cost.as_deref().and_then(parse_amount))
+            .sum();
+
+        SendReport {
+            provider: "africastalking",
+            message_ids: statuses
+                .iter()
+                .filter_map(|status| status.message_id.clone())
+                .collect(),
+            recipients: Some(data.recipients.len() as f64),
+            credits_deducted: cost,
+            available_credits: None,
+            statuses,
+            raw,
+        }
+    }
+}
+
+impl SmsProvider for AfricasTalkingProvider {
+    fn name(&self) -> &'static str {
+        "africastalking"
+    }
+
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        let (data, raw) = self.send_message(&[phone], message, sender_id).await?;
+
+        // The request succeeds even when the recipient is refused, so a
+        // refusal is turned into an error here for retries to see
+        match data.recipients.first() {
+            Some(recipient)
+                if matches!(
+                    send_status(recipient.status_code),
+                    DeliveryStatus::Rejected | DeliveryStatus::Failed
+                ) =>
+            {
+                Err(ApiError::ProviderFailed {
+                    provider: "africastalking",
+                    reason: format!("{} {}", recipient.status_code, recipient.status),
+                    retry_after: None,
+                    // InternalServerError and GatewayError; others refuse the send
+                    transient: matches!(recipient.status_code, 500 | 501),
+                })
+            }
+            Some(_) => Ok(Self::report(&data, raw)),
+            None => Err(ApiError::ProviderFailed {
+                provider: "africastalking",
+                reason: data.message,
+                retry_after: None,
+                transient: false,
+            }),
+        }
+    }
+
+    // Messages sharing a body and sender go out in one request; per-recipient
+    // refusals are reported in `statuses` rather than failing the batch
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        let mut groups: Vec<(&str, &str, Vec<&str>)> = Vec::new();
+        for message in messages {
+            match groups.iter_mut().find(|(body, sender, _)| {
+                *body == message.message.as_str() && *sender == message.sender_id.as_str()
+            }) {
+                Some((_, _, phones)) => phones.push(&message.phone),
+                None => groups.push((&message.message, &message.sender_id, vec![&message.phone])),
+            }
+        }
+
+        let mut recipients = Vec::with_capacity(messages.len());
+        let mut raw = Vec::with_capacity(groups.len());
+        for (body, sender_id, phones) in groups {
+            let (data, response) = self.send_message(&phones, body, sender_id).await?;
+            if data.recipients.len() != phones.len() {
+                warn!(
+                    "Africa's Talking reported {} of {} recipients: {}",
+                    data.recipients.len(),
+                    phones.len(),
+                    data.message
+                );
+            }
+            recipients.extend(data.recipients);
+            raw.push(response);
+        }
+
+        let data = AtMessageData {
+            message: format!("{} recipients", recipients.len()),
+            recipients,
+        };
+        Ok(Self::report(&data, Value::Array(raw)))
+    }
+
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        let raw = self
+            .call(
+                self.http
+                    .get(format!("{}/user", self.base_url))
+                    .query(&[("username", &self.username)]),
+            )
+            .await?;
+        let credits = raw
+            .pointer("/UserData/balance")
+            .and_then(Value::as_str)
+            .and_then(parse_amount);
+        Ok(Balance {
+            provider: "africastalking",
+            credits,
+            raw,
+        })
+    }
+}
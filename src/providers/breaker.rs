@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::error::ApiError;
+use crate::retry::RetryHint;
+
+/// When a provider's breaker opens and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BreakerPolicy {
+    /// Share of recent calls, in percent, that failing opens the breaker
+    pub failure_percent: u64,
+    /// Fewest calls in the window before the rate counts
+    pub min_calls: usize,
+    /// How far back calls are counted
+    #[serde(rename = "window_secs", serialize_with = "secs")]
+    pub window: Duration,
+    /// How long the breaker stays open before a trial call
+    #[serde(rename = "open_secs", serialize_with = "secs")]
+    pub open_for: Duration,
+}
+
+fn secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        BreakerPolicy {
+            failure_percent: 50,
+            min_calls: 5,
+            window: Duration::from_secs(60),
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Calls go through and are counted
+    Closed,
+    /// Calls fail fast until `until`
+    Open { until: Instant },
+    /// One trial call, started at `since`, decides whether to close again
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    /// When each call in the window finished, and whether it failed
+    calls: VecDeque<(Instant, bool)>,
+}
+
+/// Where a breaker stands, for `/status` and `/health/provider`
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub provider: &'static str,
+    /// `closed`, `open` or `half_open`
+    pub state: &'static str,
+    /// Calls counted in the window and how many failed
+    pub calls: usize,
+    pub failures: usize,
+    /// Seconds until the trial call, while open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_secs: Option<u64>,
+}
+
+/// A circuit breaker in front of one provider, for this instance. Once
+/// `failure_percent` of the calls in the window have failed, it opens and
+/// calls fail fast with `CircuitOpen` instead of waiting out a provider
+/// that's down. After `open_for` one trial call goes through: success
+/// closes it, failure opens it again.
+#[derive(Debug)]
+pub struct Breaker {
+    provider: &'static str,
+    policy: BreakerPolicy,
+    inner: Mutex<Inner>,
+}
+
+/// Whether a failed call says the provider is down, rather than the send
+/// was refused or the provider asked us to slow down
+pub fn counts_as_failure(error: &ApiError) -> bool {
+    error.is_transient() && error.retry_after().is_none()
+}
+
+impl Breaker {
+    pub fn new(provider: &'static str, policy: BreakerPolicy) -> Self {
+        Breaker {
+            provider,
+            policy,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                calls: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open_error(&self, wait: Duration) -> ApiError {
+        ApiError::CircuitOpen {
+            provider: self.provider,
+            retry_after_secs: wait.as_secs().max(1),
+        }
+    }
+
+    /// Lets a call through, or refuses it while the breaker is open or a
+    /// trial call is under way
+    pub fn admit(&self) -> Result<(), ApiError> {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        match inner.state {
+            State::Closed => Ok(()),
+            State::Open { until } if now < until => Err(self.open_error(until - now)),
+            State::Open { .. } => {
+                info!(
+                    "Circuit for {} is half-open: trying one call",
+                    self.provider
+                );
+                inner.state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            // A trial that never reported back, e.g. from a request that
+            // was cut off, doesn't hold the breaker forever
+            State::HalfOpen { since } if now - since >= self.policy.open_for => {
+                inner.state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            State::HalfOpen { since } => Err(self.open_error(self.policy.open_for - (now - since))),
+        }
+    }
+
+    /// Counts the outcome of a call `admit` let through
+    pub fn record(&self, failed: bool) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        if let State::HalfOpen { .. } = inner.state {
+            inner.calls.clear();
+            if failed {
+                warn!(
+                    "Trial call to {} failed: circuit open for another {:?}",
+                    self.provider, self.policy.open_for
+                );
+                inner.state = State::Open {
+                    until: now + self.policy.open_for,
+                };
+            } else {
+                info!("Trial call to {} succeeded: circuit closed", self.provider);
+                inner.state = State::Closed;
+            }
+            return;
+        }
+        inner.calls.push_back((now, failed));
+        let window = self.policy.window;
+        while inner
+            .calls
+            .front()
+            .is_some_and(|(at, _)| now - *at > window)
+        {
+            inner.calls.pop_front();
+        }
+        let calls = inner.calls.len();
+        let failures = inner.calls.iter().filter(|(_, failed)| *failed).count();
+        if inner.state == State::Closed
+            && calls >= self.policy.min_calls
+            && failures * 100 >= calls * self.policy.failure_percent as usize
+        {
+            warn!(
+                "{} of the last {} calls to {} failed: circuit open for {:?}",
+                failures, calls, self.provider, self.policy.open_for
+            );
+            inner.state = State::Open {
+                until: now + self.policy.open_for,
+            };
+            inner.calls.clear();
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.lock();
+        let now = Instant::now();
+        let (state, retry_in) = match inner.state {
+            State::Closed => ("closed", None),
+            State::Open { until } if now < until => ("open", Some(until - now)),
+            // The next call will be the trial
+            State::Open { .. } | State::HalfOpen { .. } => ("half_open", None),
+        };
+        BreakerStatus {
+            provider: self.provider,
+            state,
+            calls: inner.calls.len(),
+            failures: inner.calls.iter().filter(|(_, failed)| *failed).count(),
+            retry_in_secs: retry_in.map(|wait| wait.as_secs().max(1)),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use serde_json::json;
+
+use super::{DeliveryStatus, OutboundMessage, RecipientStatus, SendReport};
+use crate::cost::Segments;
+
+/// Where dry-run message ids start, so they're never mistaken for a
+/// provider's
+pub const MESSAGE_ID_PREFIX: &str = "dryrun-";
+
+/// What `provider` would have been asked to send, reported as if it had
+/// queued every message. Nothing leaves the process and nothing is charged.
+pub fn report(provider: &'static str, messages: &[OutboundMessage]) -> SendReport {
+    let message_ids: Vec<String> = messages
+        .iter()
+        .map(|_| format!("{}{}", MESSAGE_ID_PREFIX, uuid::Uuid::new_v4().simple()))
+        .collect();
+    let raw = json!({
+        "dry_run": true,
+        "provider": provider,
+        "messages": messages
+            .iter()
+            .zip(&message_ids)
+            .map(|(message, message_id)| {
+                let segments = Segments::of(&message.message);
+                json!({
+                    "message_id": message_id,
+                    "phone": message.phone,
+                    "message": message.message,
+                    "sender_id": message.sender_id,
+                    "encoding": segments.encoding,
+                    "segments": segments.segments,
+                })
+            })
+            .collect::<Vec<_>>(),
+    });
+    SendReport {
+        provider,
+        statuses: message_ids
+            .iter()
+            .map(|message_id| RecipientStatus {
+                message_id: Some(message_id.clone()),
+                status: DeliveryStatus::Queued,
+                provider_status: "dry run".to_string(),
+            })
+            .collect(),
+        message_ids,
+        recipients: Some(messages.len() as f64),
+        credits_deducted: None,
+        available_credits: None,
+        raw,
+    }
+}
@@ -0,0 +1,289 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Balance, DeliveryStatus, OutboundMessage, RecipientStatus, SendReport, SmsProvider};
+use crate::error::ApiError;
+
+/// Credits the mock starts with; each accepted message costs one
+const DEFAULT_BALANCE: f64 = 1000.0;
+
+/// How the mock answers one submission
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockOutcome {
+    /// Accepted and queued
+    Accept,
+    /// Refused as a provider would; retried by the policy when `transient`
+    Fail { reason: String, transient: bool },
+    /// Throttled, asking to be retried after the delay
+    RateLimited { retry_after: Duration },
+    /// Answered with this body, read as JSON the way real providers' are,
+    /// e.g. an HTML error page during an incident
+    Body(String),
+}
+
+/// An outcome and how long the mock takes to give it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockResponse {
+    pub outcome: MockOutcome,
+    pub latency: Duration,
+}
+
+impl MockResponse {
+    pub fn accept() -> Self {
+        MockResponse {
+            outcome: MockOutcome::Accept,
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn fail(reason: &str, transient: bool) -> Self {
+        MockResponse {
+            outcome: MockOutcome::Fail {
+                reason: reason.to_string(),
+                transient,
+            },
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        MockResponse {
+            outcome: MockOutcome::RateLimited { retry_after },
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn body(body: &str) -> Self {
+        MockResponse {
+            outcome: MockOutcome::Body(body.to_string()),
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// The same outcome, given after `latency`, e.g. to trip
+    /// `PROVIDER_TIMEOUT_SECS`
+    pub fn after(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+/// One submission the mock received, whatever it answered
+#[derive(Debug, Clone, Serialize)]
+pub struct MockSend {
+    pub phone: String,
+    pub message: String,
+    pub sender_id: String,
+    /// Set when the submission was accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct MockState {
+    /// Responses still to give, by the phone's digits
+    scripts: HashMap<String, VecDeque<MockResponse>>,
+    /// Given once a phone's script runs out, or it has none
+    default: MockResponse,
+    sends: Vec<MockSend>,
+    balance: f64,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        MockState {
+            scripts: HashMap::new(),
+            default: MockResponse::accept(),
+            sends: Vec::new(),
+            balance: DEFAULT_BALANCE,
+        }
+    }
+}
+
+static SHARED: Lazy<Arc<Mutex<MockState>>> = Lazy::new(Arc::default);
+
+fn digits(phone: &str) -> String {
+    phone.chars().filter(char::is_ascii_digit).collect()
+}
+
+/// A provider that sends nothing, for tests and local runs. It answers each
+/// submission from a per-number script, accepting by default, and keeps
+/// every submission so tests can check what would have gone out. Clones
+/// share their script and record.
+#[derive(Debug, Clone, Default)]
+pub struct MockSmsProvider {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSmsProvider {
+    /// A mock of its own, sharing nothing with `SMS_PROVIDER=mock`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The mock `SMS_PROVIDER=mock` sends through, so tests driving the
+    /// handler can script it and see what it was sent
+    pub fn shared() -> Self {
+        MockSmsProvider {
+            state: SHARED.clone(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Answers the next submissions to `phone` with `responses`, in order,
+    /// after any still scripted
+    pub fn script(&self, phone: &str, responses: impl IntoIterator<Item = MockResponse>) {
+        self.state()
+            .scripts
+            .entry(digits(phone))
+            .or_default()
+            .extend(responses);
+    }
+
+    /// How numbers without a script are answered
+    pub fn set_default(&self, response: MockResponse) {
+        self.state().default = response;
+    }
+
+    pub fn set_balance(&self, credits: f64) {
+        self.state().balance = credits;
+    }
+
+    /// Every submission, oldest first
+    pub fn sends(&self) -> Vec<MockSend> {
+        self.state().sends.clone()
+    }
+
+    /// Submissions to `phone`, oldest first
+    pub fn sends_to(&self, phone: &str) -> Vec<MockSend> {
+        let phone = digits(phone);
+        self.state()
+            .sends
+            .iter()
+            .filter(|send| digits(&send.phone) == phone)
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets scripts and submissions and restores the defaults
+    pub fn reset(&self) {
+        *self.state() = MockState::default();
+    }
+
+    async fn submit(&self, message: &OutboundMessage) -> Result<RecipientStatus, ApiError> {
+        let response = {
+            let mut state = self.state();
+            let default = state.default.clone();
+            state
+                .scripts
+                .get_mut(&digits(&message.phone))
+                .and_then(VecDeque::pop_front)
+                .unwrap_or(default)
+        };
+        if !response.latency.is_zero() {
+            tokio::time::sleep(response.latency).await;
+        }
+        let message_id = (response.outcome == MockOutcome::Accept)
+            .then(|| format!("mock-{}", uuid::Uuid::new_v4().simple()));
+        let mut state = self.state();
+        if message_id.is_some() {
+            state.balance -= 1.0;
+        }
+        state.sends.push(MockSend {
+            phone: message.phone.clone(),
+            message: message.message.clone(),
+            sender_id: message.sender_id.clone(),
+            message_id: message_id.clone(),
+            at: Utc::now(),
+        });
+        drop(state);
+        match response.outcome {
+            MockOutcome::Accept => Ok(RecipientStatus {
+                message_id,
+                status: DeliveryStatus::Queued,
+                provider_status: "queued".to_string(),
+            }),
+            MockOutcome::Fail { reason, transient } => Err(ApiError::ProviderFailed {
+                provider: "mock",
+                reason,
+                retry_after: None,
+                transient,
+            }),
+            MockOutcome::RateLimited { retry_after } => Err(ApiError::ProviderFailed {
+                provider: "mock",
+                reason: "rate limited".to_string(),
+                retry_after: Some(retry_after),
+                transient: true,
+            }),
+            MockOutcome::Body(body) => match serde_json::from_str::<serde_json::Value>(&body) {
+                Ok(_) => Err(ApiError::ProviderBadResponse {
+                    raw: Some(body),
+                    parse_error: "not a mock response".to_string(),
+                }),
+                Err(e) => Err(ApiError::ProviderBadResponse {
+                    raw: Some(body),
+                    parse_error: e.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+impl SmsProvider for MockSmsProvider {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        self.send_bulk(&[OutboundMessage {
+            phone: phone.to_string(),
+            message: message.to_string(),
+            sender_id: sender_id.to_string(),
+        }])
+        .await
+    }
+
+    /// Submits each message in turn; the first failure fails the batch, as
+    /// providers that check a batch up front do
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        let mut statuses = Vec::new();
+        for message in messages {
+            statuses.push(self.submit(message).await?);
+        }
+        Ok(SendReport {
+            provider: "mock",
+            message_ids: statuses
+                .iter()
+                .filter_map(|status| status.message_id.clone())
+                .collect(),
+            recipients: Some(messages.len() as f64),
+            credits_deducted: Some(messages.len() as f64),
+            available_credits: Some(self.state().balance),
+            raw: json!({ "mock": true, "statuses": statuses }),
+            statuses,
+        })
+    }
+
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        let credits = self.state().balance;
+        Ok(Balance {
+            provider: "mock",
+            credits: Some(credits),
+            raw: json!({ "mock": true, "credits": credits }),
+        })
+    }
+}
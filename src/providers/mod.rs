@@ -0,0 +1,234 @@
+pub mod africastalking;
+pub mod breaker;
+pub mod dryrun;
+pub mod mock;
+pub mod routing;
+pub mod twilio;
+pub mod ujumbe;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use tracing::instrument;
+
+use crate::config::ProviderCredentials;
+use crate::error::ApiError;
+use crate::proxy::ProxyUrl;
+use crate::redact::Redact;
+
+/// One message for `SmsProvider::send_bulk`
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboundMessage {
+    pub phone: String,
+    pub message: String,
+    pub sender_id: String,
+}
+
+/// Where a message is on its way to the handset, normalized across providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Accepted, not yet handed to the network
+    Queued,
+    /// Handed to the network
+    Sent,
+    Delivered,
+    /// Refused for this recipient, e.g. an invalid or blacklisted number
+    Rejected,
+    /// Accepted but could not be delivered
+    Failed,
+    /// Not delivered before the network gave up retrying, e.g. a handset
+    /// that stayed off
+    Expired,
+    /// A status the provider added that we don't map yet
+    Unknown,
+}
+
+impl DeliveryStatus {
+    /// Whether the message can't move on from here
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            DeliveryStatus::Delivered
+                | DeliveryStatus::Rejected
+                | DeliveryStatus::Failed
+                | DeliveryStatus::Expired
+        )
+    }
+}
+
+/// Status of one recipient of a submission
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    pub status: DeliveryStatus,
+    /// The provider's own status, e.g. `101 Success`
+    pub provider_status: String,
+}
+
+/// Provider-neutral outcome of an accepted submission
+#[derive(Debug, Clone)]
+pub struct SendReport {
+    pub provider: &'static str,
+    /// Ids for delivery tracking, when the provider hands them out
+    pub message_ids: Vec<String>,
+    pub recipients: Option<f64>,
+    pub credits_deducted: Option<f64>,
+    pub available_credits: Option<f64>,
+    /// Per-recipient statuses, for providers that report them on submission
+    pub statuses: Vec<RecipientStatus>,
+    /// The provider's own response, returned to API clients as-is
+    pub raw: Value,
+}
+
+// `raw` can echo recipient numbers back, so only the summary is logged
+impl Redact for SendReport {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendReport")
+            .field("provider", &self.provider)
+            .field("message_ids", &self.message_ids)
+            .field("recipients", &self.recipients)
+            .field("credits_deducted", &self.credits_deducted)
+            .field("available_credits", &self.available_credits)
+            .field("statuses", &self.statuses)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Balance {
+    pub provider: &'static str,
+    pub credits: Option<f64>,
+    pub raw: Value,
+}
+
+/// An SMS gateway. Implementations translate their own failures into
+/// `ApiError` so the handler can report them without knowing the gateway.
+pub trait SmsProvider: Send + Sync {
+    /// Short identifier used in logs, metrics and reports
+    fn name(&self) -> &'static str;
+
+    fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> impl Future<Output = Result<SendReport, ApiError>> + Send;
+
+    fn send_bulk(
+        &self,
+        messages: &[OutboundMessage],
+    ) -> impl Future<Output = Result<SendReport, ApiError>> + Send;
+
+    fn get_balance(&self) -> impl Future<Output = Result<Balance, ApiError>> + Send;
+
+    /// Whether sends are only reported, never submitted
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+/// The provider selected by `SMS_PROVIDER`, or one in `SMS_PROVIDERS`. An
+/// enum rather than a trait object, since `SmsProvider`'s async methods
+/// aren't object safe.
+pub enum AnyProvider {
+    Ujumbe(ujumbe::UjumbeProvider),
+    Twilio(twilio::TwilioProvider),
+    AfricasTalking(africastalking::AfricasTalkingProvider),
+    Mock(mock::MockSmsProvider),
+}
+
+/// An HTTP client for a provider, going through `proxy` when there is one
+fn http_client(proxy: Option<&ProxyUrl>) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder();
+    match proxy {
+        Some(proxy) => builder.proxy(proxy.to_reqwest()?).build(),
+        None => builder.build(),
+    }
+}
+
+impl AnyProvider {
+    /// A client for the provider the credentials are for. UjumbeSMS builds
+    /// its own HTTP client, which only finds a proxy in the environment (see
+    /// `runtime::export_sms_proxy`).
+    pub fn from_credentials(
+        credentials: &ProviderCredentials,
+        proxy: Option<&ProxyUrl>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(match credentials {
+            ProviderCredentials::Ujumbe { api_key, email } => {
+                AnyProvider::Ujumbe(ujumbe::UjumbeProvider::new(api_key.clone(), email.clone())?)
+            }
+            ProviderCredentials::Twilio {
+                account_sid,
+                auth_token,
+                messaging_service_sid,
+            } => AnyProvider::Twilio(twilio::TwilioProvider::new(
+                account_sid.clone(),
+                auth_token.clone(),
+                messaging_service_sid.clone(),
+                proxy,
+            )?),
+            ProviderCredentials::AfricasTalking {
+                username,
+                api_key,
+                sandbox,
+            } => AnyProvider::AfricasTalking(africastalking::AfricasTalkingProvider::new(
+                username.clone(),
+                api_key.clone(),
+                *sandbox,
+                proxy,
+            )?),
+            ProviderCredentials::Mock => AnyProvider::Mock(mock::MockSmsProvider::shared()),
+        })
+    }
+}
+
+impl SmsProvider for AnyProvider {
+    fn name(&self) -> &'static str {
+        match self {
+            AnyProvider::Ujumbe(provider) => provider.name(),
+            AnyProvider::Twilio(provider) => provider.name(),
+            AnyProvider::AfricasTalking(provider) => provider.name(),
+            AnyProvider::Mock(provider) => provider.name(),
+        }
+    }
+
+    #[instrument(name = "provider_request", level = "info", skip_all, fields(provider = self.name()))]
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        match self {
+            AnyProvider::Ujumbe(provider) => provider.send_single(phone, message, sender_id).await,
+            AnyProvider::Twilio(provider) => provider.send_single(phone, message, sender_id).await,
+            AnyProvider::AfricasTalking(provider) => {
+                provider.send_single(phone, message, sender_id).await
+            }
+            AnyProvider::Mock(provider) => provider.send_single(phone, message, sender_id).await,
+        }
+    }
+
+    #[instrument(name = "provider_request", level = "info", skip_all, fields(provider = self.name()))]
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        match self {
+            AnyProvider::Ujumbe(provider) => provider.send_bulk(messages).await,
+            AnyProvider::Twilio(provider) => provider.send_bulk(messages).await,
+            AnyProvider::AfricasTalking(provider) => provider.send_bulk(messages).await,
+            AnyProvider::Mock(provider) => provider.send_bulk(messages).await,
+        }
+    }
+
+    #[instrument(name = "provider_request", level = "info", skip_all, fields(provider = self.name()))]
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        match self {
+            AnyProvider::Ujumbe(provider) => provider.get_balance().await,
+            AnyProvider::Twilio(provider) => provider.get_balance().await,
+            AnyProvider::AfricasTalking(provider) => provider.get_balance().await,
+            AnyProvider::Mock(provider) => provider.get_balance().await,
+        }
+    }
+}
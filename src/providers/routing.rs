@@ -0,0 +1,396 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::breaker::{self, Breaker, BreakerPolicy, BreakerStatus};
+use super::{dryrun, AnyProvider, Balance, OutboundMessage, SendReport, SmsProvider};
+use crate::config::{Config, ProviderCredentials};
+use crate::deadline;
+use crate::error::ApiError;
+use crate::killswitch;
+use crate::proxy::ProxyUrl;
+
+/// The provider a recipient is sent through first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Route<'a> {
+    /// The `SMS_ROUTES` prefix that matched, `*` for the catch-all, or
+    /// `None` when no route did and the chain's primary is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<&'a str>,
+    pub provider: &'static str,
+}
+
+/// Every configured provider. `SMS_ROUTES` picks the first one tried for a
+/// recipient by the longest matching phone prefix, the primary of
+/// `SMS_PROVIDERS` otherwise. When the chain has fallbacks, a submission
+/// that fails or times out moves on through the rest of it; the report says
+/// which provider took it, and when all fail the last one's error is
+/// returned. Every call gets `PROVIDER_TIMEOUT_SECS`, or what's left of the
+/// invocation when that's less, and is dropped when it runs out. Each
+/// provider sits behind a circuit breaker: while it's open the provider is
+/// skipped, and when none is left the send fails fast with `CircuitOpen`.
+pub struct ProviderRouter {
+    providers: Vec<AnyProvider>,
+    /// One for each of `providers`, when `CIRCUIT_FAILURE_PERCENT` isn't 0
+    breakers: Vec<Breaker>,
+    breaker_policy: Option<BreakerPolicy>,
+    /// Indexes into `providers`, primary first
+    chain: Vec<usize>,
+    /// Prefixes and the provider index they route to, longest first
+    routes: Vec<(String, usize)>,
+    /// How long each provider call gets
+    timeout: Duration,
+    /// Routes as usual but reports sends instead of submitting them
+    dry_run: bool,
+}
+
+impl ProviderRouter {
+    /// The providers `config` names, their HTTP calls going through `proxy`
+    pub fn from_config(
+        config: &Config,
+        proxy: Option<&ProxyUrl>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut router = ProviderRouter {
+            providers: Vec::new(),
+            breakers: Vec::new(),
+            breaker_policy: config.circuit_breaker,
+            chain: Vec::new(),
+            routes: Vec::new(),
+            timeout: config.provider_timeout,
+            dry_run: false,
+        };
+        for credentials in std::iter::once(&config.credentials).chain(&config.fallback_credentials)
+        {
+            let index = router.provider_index(credentials, proxy)?;
+            router.chain.push(index);
+        }
+        for (prefix, credentials) in &config.routes {
+            let index = router.provider_index(credentials, proxy)?;
+            router.routes.push((prefix.clone(), index));
+        }
+        // `*` is stored as the empty prefix, so it sorts last and matches all
+        router
+            .routes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(router)
+    }
+
+    /// The same routes, with sends reported instead of submitted, for
+    /// `DRY_RUN` and `dry_run` requests
+    pub fn into_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    // The index of the credentials' provider, building it the first time
+    fn provider_index(
+        &mut self,
+        credentials: &ProviderCredentials,
+        proxy: Option<&ProxyUrl>,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(index) = self
+            .providers
+            .iter()
+            .position(|provider| provider.name() == credentials.provider())
+        {
+            return Ok(index);
+        }
+        let provider = AnyProvider::from_credentials(credentials, proxy)?;
+        if let Some(policy) = self.breaker_policy {
+            self.breakers.push(Breaker::new(provider.name(), policy));
+        }
+        self.providers.push(provider);
+        Ok(self.providers.len() - 1)
+    }
+
+    fn matching_route(&self, phone: &str) -> Option<(&str, usize)> {
+        let digits = phone.trim_start_matches('+');
+        self.routes
+            .iter()
+            .find(|(prefix, _)| digits.starts_with(prefix.as_str()))
+            .map(|(prefix, index)| (prefix.as_str(), *index))
+    }
+
+    /// Where a send to the number goes first
+    pub fn route(&self, phone: &str) -> Route<'_> {
+        match self.matching_route(phone) {
+            Some((prefix, index)) => Route {
+                prefix: Some(if prefix.is_empty() { "*" } else { prefix }),
+                provider: self.providers[index].name(),
+            },
+            None => Route {
+                prefix: None,
+                provider: self.providers[self.chain[0]].name(),
+            },
+        }
+    }
+
+    /// Each provider's circuit breaker, primary first; empty when they're off
+    pub fn breakers(&self) -> Vec<BreakerStatus> {
+        self.breakers.iter().map(Breaker::status).collect()
+    }
+
+    // The routed provider, then the chain's failover order without it
+    fn order(&self, phone: &str) -> Vec<usize> {
+        let Some((_, routed)) = self.matching_route(phone) else {
+            return self.chain.clone();
+        };
+        let mut order = vec![routed];
+        if self.chain.len() > 1 {
+            order.extend(self.chain.iter().filter(|&&index| index != routed));
+        }
+        order
+    }
+
+    async fn first_success<'a, F, Fut, T>(&'a self, order: &[usize], call: F) -> Result<T, ApiError>
+    where
+        F: Fn(&'a AnyProvider) -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let mut last_error = None;
+        for (position, &index) in order.iter().enumerate() {
+            let provider = &self.providers[index];
+            let breaker = self.breakers.get(index);
+            if let Some(Err(e)) = breaker.map(Breaker::admit) {
+                debug!("Skipping provider {}: {}", provider.name(), e);
+                // A provider tried earlier failing says more than this one
+                // being skipped
+                last_error = last_error.or(Some(e));
+                continue;
+            }
+            let timeout = deadline::limit(self.timeout);
+            let outcome = if timeout.is_zero() {
+                // The invocation is about to be cut off; no call is made
+                Err(None)
+            } else {
+                tokio::time::timeout(timeout, call(provider))
+                    .await
+                    .map_err(Some)
+            };
+            if let Some(breaker) = breaker {
+                breaker.record(match &outcome {
+                    Ok(Ok(_)) => false,
+                    Ok(Err(e)) => breaker::counts_as_failure(e),
+                    // Only a provider that had its full time is at fault
+                    Err(_) => timeout == self.timeout,
+                });
+            }
+            let error = match outcome {
+                Ok(Ok(value)) => {
+                    if position > 0 {
+                        info!("Failed over to provider {}", provider.name());
+                    }
+                    return Ok(value);
+                }
+                Ok(Err(e)) => e,
+                Err(_) => ApiError::ProviderTimeout {
+                    provider: provider.name(),
+                    timeout_ms: timeout.as_millis() as u64,
+                    budget_remaining_ms: deadline::remaining().map(|left| left.as_millis() as u64),
+                },
+            };
+            if let Some(&next) = order.get(position + 1) {
+                warn!(
+                    "Provider {} failed, trying {}: {}",
+                    provider.name(),
+                    self.providers[next].name(),
+                    error
+                );
+            }
+            last_error = Some(error);
+        }
+        Err(last_error.expect("every order has a provider"))
+    }
+}
+
+impl SmsProvider for ProviderRouter {
+    /// The chain primary's name
+    fn name(&self) -> &'static str {
+        self.providers[self.chain[0]].name()
+    }
+
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        let order = self.order(phone);
+        debug!("Sending through {:?}", self.route(phone));
+        if self.dry_run {
+            let message = OutboundMessage {
+                phone: phone.to_string(),
+                message: message.to_string(),
+                sender_id: sender_id.to_string(),
+            };
+            return Ok(dryrun::report(self.providers[order[0]].name(), &[message]));
+        }
+        killswitch::check().await?;
+        self.first_success(&order, |provider| {
+            provider.send_single(phone, message, sender_id)
+        })
+        .await
+    }
+
+    /// Messages routed alike are submitted together. When they split across
+    /// routes, the reports are combined and name the first group's provider.
+    /// A group failing stops the rest; when earlier groups already went out
+    /// the error is `PartiallySent`, carrying their combined report.
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        let mut groups: Vec<(Vec<usize>, Vec<OutboundMessage>)> = Vec::new();
+        for message in messages {
+            let order = self.order(&message.phone);
+            match groups.iter_mut().find(|(existing, _)| *existing == order) {
+                Some((_, group)) => group.push(message.clone()),
+                None => groups.push((order, vec![message.clone()])),
+            }
+        }
+        let mut reports = Vec::new();
+        let mut sent = 0;
+        for (order, group) in &groups {
+            if self.dry_run {
+                reports.push(dryrun::report(self.providers[order[0]].name(), group));
+                continue;
+            }
+            // Checked per group so a batch that's under way stops too
+            let outcome = match killswitch::check().await {
+                Ok(()) => {
+                    self.first_success(order, |provider| provider.send_bulk(group))
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match outcome {
+                Ok(report) => {
+                    reports.push(report);
+                    sent += group.len();
+                }
+                Err(error) if reports.is_empty() => return Err(error),
+                Err(error) => {
+                    warn!(
+                        "Bulk send stopped after {} of {} messages: {}",
+                        sent,
+                        messages.len(),
+                        error
+                    );
+                    return Err(ApiError::PartiallySent {
+                        sent: Box::new(combine(reports)),
+                        unsent: messages.len() - sent,
+                        error: Box::new(error),
+                    });
+                }
+            }
+        }
+        if reports.is_empty() {
+            return Err(ApiError::InvalidBody {
+                reason: "no messages to send".to_string(),
+            });
+        }
+        Ok(combine(reports))
+    }
+
+    /// The chain primary's balance, which is what most sends spend
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        let provider = &self.providers[self.chain[0]];
+        let timeout = deadline::limit(self.timeout);
+        tokio::time::timeout(timeout, provider.get_balance())
+            .await
+            .unwrap_or_else(|_| {
+                Err(ApiError::ProviderTimeout {
+                    provider: provider.name(),
+                    timeout_ms: timeout.as_millis() as u64,
+                    budget_remaining_ms: deadline::remaining().map(|left| left.as_millis() as u64),
+                })
+            })
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+// One report for several route groups, named after the first group's provider
+fn combine(mut reports: Vec<SendReport>) -> SendReport {
+    if reports.len() == 1 {
+        return reports.remove(0);
+    }
+    let sum = |values: Vec<Option<f64>>| {
+        let present: Vec<f64> = values.into_iter().flatten().collect();
+        (!present.is_empty()).then(|| present.iter().sum())
+    };
+    SendReport {
+        provider: reports[0].provider,
+        message_ids: reports
+            .iter()
+            .flat_map(|report| report.message_ids.clone())
+            .collect(),
+        recipients: sum(reports.iter().map(|report| report.recipients).collect()),
+        credits_deducted: sum(reports
+            .iter()
+            .map(|report| report.credits_deducted)
+            .collect()),
+        available_credits: None,
+        statuses: reports
+            .iter()
+            .flat_map(|report| report.statuses.clone())
+            .collect(),
+        raw: Value::Array(reports.into_iter().map(|report| report.raw).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::mock::{MockResponse, MockSmsProvider};
+
+    fn message(phone: &str) -> OutboundMessage {
+        OutboundMessage {
+            phone: phone.to_string(),
+            message: "Hello".to_string(),
+            sender_id: "UjumbeSMS".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_route_group_keeps_the_groups_already_sent() {
+        let kenya = MockSmsProvider::new();
+        let tanzania = MockSmsProvider::new();
+        tanzania.script("255712000001", [MockResponse::fail("refused", false)]);
+        let router = ProviderRouter {
+            providers: vec![
+                AnyProvider::Mock(kenya.clone()),
+                AnyProvider::Mock(tanzania.clone()),
+            ],
+            breakers: Vec::new(),
+            breaker_policy: None,
+            chain: vec![0],
+            routes: vec![("255".to_string(), 1)],
+            timeout: Duration::from_secs(5),
+            dry_run: false,
+        };
+
+        let messages = [
+            message("254712000001"),
+            message("254712000002"),
+            message("255712000001"),
+            message("255712000002"),
+        ];
+        let outcome = router.send_bulk(&messages).await.unwrap_err();
+        let ApiError::PartiallySent {
+            sent,
+            unsent,
+            error,
+        } = &outcome
+        else {
+            panic!("expected a partial send, got {:?}", outcome);
+        };
+        assert_eq!(sent.recipients, Some(2.0));
+        assert_eq!(sent.message_ids.len(), 2);
+        assert_eq!(*unsent, 2);
+        assert_eq!(error.code(), "send_failed");
+        assert_eq!(kenya.sends().len(), 2);
+        assert_eq!(tanzania.sends().len(), 1);
+    }
+}
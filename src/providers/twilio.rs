@@ -0,0 +1,239 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+use tracing::debug;
+
+use super::{Balance, DeliveryStatus, OutboundMessage, RecipientStatus, SendReport, SmsProvider};
+use crate::error::ApiError;
+use crate::proxy::ProxyUrl;
+use crate::tracecontext;
+
+const BASE_URL: &str = "https://api.twilio.com/2010-04-01";
+
+/// Twilio Programmable Messaging behind the `SmsProvider` trait
+pub struct TwilioProvider {
+    http: reqwest::Client,
+    account_sid: String,
+    auth_token: String,
+    /// Sends through a Messaging Service instead of a `From` sender
+    messaging_service_sid: Option<String>,
+}
+
+/// The parts of Twilio's Message resource we report on
+#[derive(Debug, Deserialize)]
+struct TwilioMessage {
+    sid: String,
+    status: String,
+    #[serde(default)]
+    price: Option<String>,
+}
+
+/// A Twilio message status, as in its resource or a status callback's
+/// `MessageStatus`
+pub fn delivery_status(status: &str) -> DeliveryStatus {
+    match status {
+        "accepted" | "scheduled" | "queued" | "sending" => DeliveryStatus::Queued,
+        "sent" => DeliveryStatus::Sent,
+        "delivered" | "read" => DeliveryStatus::Delivered,
+        "undelivered" | "failed" => DeliveryStatus::Failed,
+        "canceled" => DeliveryStatus::Rejected,
+        _ => DeliveryStatus::Unknown,
+    }
+}
+
+impl TwilioMessage {
+    fn delivery_status(&self) -> RecipientStatus {
+        RecipientStatus {
+            message_id: Some(self.sid.clone()),
+            status: delivery_status(&self.status),
+            provider_status: self.status.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TwilioErrorBody {
+    #[serde(default)]
+    code: Option<i64>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl TwilioProvider {
+    pub fn new(
+        account_sid: String,
+        auth_token: String,
+        messaging_service_sid: Option<String>,
+        proxy: Option<&ProxyUrl>,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(TwilioProvider {
+            http: super::http_client(proxy)?,
+            account_sid,
+            auth_token,
+            messaging_service_sid,
+        })
+    }
+
+    fn url(&self, resource: &str) -> String {
+        format!("{}/Accounts/{}/{}", BASE_URL, self.account_sid, resource)
+    }
+
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<Value, ApiError> {
+        let failed = |reason: String, retry_after: Option<Duration>, transient: bool| {
+            ApiError::ProviderFailed {
+                provider: "twilio",
+                reason,
+                retry_after,
+                transient,
+            }
+        };
+
+        let response = tracecontext::propagate(request)
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| crate::retry::parse_retry_after(value, chrono::Utc::now()));
+        let body = response
+            .text()
+            .await
+            .map_err(|e| failed(e.to_string(), None, true))?;
+
+        let parsed: Value = match serde_json::from_str(&body) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return Err(ApiError::ProviderBadResponse {
+                    raw: Some(body.chars().take(1024).collect()),
+                    parse_error: format!("{} (HTTP {})", e, status),
+                })
+            }
+        };
+
+        if status.is_success() {
+            return Ok(parsed);
+        }
+        let error: TwilioErrorBody = serde_json::from_value(parsed).unwrap_or(TwilioErrorBody {
+            code: None,
+            message: None,
+        });
+        let reason = format!(
+            "HTTP {}: {} (code {})",
+            status.as_u16(),
+            error.message.unwrap_or_else(|| "no message".to_string()),
+            error
+                .code
+                .map_or_else(|| "none".to_string(), |c| c.to_string())
+        );
+        // Only a rate limit is worth waiting out as the provider asks
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS;
+        let retry_after = retry_after.filter(|_| rate_limited);
+        Err(failed(
+            reason,
+            retry_after,
+            rate_limited || status.is_server_error(),
+        ))
+    }
+
+    async fn send_message(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<(TwilioMessage, Value), ApiError> {
+        // Twilio wants E.164; our normalized numbers are digits only
+        let to = format!("+{}", phone.trim_start_matches('+'));
+        let mut form = vec![("To", to), ("Body", message.to_string())];
+        match &self.messaging_service_sid {
+            Some(sid) => form.push(("MessagingServiceSid", sid.clone())),
+            None if sender_id.chars().all(|c| c.is_ascii_digit() || c == '+') => {
+                form.push(("From", format!("+{}", sender_id.trim_start_matches('+'))))
+            }
+            None => form.push(("From", sender_id.to_string())),
+        }
+
+        let raw = self
+            .call(self.http.post(self.url("Messages.json")).form(&form))
+            .await?;
+        let parsed =
+            serde_json::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+        Ok((parsed, raw))
+    }
+}
+
+impl SmsProvider for TwilioProvider {
+    fn name(&self) -> &'static str {
+        "twilio"
+    }
+
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        let (sent, raw) = self.send_message(phone, message, sender_id).await?;
+        Ok(SendReport {
+            provider: "twilio",
+            statuses: vec![sent.delivery_status()],
+            message_ids: vec![sent.sid],
+            recipients: Some(1.0),
+            // Twilio prices a message once it's sent; usually still null here
+            credits_deducted: sent
+                .price
+                .and_then(|price| price.parse::<f64>().ok())
+                .map(f64::abs),
+            available_credits: None,
+            raw,
+        })
+    }
+
+    // Twilio has no batch endpoint, so messages go one request at a time; a
+    // failure stops the batch after the messages already accepted
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        let mut message_ids = Vec::with_capacity(messages.len());
+        let mut statuses = Vec::with_capacity(messages.len());
+        let mut raw = Vec::with_capacity(messages.len());
+        for message in messages {
+            let (sent, response) = self
+                .send_message(&message.phone, &message.message, &message.sender_id)
+                .await?;
+            debug!("Twilio accepted bulk message {}", sent.sid);
+            statuses.push(sent.delivery_status());
+            message_ids.push(sent.sid);
+            raw.push(response);
+        }
+
+        Ok(SendReport {
+            provider: "twilio",
+            recipients: Some(message_ids.len() as f64),
+            message_ids,
+            credits_deducted: None,
+            available_credits: None,
+            statuses,
+            raw: Value::Array(raw),
+        })
+    }
+
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        let raw = self.call(self.http.get(self.url("Balance.json"))).await?;
+        let credits = raw
+            .get("balance")
+            .and_then(Value::as_str)
+            .and_then(|balance| balance.parse().ok());
+        Ok(Balance {
+            provider: "twilio",
+            credits,
+            raw,
+        })
+    }
+}
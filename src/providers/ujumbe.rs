@@ -0,0 +1,324 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use ujumbe_sms::models::MessageRequest;
+use ujumbe_sms::{UjumbeSmsClient, UjumbeSmsConfig, UjumbeSmsError};
+
+use super::{Balance, OutboundMessage, SendReport, SmsProvider};
+use crate::error::ApiError;
+use crate::redact::{self, Redact};
+use crate::retry::{self, RetryHint};
+
+/// Typed view of a UjumbeSMS messaging response. Fields the API adds later
+/// are kept in `extra` rather than rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UjumbeResponse {
+    pub status: UjumbeStatus,
+    #[serde(default)]
+    pub meta: Option<UjumbeMeta>,
+    /// Per-recipient outcomes, when the API reports them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<UjumbeRecipient>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UjumbeStatus {
+    pub code: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UjumbeMeta {
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub recipients: Option<f64>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub credits_deducted: Option<f64>,
+    // The API sends the balance as a string, e.g. "6608"
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub available_credits: Option<f64>,
+    #[serde(default)]
+    pub user_email: Option<String>,
+    #[serde(default)]
+    pub date_time: Option<UjumbeDateTime>,
+    #[serde(default, alias = "messageId")]
+    pub message_id: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UjumbeDateTime {
+    pub date: String,
+    #[serde(default)]
+    pub timezone_type: Option<i32>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UjumbeRecipient {
+    #[serde(alias = "phone", alias = "numbers")]
+    pub number: String,
+    #[serde(default, alias = "messageId")]
+    pub message_id: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub cost: Option<f64>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl UjumbeResponse {
+    pub fn from_value(value: Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.kind.eq_ignore_ascii_case("success")
+    }
+
+    pub fn recipient_count(&self) -> Option<f64> {
+        self.meta.as_ref()?.recipients
+    }
+
+    pub fn credits_deducted(&self) -> Option<f64> {
+        self.meta.as_ref()?.credits_deducted
+    }
+
+    pub fn available_credits(&self) -> Option<f64> {
+        self.meta.as_ref()?.available_credits
+    }
+
+    /// Message ids for delivery tracking, from the recipients or the meta block
+    pub fn message_ids(&self) -> Vec<&str> {
+        let from_recipients = self
+            .recipients
+            .iter()
+            .filter_map(|recipient| recipient.message_id.as_deref());
+        let from_meta = self
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.message_id.as_deref());
+        from_recipients.chain(from_meta).collect()
+    }
+}
+
+// Recipient numbers are masked and the account email left out
+impl Redact for UjumbeResponse {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let recipients: Vec<_> = self
+            .recipients
+            .iter()
+            .map(|recipient| redact::phone(&recipient.number))
+            .collect();
+        f.debug_struct("UjumbeResponse")
+            .field("status", &self.status)
+            .field("recipient_count", &self.recipient_count())
+            .field("credits_deducted", &self.credits_deducted())
+            .field("available_credits", &self.available_credits())
+            .field("recipients", &recipients)
+            .field("message_ids", &self.message_ids())
+            .finish()
+    }
+}
+
+// Accepts `12`, `12.5` and `"12"` alike; anything else is treated as absent
+fn number_or_string<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+// ujumbe_sms only exposes the status line and body of a failed call, so a
+// rate-limited answer's wait time is read from the body
+impl RetryHint for UjumbeSmsError {
+    fn retry_after(&self) -> Option<Duration> {
+        let UjumbeSmsError::ApiError(status, body) = self else {
+            return None;
+        };
+        if !status.starts_with("429") {
+            return None;
+        }
+
+        let body: Value = serde_json::from_str(body).ok()?;
+        let value = ["retry_after", "Retry-After", "retryAfter"]
+            .iter()
+            .find_map(|key| body.get(*key))?;
+        let value = match value {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => s.clone(),
+            _ => return None,
+        };
+        retry::parse_retry_after(&value, chrono::Utc::now())
+    }
+
+    // Rate limits, server errors and network trouble pass; other statuses
+    // mean the request itself was refused
+    fn is_transient(&self) -> bool {
+        match self {
+            UjumbeSmsError::NetworkError(_) => true,
+            UjumbeSmsError::ApiError(status, _) => {
+                status.starts_with("429") || status.starts_with('5')
+            }
+            _ => false,
+        }
+    }
+}
+
+/// UjumbeSMS behind the `SmsProvider` trait
+pub struct UjumbeProvider {
+    client: UjumbeSmsClient,
+}
+
+impl UjumbeProvider {
+    pub fn new(api_key: String, email: String) -> Result<Self, UjumbeSmsError> {
+        let client = UjumbeSmsClient::new(UjumbeSmsConfig::new(api_key, email))?;
+        Ok(UjumbeProvider { client })
+    }
+
+    // Anything the typed view can't read is reported rather than passed on
+    fn report(raw: Value) -> Result<SendReport, ApiError> {
+        let typed =
+            UjumbeResponse::from_value(raw.clone()).map_err(|e| ApiError::ProviderBadResponse {
+                raw: Some(raw.to_string()),
+                parse_error: e.to_string(),
+            })?;
+
+        Ok(SendReport {
+            provider: "ujumbe",
+            message_ids: typed
+                .message_ids()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            recipients: typed.recipient_count(),
+            credits_deducted: typed.credits_deducted(),
+            available_credits: typed.available_credits(),
+            statuses: Vec::new(),
+            raw: serde_json::json!(typed),
+        })
+    }
+}
+
+impl SmsProvider for UjumbeProvider {
+    fn name(&self) -> &'static str {
+        "ujumbe"
+    }
+
+    async fn send_single(
+        &self,
+        phone: &str,
+        message: &str,
+        sender_id: &str,
+    ) -> Result<SendReport, ApiError> {
+        let response = self
+            .client
+            .send_single_message(phone, message, sender_id)
+            .await?;
+        Self::report(serde_json::json!(response))
+    }
+
+    async fn send_bulk(&self, messages: &[OutboundMessage]) -> Result<SendReport, ApiError> {
+        let mut request = MessageRequest::new();
+        for message in messages {
+            request.add_message_bag(
+                message.phone.clone(),
+                message.message.clone(),
+                message.sender_id.clone(),
+            );
+        }
+        let response = self.client.send_messages(request).await?;
+        Self::report(serde_json::json!(response))
+    }
+
+    async fn get_balance(&self) -> Result<Balance, ApiError> {
+        let raw = serde_json::json!(self.client.balance().await?);
+        let credits = raw
+            .pointer("/meta/credits")
+            .and_then(|credits| match credits {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            });
+        Ok(Balance {
+            provider: "ujumbe",
+            credits,
+            raw,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(raw: &str) -> UjumbeResponse {
+        UjumbeResponse::from_value(serde_json::from_str(raw).expect("fixture JSON"))
+            .expect("fixture response")
+    }
+
+    #[test]
+    fn reads_a_successful_send() {
+        let response = fixture(include_str!(
+            "../../tests/fixtures/ujumbe/send_success.json"
+        ));
+        assert!(response.is_success());
+        assert_eq!(response.status.code, "1008");
+        assert_eq!(response.recipient_count(), Some(1.0));
+        assert_eq!(response.credits_deducted(), Some(1.0));
+        // Sent as the string "6608"
+        assert_eq!(response.available_credits(), Some(6608.0));
+        let meta = response.meta.as_ref().expect("meta");
+        assert_eq!(meta.user_email.as_deref(), Some("test@email.com"));
+        assert_eq!(
+            meta.date_time.as_ref().map(|at| at.date.as_str()),
+            Some("20150815 18:19:47")
+        );
+        assert!(response.message_ids().is_empty());
+        assert!(response.extra.is_empty());
+
+        let report =
+            UjumbeProvider::report(serde_json::to_value(&response).unwrap()).expect("report");
+        assert_eq!(report.available_credits, Some(6608.0));
+    }
+
+    #[test]
+    fn reads_a_refused_send() {
+        let response = fixture(include_str!("../../tests/fixtures/ujumbe/send_error.json"));
+        assert!(!response.is_success());
+        assert_eq!(response.status.code, "1001");
+        assert_eq!(response.status.description, "Invalid API credentials");
+        assert!(response.meta.is_none());
+        assert_eq!(response.credits_deducted(), None);
+    }
+
+    #[test]
+    fn keeps_fields_it_does_not_know() {
+        let response = UjumbeResponse::from_value(serde_json::json!({
+            "status": { "code": "1008", "type": "success", "channel": "sms" },
+            "meta": { "recipients": "2", "messageId": "m-1", "sms_parts": 2 },
+            "request_id": "r-1",
+        }))
+        .expect("response");
+        assert_eq!(response.extra["request_id"], "r-1");
+        assert_eq!(response.status.extra["channel"], "sms");
+        let meta = response.meta.as_ref().expect("meta");
+        assert_eq!(meta.extra["sms_parts"], 2);
+        assert_eq!(response.recipient_count(), Some(2.0));
+        assert_eq!(response.message_ids(), ["m-1"]);
+    }
+}
@@ -0,0 +1,231 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::error::ApiError;
+use crate::kv::{self, KvClient};
+use crate::redis::{RedisClient, Reply};
+
+// KEYS: the bucket hash. ARGV: capacity, tokens added per millisecond, now
+// in unix milliseconds, and how long an untouched bucket is kept. Returns 0
+// when a token was taken, otherwise the milliseconds until one is free.
+const TAKE_SCRIPT: &str = "\
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local tokens = tonumber(bucket[1]) or capacity
+local at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - at) * rate)
+local wait = 0
+if tokens >= 1 then tokens = tokens - 1 else wait = math.ceil((1 - tokens) / rate) end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return wait";
+
+/// A token bucket: up to `count` at once, refilled evenly over `per`, so
+/// `3/hour` allows a burst of three and then one every twenty minutes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Limit {
+    pub count: u32,
+    #[serde(rename = "per_secs", serialize_with = "secs")]
+    pub per: Duration,
+}
+
+fn secs<S: serde::Serializer>(per: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(per.as_secs())
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// `<count>/<unit>` with a unit of `second`, `minute`, `hour` or `day`
+    /// (or `s`, `m`, `h`, `d`), e.g. `3/hour`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("{} is not <count>/<unit>, e.g. 3/hour", s))?;
+        let count: u32 = count
+            .trim()
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("{} is not a positive count", count.trim()))?;
+        let per = match unit.trim().to_ascii_lowercase().as_str() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            "d" | "day" => Duration::from_secs(86_400),
+            other => return Err(format!("{} is not second, minute, hour or day", other)),
+        };
+        Ok(Limit { count, per })
+    }
+}
+
+impl Limit {
+    fn rate_per_ms(&self) -> f64 {
+        self.count as f64 / self.per.as_millis() as f64
+    }
+}
+
+/// The limits on what one caller or number can do, from
+/// `RATE_LIMIT_PER_KEY` and `RATE_LIMIT_PER_NUMBER`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RateLimits {
+    /// Requests per API key or token subject
+    pub per_key: Option<Limit>,
+    /// SMS sent to one phone number
+    pub per_number: Option<Limit>,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Result<Self, (&'static str, String)> {
+        let limit = |key: &'static str| match std::env::var(key) {
+            Ok(raw) if !raw.trim().is_empty() => raw
+                .parse::<Limit>()
+                .map(Some)
+                .map_err(|reason| (key, reason)),
+            _ => Ok(None),
+        };
+        Ok(RateLimits {
+            per_key: limit("RATE_LIMIT_PER_KEY")?,
+            per_number: limit("RATE_LIMIT_PER_NUMBER")?,
+        })
+    }
+}
+
+/// Where buckets are kept: Redis when `RATE_LIMIT_REDIS_URL` is set, else
+/// Vercel KV when it's linked, so every instance shares them; this
+/// instance's memory otherwise
+pub enum LimiterStore {
+    /// Tokens left, when they were counted, and how long the bucket takes to refill
+    Memory(Mutex<HashMap<String, (f64, Instant, Duration)>>),
+    Kv(&'static KvClient),
+    Redis(RedisClient),
+}
+
+impl LimiterStore {
+    /// Takes a token from the bucket, returning how long until one is free
+    /// if it's empty
+    async fn take(&self, key: &str, limit: Limit) -> io::Result<Option<Duration>> {
+        let rate = limit.rate_per_ms();
+        let capacity = limit.count.to_string();
+        let rate_arg = rate.to_string();
+        let now = chrono::Utc::now().timestamp_millis().to_string();
+        let ttl = limit.per.as_millis().max(1).to_string();
+        let wait_ms = match self {
+            LimiterStore::Memory(buckets) => {
+                let mut buckets = buckets.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                // Buckets untouched for a full refill are full again anyway
+                buckets.retain(|_, (_, at, per)| now.duration_since(*at) < *per);
+                let (tokens, at, _) =
+                    buckets
+                        .entry(key.to_string())
+                        .or_insert((limit.count as f64, now, limit.per));
+                let elapsed = now.duration_since(*at).as_millis() as f64;
+                *tokens = (*tokens + elapsed * rate).min(limit.count as f64);
+                *at = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    0
+                } else {
+                    ((1.0 - *tokens) / rate).ceil() as i64
+                }
+            }
+            LimiterStore::Kv(client) => client
+                .command(&[
+                    "EVAL",
+                    TAKE_SCRIPT,
+                    "1",
+                    key,
+                    &capacity,
+                    &rate_arg,
+                    &now,
+                    &ttl,
+                ])
+                .await?
+                .as_i64()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad EVAL reply"))?,
+            LimiterStore::Redis(client) => {
+                let reply = client
+                    .command(&[
+                        b"EVAL",
+                        TAKE_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        capacity.as_bytes(),
+                        rate_arg.as_bytes(),
+                        now.as_bytes(),
+                        ttl.as_bytes(),
+                    ])
+                    .await?;
+                match reply {
+                    Reply::Integer(wait) => wait,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad EVAL reply")),
+                }
+            }
+        };
+        Ok((wait_ms > 0).then(|| Duration::from_millis(wait_ms as u64)))
+    }
+}
+
+static MEMORY: Lazy<LimiterStore> = Lazy::new(|| LimiterStore::Memory(Mutex::new(HashMap::new())));
+
+// Unlike idempotency, a broken shared store falls back to memory: limits
+// only one instance enforces beat failing every send
+static STORE: Lazy<&'static LimiterStore> = Lazy::new(|| {
+    let shared = match std::env::var("RATE_LIMIT_REDIS_URL") {
+        Ok(url) if !url.is_empty() => match RedisClient::parse(&url) {
+            Ok(client) => {
+                info!("Using Redis rate limits at: {}", client.address());
+                Some(LimiterStore::Redis(client))
+            }
+            Err(e) => {
+                error!("Invalid RATE_LIMIT_REDIS_URL, limiting in memory: {}", e);
+                None
+            }
+        },
+        _ => match kv::client() {
+            Some(Ok(client)) => Some(LimiterStore::Kv(client)),
+            Some(Err(e)) => {
+                error!("Vercel KV unavailable, limiting in memory: {}", e);
+                None
+            }
+            None => None,
+        },
+    };
+    match shared {
+        Some(store) => Box::leak(Box::new(store)),
+        None => &MEMORY,
+    }
+});
+
+/// Counts one use of `limit` by `subject`, e.g. `key` and an API key's name
+/// or `number` and a phone, failing with 429 once its bucket is empty
+pub async fn take(limit: Option<Limit>, kind: &'static str, subject: &str) -> Result<(), ApiError> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let key = format!("ratelimit:{}:{}", kind, subject);
+    let wait = match STORE.take(&key, limit).await {
+        Ok(wait) => wait,
+        Err(e) => {
+            warn!("Rate limit check failed, using memory: {}", e);
+            MEMORY.take(&key, limit).await.unwrap_or_default()
+        }
+    };
+    match wait {
+        None => Ok(()),
+        Some(wait) => Err(ApiError::RateLimited {
+            limit: kind,
+            retry_after_secs: wait.as_secs_f64().ceil() as u64,
+        }),
+    }
+}
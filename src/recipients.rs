@@ -0,0 +1,341 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::error::ApiError;
+use crate::phone;
+use crate::storage::{self, unavailable};
+
+/// How long rules read from storage are used before they're read again, so
+/// a rule added on another instance applies within this
+const STORED_TTL: Duration = Duration::from_secs(30);
+
+/// A single entry from `ALLOWED_NUMBERS`/`BLOCKED_NUMBERS`. Entries ending in
+/// `*` match any number starting with the given digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumberPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl NumberPattern {
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return None;
+        }
+
+        match raw.strip_suffix('*') {
+            Some(prefix) => {
+                // Prefixes are shorter than a full number, so only strip separators
+                let digits: String = prefix.chars().filter(|c| c.is_ascii_digit()).collect();
+                Some(NumberPattern::Prefix(digits))
+            }
+            None => Some(NumberPattern::Exact(phone::normalize(raw))),
+        }
+    }
+
+    pub fn matches(&self, normalized_phone: &str) -> bool {
+        match self {
+            NumberPattern::Exact(number) => number == normalized_phone,
+            NumberPattern::Prefix(prefix) => normalized_phone.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl std::fmt::Display for NumberPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumberPattern::Exact(number) => write!(f, "{}", number),
+            NumberPattern::Prefix(prefix) => write!(f, "{}*", prefix),
+        }
+    }
+}
+
+/// Outcome of checking a destination against the configured rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// No allowlist is configured and no block rule matched
+    Allowed,
+    /// The number matched this allowlist entry
+    AllowedBy(NumberPattern),
+    /// The number matched this blocklist entry
+    BlockedBy(NumberPattern),
+    /// An allowlist is configured and the number is not on it
+    NotAllowed,
+}
+
+impl Verdict {
+    pub fn is_permitted(&self) -> bool {
+        matches!(self, Verdict::Allowed | Verdict::AllowedBy(_))
+    }
+}
+
+/// Destination allow/deny lists. Block rules always win over allow rules.
+#[derive(Debug, Clone, Default)]
+pub struct NumberRules {
+    pub allowed: Vec<NumberPattern>,
+    pub blocked: Vec<NumberPattern>,
+    /// From `SANDBOX_MODE`: only allowlisted numbers get real SMS, so an
+    /// empty allowlist sends to nobody
+    pub sandbox: bool,
+}
+
+impl NumberRules {
+    pub fn new(allowed: &str, blocked: &str) -> Self {
+        NumberRules {
+            allowed: parse_list(allowed),
+            blocked: parse_list(blocked),
+            sandbox: false,
+        }
+    }
+
+    /// Loads the rules from the comma-separated `ALLOWED_NUMBERS` and
+    /// `BLOCKED_NUMBERS` environment variables, and from the files named by
+    /// `ALLOWED_NUMBERS_FILE` and `BLOCKED_NUMBERS_FILE`, one entry a line
+    /// with `#` comments. All are optional.
+    pub fn from_env() -> Result<Self, (&'static str, String)> {
+        let allowed = std::env::var("ALLOWED_NUMBERS").unwrap_or_default();
+        let blocked = std::env::var("BLOCKED_NUMBERS").unwrap_or_default();
+        let mut rules = Self::new(&allowed, &blocked);
+        rules.allowed.extend(read_file("ALLOWED_NUMBERS_FILE")?);
+        rules.blocked.extend(read_file("BLOCKED_NUMBERS_FILE")?);
+        rules.sandbox = matches!(
+            std::env::var("SANDBOX_MODE").as_deref(),
+            Ok("1") | Ok("true")
+        );
+        if rules.sandbox && rules.allowed.is_empty() {
+            warn!(
+                "SANDBOX_MODE is set with no allowlist; only stored allow rules will receive SMS"
+            );
+        }
+        Ok(rules)
+    }
+
+    pub fn check(&self, normalized_phone: &str) -> Verdict {
+        if let Some(rule) = self.blocked.iter().find(|p| p.matches(normalized_phone)) {
+            return Verdict::BlockedBy(rule.clone());
+        }
+
+        if self.allowed.is_empty() && !self.sandbox {
+            return Verdict::Allowed;
+        }
+
+        match self.allowed.iter().find(|p| p.matches(normalized_phone)) {
+            Some(rule) => Verdict::AllowedBy(rule.clone()),
+            None => Verdict::NotAllowed,
+        }
+    }
+}
+
+fn parse_list(raw: &str) -> Vec<NumberPattern> {
+    raw.split(',').filter_map(NumberPattern::parse).collect()
+}
+
+fn read_file(key: &'static str) -> Result<Vec<NumberPattern>, (&'static str, String)> {
+    let path = match std::env::var(key) {
+        Ok(path) if !path.trim().is_empty() => path,
+        _ => return Ok(Vec::new()),
+    };
+    let text = std::fs::read_to_string(path.trim())
+        .map_err(|e| (key, format!("can't read {}: {}", path.trim(), e)))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(NumberPattern::parse)
+        .collect())
+}
+
+/// Which list a stored rule is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleList {
+    Allow,
+    Block,
+}
+
+impl RuleList {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleList::Allow => "allow",
+            RuleList::Block => "block",
+        }
+    }
+}
+
+impl std::str::FromStr for RuleList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(RuleList::Allow),
+            "block" => Ok(RuleList::Block),
+            other => Err(format!("{} is not allow or block", other)),
+        }
+    }
+}
+
+/// An allow or block entry added through `/admin/number-rules`, kept in
+/// the storage backend alongside those from the environment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRule {
+    pub list: RuleList,
+    /// As [`NumberPattern`] displays it, e.g. `254712345678` or `2547*`
+    pub pattern: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl StoredRule {
+    pub fn new(list: RuleList, raw: &str) -> Result<Self, ApiError> {
+        let pattern = NumberPattern::parse(raw).ok_or_else(|| ApiError::InvalidBody {
+            reason: "pattern must not be empty".to_string(),
+        })?;
+        if matches!(&pattern, NumberPattern::Prefix(prefix) if prefix.is_empty()) {
+            return Err(ApiError::InvalidBody {
+                reason: "a prefix pattern needs at least one digit".to_string(),
+            });
+        }
+        Ok(StoredRule {
+            list,
+            pattern: pattern.to_string(),
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Unique per list and pattern, e.g. `block:2547*`
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.list.as_str(), self.pattern)
+    }
+}
+
+/// Stored rules as last read, and when
+type Fetched = (Instant, Vec<StoredRule>);
+
+static STORED: Lazy<Mutex<Option<Fetched>>> = Lazy::new(|| Mutex::new(None));
+
+fn forget_stored() {
+    *STORED.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Rules added through `/admin/number-rules`; none without a storage backend
+pub async fn stored() -> Result<Vec<StoredRule>, ApiError> {
+    let storage = match storage::backend() {
+        None => return Ok(Vec::new()),
+        Some(Ok(storage)) => storage,
+        Some(Err(reason)) => return Err(ApiError::StorageUnavailable { reason }),
+    };
+    if let Some((fetched, rules)) = &*STORED.lock().unwrap_or_else(|e| e.into_inner()) {
+        if fetched.elapsed() < STORED_TTL {
+            return Ok(rules.clone());
+        }
+    }
+    let rules = storage.list_number_rules().await.map_err(unavailable)?;
+    *STORED.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), rules.clone()));
+    Ok(rules)
+}
+
+/// `base`, from the environment, with the stored rules added. A storage
+/// failure is an error rather than a fallback to `base`, so sends can't
+/// slip past a stored block rule or a sandbox's stored allowlist.
+pub async fn effective(base: &NumberRules) -> Result<NumberRules, ApiError> {
+    let mut rules = base.clone();
+    for rule in stored().await? {
+        let Some(pattern) = NumberPattern::parse(&rule.pattern) else {
+            continue;
+        };
+        match rule.list {
+            RuleList::Allow => rules.allowed.push(pattern),
+            RuleList::Block => rules.blocked.push(pattern),
+        }
+    }
+    Ok(rules)
+}
+
+fn backend() -> Result<&'static dyn storage::Storage, ApiError> {
+    match storage::backend() {
+        Some(Ok(storage)) => Ok(storage),
+        Some(Err(reason)) => Err(ApiError::StorageUnavailable { reason }),
+        None => Err(ApiError::StorageUnavailable {
+            reason: "storing number rules needs a storage backend".to_string(),
+        }),
+    }
+}
+
+/// Stores an allow or block rule, failing if the list already has it
+pub async fn add(list: RuleList, raw: &str) -> Result<StoredRule, ApiError> {
+    let rule = StoredRule::new(list, raw)?;
+    let created = backend()?
+        .create_number_rule(rule.clone())
+        .await
+        .map_err(unavailable)?;
+    if !created {
+        return Err(ApiError::NumberRuleExists { id: rule.id() });
+    }
+    forget_stored();
+    Ok(rule)
+}
+
+/// Removes a stored rule; rules from the environment can't be removed here
+pub async fn remove(list: RuleList, raw: &str) -> Result<(), ApiError> {
+    let id = StoredRule::new(list, raw)?.id();
+    if !backend()?
+        .delete_number_rule(&id)
+        .await
+        .map_err(unavailable)?
+    {
+        return Err(ApiError::NumberRuleNotFound { id });
+    }
+    forget_stored();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_match_numbers_starting_with_them() {
+        let rules = NumberRules::new("", "+254 70*, 255123456789");
+        let prefix = NumberPattern::Prefix("25470".to_string());
+        assert_eq!(rules.check("254700000001"), Verdict::BlockedBy(prefix));
+        assert_eq!(
+            rules.check("255123456789"),
+            Verdict::BlockedBy(NumberPattern::Exact("255123456789".to_string()))
+        );
+        assert_eq!(rules.check("254710000001"), Verdict::Allowed);
+        // An exact entry doesn't match a longer number
+        assert_eq!(rules.check("2551234567890"), Verdict::Allowed);
+    }
+
+    #[test]
+    fn an_allowlist_refuses_numbers_not_on_it() {
+        let rules = NumberRules::new("2547*, 0712 000 001", "");
+        assert_eq!(
+            rules.check("254712000001"),
+            Verdict::AllowedBy(NumberPattern::Prefix("2547".to_string()))
+        );
+        assert_eq!(rules.check("255700000001"), Verdict::NotAllowed);
+        assert!(!rules.check("255700000001").is_permitted());
+    }
+
+    #[test]
+    fn block_rules_win_over_allow_rules() {
+        let rules = NumberRules::new("254*, 254700000009", "25470*");
+        assert_eq!(
+            rules.check("254700000009"),
+            Verdict::BlockedBy(NumberPattern::Prefix("25470".to_string()))
+        );
+        assert!(rules.check("254711000000").is_permitted());
+    }
+
+    #[test]
+    fn sandbox_with_no_allowlist_sends_to_nobody() {
+        let mut rules = NumberRules::new("", "");
+        assert_eq!(rules.check("254700000001"), Verdict::Allowed);
+        rules.sandbox = true;
+        assert_eq!(rules.check("254700000001"), Verdict::NotAllowed);
+    }
+}
@@ -0,0 +1,309 @@
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use tracing_subscriber::fmt::MakeWriter;
+
+// Set `LOG_UNREDACTED=1` to log phones and message bodies verbatim while
+// debugging locally
+static UNREDACTED: Lazy<bool> = Lazy::new(|| {
+    matches!(
+        std::env::var("LOG_UNREDACTED").as_deref(),
+        Ok("1") | Ok("true")
+    )
+});
+
+pub fn unredacted() -> bool {
+    *UNREDACTED
+}
+
+/// Values with a log-safe rendering that hides personal data
+pub trait Redact {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl<T: Redact + ?Sized> Redact for &T {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt_redacted(f)
+    }
+}
+
+impl<T: Redact> Redact for Option<T> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Some(value) => value.fmt_redacted(f),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// Wraps a value for logging: formats through `Redact` unless
+/// `LOG_UNREDACTED` is set, in which case the value's own `Debug` is used
+pub struct Redacted<T>(pub T);
+
+impl<T: Redact + fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if unredacted() {
+            self.0.fmt(f)
+        } else {
+            self.0.fmt_redacted(f)
+        }
+    }
+}
+
+impl<T: Redact + fmt::Debug> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A phone number, masked but for its country prefix and last three digits
+pub struct Phone<'a>(pub &'a str);
+
+impl fmt::Debug for Phone<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Redact for Phone<'_> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&mask_phone(self.0))
+    }
+}
+
+/// A message body, reduced to its length and a short hash so identical
+/// messages can still be correlated across log lines
+pub struct Message<'a>(pub &'a str);
+
+impl fmt::Debug for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl Redact for Message<'_> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&mask_message(self.0))
+    }
+}
+
+pub fn phone(phone: &str) -> Redacted<Phone<'_>> {
+    Redacted(Phone(phone))
+}
+
+pub fn message(message: &str) -> Redacted<Message<'_>> {
+    Redacted(Message(message))
+}
+
+/// `254717135176` becomes `2547*****176`. Numbers too short to hide much
+/// behind a prefix keep only their last three digits.
+pub fn mask_phone(phone: &str) -> String {
+    let chars: Vec<char> = phone.chars().collect();
+    let tail = chars.len().min(3);
+    let head = match chars.first() {
+        _ if chars.len() < 10 => 0,
+        Some('+') => 5,
+        _ => 4,
+    };
+    let masked = "*".repeat(chars.len() - tail - head);
+    let head: String = chars[..head].iter().collect();
+    let tail: String = chars[chars.len() - tail..].iter().collect();
+    format!("{}{}{}", head, masked, tail)
+}
+
+pub fn mask_message(message: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    message.hash(&mut hasher);
+    format!(
+        "<{} chars #{:08x}>",
+        message.chars().count(),
+        hasher.finish() as u32
+    )
+}
+
+/// Lengths of the digit runs taken for phone numbers, national or E.164
+const PHONE_DIGITS: RangeInclusive<usize> = 9..=15;
+
+/// Keys holding message bodies: as log fields, or quoted inside log text
+/// such as a struct's `Debug`, a provider's JSON or a text line's fields
+const BODY_KEYS: [&str; 6] = [
+    "message",
+    "body",
+    "text",
+    "content",
+    "sms_body",
+    "message_body",
+];
+
+/// Masks what looks like personal data in log text: runs of 9 to 15
+/// digits, with or without a `+`, and quoted message bodies. It's a net for
+/// what call sites don't already redact, so it errs towards masking.
+pub fn scrub(text: &str) -> String {
+    scrub_phones(&scrub_bodies(text))
+}
+
+fn scrub_phones(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let digits_from = if chars[i] == '+' { i + 1 } else { i };
+        let mut end = digits_from;
+        while end < chars.len() && chars[end].is_ascii_digit() {
+            end += 1;
+        }
+        if end == digits_from {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        // Part of a word, an ID or a decimal rather than a number of its own
+        let joined = |c: &char| c.is_alphanumeric() || *c == '.' || *c == '_';
+        let after = chars.get(end).is_some_and(joined);
+        let before = i > 0 && joined(&chars[i - 1]);
+        let run: String = chars[i..end].iter().collect();
+        if !before && !after && PHONE_DIGITS.contains(&(end - digits_from)) {
+            out.push_str(&mask_phone(&run));
+        } else {
+            out.push_str(&run);
+        }
+        i = end;
+    }
+    out
+}
+
+fn scrub_bodies(text: &str) -> String {
+    let mut out = text.to_string();
+    for key in BODY_KEYS {
+        for marker in [
+            format!("{}: \"", key),
+            format!("{}=\"", key),
+            format!("\"{}\":\"", key),
+            format!("\"{}\": \"", key),
+        ] {
+            out = mask_quoted_after(&out, &marker);
+        }
+    }
+    out
+}
+
+/// Replaces each string that opens with `marker` by its `mask_message`
+fn mask_quoted_after(text: &str, marker: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(marker) {
+        let (before, after) = rest.split_at(start + marker.len());
+        out.push_str(before);
+        // `text: "` inside `context: "` isn't a body
+        let word = before[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if word {
+            rest = after;
+            continue;
+        }
+        // The closing quote: the first one not escaped
+        let mut escaped = false;
+        let close = after.char_indices().find_map(|(index, c)| match c {
+            '\\' if !escaped => {
+                escaped = true;
+                None
+            }
+            '"' if !escaped => Some(index),
+            _ => {
+                escaped = false;
+                None
+            }
+        });
+        let Some(close) = close else {
+            rest = after;
+            break;
+        };
+        let body = &after[..close];
+        if body.starts_with('<') && body.ends_with('>') {
+            // Already masked
+            out.push_str(body);
+        } else {
+            out.push_str(&mask_message(body));
+        }
+        rest = &after[close..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Scrubs every string in a JSON log line and masks body fields outright.
+/// `message` is the event's own text there, so it's only scrubbed.
+pub fn scrub_json(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = scrub(text),
+        Value::Array(items) => items.iter_mut().for_each(scrub_json),
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value {
+                    Value::String(text)
+                        if key != "message" && BODY_KEYS.contains(&key.as_str()) =>
+                    {
+                        *text = mask_message(text)
+                    }
+                    _ => scrub_json(value),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// One formatted log line, scrubbed unless `LOG_UNREDACTED` is set
+pub fn scrub_line(line: &str) -> String {
+    if unredacted() {
+        return line.to_string();
+    }
+    match serde_json::from_str::<Value>(line.trim_end()) {
+        Ok(mut value) => {
+            scrub_json(&mut value);
+            format!("{}\n", value)
+        }
+        Err(_) => scrub(line),
+    }
+}
+
+/// Stdout for the log subscriber, passing each line through `scrub_line`
+pub struct LogWriter;
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = LogLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogLine(Vec::new())
+    }
+}
+
+/// A line being formatted; it's scrubbed and written out when dropped
+pub struct LogLine(Vec<u8>);
+
+impl Write for LogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogLine {
+    fn drop(&mut self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let line = scrub_line(&String::from_utf8_lossy(&self.0));
+        let _ = io::stdout().lock().write_all(line.as_bytes());
+    }
+}
@@ -0,0 +1,172 @@
+use http::Uri;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Timeout for each Redis round trip
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A minimal Redis client speaking RESP, opening a connection per command
+/// since invocations are short-lived
+#[derive(Debug, Clone)]
+pub struct RedisClient {
+    address: String,
+    host: String,
+    /// `rediss://`, as hosted Redis such as Upstash requires
+    tls: bool,
+    username: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+/// One RESP reply
+#[derive(Debug)]
+pub enum Reply {
+    Ok,
+    Nil,
+    Bulk(Vec<u8>),
+    Integer(i64),
+    Array(Vec<Reply>),
+}
+
+impl RedisClient {
+    /// Accepts `redis://[[user]:password@]host[:port][/db]`, or `rediss://`
+    /// for TLS
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let uri: Uri = raw
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid Redis URL: {}", e))?;
+        let tls = match uri.scheme_str() {
+            Some("redis") => false,
+            Some("rediss") => true,
+            _ => return Err("Redis URL must use the redis:// or rediss:// scheme".to_string()),
+        };
+        let authority = uri
+            .authority()
+            .ok_or_else(|| "Redis URL must include a host".to_string())?;
+        let (username, password) = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => match userinfo.split_once(':') {
+                Some((user, password)) => (
+                    Some(user.to_string()).filter(|user| !user.is_empty()),
+                    Some(password.to_string()),
+                ),
+                None => (None, Some(userinfo.to_string())),
+            },
+            None => (None, None),
+        };
+        let db = match uri.path().trim_start_matches('/') {
+            "" => 0,
+            db => db
+                .parse()
+                .map_err(|_| format!("invalid Redis database: {}", db))?,
+        };
+
+        Ok(RedisClient {
+            address: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(6379)
+            ),
+            host: authority.host().to_string(),
+            tls,
+            username,
+            password,
+            db,
+        })
+    }
+
+    /// `host:port`, safe to log
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub async fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        tokio::time::timeout(TIMEOUT, self.command_inner(args))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Redis timed out"))?
+    }
+
+    async fn command_inner(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let stream = TcpStream::connect(&self.address).await?;
+        if !self.tls {
+            return self.run(BufReader::new(stream), args).await;
+        }
+        let connector = tokio_native_tls::TlsConnector::from(
+            tokio_native_tls::native_tls::TlsConnector::new().map_err(io::Error::other)?,
+        );
+        let stream = connector
+            .connect(&self.host, stream)
+            .await
+            .map_err(io::Error::other)?;
+        self.run(BufReader::new(stream), args).await
+    }
+
+    async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: BufReader<S>,
+        args: &[&[u8]],
+    ) -> io::Result<Reply> {
+        if let Some(password) = &self.password {
+            let mut auth: Vec<&[u8]> = vec![b"AUTH"];
+            if let Some(username) = &self.username {
+                auth.push(username.as_bytes());
+            }
+            auth.push(password.as_bytes());
+            send(&mut stream, &auth).await?;
+        }
+        if self.db != 0 {
+            send(&mut stream, &[b"SELECT", self.db.to_string().as_bytes()]).await?;
+        }
+        send(&mut stream, args).await
+    }
+}
+
+/// Writes one command and reads its reply
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    args: &[&[u8]],
+) -> io::Result<Reply> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    stream.get_mut().write_all(&request).await?;
+    read_reply(stream).await
+}
+
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> io::Result<Reply> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let line = line.trim_end();
+    match line.split_at_checked(1) {
+        Some(("+", _)) => Ok(Reply::Ok),
+        Some(("-", message)) => Err(io::Error::other(format!("Redis error: {}", message))),
+        Some((":", value)) => value
+            .parse()
+            .map(Reply::Integer)
+            .map_err(|_| invalid("bad integer reply")),
+        Some(("$" | "*", "-1")) => Ok(Reply::Nil),
+        Some(("$", len)) => {
+            let len: usize = len.parse().map_err(|_| invalid("bad bulk length"))?;
+            let mut value = vec![0; len + 2];
+            stream.read_exact(&mut value).await?;
+            value.truncate(len);
+            Ok(Reply::Bulk(value))
+        }
+        Some(("*", len)) => {
+            let len: usize = len.parse().map_err(|_| invalid("bad array length"))?;
+            let mut items = Vec::with_capacity(len);
+            for _ in 0..len {
+                items.push(Box::pin(read_reply(stream)).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        _ => Err(invalid("unexpected Redis reply")),
+    }
+}
@@ -0,0 +1,219 @@
+use once_cell::sync::OnceCell;
+use sentry::protocol::{Context, Event, Exception, Level, Request, Url};
+use sentry::{ClientInitGuard, ClientOptions, Hub, SentryFutureExt};
+use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::error::ApiError;
+use crate::redact;
+use crate::tracecontext::TraceContext;
+
+/// Longest a response waits for reports to go out
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Request headers attached to reports; the others may carry credentials
+const REPORTED_HEADERS: [&str; 6] = [
+    "accept",
+    "accept-language",
+    "content-length",
+    "content-type",
+    "user-agent",
+    "x-vercel-id",
+];
+
+/// Set once errors are being reported
+static GUARD: OnceCell<ClientInitGuard> = OnceCell::new();
+
+/// Starts reporting panics, and the failures passed to `failed_send` and
+/// `failed_request`, to the Sentry project `SENTRY_DSN` names. Nothing is
+/// reported when it's unset or doesn't parse.
+pub fn init() {
+    let Ok(dsn) = std::env::var("SENTRY_DSN") else {
+        return;
+    };
+    if dsn.trim().is_empty() {
+        return;
+    }
+    let dsn = match dsn.trim().parse() {
+        Ok(dsn) => dsn,
+        Err(e) => {
+            error!("Not reporting errors, SENTRY_DSN is invalid: {}", e);
+            return;
+        }
+    };
+    let options = ClientOptions {
+        dsn: Some(dsn),
+        release: std::env::var("VERCEL_GIT_COMMIT_SHA")
+            .ok()
+            .map(Cow::Owned)
+            .or_else(|| sentry::release_name!()),
+        environment: std::env::var("VERCEL_ENV").ok().map(Cow::Owned),
+        before_send: Some(Arc::new(scrub)),
+        ..Default::default()
+    };
+    if GUARD.set(sentry::init(options)).is_err() {
+        return;
+    }
+    // Sentry's own hook captures the panic; the instance may not be around
+    // to send it later
+    let next = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        next(info);
+        if let Some(client) = Hub::main().client() {
+            client.flush(Some(FLUSH_TIMEOUT));
+        }
+    }));
+    info!("Reporting errors to Sentry");
+}
+
+/// Masks phones and message bodies in what's sent, as the logs do, whatever
+/// `LOG_UNREDACTED` says
+fn scrub(mut event: Event<'static>) -> Option<Event<'static>> {
+    if let Some(message) = event.message.as_mut() {
+        *message = redact::scrub(message);
+    }
+    for exception in event.exception.values.iter_mut() {
+        if let Some(value) = exception.value.as_mut() {
+            *value = redact::scrub(value);
+        }
+    }
+    Some(event)
+}
+
+/// The request as reports show it: where it went and a few headers. The
+/// query string and body are left out, as they carry numbers and messages.
+fn request_context<B>(req: &http::Request<B>) -> Request {
+    let host = req
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or(req.uri().host())
+        .unwrap_or("localhost");
+    let headers = REPORTED_HEADERS
+        .iter()
+        .filter_map(|&name| {
+            let value = req.headers().get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    Request {
+        url: Url::parse(&format!("https://{}{}", host, req.uri().path())).ok(),
+        method: Some(req.method().to_string()),
+        headers,
+        ..Default::default()
+    }
+}
+
+/// A hub for one request, so what it reports carries its route, trace and
+/// request context
+pub fn for_request<B>(req: &http::Request<B>, context: &TraceContext) -> Arc<Hub> {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    if GUARD.get().is_none() {
+        return hub;
+    }
+    let route = req.uri().path().to_string();
+    let request = request_context(req);
+    hub.configure_scope(|scope| {
+        scope.set_transaction(Some(&route));
+        scope.set_tag("route", &route);
+        scope.set_tag("trace_id", &context.trace_id);
+        if let (Ok(trace_id), Ok(span_id)) = (context.trace_id.parse(), context.span_id.parse()) {
+            scope.set_context(
+                "trace",
+                Context::Trace(Box::new(sentry::protocol::TraceContext {
+                    trace_id,
+                    span_id,
+                    parent_span_id: context.parent_id.as_deref().and_then(|id| id.parse().ok()),
+                    ..Default::default()
+                })),
+            );
+        }
+        scope.add_event_processor(move |mut event| {
+            event.request = Some(request.clone());
+            Some(event)
+        });
+    });
+    hub
+}
+
+/// Runs `future` reporting through `hub`
+pub fn scope<F: Future>(hub: Arc<Hub>, future: F) -> impl Future<Output = F::Output> {
+    future.bind_hub(hub)
+}
+
+/// The hub of the request being handled, for tasks it spawns
+pub fn current() -> Arc<Hub> {
+    Hub::current()
+}
+
+fn capture(error: &ApiError) {
+    if GUARD.get().is_none() {
+        return;
+    }
+    let mut event = Event {
+        level: Level::Error,
+        exception: vec![Exception {
+            ty: error.code().to_string(),
+            value: Some(error.to_string()),
+            ..Default::default()
+        }]
+        .into(),
+        ..Default::default()
+    };
+    event
+        .tags
+        .insert("error_code".to_string(), error.code().to_string());
+    let id = Hub::current().capture_event(event);
+    debug!("Reported {} to Sentry as {}", error.code(), id.simple());
+}
+
+/// Reports a send the provider failed or refused, after its retries. Each
+/// failed send comes through here once, whether or not it fails the request.
+pub fn failed_send(error: &ApiError) {
+    // A bulk send stopped partway is reported when what stopped it would be
+    let cause = match error {
+        ApiError::PartiallySent { error, .. } => error,
+        _ => error,
+    };
+    if matches!(
+        cause,
+        ApiError::Provider(_)
+            | ApiError::ProviderFailed { .. }
+            | ApiError::ProviderBadResponse { .. }
+            | ApiError::ProviderTimeout { .. }
+    ) {
+        capture(error);
+    }
+}
+
+/// Reports a request failed by storage being unavailable. Provider failures
+/// are left to `failed_send`, and the caller's mistakes aren't reported.
+pub fn failed_request(error: &ApiError) {
+    if matches!(
+        error,
+        ApiError::StorageUnavailable { .. }
+            | ApiError::JobStoreUnavailable { .. }
+            | ApiError::DlqUnavailable { .. }
+            | ApiError::OptOutUnavailable { .. }
+            | ApiError::IdempotencyUnavailable { .. }
+    ) {
+        capture(error);
+    }
+}
+
+/// Sends the reports still queued. Called before each response is returned,
+/// since a serverless function can be frozen as soon as it has answered.
+pub async fn flush() {
+    let Some(client) = GUARD.get().and_then(|_| Hub::main().client()) else {
+        return;
+    };
+    let started = Instant::now();
+    match tokio::task::spawn_blocking(move || client.flush(Some(FLUSH_TIMEOUT))).await {
+        Ok(true) => debug!("Flushed error reports in {:?}", started.elapsed()),
+        Ok(false) => warn!("Error reports still queued after {:?}", FLUSH_TIMEOUT),
+        Err(e) => warn!("Failed to flush error reports: {}", e),
+    }
+}
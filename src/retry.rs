@@ -0,0 +1,329 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::deadline;
+
+/// What to do with a message once every attempt has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GiveUpAction {
+    /// Report the failure and forget the message
+    Drop,
+    /// Keep the message for an operator to inspect and replay
+    DeadLetter,
+}
+
+impl std::str::FromStr for GiveUpAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "drop" => Ok(GiveUpAction::Drop),
+            "dead_letter" | "dead-letter" | "dlq" => Ok(GiveUpAction::DeadLetter),
+            other => Err(format!("unknown give-up action: {}", other)),
+        }
+    }
+}
+
+/// Effective retry behavior for a send.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Delay before the second attempt; doubled for every attempt after that
+    pub backoff_ms: u64,
+    /// Fraction each backoff is randomly stretched or shrunk by, so clients
+    /// failing together don't retry together
+    pub jitter: f64,
+    pub give_up: GiveUpAction,
+    /// Longest provider-requested `Retry-After` we are willing to sleep for
+    pub retry_after_cap_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff_ms: 500,
+            jitter: 0.2,
+            give_up: GiveUpAction::Drop,
+            retry_after_cap_ms: 30_000,
+        }
+    }
+}
+
+/// Most attempts a send or job may ask for
+pub const MAX_OVERRIDE_ATTEMPTS: u32 = 10;
+
+/// Shortest backoff a send or job may ask for
+pub const MIN_OVERRIDE_BACKOFF_MS: u64 = 100;
+
+/// Per-job overrides; unset fields fall back to the global policy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicyOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub give_up: Option<GiveUpAction>,
+}
+
+impl RetryPolicyOverride {
+    /// Refuses overrides that would hammer a failing provider: more than
+    /// `MAX_OVERRIDE_ATTEMPTS` attempts or backoff under
+    /// `MIN_OVERRIDE_BACKOFF_MS`. Only callers are held to these; the
+    /// global policy is the operator's to set.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(attempts) = self.max_attempts {
+            if !(1..=MAX_OVERRIDE_ATTEMPTS).contains(&attempts) {
+                return Err(format!(
+                    "retry_policy.max_attempts must be 1 to {}, got {}",
+                    MAX_OVERRIDE_ATTEMPTS, attempts
+                ));
+            }
+        }
+        if let Some(backoff_ms) = self.backoff_ms {
+            if backoff_ms < MIN_OVERRIDE_BACKOFF_MS {
+                return Err(format!(
+                    "retry_policy.backoff_ms must be at least {}, got {}",
+                    MIN_OVERRIDE_BACKOFF_MS, backoff_ms
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RetryPolicy {
+    /// Loads the global policy from `RETRY_MAX_ATTEMPTS`, `RETRY_BACKOFF_MS`,
+    /// `RETRY_JITTER`, `RETRY_GIVE_UP` (`drop` or `dead_letter`) and
+    /// `RETRY_AFTER_CAP_MS`.
+    /// Invalid values are logged and replaced by the defaults.
+    pub fn from_env() -> Self {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: env_or("RETRY_MAX_ATTEMPTS", defaults.max_attempts),
+            backoff_ms: env_or("RETRY_BACKOFF_MS", defaults.backoff_ms),
+            jitter: clamp_jitter(env_or("RETRY_JITTER", defaults.jitter)),
+            give_up: env_or("RETRY_GIVE_UP", defaults.give_up),
+            retry_after_cap_ms: env_or("RETRY_AFTER_CAP_MS", defaults.retry_after_cap_ms),
+        }
+    }
+
+    /// This policy with `overrides` applied. Overrides stored before they
+    /// were validated are held to the same bounds here.
+    pub fn with_override(&self, overrides: &RetryPolicyOverride) -> Self {
+        RetryPolicy {
+            max_attempts: overrides
+                .max_attempts
+                .map_or(self.max_attempts, |attempts| {
+                    attempts.clamp(1, MAX_OVERRIDE_ATTEMPTS)
+                }),
+            backoff_ms: overrides
+                .backoff_ms
+                .map_or(self.backoff_ms, |ms| ms.max(MIN_OVERRIDE_BACKOFF_MS)),
+            jitter: overrides.jitter.map_or(self.jitter, clamp_jitter),
+            give_up: overrides.give_up.unwrap_or(self.give_up),
+            retry_after_cap_ms: self.retry_after_cap_ms,
+        }
+    }
+
+    /// The policy a job or send runs under: this one with its overrides, if
+    /// it has any
+    pub fn for_send(&self, overrides: Option<&RetryPolicyOverride>) -> Self {
+        match overrides {
+            Some(overrides) => self.with_override(overrides),
+            None => self.clone(),
+        }
+    }
+
+    /// Delay to wait after the given (1-based) failed attempt, before jitter
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+
+    /// `delay` stretched or shrunk by up to `jitter` of itself
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        // uuid's v4 generator is the randomness we already depend on
+        let unit = (uuid::Uuid::new_v4().as_u128() as u64) as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 + self.jitter * (unit * 2.0 - 1.0))
+    }
+
+    /// Delay before retrying after `error`: the provider's `Retry-After` when
+    /// it sent one (capped), otherwise the jittered exponential backoff
+    pub fn delay_for<E: RetryHint>(&self, attempt: u32, error: &E) -> Duration {
+        match error.retry_after() {
+            Some(requested) => {
+                let cap = Duration::from_millis(self.retry_after_cap_ms);
+                if requested > cap {
+                    warn!(
+                        "Provider asked to wait {:?} - capping at {:?}",
+                        requested, cap
+                    );
+                }
+                requested.min(cap)
+            }
+            None => self.jittered(self.delay_after(attempt)),
+        }
+    }
+
+    /// Runs `op` until it succeeds, fails with an error that isn't transient
+    /// or the attempts run out, returning the last result together with the
+    /// number of attempts made.
+    pub async fn run<T, E, F, Fut>(&self, mut op: F) -> (Result<T, E>, u32)
+    where
+        E: std::fmt::Display + RetryHint,
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+
+        loop {
+            match op(attempt).await {
+                Ok(value) => return (Ok(value), attempt),
+                Err(e) if attempt >= max_attempts => return (Err(e), attempt),
+                Err(e) if !e.is_transient() => {
+                    debug!("Attempt {} failed permanently: {}", attempt, e);
+                    return (Err(e), attempt);
+                }
+                Err(e) => {
+                    let delay = self.delay_for(attempt, &e);
+                    // Another attempt would start after the invocation is
+                    // cut off, taking the response with it
+                    if deadline::remaining().is_some_and(|left| delay >= left) {
+                        warn!(
+                            "Attempt {}/{} failed: {} - no time left in the invocation to retry",
+                            attempt, max_attempts, e
+                        );
+                        return (Err(e), attempt);
+                    }
+                    warn!(
+                        "Attempt {}/{} failed: {} - retrying in {:?}",
+                        attempt, max_attempts, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Errors that can tell the retry loop whether and when to try again
+pub trait RetryHint {
+    /// How long the other side asked us to wait
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether trying again could succeed; rejections of the request itself
+    /// should say no so they aren't repeated
+    fn is_transient(&self) -> bool {
+        true
+    }
+}
+
+fn clamp_jitter(jitter: f64) -> f64 {
+    if jitter.is_finite() {
+        jitter.clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Parses a `Retry-After` value: either delay seconds or an HTTP-date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value)
+        .ok()?
+        .with_timezone(&Utc);
+    // A date in the past means "retry now"
+    Some((at - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+fn env_or<T>(key: &str, default: T) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(key) {
+        Ok(raw) => raw.parse().unwrap_or_else(|e| {
+            warn!("Invalid {} value {:?}: {} - using default", key, raw, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RateLimited(Option<Duration>);
+
+    impl RetryHint for RateLimited {
+        fn retry_after(&self) -> Option<Duration> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn honors_the_providers_retry_after_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 100,
+            jitter: 0.0,
+            give_up: GiveUpAction::Drop,
+            retry_after_cap_ms: 30_000,
+        };
+        let now: DateTime<Utc> = "2024-09-02T09:00:00Z".parse().unwrap();
+        let asked = parse_retry_after("Mon, 02 Sep 2024 09:00:12 GMT", now);
+        assert_eq!(asked, Some(Duration::from_secs(12)));
+
+        // The provider's wait replaces the 100ms backoff
+        assert_eq!(
+            policy.delay_for(1, &RateLimited(asked)),
+            Duration::from_secs(12)
+        );
+        assert_eq!(
+            policy.delay_for(2, &RateLimited(parse_retry_after("7", now))),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            policy.delay_for(1, &RateLimited(parse_retry_after("120", now))),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            policy.delay_for(2, &RateLimited(None)),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[test]
+    fn overrides_are_held_to_bounds() {
+        let limited = |max_attempts, backoff_ms| RetryPolicyOverride {
+            max_attempts,
+            backoff_ms,
+            ..Default::default()
+        };
+        assert_eq!(limited(Some(5), Some(250)).validate(), Ok(()));
+        assert_eq!(RetryPolicyOverride::default().validate(), Ok(()));
+        assert!(limited(Some(4_000_000_000), None).validate().is_err());
+        assert!(limited(Some(0), None).validate().is_err());
+        assert!(limited(None, Some(0)).validate().is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use http::StatusCode;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Instant;
+use tracing::{debug, debug_span, error, info, instrument, warn};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use vercel_runtime::{Body, Error, Request, Response};
+
+use crate::config::Config;
+use crate::error::{ApiError, PROBLEM_CONTENT_TYPE};
+use crate::format::Format;
+use crate::i18n::Lang;
+use crate::logfilter::RequestFilter;
+use crate::metrics;
+use crate::providers::routing::ProviderRouter;
+use crate::providers::SmsProvider;
+use crate::proxy::ProxyUrl;
+use crate::redact;
+use crate::reporting;
+use crate::telemetry;
+
+// Built once per instance and reused across warm invocations
+static SMS_CLIENT: OnceCell<ProviderRouter> = OnceCell::new();
+
+static DRY_RUN_CLIENT: OnceCell<ProviderRouter> = OnceCell::new();
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The deployment's config, read from the environment on first use
+pub fn config() -> Result<&'static Config, Error> {
+    CONFIG.get_or_try_init(|| {
+        let _span = debug_span!("config_load").entered();
+        Ok(Config::from_env()?)
+    })
+}
+
+static PROXY: OnceCell<Option<ProxyUrl>> = OnceCell::new();
+
+/// The outbound proxy, if one is configured. A proxy that is set but
+/// malformed is an error rather than ignored, so traffic never silently
+/// bypasses it.
+pub fn proxy() -> Result<Option<&'static ProxyUrl>, Error> {
+    let proxy = PROXY.get_or_try_init(|| match ProxyUrl::from_env() {
+        Some(Ok(proxy)) => Ok(Some(proxy)),
+        Some(Err(e)) => {
+            error!("Invalid outbound proxy configuration: {}", e);
+            Err(Error::from(e))
+        }
+        None => Ok(None),
+    })?;
+    Ok(proxy.as_ref())
+}
+
+#[instrument(level = "debug")]
+fn init_sms_client() -> Result<ProviderRouter, Error> {
+    let config = config()?;
+
+    let proxy = proxy()?;
+    if let Some(proxy) = proxy {
+        info!("Routing provider traffic through proxy: {}", proxy);
+    }
+
+    info!("Initializing SMS client for provider: {}", config.provider);
+    if !config.fallback_credentials.is_empty() {
+        info!(
+            "Failing over to providers: {:?}",
+            config
+                .fallback_credentials
+                .iter()
+                .map(|credentials| credentials.provider())
+                .collect::<Vec<_>>()
+        );
+    }
+    match ProviderRouter::from_config(config, proxy) {
+        Ok(client) if config.dry_run => {
+            warn!("DRY_RUN is set: sends are reported, not submitted");
+            Ok(client.into_dry_run())
+        }
+        Ok(client) => {
+            debug!("SMS client initialized successfully");
+            Ok(client)
+        }
+        Err(e) => {
+            error!("Failed to initialize SMS client: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// The provider router sends go through, honouring `DRY_RUN`
+pub fn sms_client() -> Result<&'static ProviderRouter, Error> {
+    if let Some(client) = SMS_CLIENT.get() {
+        debug!("Reusing the SMS client from an earlier invocation");
+        return Ok(client);
+    }
+    SMS_CLIENT.get_or_try_init(|| {
+        let started = Instant::now();
+        let client = init_sms_client()?;
+        info!("SMS client initialized in {:?}", started.elapsed());
+        Ok(client)
+    })
+}
+
+/// A router that reports sends instead of submitting them, for `dry_run`
+/// requests on a deployment that otherwise sends
+pub fn dry_run_client() -> Result<&'static ProviderRouter, Error> {
+    DRY_RUN_CLIENT
+        .get_or_try_init(|| Ok(ProviderRouter::from_config(config()?, proxy()?)?.into_dry_run()))
+}
+
+/// Copies `SMS_PROXY_URL` into `HTTPS_PROXY`, for ujumbe_sms, whose HTTP
+/// client only finds a proxy in the environment; the other providers are
+/// handed theirs. Call it first thing in `main`, before the Tokio runtime
+/// starts its threads, since changing the environment races with threads
+/// reading it. Every HTTP client that reads the system proxy goes through it
+/// too, so it's only done when the deployment sends with UjumbeSMS.
+pub fn export_sms_proxy() {
+    let Ok(raw) = std::env::var("SMS_PROXY_URL") else {
+        return;
+    };
+    // UjumbeSMS is the provider when SMS_PROVIDER is unset
+    let uses_ujumbe = std::env::var("SMS_PROVIDER").is_err()
+        || ["SMS_PROVIDER", "SMS_PROVIDERS", "SMS_ROUTES"]
+            .iter()
+            .filter_map(|key| std::env::var(key).ok())
+            .any(|value| value.to_ascii_lowercase().contains("ujumbe"));
+    let already_set = ["HTTPS_PROXY", "https_proxy"]
+        .iter()
+        .any(|key| std::env::var(key).is_ok_and(|value| !value.is_empty()));
+    if uses_ujumbe && !already_set && ProxyUrl::parse(&raw).is_ok() {
+        std::env::set_var("HTTPS_PROXY", raw.trim());
+    }
+}
+
+/// Initializes the cached SMS client ahead of the first request so cold
+/// starts don't pay for it. Set `WARMUP_PING=true` to also check that the
+/// provider is reachable; a configured proxy is always checked. Failures
+/// are only logged.
+pub async fn warm_up() {
+    let started = Instant::now();
+    info!("Starting warm-up");
+
+    let client = match sms_client() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Warm-up failed after {:?}: {}", started.elapsed(), e);
+            return;
+        }
+    };
+    debug!("SMS client ready after {:?}", started.elapsed());
+
+    if let Ok(Some(proxy)) = proxy() {
+        let connect = tokio::net::TcpStream::connect(proxy.address());
+        match tokio::time::timeout(std::time::Duration::from_secs(5), connect).await {
+            Ok(Ok(_)) => debug!("Outbound proxy {} is reachable", proxy),
+            Ok(Err(e)) => error!("Outbound proxy {} is unreachable: {}", proxy, e),
+            Err(_) => error!(
+                "Outbound proxy {} did not accept a connection within 5s",
+                proxy
+            ),
+        }
+    }
+
+    if matches!(
+        std::env::var("WARMUP_PING").as_deref(),
+        Ok("1") | Ok("true")
+    ) {
+        match client.get_balance().await {
+            Ok(_) => info!("Provider reachable after {:?}", started.elapsed()),
+            Err(e) => match proxy() {
+                Ok(Some(proxy)) => error!(
+                    "Provider ping through proxy {} failed during warm-up: {}",
+                    proxy, e
+                ),
+                _ => warn!("Provider ping failed during warm-up: {}", e),
+            },
+        }
+    }
+
+    info!("Warm-up completed in {:?}", started.elapsed());
+}
+
+/// The query string's pairs, percent-decoded
+#[instrument(level = "debug")]
+pub fn parse_query_params(query: Option<&str>) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+
+    if let Some(query_str) = query {
+        debug!("Parsing query string: {}", query_str);
+        for pair in query_str.split('&') {
+            if let Some((key, value)) = pair.split_once('=') {
+                let decoded_key = urlencoding::decode(key).unwrap_or_default().to_string();
+                let decoded_value = urlencoding::decode(value).unwrap_or_default().to_string();
+
+                debug!("Parsed query param: {} = {}", decoded_key, decoded_value);
+                params.insert(decoded_key, decoded_value);
+            }
+        }
+        info!("Parsed {} query parameters", params.len());
+    } else {
+        debug!("No query string found");
+    }
+
+    params
+}
+
+/// The request body's bytes, whatever form Vercel passed it in
+pub fn read_body(body: Body) -> Vec<u8> {
+    info!("Reading request body");
+    match body {
+        Body::Binary(bytes) => {
+            debug!("Received binary body with {} bytes", bytes.len());
+            bytes
+        }
+        Body::Text(text) => {
+            debug!("Received text body with {} characters", text.len());
+            text.into_bytes()
+        }
+        Body::Empty => {
+            debug!("Received empty body");
+            Vec::new()
+        }
+    }
+}
+
+/// Refuses a request whose body is over `limit` bytes, going by the larger
+/// of its length and the `Content-Length` it declared, before anything
+/// copies or parses it
+pub fn check_body_size(req: &Request, limit: usize) -> Result<(), ApiError> {
+    let declared = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let read = match req.body() {
+        Body::Binary(bytes) => bytes.len(),
+        Body::Text(text) => text.len(),
+        Body::Empty => 0,
+    };
+    let size = declared.max(read);
+    if size > limit {
+        warn!(
+            "Refusing a {} byte body, over MAX_BODY_BYTES of {}",
+            size, limit
+        );
+        return Err(ApiError::PayloadTooLarge {
+            size_bytes: size,
+            limit_bytes: limit,
+        });
+    }
+    Ok(())
+}
+
+/// An error as RFC 9457 problem details, with `Retry-After` when the error
+/// says when to retry and `WWW-Authenticate` on 401
+pub fn error_response(
+    error: &ApiError,
+    lang: Lang,
+    format: Format,
+    trace_id: &str,
+) -> Result<Response<Body>, Error> {
+    reporting::failed_request(error);
+    let mut body = error.problem(lang);
+    body["trace_id"] = json!(trace_id);
+
+    let content_type = match format {
+        Format::Json => PROBLEM_CONTENT_TYPE,
+        Format::MessagePack => format.content_type(),
+    };
+    let mut response = respond_as(error.status(), &body, format, content_type, trace_id)?;
+    if let Some(secs) = error.retry_after_secs() {
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, secs.into());
+    }
+    if error.status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_static("Bearer"),
+        );
+    }
+    Ok(response)
+}
+
+/// A response with the headers every response shares: the content type,
+/// `X-Trace-Id` and the deployment's `RESPONSE_HEADERS`
+pub fn response_builder(
+    status: StatusCode,
+    content_type: &str,
+    trace_id: &str,
+) -> http::response::Builder {
+    let mut builder = Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("X-Trace-Id", trace_id); // Include trace ID in response headers
+
+    // Deployment headers replace the defaults above, and the CORS
+    // headers added once the response is built, rather than repeat them
+    if let (Ok(config), Some(headers)) = (config(), builder.headers_mut()) {
+        for (name, value) in &config.response_headers {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    builder
+}
+
+/// `body` serialized in `format`
+pub fn respond<T: Serialize>(
+    status: StatusCode,
+    body: &T,
+    format: Format,
+    trace_id: &str,
+) -> Result<Response<Body>, Error> {
+    respond_as(status, body, format, format.content_type(), trace_id)
+}
+
+/// `body` serialized in `format` and sent as `content_type`
+pub fn respond_as<T: Serialize>(
+    status: StatusCode,
+    body: &T,
+    format: Format,
+    content_type: &str,
+    trace_id: &str,
+) -> Result<Response<Body>, Error> {
+    Ok(
+        response_builder(status, content_type, trace_id).body(match format.serialize(body) {
+            Ok(bytes) => {
+                debug!("Response serialized successfully as {:?}", format);
+                match format {
+                    Format::Json => Body::Text(String::from_utf8_lossy(&bytes).into_owned()),
+                    Format::MessagePack => Body::Binary(bytes),
+                }
+            }
+            Err(e) => {
+                error!("Failed to serialize response: {}", e);
+                return Err(e.into());
+            }
+        })?,
+    )
+}
+
+/// Logs to stdout, filtered by `RUST_LOG` (`info` by default) except under
+/// requests sent with `X-Debug` (see `logfilter`), as one JSON
+/// object a line, or as text with `LOG_FORMAT=text`. Either way each line
+/// passes through `redact::LogWriter`, which masks phones and bodies.
+pub fn init_tracing() {
+    let text = std::env::var("LOG_FORMAT").is_ok_and(|format| format.trim() == "text");
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_target(true)
+        .with_line_number(true)
+        .with_writer(redact::LogWriter);
+    let fmt = if text {
+        // Colour codes would split the `key="value"` fields the writer masks
+        fmt.with_ansi(false).boxed()
+    } else {
+        fmt.json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed()
+    };
+    let (otel, otel_error) = match telemetry::layer() {
+        Ok(layer) => (layer, None),
+        Err(e) => (None, Some(e)),
+    };
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(RequestFilter::from_env()))
+        .with(otel.with_filter(RequestFilter::from_env()))
+        .init();
+    if let Some(e) = otel_error {
+        warn!("Not exporting traces, the OTLP exporter failed: {}", e);
+    }
+}
+
+/// Checks the config, then runs a function's handler until Vercel shuts the
+/// instance down, logging how it ended under `name`. A misconfigured
+/// deployment fails its cold start with every problem listed, rather than
+/// its first request.
+pub async fn serve<F, Fut>(name: &str, handler: F) -> Result<(), Error>
+where
+    F: FnMut(Request) -> Fut,
+    Fut: Future<Output = Result<Response<Body>, Error>>,
+{
+    if let Err(e) = config() {
+        error!("{} can't start: {}", name, e);
+        return Err(e);
+    }
+    reporting::init();
+    metrics::install();
+    let mut handler = handler;
+    let handler = move |req: Request| {
+        let method = req.method().to_string();
+        let started = Instant::now();
+        let response = handler(req);
+        async move {
+            let response = response.await;
+            let status = response.as_ref().map_or(500, |r| r.status().as_u16());
+            metrics::record_request(&method, status, started.elapsed());
+            metrics::push().await;
+            telemetry::flush().await;
+            reporting::flush().await;
+            response
+        }
+    };
+    match vercel_runtime::run(handler).await {
+        Ok(_) => {
+            info!("{} shutdown gracefully", name);
+            Ok(())
+        }
+        Err(e) => {
+            error!("{} error: {}", name, e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,548 @@
+pub mod natural;
+pub mod rrule;
+
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    UnknownTimezone(String),
+    InvalidTime(String),
+    InvalidPace(String),
+    InvalidCron(String),
+    InvalidRrule(String),
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::UnknownTimezone(name) => write!(f, "unknown timezone: {}", name),
+            ScheduleError::InvalidTime(reason) => write!(f, "invalid time: {}", reason),
+            ScheduleError::InvalidPace(reason) => write!(f, "invalid pace: {}", reason),
+            ScheduleError::InvalidCron(reason) => write!(f, "invalid cron expression: {}", reason),
+            ScheduleError::InvalidRrule(reason) => write!(f, "invalid RRULE: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// Parses an IANA timezone name such as `Africa/Nairobi`
+pub fn parse_timezone(name: &str) -> Result<Tz, ScheduleError> {
+    name.trim()
+        .parse::<Tz>()
+        .map_err(|_| ScheduleError::UnknownTimezone(name.to_string()))
+}
+
+/// Parses a wall-clock time without an offset, e.g. `2024-09-01T09:00:00`
+pub fn parse_local(raw: &str) -> Result<NaiveDateTime, ScheduleError> {
+    let raw = raw.trim();
+    NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(raw, "%Y-%m-%dT%H:%M"))
+        .map_err(|e| ScheduleError::InvalidTime(format!("{}: {}", raw, e)))
+}
+
+/// Resolves a wall-clock time in `tz` to an absolute instant. A time falling
+/// in a DST gap moves forward past the gap; an ambiguous one takes the
+/// earlier instant.
+pub fn local_to_utc(local: NaiveDateTime, tz: Tz) -> Result<DateTime<Utc>, ScheduleError> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(at) => Ok(at.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earliest, _) => Ok(earliest.with_timezone(&Utc)),
+        LocalResult::None => match tz.from_local_datetime(&(local + Duration::hours(1))) {
+            LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => Ok(at.with_timezone(&Utc)),
+            LocalResult::None => Err(ScheduleError::InvalidTime(format!(
+                "{} does not exist in {}",
+                local, tz
+            ))),
+        },
+    }
+}
+
+/// A daily do-not-disturb window of wall-clock times, e.g. `21:00` to
+/// `07:00`; a window whose end is earlier than its start runs overnight
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+fn parse_clock(raw: &str) -> Result<NaiveTime, ScheduleError> {
+    NaiveTime::parse_from_str(raw.trim(), "%H:%M")
+        .map_err(|e| ScheduleError::InvalidTime(format!("{}: expected HH:MM, {}", raw, e)))
+}
+
+impl QuietHours {
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        if parse_clock(&self.start)? == parse_clock(&self.end)? {
+            return Err(ScheduleError::InvalidTime(
+                "quiet hours must start and end at different times".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// When the window holding `at` (as read in `tz`) ends, or `None` if
+    /// `at` is outside it
+    pub fn end_after(&self, at: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let (start, end) = (parse_clock(&self.start).ok()?, parse_clock(&self.end).ok()?);
+        let local = at.with_timezone(&tz).naive_local();
+        let time = local.time();
+        let quiet = if start < end {
+            start <= time && time < end
+        } else {
+            time >= start || time < end
+        };
+        if !quiet {
+            return None;
+        }
+        let date = match time < end {
+            true => local.date(),
+            false => local.date().succ_opt()?,
+        };
+        local_to_utc(date.and_time(end), tz).ok()
+    }
+}
+
+/// Parses an interval such as `30m`, `12h`, `1d` or `2w`. Cron ticks come
+/// a minute apart at best, so anything shorter is refused.
+pub fn parse_every(raw: &str) -> Result<Duration, ScheduleError> {
+    let raw = raw.trim();
+    let invalid = |reason: &str| ScheduleError::InvalidTime(format!("{}: {}", raw, reason));
+    let split = raw
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| invalid("missing unit (s, m, h, d or w)"))?;
+    let (count, unit) = raw.split_at(split);
+    let count: i64 = count.parse().map_err(|_| invalid("bad count"))?;
+    let every = match unit {
+        "s" => Duration::try_seconds(count),
+        "m" => Duration::try_minutes(count),
+        "h" => Duration::try_hours(count),
+        "d" => Duration::try_days(count),
+        "w" => Duration::try_weeks(count),
+        _ => return Err(invalid("unit must be s, m, h, d or w")),
+    }
+    .ok_or_else(|| invalid("too long"))?;
+    if every < Duration::minutes(1) {
+        return Err(invalid("must be at least a minute"));
+    }
+    Ok(every)
+}
+
+/// One recipient of a batch; without a timezone it uses the batch default
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchJob {
+    pub phone: String,
+    pub message: String,
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+/// A batch job with its fire time resolved in its own timezone
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedJob {
+    pub phone: String,
+    pub message: String,
+    pub timezone: String,
+    /// The requested wall-clock time with the zone's offset, e.g. `2024-09-01T09:00:00+03:00`
+    pub local_time: String,
+    pub fire_at_utc: DateTime<Utc>,
+}
+
+/// Resolves the same wall-clock time independently for every job, so a
+/// "9am local" batch fires at a different instant per timezone
+pub fn plan_batch(
+    local: NaiveDateTime,
+    default_tz: Tz,
+    jobs: &[BatchJob],
+) -> Vec<Result<PlannedJob, ScheduleError>> {
+    jobs.iter()
+        .map(|job| {
+            let tz = match &job.timezone {
+                Some(name) => parse_timezone(name)?,
+                None => default_tz,
+            };
+            let fire_at_utc = local_to_utc(local, tz)?;
+
+            Ok(PlannedJob {
+                phone: job.phone.clone(),
+                message: job.message.clone(),
+                timezone: tz.name().to_string(),
+                local_time: fire_at_utc.with_timezone(&tz).to_rfc3339(),
+                fire_at_utc,
+            })
+        })
+        .collect()
+}
+
+/// How fast a campaign may go out: `{"per_second": 5}` or
+/// `{"over_secs": 3600}` to spread the whole batch over an hour
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaceConfig {
+    PerSecond(f64),
+    OverSecs(u64),
+}
+
+impl PaceConfig {
+    /// Minimum gap between consecutive sends of a `count`-job batch
+    pub fn interval(&self, count: usize) -> Result<Duration, ScheduleError> {
+        match *self {
+            PaceConfig::PerSecond(rate) if rate.is_finite() && rate > 0.0 => {
+                Ok(Duration::microseconds((1_000_000.0 / rate).round() as i64))
+            }
+            PaceConfig::PerSecond(rate) => Err(ScheduleError::InvalidPace(format!(
+                "per_second must be positive, got {}",
+                rate
+            ))),
+            // The first job fires at the start and the last at the end
+            PaceConfig::OverSecs(secs) => match count {
+                0 | 1 => Ok(Duration::zero()),
+                n => Ok(Duration::microseconds(
+                    (secs as i64).saturating_mul(1_000_000) / (n as i64 - 1),
+                )),
+            },
+        }
+    }
+}
+
+/// Fire times for `count` sends starting at `start`, each the pace's
+/// interval after the one before
+pub fn paced_times(
+    start: DateTime<Utc>,
+    count: usize,
+    pace: &PaceConfig,
+) -> Result<Vec<DateTime<Utc>>, ScheduleError> {
+    let interval = pace.interval(count)?;
+    (0..count)
+        .map(|n| {
+            i32::try_from(n)
+                .ok()
+                .and_then(|n| interval.checked_mul(n))
+                .and_then(|offset| start.checked_add_signed(offset))
+                .ok_or_else(|| {
+                    ScheduleError::InvalidPace("batch would finish out of range".to_string())
+                })
+        })
+        .collect()
+}
+
+/// Delays jobs so that no two fire closer together than the pace allows.
+/// Jobs keep their order by fire time and never fire earlier than planned.
+/// Returns when the last job is projected to fire.
+pub fn apply_pace(
+    jobs: &mut [PlannedJob],
+    pace: &PaceConfig,
+) -> Result<Option<DateTime<Utc>>, ScheduleError> {
+    let interval = pace.interval(jobs.len())?;
+
+    let mut order: Vec<usize> = (0..jobs.len()).collect();
+    order.sort_by_key(|&index| jobs[index].fire_at_utc);
+
+    let mut previous: Option<DateTime<Utc>> = None;
+    for index in order {
+        let job = &mut jobs[index];
+        if let Some(previous) = previous {
+            let earliest = previous.checked_add_signed(interval).ok_or_else(|| {
+                ScheduleError::InvalidPace("batch would finish out of range".to_string())
+            })?;
+            if job.fire_at_utc < earliest {
+                job.fire_at_utc = earliest;
+                let tz = parse_timezone(&job.timezone)?;
+                job.local_time = earliest.with_timezone(&tz).to_rfc3339();
+            }
+        }
+        previous = Some(job.fire_at_utc);
+    }
+
+    Ok(previous)
+}
+
+/// A standard five-field cron expression (`minute hour day-of-month month
+/// day-of-week`), evaluated in UTC like Vercel cron unless given a timezone
+/// with `with_timezone`. Fields accept `*`,
+/// numbers, `a-b` ranges, `/n` steps and comma-separated lists; day-of-week
+/// runs 0-7 with both 0 and 7 meaning Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    // Cron matches either day field when both are restricted
+    dom_restricted: bool,
+    dow_restricted: bool,
+    timezone: Tz,
+}
+
+/// How far ahead `next_after` looks before giving up, e.g. on `0 0 30 2 *`
+const CRON_SEARCH_DAYS: i64 = 366 * 5;
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, ScheduleError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, dom, month, dow] = fields[..] else {
+            return Err(ScheduleError::InvalidCron(format!(
+                "expected 5 fields, got {}: {}",
+                fields.len(),
+                expression
+            )));
+        };
+
+        let mut days_of_week = parse_cron_field(dow, 0, 7)?;
+        // Fold 7 onto 0 so either spelling of Sunday matches
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        days_of_week.truncate(7);
+
+        Ok(CronSchedule {
+            expression: expression.trim().to_string(),
+            minutes: parse_cron_field(minute, 0, 59)?,
+            hours: parse_cron_field(hour, 0, 23)?,
+            days_of_month: parse_cron_field(dom, 1, 31)?,
+            months: parse_cron_field(month, 1, 12)?,
+            days_of_week,
+            dom_restricted: !dom.starts_with('*'),
+            dow_restricted: !dow.starts_with('*'),
+            timezone: Tz::UTC,
+        })
+    }
+
+    /// Evaluates the fields against wall-clock time in `timezone`. Local
+    /// times skipped by a DST change don't fire; repeated ones fire once.
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    fn matches_day(&self, at: NaiveDateTime) -> bool {
+        use chrono::Datelike;
+
+        let dom = self.days_of_month[at.day() as usize];
+        let dow = self.days_of_week[at.weekday().num_days_from_sunday() as usize];
+        match (self.dom_restricted, self.dow_restricted) {
+            (true, true) => dom || dow,
+            (true, false) => dom,
+            (false, true) => dow,
+            (false, false) => true,
+        }
+    }
+
+    pub fn matches(&self, at: DateTime<Utc>) -> bool {
+        use chrono::{Datelike, Timelike};
+
+        let at = at.with_timezone(&self.timezone).naive_local();
+        self.months[at.month() as usize]
+            && self.matches_day(at)
+            && self.hours[at.hour() as usize]
+            && self.minutes[at.minute() as usize]
+    }
+
+    /// First scheduled minute strictly after `after`
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        use chrono::{Datelike, DurationRound, Timelike};
+
+        // Walk local wall-clock minutes and only resolve candidates to
+        // instants, so hour and day skips follow the local calendar
+        let local = after.with_timezone(&self.timezone).naive_local();
+        let mut at = local.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        let limit = local + Duration::days(CRON_SEARCH_DAYS);
+
+        while at <= limit {
+            // Skip whole days and hours that can't match instead of walking
+            // every minute
+            if !self.months[at.month() as usize] || !self.matches_day(at) {
+                at = at.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.hours[at.hour() as usize] {
+                at = at.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+                continue;
+            }
+            if self.minutes[at.minute() as usize] {
+                let instant = match self.timezone.from_local_datetime(&at) {
+                    LocalResult::Single(instant) => Some(instant),
+                    // The first instant after `after`, so a tick during the
+                    // repeated hour doesn't see its own run again
+                    LocalResult::Ambiguous(earliest, latest) => Some(earliest)
+                        .filter(|earliest| *earliest > after)
+                        .or(Some(latest)),
+                    LocalResult::None => None,
+                };
+                if let Some(instant) = instant.filter(|instant| *instant > after) {
+                    return Some(instant.with_timezone(&Utc));
+                }
+            }
+            at += Duration::minutes(1);
+        }
+        None
+    }
+
+    /// The next `count` scheduled times after `after`
+    pub fn upcoming(&self, after: DateTime<Utc>, count: usize) -> Vec<DateTime<Utc>> {
+        let mut runs = Vec::with_capacity(count);
+        let mut cursor = after;
+        while runs.len() < count {
+            match self.next_after(cursor) {
+                Some(next) => {
+                    runs.push(next);
+                    cursor = next;
+                }
+                None => break,
+            }
+        }
+        runs
+    }
+
+    /// Whether a scheduled time fell within the `window` ending at `now`, so
+    /// an invocation that arrives a little late still fires
+    pub fn is_due(&self, now: DateTime<Utc>, window: Duration) -> bool {
+        self.next_after(now - window)
+            .is_some_and(|scheduled| scheduled <= now)
+    }
+}
+
+impl std::fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// Expands one cron field into a lookup table indexed by value
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, ScheduleError> {
+    let invalid = |reason: &str| ScheduleError::InvalidCron(format!("{}: {}", field, reason));
+    let mut allowed = vec![false; max as usize + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| invalid("bad step"))?;
+                if step == 0 {
+                    return Err(invalid("step must be positive"));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid("bad range start"))?,
+                    end.parse().map_err(|_| invalid("bad range end"))?,
+                ),
+                None => {
+                    let value: u32 = range.parse().map_err(|_| invalid("bad value"))?;
+                    // `5/15` means "from 5, every 15"
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+
+        if start < min || end > max || start > end {
+            return Err(invalid(&format!("out of range {}-{}", min, max)));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(phone: &str, timezone: Option<&str>) -> BatchJob {
+        BatchJob {
+            phone: phone.to_string(),
+            message: "Reminder".to_string(),
+            timezone: timezone.map(str::to_string),
+        }
+    }
+
+    fn utc(raw: &str) -> DateTime<Utc> {
+        raw.parse().expect("UTC instant")
+    }
+
+    #[test]
+    fn nine_am_local_fires_at_a_distinct_instant_per_zone() {
+        let local = parse_local("2024-09-02T09:00:00").unwrap();
+        let jobs = [
+            job("254700000001", Some("Africa/Nairobi")),
+            job("234800000002", Some("Africa/Lagos")),
+        ];
+        let planned: Vec<PlannedJob> = plan_batch(local, chrono_tz::UTC, &jobs)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .expect("planned");
+
+        // EAT is UTC+3 and WAT is UTC+1, neither with DST
+        assert_eq!(planned[0].fire_at_utc, utc("2024-09-02T06:00:00Z"));
+        assert_eq!(planned[0].local_time, "2024-09-02T09:00:00+03:00");
+        assert_eq!(planned[1].fire_at_utc, utc("2024-09-02T08:00:00Z"));
+        assert_eq!(planned[1].local_time, "2024-09-02T09:00:00+01:00");
+        assert_eq!(
+            planned[1].fire_at_utc - planned[0].fire_at_utc,
+            Duration::hours(2)
+        );
+    }
+
+    #[test]
+    fn pacing_spaces_jobs_at_the_configured_rate() {
+        let local = parse_local("2024-09-02T09:00:00").unwrap();
+        let jobs: Vec<BatchJob> = (1..=4)
+            .map(|n| job(&format!("25470000000{}", n), None))
+            .collect();
+        let plan = |pace: PaceConfig| {
+            let mut planned: Vec<PlannedJob> = plan_batch(local, chrono_tz::UTC, &jobs)
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .expect("planned");
+            let done = apply_pace(&mut planned, &pace).expect("paced");
+            let fire_at: Vec<_> = planned.iter().map(|job| job.fire_at_utc).collect();
+            (fire_at, done)
+        };
+        let start = utc("2024-09-02T09:00:00Z");
+
+        assert_eq!(
+            PaceConfig::PerSecond(4.0).interval(4),
+            Ok(Duration::milliseconds(250))
+        );
+        let (fire_at, done) = plan(PaceConfig::PerSecond(4.0));
+        assert_eq!(
+            fire_at,
+            [0, 250, 500, 750].map(|ms| start + Duration::milliseconds(ms))
+        );
+        assert_eq!(done, Some(start + Duration::milliseconds(750)));
+
+        // The first job fires at the start and the last at the end
+        let (fire_at, done) = plan(PaceConfig::OverSecs(60));
+        assert_eq!(
+            fire_at,
+            [0, 20, 40, 60].map(|secs| start + Duration::seconds(secs))
+        );
+        assert_eq!(done, Some(start + Duration::seconds(60)));
+
+        assert_eq!(
+            paced_times(start, 3, &PaceConfig::PerSecond(0.5)),
+            Ok([0, 2, 4]
+                .map(|secs| start + Duration::seconds(secs))
+                .to_vec())
+        );
+    }
+}
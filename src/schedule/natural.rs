@@ -0,0 +1,336 @@
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+
+use super::local_to_utc;
+
+/// What a natural-language schedule resolves to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Natural {
+    /// A recurring schedule as a five-field cron expression
+    Cron(String),
+    /// A single run, e.g. `in 2 hours`
+    Once(DateTime<Utc>),
+}
+
+/// Why a schedule couldn't be parsed, with the phrases read before the
+/// point it went wrong so a client can see how far it got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaturalError {
+    pub input: String,
+    pub understood: Vec<String>,
+    pub unparsed: String,
+    pub expected: &'static str,
+}
+
+impl std::fmt::Display for NaturalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {}", self.expected)?;
+        match self.unparsed.as_str() {
+            "" => write!(f, " at the end")?,
+            rest => write!(f, " at {:?}", rest)?,
+        }
+        if !self.understood.is_empty() {
+            write!(f, " after {:?}", self.understood.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NaturalError {}
+
+const DAYS: [&str; 7] = [
+    "sunday",
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+];
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<String>,
+    position: usize,
+    understood: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += token.is_some() as usize;
+        token
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        if self.peek() == Some(word) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Records the tokens consumed since `start` as one understood phrase
+    fn understood_since(&mut self, start: usize) {
+        if self.position > start {
+            self.understood
+                .push(self.tokens[start..self.position].join(" "));
+        }
+    }
+
+    fn fail(&self, at: usize, expected: &'static str) -> NaturalError {
+        NaturalError {
+            input: self.input.to_string(),
+            understood: self.understood.clone(),
+            unparsed: self.tokens[at.min(self.tokens.len())..].join(" "),
+            expected,
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let value = self.peek()?.parse().ok()?;
+        self.position += 1;
+        Some(value)
+    }
+
+    /// `at 9am`, `at 9:30 pm`, `at 21:00`, `at noon`
+    fn at_time(&mut self) -> Result<NaiveTime, NaturalError> {
+        let start = self.position;
+        if !self.eat("at") {
+            return Err(self.fail(start, "\"at\" and a time"));
+        }
+        let time = self
+            .time()
+            .ok_or_else(|| self.fail(start + 1, "a time such as 9am, 9:30pm or 21:00"))?;
+        self.understood_since(start);
+        Ok(time)
+    }
+
+    fn time(&mut self) -> Option<NaiveTime> {
+        let token = self.next()?;
+        match token.as_str() {
+            "noon" | "midday" => return NaiveTime::from_hms_opt(12, 0, 0),
+            "midnight" => return NaiveTime::from_hms_opt(0, 0, 0),
+            _ => {}
+        }
+        let (clock, mut suffix) = match token.strip_suffix("am").or(token.strip_suffix("pm")) {
+            Some(clock) => (clock.to_string(), Some(token[clock.len()..].to_string())),
+            None => (token.clone(), None),
+        };
+        if suffix.is_none() && matches!(self.peek(), Some("am" | "pm")) {
+            suffix = self.next();
+        }
+        let (hour, minute) = match clock.split_once(':') {
+            Some((hour, minute)) => (hour.parse().ok()?, minute.parse().ok()?),
+            None => (clock.parse().ok()?, 0),
+        };
+        let hour: u32 = match suffix.as_deref() {
+            Some(_) if !(1..=12).contains(&hour) => return None,
+            Some("am") => hour % 12,
+            Some(_) => hour % 12 + 12,
+            // A bare number is only a time with minutes, e.g. 21:00
+            None if !clock.contains(':') => return None,
+            None => hour,
+        };
+        NaiveTime::from_hms_opt(hour, minute, 0)
+    }
+
+    /// `2 hours`, `30 minutes`, `1 day`
+    fn amount(&mut self) -> Result<(u32, &'static str), NaturalError> {
+        let start = self.position;
+        let count = self
+            .number()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| self.fail(start, "a positive number"))?;
+        let unit = match self.next().as_deref() {
+            Some("minute" | "minutes" | "min" | "mins") => "minute",
+            Some("hour" | "hours") => "hour",
+            Some("day" | "days") => "day",
+            Some("week" | "weeks") => "week",
+            _ => return Err(self.fail(start + 1, "minutes, hours, days or weeks")),
+        };
+        Ok((count, unit))
+    }
+
+    fn end(&self) -> Result<(), NaturalError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.fail(self.position, "the end of the schedule")),
+        }
+    }
+}
+
+fn day_index(word: &str) -> Option<usize> {
+    let word = word.strip_suffix('s').unwrap_or(word);
+    DAYS.iter()
+        .position(|day| *day == word || (word.len() >= 3 && day.starts_with(word)))
+}
+
+/// `1st`, `2nd`, `15th` or a bare number
+fn ordinal(word: &str) -> Option<u32> {
+    let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    digits.parse().ok().filter(|day| (1..=31).contains(day))
+}
+
+/// Parses schedules such as `every weekday at 9am`, `every monday and
+/// friday at 8:30am`, `every 15 minutes`, `every month on the 1st at noon`,
+/// `tomorrow at 7pm` and `in 2 hours`. Times of day are read in `tz`.
+pub fn parse(input: &str, now: DateTime<Utc>, tz: Tz) -> Result<Natural, NaturalError> {
+    let mut parser = Parser {
+        input,
+        tokens: input
+            .to_lowercase()
+            .replace(',', " , ")
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+        position: 0,
+        understood: Vec::new(),
+    };
+
+    let natural = match parser.next().as_deref() {
+        Some("in") => {
+            let (count, unit) = parser.amount()?;
+            parser.understood_since(0);
+            let count = count as i64;
+            let after = match unit {
+                "minute" => Duration::minutes(count),
+                "hour" => Duration::hours(count),
+                "day" => Duration::days(count),
+                _ => Duration::weeks(count),
+            };
+            Natural::Once(now + after)
+        }
+        Some(day @ ("today" | "tomorrow")) => {
+            let offset = (day == "tomorrow") as i64;
+            parser.understood_since(0);
+            let time = parser.at_time()?;
+            let date = now.with_timezone(&tz).date_naive() + Duration::days(offset);
+            let at = local_to_utc(date.and_time(time), tz)
+                .map_err(|_| parser.fail(1, "a time that exists on that day"))?;
+            if at <= now {
+                return Err(parser.fail(1, "a time later than now"));
+            }
+            Natural::Once(at)
+        }
+        Some("hourly") => {
+            parser.understood_since(0);
+            Natural::Cron("0 * * * *".to_string())
+        }
+        Some("daily") => {
+            parser.understood_since(0);
+            let time = parser.at_time()?;
+            Natural::Cron(format!("{} {} * * *", time.minute(), time.hour()))
+        }
+        Some("every") => every(&mut parser)?,
+        _ => {
+            return Err(parser.fail(
+                0,
+                "\"every\", \"daily\", \"hourly\", \"today\", \"tomorrow\" or \"in\"",
+            ))
+        }
+    };
+    parser.end()?;
+    Ok(natural)
+}
+
+/// Everything after `every`
+fn every(parser: &mut Parser) -> Result<Natural, NaturalError> {
+    parser.understood_since(parser.position - 1);
+    let start = parser.position;
+    if parser
+        .peek()
+        .is_some_and(|token| token.parse::<u32>().is_ok())
+    {
+        let (count, unit) = parser.amount()?;
+        let expression = match unit {
+            "minute" if count < 60 => format!("*/{} * * * *", count),
+            "hour" if count < 24 => format!("0 */{} * * *", count),
+            _ => {
+                return Err(parser.fail(start, "fewer than 60 minutes or 24 hours"));
+            }
+        };
+        parser.understood_since(start);
+        return Ok(Natural::Cron(expression));
+    }
+
+    let days = match parser.next().as_deref() {
+        Some("minute") => {
+            parser.understood_since(start);
+            return Ok(Natural::Cron("* * * * *".to_string()));
+        }
+        Some("hour") => {
+            parser.understood_since(start);
+            return Ok(Natural::Cron("0 * * * *".to_string()));
+        }
+        Some("month") => {
+            parser.understood_since(start);
+            let on = parser.position;
+            parser.eat("on");
+            parser.eat("the");
+            let day = parser
+                .next()
+                .as_deref()
+                .and_then(ordinal)
+                .ok_or_else(|| parser.fail(on, "a day of the month such as \"on the 1st\""))?;
+            parser.understood_since(on);
+            let time = parser.at_time()?;
+            return Ok(Natural::Cron(format!(
+                "{} {} {} * *",
+                time.minute(),
+                time.hour(),
+                day
+            )));
+        }
+        Some("day") => "*".to_string(),
+        Some("weekday" | "weekdays") => "1-5".to_string(),
+        Some("weekend" | "weekends") => "0,6".to_string(),
+        Some(word) => {
+            let first =
+                day_index(word).ok_or_else(|| parser.fail(start, "a day, weekday or interval"))?;
+            let mut days = vec![first];
+            while matches!(parser.peek(), Some("and" | ",")) {
+                let separator = parser.position;
+                while parser.eat("and") || parser.eat(",") {}
+                let day = parser
+                    .next()
+                    .as_deref()
+                    .and_then(day_index)
+                    .ok_or_else(|| parser.fail(separator + 1, "a day of the week"))?;
+                days.push(day);
+            }
+            days.sort_unstable();
+            days.dedup();
+            days.iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+        None => return Err(parser.fail(start, "a day, weekday or interval")),
+    };
+    parser.understood_since(start);
+    let time = parser.at_time()?;
+    Ok(Natural::Cron(format!(
+        "{} {} * * {}",
+        time.minute(),
+        time.hour(),
+        days
+    )))
+}
+
+/// A cron expression firing once at `at`, as `at` reads in `tz`. Cron has
+/// no year, so callers should limit it to one run.
+pub fn once_as_cron(at: DateTime<Utc>, tz: Tz) -> String {
+    let local = at.with_timezone(&tz);
+    format!(
+        "{} {} {} {} *",
+        local.minute(),
+        local.hour(),
+        local.day(),
+        local.month()
+    )
+}
@@ -0,0 +1,370 @@
+use chrono::{
+    DateTime, Datelike, Duration, DurationRound, NaiveDate, NaiveDateTime, NaiveTime, Timelike,
+    Utc, Weekday,
+};
+use chrono_tz::Tz;
+
+use super::{local_to_utc, parse_local, ScheduleError};
+
+/// How far past `after` (or the rule's start, with `COUNT`) a search looks
+/// before giving up, e.g. on `FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=30`
+const SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// An iCalendar (RFC 5545) recurrence rule such as
+/// `FREQ=MONTHLY;BYDAY=2TU;BYHOUR=9`, optionally preceded by a
+/// `DTSTART:20240901T090000` line. Supports `FREQ` of `DAILY` to `YEARLY`
+/// with `INTERVAL`, `COUNT`, `UNTIL`, `BYMONTH`, `BYMONTHDAY`, `BYDAY`
+/// (with ordinals like `-1FR`), `BYHOUR`, `BYMINUTE` and `WKST=MO`. Times
+/// are wall-clock times in the zone the rule is evaluated in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRule {
+    rule: String,
+    freq: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<DateTime<Utc>>,
+    by_month: Vec<u32>,
+    by_month_day: Vec<i32>,
+    /// Weekday with its ordinal within the month or year, if any
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_hour: Vec<u32>,
+    by_minute: Vec<u32>,
+    dtstart: Option<NaiveDateTime>,
+}
+
+fn invalid(reason: impl Into<String>) -> ScheduleError {
+    ScheduleError::InvalidRrule(reason.into())
+}
+
+fn list<T: std::str::FromStr>(
+    name: &str,
+    value: &str,
+    valid: impl Fn(&T) -> bool,
+) -> Result<Vec<T>, ScheduleError> {
+    value
+        .split(',')
+        .map(|item| {
+            item.trim()
+                .parse()
+                .ok()
+                .filter(&valid)
+                .ok_or_else(|| invalid(format!("bad {} value: {}", name, item)))
+        })
+        .collect()
+}
+
+fn weekday(code: &str) -> Option<Weekday> {
+    Some(match code {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// `20240901T090000Z`, `20240901T090000` (local) or `20240901`
+fn parse_ical_time(raw: &str) -> Result<(NaiveDateTime, bool), ScheduleError> {
+    let raw = raw.trim();
+    let (raw, utc) = match raw.strip_suffix('Z') {
+        Some(raw) => (raw, true),
+        None => (raw, false),
+    };
+    let local = NaiveDateTime::parse_from_str(raw, "%Y%m%dT%H%M%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(raw, "%Y%m%d").map(|date| date.and_time(NaiveTime::MIN))
+        })
+        .or_else(|_| parse_local(raw).map_err(|_| ()))
+        .map_err(|_| invalid(format!("bad date-time: {}", raw)))?;
+    Ok((local, utc))
+}
+
+impl RRule {
+    pub fn parse(raw: &str) -> Result<Self, ScheduleError> {
+        let mut dtstart = None;
+        let mut rule = None;
+        for line in raw.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if let Some(value) = line.strip_prefix("DTSTART:") {
+                let (start, utc) = parse_ical_time(value)?;
+                if utc {
+                    return Err(invalid("DTSTART is read in the job's timezone; drop the Z"));
+                }
+                dtstart = Some(start);
+            } else {
+                rule = Some(line.strip_prefix("RRULE:").unwrap_or(line));
+            }
+        }
+        let rule = rule.ok_or_else(|| invalid("missing RRULE"))?;
+
+        let mut freq = None;
+        let mut parsed = RRule {
+            rule: rule.to_string(),
+            freq: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_month: Vec::new(),
+            by_month_day: Vec::new(),
+            by_day: Vec::new(),
+            by_hour: Vec::new(),
+            by_minute: Vec::new(),
+            dtstart,
+        };
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected NAME=VALUE, got {}", part)))?;
+            let value = value.trim();
+            match name.trim().to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => {
+                            return Err(invalid(format!(
+                                "unsupported FREQ {}; use a cron schedule for anything \
+                                 more often than daily",
+                                other
+                            )))
+                        }
+                    })
+                }
+                "INTERVAL" => {
+                    parsed.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| invalid(format!("bad INTERVAL: {}", value)))?
+                }
+                "COUNT" => {
+                    parsed.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| invalid(format!("bad COUNT: {}", value)))?,
+                    )
+                }
+                "UNTIL" => {
+                    let (until, utc) = parse_ical_time(value)?;
+                    if !utc {
+                        return Err(invalid("UNTIL must be in UTC, e.g. 20241231T235959Z"));
+                    }
+                    parsed.until = Some(until.and_utc());
+                }
+                "BYMONTH" => parsed.by_month = list("BYMONTH", value, |m| (1..=12).contains(m))?,
+                "BYMONTHDAY" => {
+                    parsed.by_month_day = list("BYMONTHDAY", value, |d: &i32| {
+                        *d != 0 && (-31..=31).contains(d)
+                    })?
+                }
+                "BYHOUR" => parsed.by_hour = list("BYHOUR", value, |h| *h < 24)?,
+                "BYMINUTE" => parsed.by_minute = list("BYMINUTE", value, |m| *m < 60)?,
+                "BYDAY" => {
+                    for item in value.split(',') {
+                        let item = item.trim().to_ascii_uppercase();
+                        let (ordinal, code) = item.split_at(item.len().saturating_sub(2));
+                        let day = weekday(code)
+                            .ok_or_else(|| invalid(format!("bad BYDAY value: {}", item)))?;
+                        let ordinal = match ordinal {
+                            "" => None,
+                            ordinal => Some(
+                                ordinal
+                                    .trim_start_matches('+')
+                                    .parse::<i32>()
+                                    .ok()
+                                    .filter(|n| *n != 0 && (-53..=53).contains(n))
+                                    .ok_or_else(|| invalid(format!("bad BYDAY value: {}", item)))?,
+                            ),
+                        };
+                        parsed.by_day.push((ordinal, day));
+                    }
+                }
+                "WKST" if value.eq_ignore_ascii_case("MO") => {}
+                other => return Err(invalid(format!("unsupported rule part: {}", other))),
+            }
+        }
+        parsed.freq = freq.ok_or_else(|| invalid("FREQ is required"))?;
+        if parsed.count.is_some() && parsed.until.is_some() {
+            return Err(invalid("COUNT and UNTIL can't be combined"));
+        }
+        if parsed.freq == Frequency::Weekly
+            && parsed.by_day.iter().any(|(ordinal, _)| ordinal.is_some())
+        {
+            return Err(invalid("BYDAY ordinals need FREQ=MONTHLY or YEARLY"));
+        }
+        parsed.by_hour.sort_unstable();
+        parsed.by_hour.dedup();
+        parsed.by_minute.sort_unstable();
+        parsed.by_minute.dedup();
+        Ok(parsed)
+    }
+
+    /// Whether `day` falls in a period the interval selects, counting from
+    /// the period holding `start`
+    fn in_interval(&self, day: NaiveDate, start: NaiveDate) -> bool {
+        let interval = self.interval as i64;
+        let periods = match self.freq {
+            Frequency::Daily => (day - start).num_days(),
+            Frequency::Weekly => {
+                let monday = |date: NaiveDate| {
+                    date - Duration::days(date.weekday().num_days_from_monday() as i64)
+                };
+                (monday(day) - monday(start)).num_days() / 7
+            }
+            Frequency::Monthly => {
+                (day.year() - start.year()) as i64 * 12 + day.month() as i64 - start.month() as i64
+            }
+            Frequency::Yearly => (day.year() - start.year()) as i64,
+        };
+        periods % interval == 0
+    }
+
+    fn matches_weekday(&self, day: NaiveDate, ordinal: Option<i32>, weekday: Weekday) -> bool {
+        if day.weekday() != weekday {
+            return false;
+        }
+        let Some(n) = ordinal else {
+            return true;
+        };
+        // Ordinals count within the month, or within the year for a yearly
+        // rule without BYMONTH
+        let (index, total) = if self.freq == Frequency::Yearly && self.by_month.is_empty() {
+            let days_in_year = if day.leap_year() { 366 } else { 365 };
+            (day.ordinal() as i32, days_in_year)
+        } else {
+            (day.day() as i32, days_in_month(day) as i32)
+        };
+        if n > 0 {
+            (index - 1) / 7 + 1 == n
+        } else {
+            (total - index) / 7 + 1 == -n
+        }
+    }
+
+    fn matches_day(&self, day: NaiveDate, start: NaiveDate) -> bool {
+        if !self.in_interval(day, start) {
+            return false;
+        }
+        if !self.by_month.is_empty() && !self.by_month.contains(&day.month()) {
+            return false;
+        }
+        if !self.by_month_day.is_empty() {
+            let last = days_in_month(day) as i32;
+            let day_of_month = day.day() as i32;
+            if !self
+                .by_month_day
+                .iter()
+                .any(|d| *d == day_of_month || last + 1 + *d == day_of_month)
+            {
+                return false;
+            }
+        }
+        if !self.by_day.is_empty() {
+            return self
+                .by_day
+                .iter()
+                .any(|(ordinal, weekday)| self.matches_weekday(day, *ordinal, *weekday));
+        }
+        // Without day parts the start's position in its period repeats
+        match self.freq {
+            Frequency::Daily => true,
+            Frequency::Weekly => day.weekday() == start.weekday(),
+            Frequency::Monthly => !self.by_month_day.is_empty() || day.day() == start.day(),
+            Frequency::Yearly => {
+                !self.by_month_day.is_empty()
+                    || (day.day() == start.day()
+                        && (!self.by_month.is_empty() || day.month() == start.month()))
+            }
+        }
+    }
+
+    /// First occurrence strictly after `after`, with wall-clock times read
+    /// in `tz`. Without a `DTSTART` the rule starts at `default_start`.
+    pub fn next_after(
+        &self,
+        after: DateTime<Utc>,
+        default_start: DateTime<Utc>,
+        tz: Tz,
+    ) -> Option<DateTime<Utc>> {
+        let start = match self.dtstart {
+            Some(start) => start,
+            None => default_start
+                .with_timezone(&tz)
+                .naive_local()
+                .duration_trunc(Duration::minutes(1))
+                .ok()?,
+        };
+        let hours = match self.by_hour.as_slice() {
+            [] => vec![start.hour()],
+            hours => hours.to_vec(),
+        };
+        let minutes = match self.by_minute.as_slice() {
+            [] => vec![start.minute()],
+            minutes => minutes.to_vec(),
+        };
+
+        let after_local = after.with_timezone(&tz).naive_local();
+        // COUNT numbers occurrences from the start, so they must all be seen
+        let mut day = match self.count {
+            Some(_) => start.date(),
+            None => start.date().max(after_local.date()),
+        };
+        let limit = day.max(after_local.date()) + Duration::days(SEARCH_DAYS);
+        let mut seen = 0;
+        while day <= limit {
+            if self.matches_day(day, start.date()) {
+                for hour in &hours {
+                    for minute in &minutes {
+                        let local = day.and_hms_opt(*hour, *minute, 0)?;
+                        if local < start {
+                            continue;
+                        }
+                        let at = local_to_utc(local, tz).ok()?;
+                        if self.until.is_some_and(|until| at > until) {
+                            return None;
+                        }
+                        seen += 1;
+                        if self.count.is_some_and(|count| seen > count) {
+                            return None;
+                        }
+                        if at > after {
+                            return Some(at);
+                        }
+                    }
+                }
+            }
+            day = day.succ_opt()?;
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for RRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.rule)
+    }
+}
+
+fn days_in_month(day: NaiveDate) -> u32 {
+    let (year, month) = match day.month() {
+        12 => (day.year() + 1, 1),
+        month => (day.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|first| first.pred_opt())
+        .map_or(31, |last| last.day())
+}
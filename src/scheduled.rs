@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{error, info};
+
+use crate::error::ApiError;
+use crate::priority::Priority;
+use crate::retry::RetryPolicyOverride;
+use crate::schedule;
+
+/// Where a one-off send is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendState {
+    Pending,
+    /// Claimed by a tick that is sending it
+    Dispatching,
+    Sent,
+    Failed,
+}
+
+/// A message to send once, at or after `send_at`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledSend {
+    pub id: String,
+    pub phone: String,
+    pub message: String,
+    pub sender_id: String,
+    pub send_at: DateTime<Utc>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Overrides the global retry policy when the send is dispatched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_policy: Option<RetryPolicyOverride>,
+    pub state: SendState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispatched_at: Option<DateTime<Utc>>,
+}
+
+impl ScheduledSend {
+    pub fn new(
+        phone: String,
+        message: String,
+        sender_id: String,
+        send_at: DateTime<Utc>,
+        priority: Priority,
+    ) -> Self {
+        ScheduledSend {
+            id: uuid::Uuid::new_v4().to_string(),
+            phone,
+            message,
+            sender_id,
+            send_at,
+            priority,
+            retry_policy: None,
+            state: SendState::Pending,
+            error: None,
+            created_at: Utc::now(),
+            dispatched_at: None,
+        }
+    }
+}
+
+/// Parses a `send_at` timestamp: either RFC 3339 with its UTC offset, e.g.
+/// `2024-09-01T08:00:00+03:00`, or a wall-clock time read in `timezone`
+pub fn parse_send_at(raw: &str, timezone: Tz) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(raw.trim()) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    let local = schedule::parse_local(raw).map_err(|e| {
+        format!(
+            "send_at must be an RFC 3339 timestamp or a local time: {}",
+            e
+        )
+    })?;
+    schedule::local_to_utc(local, timezone).map_err(|e| e.to_string())
+}
+
+/// Where one-off sends wait for their time
+pub trait ScheduledStore: Send + Sync {
+    fn put(&self, send: ScheduledSend) -> io::Result<()>;
+    /// Marks up to `limit` pending sends in `lane` due at `now` as
+    /// dispatching and returns them, earliest first, so overlapping ticks
+    /// don't send the same message twice
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Vec<ScheduledSend>>;
+}
+
+/// Sends kept for the lifetime of the instance
+#[derive(Debug, Default)]
+pub struct InMemoryScheduledStore {
+    sends: Mutex<BTreeMap<String, ScheduledSend>>,
+}
+
+impl ScheduledStore for InMemoryScheduledStore {
+    fn put(&self, send: ScheduledSend) -> io::Result<()> {
+        lock(&self.sends).insert(send.id.clone(), send);
+        Ok(())
+    }
+
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Vec<ScheduledSend>> {
+        Ok(claim(&mut lock(&self.sends), now, lane, limit))
+    }
+}
+
+/// Sends persisted to a JSON file, rewritten on every change
+#[derive(Debug)]
+pub struct FileScheduledStore {
+    path: PathBuf,
+    sends: Mutex<BTreeMap<String, ScheduledSend>>,
+}
+
+impl FileScheduledStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let sends: Vec<ScheduledSend> = match std::fs::read_to_string(&path) {
+            Ok(contents) if contents.trim().is_empty() => Vec::new(),
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(FileScheduledStore {
+            path,
+            sends: Mutex::new(
+                sends
+                    .into_iter()
+                    .map(|send| (send.id.clone(), send))
+                    .collect(),
+            ),
+        })
+    }
+
+    fn persist(&self, sends: &BTreeMap<String, ScheduledSend>) -> io::Result<()> {
+        let sends: Vec<&ScheduledSend> = sends.values().collect();
+        let contents = serde_json::to_string_pretty(&sends)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(&self.path, contents)
+    }
+}
+
+impl ScheduledStore for FileScheduledStore {
+    fn put(&self, send: ScheduledSend) -> io::Result<()> {
+        let mut sends = lock(&self.sends);
+        sends.insert(send.id.clone(), send);
+        self.persist(&sends)
+    }
+
+    fn claim_due(
+        &self,
+        now: DateTime<Utc>,
+        lane: Priority,
+        limit: usize,
+    ) -> io::Result<Vec<ScheduledSend>> {
+        let mut sends = lock(&self.sends);
+        let claimed = claim(&mut sends, now, lane, limit);
+        if !claimed.is_empty() {
+            self.persist(&sends)?;
+        }
+        Ok(claimed)
+    }
+}
+
+fn claim(
+    sends: &mut BTreeMap<String, ScheduledSend>,
+    now: DateTime<Utc>,
+    lane: Priority,
+    limit: usize,
+) -> Vec<ScheduledSend> {
+    let mut due: Vec<&mut ScheduledSend> = sends
+        .values_mut()
+        .filter(|send| {
+            send.state == SendState::Pending && send.send_at <= now && send.priority == lane
+        })
+        .collect();
+    // Those past the limit wait for the next tick, oldest going first then
+    due.sort_by_key(|send| send.send_at);
+    due.into_iter()
+        .take(limit)
+        .map(|send| {
+            send.state = SendState::Dispatching;
+            send.clone()
+        })
+        .collect()
+}
+
+fn lock(
+    sends: &Mutex<BTreeMap<String, ScheduledSend>>,
+) -> std::sync::MutexGuard<'_, BTreeMap<String, ScheduledSend>> {
+    sends.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// As with jobs, an unreadable file is kept as an error rather than replaced
+// by an empty store that would overwrite it on the next change
+static STORE: Lazy<Result<Box<dyn ScheduledStore>, String>> =
+    Lazy::new(|| match std::env::var("SCHEDULED_SENDS_FILE") {
+        Ok(path) if !path.is_empty() => match FileScheduledStore::open(&path) {
+            Ok(store) => {
+                info!("Using file scheduled-send store at: {}", path);
+                Ok(Box::new(store) as Box<dyn ScheduledStore>)
+            }
+            Err(e) => {
+                error!("Failed to open scheduled sends file {}: {}", path, e);
+                Err(format!("failed to open {}: {}", path, e))
+            }
+        },
+        _ => Ok(Box::new(InMemoryScheduledStore::default())),
+    });
+
+/// The instance-wide store of one-off sends: file-backed when
+/// `SCHEDULED_SENDS_FILE` is set, in-memory otherwise
+pub fn store() -> Result<&'static dyn ScheduledStore, ApiError> {
+    match STORE.as_ref() {
+        Ok(store) => Ok(store.as_ref()),
+        Err(reason) => Err(ApiError::JobStoreUnavailable {
+            reason: reason.clone(),
+        }),
+    }
+}
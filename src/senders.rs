@@ -0,0 +1,141 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Longest sender ID carriers accept for alphanumeric senders
+pub const MAX_SENDER_ID_LEN: usize = 11;
+
+/// Longest numeric sender, the E.164 maximum
+pub const MAX_NUMERIC_SENDER_LEN: usize = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WeightedSender {
+    pub sender_id: String,
+    pub weight: u32,
+}
+
+/// Sender IDs used in turn when a request doesn't pick one, configured as
+/// `SENDER_POOL=UjumbeSMS:3,AltSender:1`.
+#[derive(Debug, Clone, Default)]
+pub struct SenderPool {
+    pub senders: Vec<WeightedSender>,
+    // Running weight per sender for smooth weighted round-robin, shared by
+    // clones so every request on this instance takes the same turns
+    current: Arc<Mutex<Vec<i64>>>,
+}
+
+/// Checks that a sender ID is something the provider will accept
+pub fn validate_sender_id(sender_id: &str) -> Result<(), String> {
+    if sender_id.is_empty() {
+        return Err("sender ID is empty".to_string());
+    }
+    // Numeric senders (phone numbers, optionally with a `+`) may be longer
+    if let Some(digits) = sender_id
+        .strip_prefix('+')
+        .or(Some(sender_id))
+        .filter(|digits| digits.chars().all(|c| c.is_ascii_digit()))
+    {
+        if digits.is_empty() || digits.len() > MAX_NUMERIC_SENDER_LEN {
+            return Err(format!(
+                "numeric sender ID {} must have 1 to {} digits",
+                sender_id, MAX_NUMERIC_SENDER_LEN
+            ));
+        }
+        return Ok(());
+    }
+    if sender_id.chars().count() > MAX_SENDER_ID_LEN {
+        return Err(format!(
+            "sender ID {} is longer than {} characters",
+            sender_id, MAX_SENDER_ID_LEN
+        ));
+    }
+    if !sender_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("sender ID {} must be alphanumeric", sender_id));
+    }
+    Ok(())
+}
+
+impl SenderPool {
+    /// Parses `id:weight` pairs; a missing weight counts as 1. Invalid entries
+    /// are logged and skipped.
+    pub fn parse(raw: &str) -> Self {
+        let senders: Vec<WeightedSender> = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let (sender_id, weight) = match entry.split_once(':') {
+                    Some((id, weight)) => (id.trim(), weight.trim().parse::<u32>()),
+                    None => (entry, Ok(1)),
+                };
+
+                if let Err(e) = validate_sender_id(sender_id) {
+                    warn!("Skipping sender pool entry {:?}: {}", entry, e);
+                    return None;
+                }
+                match weight {
+                    Ok(weight) if weight > 0 => Some(WeightedSender {
+                        sender_id: sender_id.to_string(),
+                        weight,
+                    }),
+                    _ => {
+                        warn!("Skipping sender pool entry {:?}: invalid weight", entry);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let current = Arc::new(Mutex::new(vec![0; senders.len()]));
+        SenderPool { senders, current }
+    }
+
+    pub fn from_env() -> Self {
+        Self::parse(&std::env::var("SENDER_POOL").unwrap_or_default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Picks the next sender by smooth weighted round-robin: weights 3 and 1
+    /// give A A B A rather than A A A B. Each pick is one pass over the
+    /// senders, however large the weights.
+    pub fn next(&self) -> Option<&str> {
+        let total: i64 = self.senders.iter().map(|s| i64::from(s.weight)).sum();
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+
+        for (weight, sender) in current.iter_mut().zip(&self.senders) {
+            *weight += i64::from(sender.weight);
+        }
+        let (chosen, _) = current
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, weight)| (**weight, std::cmp::Reverse(*index)))?;
+        current[chosen] -= total;
+
+        Some(self.senders[chosen].sender_id.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_interleave_smoothly() {
+        let pool = SenderPool::parse("Alpha:3,Beta:1");
+        let picks: Vec<_> = (0..8).map(|_| pool.next().unwrap()).collect();
+        assert_eq!(
+            picks,
+            ["Alpha", "Alpha", "Beta", "Alpha", "Alpha", "Alpha", "Beta", "Alpha"]
+        );
+    }
+
+    #[test]
+    fn huge_weights_are_picked_without_a_schedule() {
+        let pool = SenderPool::parse(&format!("Alpha:{},Beta:1", u32::MAX));
+        assert_eq!(pool.next(), Some("Alpha"));
+        assert!(SenderPool::parse("").next().is_none());
+    }
+}
@@ -0,0 +1,1094 @@
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
+
+use super::{MessageQuery, MessageRecord, Storage, StorageFuture};
+use crate::apikeys::ApiKey;
+use crate::autoresponder::Rule;
+use crate::contacts::{Contact, ContactGroup};
+use crate::dlq::DeadLetter;
+use crate::inbound::InboundMessage;
+use crate::jobs::{self, CatchUpPolicy, Claimed, Job};
+use crate::priority::Priority;
+use crate::recipients::StoredRule;
+
+/// Schema changes in order; each runs once, in a transaction, the first
+/// time an instance touches storage. Only ever append to this list.
+const MIGRATIONS: &[&[&str]] = &[
+    &[
+        "CREATE TABLE scheduler_jobs (
+        id TEXT PRIMARY KEY,
+        priority TEXT NOT NULL,
+        next_run_at INTEGER,
+        job TEXT NOT NULL
+    )",
+        "CREATE INDEX scheduler_jobs_due ON scheduler_jobs (priority, next_run_at)",
+        "CREATE TABLE scheduler_messages (
+        id TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        record TEXT NOT NULL
+    )",
+        "CREATE INDEX scheduler_messages_created ON scheduler_messages (created_at)",
+        "CREATE TABLE scheduler_idempotency (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    )",
+        "CREATE TABLE scheduler_dead_letters (
+        id TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        entry TEXT NOT NULL
+    )",
+    ],
+    &[
+        "CREATE TABLE scheduler_contact_groups (
+            name TEXT PRIMARY KEY,
+            created_at INTEGER NOT NULL
+        )",
+        "CREATE TABLE scheduler_contacts (
+            group_name TEXT NOT NULL,
+            phone TEXT NOT NULL,
+            contact TEXT NOT NULL,
+            PRIMARY KEY (group_name, phone)
+        )",
+    ],
+    &[
+        "CREATE TABLE scheduler_inbound (
+            id TEXT PRIMARY KEY,
+            received_at INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        "CREATE INDEX scheduler_inbound_received ON scheduler_inbound (received_at)",
+    ],
+    // Delivery reports name messages by provider id, which SQLite can't
+    // index inside the JSON record
+    &[
+        "CREATE TABLE scheduler_message_ids (
+            message_id TEXT PRIMARY KEY,
+            record_id TEXT NOT NULL
+        )",
+        "INSERT OR IGNORE INTO scheduler_message_ids (message_id, record_id)
+            SELECT ids.value, messages.id
+            FROM scheduler_messages messages, json_each(messages.record, '$.message_ids') ids",
+    ],
+    &["CREATE TABLE scheduler_autoreplies (
+            keyword TEXT PRIMARY KEY,
+            rule TEXT NOT NULL
+        )"],
+    &["CREATE TABLE scheduler_api_keys (
+            name TEXT PRIMARY KEY,
+            api_key TEXT NOT NULL
+        )"],
+    &["CREATE TABLE scheduler_number_rules (
+            id TEXT PRIMARY KEY,
+            rule TEXT NOT NULL
+        )"],
+    &["CREATE UNIQUE INDEX scheduler_api_keys_hash
+            ON scheduler_api_keys (json_extract(api_key, '$.hash'))"],
+];
+
+/// Storage in a libSQL database such as Turso, from `LIBSQL_URL` and
+/// `LIBSQL_AUTH_TOKEN`, spoken to over the Hrana HTTP protocol so no
+/// connection outlives a request
+#[derive(Debug)]
+pub struct LibsqlStorage {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    migrated: OnceCell<()>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PipelineResponse {
+    baton: Option<String>,
+    base_url: Option<String>,
+    results: Vec<StreamResult>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamResult {
+    Ok { response: StreamResponse },
+    Error { error: HranaError },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamResponse {
+    Execute {
+        result: StmtResult,
+    },
+    Batch {
+        result: BatchResult,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct StmtResult {
+    #[serde(default)]
+    rows: Vec<Vec<Value>>,
+    #[serde(default)]
+    affected_row_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResult {
+    step_results: Vec<Option<StmtResult>>,
+    step_errors: Vec<Option<HranaError>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HranaError {
+    message: String,
+}
+
+fn text(value: &str) -> Value {
+    json!({ "type": "text", "value": value })
+}
+
+fn integer(value: i64) -> Value {
+    json!({ "type": "integer", "value": value.to_string() })
+}
+
+fn null() -> Value {
+    json!({ "type": "null" })
+}
+
+fn millis(at: Option<DateTime<Utc>>) -> Value {
+    at.map_or_else(null, |at| integer(at.timestamp_millis()))
+}
+
+fn stmt(sql: &str, args: Vec<Value>) -> Value {
+    json!({ "sql": sql, "args": args })
+}
+
+fn execute(stmt: Value) -> Value {
+    json!({ "type": "execute", "stmt": stmt })
+}
+
+/// Runs `stmts` in order, each only if the one before succeeded, then
+/// commits, or rolls back if any failed. With `begin` the batch opens its
+/// own transaction; without it, one the stream already began.
+fn batch(stmts: Vec<Value>, begin: bool) -> Value {
+    let mut steps = Vec::new();
+    let mut push = |stmt: Value, condition: Option<Value>| {
+        let mut step = json!({ "stmt": stmt });
+        if let Some(condition) = condition {
+            step["condition"] = condition;
+        }
+        steps.push(step);
+        steps.len() - 1
+    };
+    let mut last = begin.then(|| push(stmt("BEGIN", vec![]), None));
+    for stmt in stmts {
+        let condition = last.map(|step| json!({ "type": "ok", "step": step }));
+        last = Some(push(stmt, condition));
+    }
+    let condition = last.map(|step| json!({ "type": "ok", "step": step }));
+    let commit = push(stmt("COMMIT", vec![]), condition);
+    push(
+        stmt("ROLLBACK", vec![]),
+        Some(json!({ "type": "not", "cond": { "type": "ok", "step": commit } })),
+    );
+    json!({ "type": "batch", "batch": { "steps": steps } })
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn cell_str(row: &[Value], index: usize) -> io::Result<&str> {
+    row.get(index)
+        .and_then(|cell| cell["value"].as_str())
+        .ok_or_else(|| invalid("unexpected libSQL column value"))
+}
+
+fn cell_json<T: DeserializeOwned>(row: &[Value], index: usize) -> io::Result<T> {
+    serde_json::from_str(cell_str(row, index)?).map_err(|e| invalid(e.to_string()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(raw: &str) -> io::Result<Vec<u8>> {
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| {
+            raw.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid("bad hex value"))
+        })
+        .collect()
+}
+
+/// One Hrana stream. The server hands back a baton after each request that
+/// the next must carry, which is what lets a transaction span requests.
+struct Stream<'a> {
+    storage: &'a LibsqlStorage,
+    url: String,
+    baton: Option<String>,
+}
+
+impl Stream<'_> {
+    async fn send(&mut self, requests: Vec<Value>) -> io::Result<Vec<StreamResponse>> {
+        let mut request = self
+            .storage
+            .http
+            .post(format!("{}/v2/pipeline", self.url.trim_end_matches('/')))
+            .json(&json!({ "baton": self.baton, "requests": requests }));
+        if let Some(token) = &self.storage.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(io::Error::other)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "libSQL returned {}: {}",
+                status, body
+            )));
+        }
+        let response: PipelineResponse = response.json().await.map_err(io::Error::other)?;
+        self.baton = response.baton;
+        if let Some(base_url) = response.base_url {
+            self.url = base_url;
+        }
+        response
+            .results
+            .into_iter()
+            .map(|result| match result {
+                StreamResult::Ok { response } => Ok(response),
+                StreamResult::Error { error } => Err(io::Error::other(error.message)),
+            })
+            .collect()
+    }
+}
+
+/// The results of a transactional batch's own statements, or the first
+/// error that rolled it back
+fn batch_results(
+    response: Option<StreamResponse>,
+    skip: usize,
+    count: usize,
+) -> io::Result<Vec<StmtResult>> {
+    let Some(StreamResponse::Batch { result }) = response else {
+        return Err(invalid("expected a libSQL batch result"));
+    };
+    if let Some(error) = result.step_errors.into_iter().flatten().next() {
+        return Err(io::Error::other(error.message));
+    }
+    Ok(result
+        .step_results
+        .into_iter()
+        .skip(skip)
+        .take(count)
+        .map(|result| {
+            result.unwrap_or(StmtResult {
+                rows: Vec::new(),
+                affected_row_count: 0,
+            })
+        })
+        .collect())
+}
+
+impl LibsqlStorage {
+    /// Accepts `libsql://`, which Turso hands out, or `https://` and
+    /// `http://`, e.g. a local `sqld`
+    pub fn connect(url: &str, token: Option<String>) -> Result<Self, String> {
+        let url = url.trim();
+        let url = match url.split_once("://") {
+            Some(("libsql", rest)) => format!("https://{}", rest),
+            Some(("https" | "http", _)) => url.to_string(),
+            _ => return Err("libSQL URL must use libsql://, https:// or http://".to_string()),
+        };
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(LibsqlStorage {
+            http,
+            url,
+            token,
+            migrated: OnceCell::new(),
+        })
+    }
+
+    /// The database URL; credentials travel in the token, not in it
+    pub fn address(&self) -> &str {
+        &self.url
+    }
+
+    fn raw_stream(&self) -> Stream<'_> {
+        Stream {
+            storage: self,
+            url: self.url.clone(),
+            baton: None,
+        }
+    }
+
+    async fn stream(&self) -> io::Result<Stream<'_>> {
+        self.migrated.get_or_try_init(|| self.migrate()).await?;
+        Ok(self.raw_stream())
+    }
+
+    async fn migrate(&self) -> io::Result<()> {
+        let mut stream = self.raw_stream();
+        let mut responses = stream
+            .send(vec![
+                execute(stmt(
+                    "CREATE TABLE IF NOT EXISTS scheduler_migrations \
+                     (version INTEGER PRIMARY KEY, applied_at INTEGER NOT NULL)",
+                    vec![],
+                )),
+                execute(stmt(
+                    "SELECT COALESCE(MAX(version), 0) FROM scheduler_migrations",
+                    vec![],
+                )),
+                json!({ "type": "close" }),
+            ])
+            .await?;
+        responses.truncate(2);
+        let applied: usize = match responses.pop() {
+            Some(StreamResponse::Execute { result }) => result
+                .rows
+                .first()
+                .and_then(|row| cell_str(row, 0).ok())
+                .and_then(|version| version.parse().ok())
+                .unwrap_or_default(),
+            _ => return Err(invalid("expected the applied migration version")),
+        };
+
+        for (index, statements) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let version = index as i64 + 1;
+            let mut stmts: Vec<Value> = statements.iter().map(|sql| stmt(sql, vec![])).collect();
+            stmts.push(stmt(
+                "INSERT INTO scheduler_migrations (version, applied_at) VALUES (?, ?)",
+                vec![integer(version), integer(Utc::now().timestamp_millis())],
+            ));
+            let count = stmts.len();
+            let mut stream = self.raw_stream();
+            let mut responses = stream
+                .send(vec![batch(stmts, true), json!({ "type": "close" })])
+                .await?;
+            responses.truncate(1);
+            batch_results(responses.pop(), 1, count)
+                .map_err(|e| io::Error::other(format!("migration {} failed: {}", version, e)))?;
+            info!("Applied libSQL migration {}", version);
+        }
+        Ok(())
+    }
+
+    /// Runs each statement on its own and returns their results
+    async fn execute(&self, stmts: Vec<Value>) -> io::Result<Vec<StmtResult>> {
+        let count = stmts.len();
+        let mut requests: Vec<Value> = stmts.into_iter().map(execute).collect();
+        requests.push(json!({ "type": "close" }));
+        let responses = self.stream().await?.send(requests).await?;
+        responses
+            .into_iter()
+            .take(count)
+            .map(|response| match response {
+                StreamResponse::Execute { result } => Ok(result),
+                _ => Err(invalid("expected a libSQL execute result")),
+            })
+            .collect()
+    }
+
+    /// Runs the statements in one transaction and returns their results
+    async fn transaction(&self, stmts: Vec<Value>) -> io::Result<Vec<StmtResult>> {
+        let count = stmts.len();
+        let mut responses = self
+            .stream()
+            .await?
+            .send(vec![batch(stmts, true), json!({ "type": "close" })])
+            .await?;
+        responses.truncate(1);
+        batch_results(responses.pop(), 1, count)
+    }
+
+    async fn query_one(&self, sql: &str, args: Vec<Value>) -> io::Result<StmtResult> {
+        self.execute(vec![stmt(sql, args)])
+            .await?
+            .pop()
+            .ok_or_else(|| invalid("missing libSQL result"))
+    }
+}
+
+/// Claims runs from the due `rows` and returns them with the updates that
+/// record the claim
+fn claim_rows(
+    rows: &[Vec<Value>],
+    now: DateTime<Utc>,
+    window: chrono::Duration,
+    catch_up: CatchUpPolicy,
+    limit: usize,
+) -> io::Result<(Claimed, Vec<Value>)> {
+    let mut due = rows
+        .iter()
+        .map(|row| cell_json::<Job>(row, 0))
+        .collect::<io::Result<Vec<Job>>>()?;
+    let before: Vec<Option<DateTime<Utc>>> = due.iter().map(|job| job.next_run_at).collect();
+    let claimed = jobs::claim_in_order(due.iter_mut(), now, window, catch_up, limit);
+    let updates = due
+        .iter()
+        .zip(before)
+        .filter(|(job, before)| job.next_run_at != *before)
+        .map(|(job, _)| {
+            Ok(stmt(
+                "UPDATE scheduler_jobs SET next_run_at = ?, job = ? WHERE id = ?",
+                vec![millis(job.next_run_at), job_json(job)?, text(&job.id)],
+            ))
+        })
+        .collect::<io::Result<Vec<Value>>>()?;
+    Ok((claimed, updates))
+}
+
+const GROUP_EXISTS: &str = "SELECT 1 FROM scheduler_contact_groups WHERE name = ?";
+
+// Written only while the group exists, so a member can't outlive a
+// concurrent delete of its group
+const UPSERT_CONTACT: &str = "INSERT INTO scheduler_contacts (group_name, phone, contact) \
+    SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM scheduler_contact_groups WHERE name = ?1) \
+    ON CONFLICT (group_name, phone) DO UPDATE SET contact = excluded.contact";
+
+const UPSERT_JOB: &str = "INSERT INTO scheduler_jobs (id, priority, next_run_at, job) \
+    VALUES (?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET priority = excluded.priority, \
+    next_run_at = excluded.next_run_at, job = excluded.job";
+
+fn job_json(job: &Job) -> io::Result<Value> {
+    Ok(text(&serde_json::to_string(job)?))
+}
+
+impl Storage for LibsqlStorage {
+    fn list_jobs(&self) -> StorageFuture<'_, Vec<Job>> {
+        Box::pin(async move {
+            self.query_one("SELECT job FROM scheduler_jobs ORDER BY id", vec![])
+                .await?
+                .rows
+                .iter()
+                .map(|row| cell_json(row, 0))
+                .collect()
+        })
+    }
+
+    fn get_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<Job>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT job FROM scheduler_jobs WHERE id = ?",
+                vec![text(id)],
+            )
+            .await?
+            .rows
+            .first()
+            .map(|row| cell_json(row, 0))
+            .transpose()
+        })
+    }
+
+    fn put_job(&self, job: Job) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let args = vec![
+                text(&job.id),
+                text(job.priority.as_str()),
+                millis(job.next_run_at),
+                job_json(&job)?,
+            ];
+            self.query_one(UPSERT_JOB, args).await.map(drop)
+        })
+    }
+
+    fn delete_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one("DELETE FROM scheduler_jobs WHERE id = ?", vec![text(id)])
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn claim_due_jobs(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> StorageFuture<'_, Claimed> {
+        Box::pin(async move {
+            if limit == 0 {
+                return Ok(Claimed::default());
+            }
+            // An immediate transaction takes the write lock up front, so
+            // overlapping ticks claim one after the other, never the same run
+            let mut stream = self.stream().await?;
+            let mut responses = stream
+                .send(vec![
+                    execute(stmt("BEGIN IMMEDIATE", vec![])),
+                    execute(stmt(
+                        "SELECT job FROM scheduler_jobs WHERE priority = ? AND next_run_at <= ? \
+                         ORDER BY next_run_at LIMIT ?",
+                        vec![
+                            text(lane.as_str()),
+                            integer(now.timestamp_millis()),
+                            integer(limit as i64),
+                        ],
+                    )),
+                ])
+                .await?;
+            let rows = match responses.pop() {
+                Some(StreamResponse::Execute { result }) => result.rows,
+                _ => return Err(invalid("expected due jobs")),
+            };
+
+            let (claimed, updates) = match claim_rows(&rows, now, window, catch_up, limit) {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    let rollback = vec![
+                        execute(stmt("ROLLBACK", vec![])),
+                        json!({ "type": "close" }),
+                    ];
+                    if let Err(rollback) = stream.send(rollback).await {
+                        warn!("Failed to roll back job claim: {}", rollback);
+                    }
+                    return Err(e);
+                }
+            };
+
+            let count = updates.len();
+            let mut responses = stream
+                .send(vec![batch(updates, false), json!({ "type": "close" })])
+                .await?;
+            responses.truncate(1);
+            batch_results(responses.pop(), 0, count)?;
+            debug!("Claimed {} runs in the {} lane", claimed.runs.len(), lane);
+            Ok(claimed)
+        })
+    }
+
+    fn record_message(&self, record: MessageRecord) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let args = vec![
+                text(&record.id),
+                integer(record.created_at.timestamp_millis()),
+                text(&serde_json::to_string(&record)?),
+            ];
+            let mut stmts = vec![stmt(
+                "INSERT INTO scheduler_messages (id, created_at, record) VALUES (?, ?, ?)",
+                args,
+            )];
+            for message_id in &record.message_ids {
+                stmts.push(stmt(
+                    "INSERT OR IGNORE INTO scheduler_message_ids (message_id, record_id) \
+                     VALUES (?, ?)",
+                    vec![text(message_id), text(&record.id)],
+                ));
+            }
+            self.transaction(stmts).await.map(drop)
+        })
+    }
+
+    fn find_messages(&self, query: MessageQuery) -> StorageFuture<'_, Vec<MessageRecord>> {
+        Box::pin(async move {
+            let args = vec![
+                query
+                    .status
+                    .map_or_else(null, |status| text(status.as_str())),
+                millis(query.from),
+                millis(query.to),
+                integer(query.limit as i64),
+                integer(query.offset as i64),
+            ];
+            self.query_one(
+                "SELECT record FROM scheduler_messages \
+                 WHERE (?1 IS NULL OR json_extract(record, '$.status') = ?1) \
+                 AND (?2 IS NULL OR created_at >= ?2) AND (?3 IS NULL OR created_at < ?3) \
+                 ORDER BY created_at DESC, id LIMIT ?4 OFFSET ?5",
+                args,
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn find_message_by_provider_id<'a>(
+        &'a self,
+        message_id: &'a str,
+    ) -> StorageFuture<'a, Option<MessageRecord>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT messages.record FROM scheduler_message_ids ids \
+                 JOIN scheduler_messages messages ON messages.id = ids.record_id \
+                 WHERE ids.message_id = ?",
+                vec![text(message_id)],
+            )
+            .await?
+            .rows
+            .first()
+            .map(|row| cell_json(row, 0))
+            .transpose()
+        })
+    }
+
+    // The record is compared as the text it was stored as, which
+    // serializing what was read from it reproduces
+    fn update_message<'a>(
+        &'a self,
+        previous: &'a MessageRecord,
+        record: MessageRecord,
+    ) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let args = vec![
+                text(&record.id),
+                text(&serde_json::to_string(&record)?),
+                text(&serde_json::to_string(previous)?),
+            ];
+            let result = self
+                .query_one(
+                    "UPDATE scheduler_messages SET record = ?2 WHERE id = ?1 AND record = ?3",
+                    args,
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn reserve_key<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> StorageFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            for _ in 0..2 {
+                let now = Utc::now().timestamp_millis();
+                let expires_at = now + ttl.as_millis() as i64;
+                // Takes the key when it's free or its value has expired
+                let results = self
+                    .execute(vec![
+                        stmt(
+                            "INSERT INTO scheduler_idempotency (key, value, expires_at) \
+                             VALUES (?, ?, ?) ON CONFLICT (key) DO UPDATE \
+                             SET value = excluded.value, expires_at = excluded.expires_at \
+                             WHERE scheduler_idempotency.expires_at <= ?",
+                            vec![
+                                text(key),
+                                text(&hex(&value)),
+                                integer(expires_at),
+                                integer(now),
+                            ],
+                        ),
+                        stmt(
+                            "SELECT value FROM scheduler_idempotency WHERE key = ?",
+                            vec![text(key)],
+                        ),
+                    ])
+                    .await?;
+                let [reserved, existing] = <[StmtResult; 2]>::try_from(results)
+                    .map_err(|_| invalid("expected two libSQL results"))?;
+                if reserved.affected_row_count > 0 {
+                    return Ok(None);
+                }
+                if let Some(row) = existing.rows.first() {
+                    return unhex(cell_str(row, 0)?).map(Some);
+                }
+                // Deleted between the two statements; try to reserve it again
+            }
+            Err(io::Error::other(
+                "idempotency key was released while being reserved",
+            ))
+        })
+    }
+
+    fn set_key<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let expires_at = Utc::now().timestamp_millis() + ttl.as_millis() as i64;
+            self.query_one(
+                "INSERT INTO scheduler_idempotency (key, value, expires_at) VALUES (?, ?, ?) \
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value, \
+                 expires_at = excluded.expires_at",
+                vec![text(key), text(&hex(&value)), integer(expires_at)],
+            )
+            .await
+            .map(drop)
+        })
+    }
+
+    fn delete_key<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.query_one(
+                "DELETE FROM scheduler_idempotency WHERE key = ?",
+                vec![text(key)],
+            )
+            .await
+            .map(drop)
+        })
+    }
+
+    fn push_dead_letter(&self, entry: DeadLetter) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let args = vec![
+                text(&entry.id),
+                integer(entry.created_at.timestamp_millis()),
+                text(&serde_json::to_string(&entry)?),
+            ];
+            self.query_one(
+                "INSERT INTO scheduler_dead_letters (id, created_at, entry) VALUES (?, ?, ?)",
+                args,
+            )
+            .await
+            .map(drop)
+        })
+    }
+
+    fn list_dead_letters(&self) -> StorageFuture<'_, Vec<DeadLetter>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT entry FROM scheduler_dead_letters ORDER BY created_at, id",
+                vec![],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn take_dead_letter<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<DeadLetter>> {
+        Box::pin(async move {
+            // Only one of two concurrent replays gets the row back
+            self.query_one(
+                "DELETE FROM scheduler_dead_letters WHERE id = ? RETURNING entry",
+                vec![text(id)],
+            )
+            .await?
+            .rows
+            .first()
+            .map(|row| cell_json(row, 0))
+            .transpose()
+        })
+    }
+
+    fn list_groups(&self) -> StorageFuture<'_, Vec<ContactGroup>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT g.name, g.created_at, COUNT(c.phone) FROM scheduler_contact_groups g \
+                 LEFT JOIN scheduler_contacts c ON c.group_name = g.name \
+                 GROUP BY g.name, g.created_at ORDER BY g.name",
+                vec![],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| {
+                let number = |index| {
+                    cell_str(row, index)?
+                        .parse::<i64>()
+                        .map_err(|e| invalid(e.to_string()))
+                };
+                Ok(ContactGroup {
+                    name: cell_str(row, 0)?.to_string(),
+                    members: number(2)? as usize,
+                    created_at: DateTime::from_timestamp_millis(number(1)?)
+                        .ok_or_else(|| invalid("bad group creation time"))?,
+                })
+            })
+            .collect()
+        })
+    }
+
+    fn create_group(&self, group: ContactGroup) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one(
+                    "INSERT INTO scheduler_contact_groups (name, created_at) VALUES (?, ?) \
+                     ON CONFLICT (name) DO NOTHING",
+                    vec![
+                        text(&group.name),
+                        integer(group.created_at.timestamp_millis()),
+                    ],
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn delete_group<'a>(&'a self, name: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let results = self
+                .transaction(vec![
+                    stmt(
+                        "DELETE FROM scheduler_contacts WHERE group_name = ?",
+                        vec![text(name)],
+                    ),
+                    stmt(
+                        "DELETE FROM scheduler_contact_groups WHERE name = ?",
+                        vec![text(name)],
+                    ),
+                ])
+                .await?;
+            Ok(results
+                .last()
+                .is_some_and(|result| result.affected_row_count > 0))
+        })
+    }
+
+    fn group_members<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<Vec<Contact>>> {
+        Box::pin(async move {
+            let results = self
+                .transaction(vec![
+                    stmt(GROUP_EXISTS, vec![text(name)]),
+                    stmt(
+                        "SELECT contact FROM scheduler_contacts WHERE group_name = ? ORDER BY phone",
+                        vec![text(name)],
+                    ),
+                ])
+                .await?;
+            let [exists, members] = results.as_slice() else {
+                return Err(invalid("expected the group and its members"));
+            };
+            if exists.rows.is_empty() {
+                return Ok(None);
+            }
+            members
+                .rows
+                .iter()
+                .map(|row| cell_json(row, 0))
+                .collect::<io::Result<Vec<Contact>>>()
+                .map(Some)
+        })
+    }
+
+    fn add_members<'a>(&'a self, name: &'a str, members: Vec<Contact>) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let mut stmts = vec![stmt(GROUP_EXISTS, vec![text(name)])];
+            for contact in &members {
+                stmts.push(stmt(
+                    UPSERT_CONTACT,
+                    vec![
+                        text(name),
+                        text(&contact.phone),
+                        text(&serde_json::to_string(contact)?),
+                    ],
+                ));
+            }
+            let results = self.transaction(stmts).await?;
+            Ok(results
+                .first()
+                .is_some_and(|exists| !exists.rows.is_empty()))
+        })
+    }
+
+    fn remove_member<'a>(
+        &'a self,
+        name: &'a str,
+        phone: &'a str,
+    ) -> StorageFuture<'a, Option<bool>> {
+        Box::pin(async move {
+            let results = self
+                .transaction(vec![
+                    stmt(GROUP_EXISTS, vec![text(name)]),
+                    stmt(
+                        "DELETE FROM scheduler_contacts WHERE group_name = ? AND phone = ?",
+                        vec![text(name), text(phone)],
+                    ),
+                ])
+                .await?;
+            let [exists, removed] = results.as_slice() else {
+                return Err(invalid("expected the group and the removal"));
+            };
+            Ok((!exists.rows.is_empty()).then_some(removed.affected_row_count > 0))
+        })
+    }
+
+    // Providers retry callbacks they think failed, so a repeat of an id
+    // already stored is dropped
+    fn record_inbound(&self, message: InboundMessage) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let args = vec![
+                text(&message.id),
+                integer(message.received_at.timestamp_millis()),
+                text(&serde_json::to_string(&message)?),
+            ];
+            self.query_one(
+                "INSERT INTO scheduler_inbound (id, received_at, message) VALUES (?, ?, ?) \
+                 ON CONFLICT (id) DO NOTHING",
+                args,
+            )
+            .await
+            .map(drop)
+        })
+    }
+
+    fn recent_inbound(&self, limit: usize) -> StorageFuture<'_, Vec<InboundMessage>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT message FROM scheduler_inbound ORDER BY received_at DESC, id LIMIT ?",
+                vec![integer(limit as i64)],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn list_autoreplies(&self) -> StorageFuture<'_, Vec<Rule>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT rule FROM scheduler_autoreplies ORDER BY keyword",
+                vec![],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn put_autoreply(&self, rule: Rule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let keyword = text(&rule.keyword);
+            let results = self
+                .transaction(vec![
+                    stmt(
+                        "SELECT keyword FROM scheduler_autoreplies WHERE keyword = ?",
+                        vec![keyword.clone()],
+                    ),
+                    stmt(
+                        "INSERT INTO scheduler_autoreplies (keyword, rule) VALUES (?, ?) \
+                         ON CONFLICT (keyword) DO UPDATE SET rule = excluded.rule",
+                        vec![keyword, text(&serde_json::to_string(&rule)?)],
+                    ),
+                ])
+                .await?;
+            Ok(results
+                .first()
+                .is_some_and(|existing| existing.rows.is_empty()))
+        })
+    }
+
+    fn delete_autoreply<'a>(&'a self, keyword: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one(
+                    "DELETE FROM scheduler_autoreplies WHERE keyword = ?",
+                    vec![text(keyword)],
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn list_api_keys(&self) -> StorageFuture<'_, Vec<ApiKey>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT api_key FROM scheduler_api_keys ORDER BY name",
+                vec![],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn find_api_key<'a>(&'a self, hash: &'a str) -> StorageFuture<'a, Option<ApiKey>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT api_key FROM scheduler_api_keys WHERE json_extract(api_key, '$.hash') = ?",
+                vec![text(hash)],
+            )
+            .await?
+            .rows
+            .first()
+            .map(|row| cell_json(row, 0))
+            .transpose()
+        })
+    }
+
+    fn create_api_key(&self, key: ApiKey) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one(
+                    "INSERT INTO scheduler_api_keys (name, api_key) VALUES (?, ?) \
+                     ON CONFLICT (name) DO NOTHING",
+                    vec![text(&key.name), text(&serde_json::to_string(&key)?)],
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn put_api_key(&self, key: ApiKey) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            self.query_one(
+                "INSERT INTO scheduler_api_keys (name, api_key) VALUES (?, ?) \
+                 ON CONFLICT (name) DO UPDATE SET api_key = excluded.api_key",
+                vec![text(&key.name), text(&serde_json::to_string(&key)?)],
+            )
+            .await
+            .map(drop)
+        })
+    }
+
+    fn list_number_rules(&self) -> StorageFuture<'_, Vec<StoredRule>> {
+        Box::pin(async move {
+            self.query_one(
+                "SELECT rule FROM scheduler_number_rules ORDER BY id",
+                vec![],
+            )
+            .await?
+            .rows
+            .iter()
+            .map(|row| cell_json(row, 0))
+            .collect()
+        })
+    }
+
+    fn create_number_rule(&self, rule: StoredRule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one(
+                    "INSERT INTO scheduler_number_rules (id, rule) VALUES (?, ?) \
+                     ON CONFLICT (id) DO NOTHING",
+                    vec![text(&rule.id()), text(&serde_json::to_string(&rule)?)],
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn delete_number_rule<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = self
+                .query_one(
+                    "DELETE FROM scheduler_number_rules WHERE id = ?",
+                    vec![text(id)],
+                )
+                .await?;
+            Ok(result.affected_row_count > 0)
+        })
+    }
+
+    fn ping(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            self.query_one("SELECT 1", vec![]).await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,484 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, error, info, instrument};
+
+use crate::apikeys::ApiKey;
+use crate::autoresponder::Rule;
+use crate::contacts::{Contact, ContactGroup};
+use crate::cost::Segments;
+use crate::delivery::{self, DeliveryReport};
+use crate::dlq::DeadLetter;
+use crate::error::ApiError;
+use crate::inbound::InboundMessage;
+use crate::jobs::{CatchUpPolicy, Claimed, Job};
+use crate::priority::Priority;
+use crate::providers::{DeliveryStatus, RecipientStatus};
+use crate::recipients::StoredRule;
+use crate::redact;
+
+pub mod libsql;
+pub mod postgres;
+pub mod redis;
+
+/// What a storage call resolves to. Boxed so backends can sit behind
+/// `dyn Storage`.
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// How far a recorded message got: `Sent` once the provider accepted it,
+/// then `Delivered`, `Failed` or `Expired` as delivery reports come in.
+/// Sends the provider refused are `Failed` from the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    Sent,
+    Delivered,
+    Failed,
+    Expired,
+}
+
+impl MessageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Sent => "sent",
+            MessageStatus::Delivered => "delivered",
+            MessageStatus::Failed => "failed",
+            MessageStatus::Expired => "expired",
+        }
+    }
+}
+
+impl std::str::FromStr for MessageStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sent" => Ok(MessageStatus::Sent),
+            "delivered" => Ok(MessageStatus::Delivered),
+            "failed" => Ok(MessageStatus::Failed),
+            "expired" => Ok(MessageStatus::Expired),
+            other => Err(format!("unknown message status: {}", other)),
+        }
+    }
+}
+
+/// A send that reached the provider, kept as message history for auditing.
+/// The phone is stored masked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRecord {
+    pub id: String,
+    pub phone: String,
+    pub message: String,
+    pub sender_id: String,
+    pub status: MessageStatus,
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_ids: Vec<String>,
+    /// What the provider reported for each recipient
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<RecipientStatus>,
+    /// Credits the provider deducted, when it reports them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    /// Segments the message is billed as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<usize>,
+    /// Segments at `SEGMENT_RATES` for the provider that took the send
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_cost: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The request that made the send, e.g. a tick for scheduled sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Reported by a dry run rather than sent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl MessageRecord {
+    pub fn new(
+        phone: String,
+        message: String,
+        sender_id: String,
+        status: MessageStatus,
+        attempts: u32,
+    ) -> Self {
+        MessageRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            phone: redact::mask_phone(&phone),
+            segments: Some(Segments::of(&message).segments),
+            message,
+            sender_id,
+            status,
+            attempts,
+            provider: None,
+            message_ids: Vec::new(),
+            statuses: Vec::new(),
+            cost: None,
+            estimated_cost: None,
+            error: None,
+            trace_id: None,
+            dry_run: false,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Applies a delivery report for one of this message's ids, returning
+    /// whether it changed anything. Reports can arrive out of order, so a
+    /// recipient's final status isn't replaced by a later non-final one.
+    pub fn apply_report(&mut self, report: &DeliveryReport) -> bool {
+        let reported = RecipientStatus {
+            message_id: Some(report.message_id.clone()),
+            status: report.status,
+            provider_status: report.provider_status.clone(),
+        };
+        match self
+            .statuses
+            .iter_mut()
+            .find(|status| status.message_id.as_deref() == Some(report.message_id.as_str()))
+        {
+            Some(status) if status.status.is_final() && !report.status.is_final() => return false,
+            Some(status) if status.provider_status == report.provider_status => return false,
+            Some(status) => *status = reported,
+            None => self.statuses.push(reported),
+        }
+        if report.reason.is_some() {
+            self.error.clone_from(&report.reason);
+        }
+
+        let any = |wanted: &[DeliveryStatus]| {
+            self.statuses
+                .iter()
+                .any(|status| wanted.contains(&status.status))
+        };
+        self.status = if any(&[DeliveryStatus::Failed, DeliveryStatus::Rejected]) {
+            MessageStatus::Failed
+        } else if any(&[DeliveryStatus::Expired]) {
+            MessageStatus::Expired
+        } else if self.message_ids.iter().all(|id| {
+            self.statuses.iter().any(|status| {
+                status.message_id.as_ref() == Some(id) && status.status == DeliveryStatus::Delivered
+            })
+        }) {
+            MessageStatus::Delivered
+        } else {
+            MessageStatus::Sent
+        };
+        true
+    }
+}
+
+/// Which recorded messages to return, newest first: those matching the
+/// filters, skipping `offset` and returning at most `limit`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageQuery {
+    pub status: Option<MessageStatus>,
+    /// Sent at or after
+    pub from: Option<DateTime<Utc>>,
+    /// Sent before
+    pub to: Option<DateTime<Utc>>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl MessageQuery {
+    /// Whether the record passes the filters, for backends that filter
+    /// after reading
+    pub fn matches(&self, record: &MessageRecord) -> bool {
+        self.status.is_none_or(|status| record.status == status)
+            && self.from.is_none_or(|from| record.created_at >= from)
+            && self.to.is_none_or(|to| record.created_at < to)
+    }
+}
+
+/// One page of message history
+#[derive(Debug, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<MessageRecord>,
+    /// Whether another page follows
+    pub has_more: bool,
+}
+
+/// Durable state shared by every instance: jobs, message history,
+/// idempotency keys, dead letters, contact groups, inbound messages,
+/// auto-responder rules and issued API keys.
+/// Modules fall back to their own memory, file or Redis stores when no
+/// backend is configured (see `backend`).
+pub trait Storage: Send + Sync {
+    fn list_jobs(&self) -> StorageFuture<'_, Vec<Job>>;
+    fn get_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<Job>>;
+    /// Inserts or replaces the job with the same id
+    fn put_job(&self, job: Job) -> StorageFuture<'_, ()>;
+    /// Removes a job, returning `false` if there was no such job
+    fn delete_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool>;
+    /// As `JobStore::claim_due`, claiming each job on one instance only
+    fn claim_due_jobs(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> StorageFuture<'_, Claimed>;
+
+    fn record_message(&self, record: MessageRecord) -> StorageFuture<'_, ()>;
+    fn find_messages(&self, query: MessageQuery) -> StorageFuture<'_, Vec<MessageRecord>>;
+    /// The message the provider gave this id on submission
+    fn find_message_by_provider_id<'a>(
+        &'a self,
+        message_id: &'a str,
+    ) -> StorageFuture<'a, Option<MessageRecord>>;
+    /// Replaces `previous` with `record` if it's still stored as read,
+    /// returning `false` if it has changed or been trimmed since
+    fn update_message<'a>(
+        &'a self,
+        previous: &'a MessageRecord,
+        record: MessageRecord,
+    ) -> StorageFuture<'a, bool>;
+
+    /// Stores `value` under `key` for `ttl` unless an unexpired value is
+    /// already there, which is returned instead
+    fn reserve_key<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> StorageFuture<'a, Option<Vec<u8>>>;
+    fn set_key<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> StorageFuture<'a, ()>;
+    fn delete_key<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()>;
+
+    fn push_dead_letter(&self, entry: DeadLetter) -> StorageFuture<'_, ()>;
+    /// Oldest first
+    fn list_dead_letters(&self) -> StorageFuture<'_, Vec<DeadLetter>>;
+    /// Removes and returns the entry, so only one replay of it can run
+    fn take_dead_letter<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<DeadLetter>>;
+
+    /// By name, with their member counts
+    fn list_groups(&self) -> StorageFuture<'_, Vec<ContactGroup>>;
+    /// Returns `false` without writing if the name is taken
+    fn create_group(&self, group: ContactGroup) -> StorageFuture<'_, bool>;
+    /// Removes the group with its members, returning `false` if there was
+    /// no such group
+    fn delete_group<'a>(&'a self, name: &'a str) -> StorageFuture<'a, bool>;
+    /// By phone; `None` if there's no such group
+    fn group_members<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<Vec<Contact>>>;
+    /// Upserts contacts by phone, returning `false` if there's no such group
+    fn add_members<'a>(&'a self, name: &'a str, members: Vec<Contact>) -> StorageFuture<'a, bool>;
+    /// Whether the phone was a member; `None` if there's no such group
+    fn remove_member<'a>(
+        &'a self,
+        name: &'a str,
+        phone: &'a str,
+    ) -> StorageFuture<'a, Option<bool>>;
+
+    fn record_inbound(&self, message: InboundMessage) -> StorageFuture<'_, ()>;
+    /// Newest first
+    fn recent_inbound(&self, limit: usize) -> StorageFuture<'_, Vec<InboundMessage>>;
+
+    /// By keyword
+    fn list_autoreplies(&self) -> StorageFuture<'_, Vec<Rule>>;
+    /// Upserts by keyword, returning `true` if the rule is new
+    fn put_autoreply(&self, rule: Rule) -> StorageFuture<'_, bool>;
+    /// Returns `false` if the keyword had no rule
+    fn delete_autoreply<'a>(&'a self, keyword: &'a str) -> StorageFuture<'a, bool>;
+
+    /// By name
+    fn list_api_keys(&self) -> StorageFuture<'_, Vec<ApiKey>>;
+    /// The key with this SHA-256, revoked or not, looked up by its index
+    fn find_api_key<'a>(&'a self, hash: &'a str) -> StorageFuture<'a, Option<ApiKey>>;
+    /// Returns `false` without writing if a key already has the name
+    fn create_api_key(&self, key: ApiKey) -> StorageFuture<'_, bool>;
+    /// Replaces the named key, e.g. to revoke it
+    fn put_api_key(&self, key: ApiKey) -> StorageFuture<'_, ()>;
+
+    /// By id, so allow rules come before block rules
+    fn list_number_rules(&self) -> StorageFuture<'_, Vec<StoredRule>>;
+    /// Returns `false` without writing if a rule already has the id
+    fn create_number_rule(&self, rule: StoredRule) -> StorageFuture<'_, bool>;
+    /// Returns `false` if no rule had the id
+    fn delete_number_rule<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool>;
+
+    /// A round trip to the backend, for readiness checks
+    fn ping(&self) -> StorageFuture<'_, ()>;
+}
+
+fn env(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn open(backend: &str) -> Result<Box<dyn Storage>, String> {
+    let url = |key: &str| env(key).ok_or_else(|| format!("{} backend needs {}", backend, key));
+    match backend {
+        "postgres" => {
+            let storage = postgres::PostgresStorage::connect(&url("DATABASE_URL")?)?;
+            info!("Using Postgres storage at: {}", storage.address());
+            Ok(Box::new(storage))
+        }
+        "redis" => {
+            let storage = redis::RedisStorage::connect(&url("REDIS_URL")?)?;
+            info!("Using Redis storage at: {}", storage.address());
+            Ok(Box::new(storage))
+        }
+        "libsql" => {
+            let storage =
+                libsql::LibsqlStorage::connect(&url("LIBSQL_URL")?, env("LIBSQL_AUTH_TOKEN"))?;
+            info!("Using libSQL storage at: {}", storage.address());
+            Ok(Box::new(storage))
+        }
+        other => Err(format!("unknown storage backend: {}", other)),
+    }
+}
+
+// A misconfigured backend is kept as an error so every store built on it
+// fails rather than quietly falling back to state other instances can't see
+static BACKEND: Lazy<Option<Result<Box<dyn Storage>, String>>> = Lazy::new(|| {
+    let backend = match env("STORAGE_BACKEND") {
+        Some(backend) => backend.trim().to_ascii_lowercase(),
+        None if env("DATABASE_URL").is_some() => "postgres".to_string(),
+        None if env("REDIS_URL").is_some() => "redis".to_string(),
+        None if env("LIBSQL_URL").is_some() => "libsql".to_string(),
+        None => return None,
+    };
+    Some(open(&backend).inspect_err(|e| error!("Invalid storage configuration: {}", e)))
+});
+
+/// The backend named by `STORAGE_BACKEND`, or else the first of Postgres,
+/// Redis and libSQL whose `DATABASE_URL`, `REDIS_URL` or `LIBSQL_URL` is
+/// set; `None` without any
+pub fn backend() -> Option<Result<&'static dyn Storage, String>> {
+    BACKEND
+        .as_ref()
+        .map(|backend| backend.as_ref().map(Box::as_ref).map_err(Clone::clone))
+}
+
+/// Most messages kept in memory without a backend; older ones are dropped
+const MEMORY_HISTORY_LIMIT: usize = 1000;
+
+static MEMORY_HISTORY: Lazy<Mutex<VecDeque<MessageRecord>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+fn history() -> std::sync::MutexGuard<'static, VecDeque<MessageRecord>> {
+    MEMORY_HISTORY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Adds a send to the message history. Failing to store it is only logged,
+/// since the send itself is already done.
+#[instrument(level = "info", skip_all)]
+pub async fn record_message(record: MessageRecord) {
+    let id = record.id.clone();
+    let result = match backend() {
+        Some(Ok(storage)) => storage.record_message(record).await,
+        Some(Err(reason)) => Err(io::Error::other(reason)),
+        None => {
+            let mut history = history();
+            history.push_front(record);
+            history.truncate(MEMORY_HISTORY_LIMIT);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        error!("Lost history of message {}: {}", id, e);
+    }
+}
+
+/// A page of recorded messages matching the query
+#[instrument(level = "info", skip_all)]
+pub async fn find_messages(query: MessageQuery) -> Result<MessagePage, ApiError> {
+    // One more than asked for tells whether there's another page
+    let probe = MessageQuery {
+        limit: query.limit + 1,
+        ..query
+    };
+    let mut messages = match backend() {
+        Some(Ok(storage)) => storage.find_messages(probe).await.map_err(unavailable)?,
+        Some(Err(reason)) => return Err(ApiError::StorageUnavailable { reason }),
+        None => history()
+            .iter()
+            .filter(|record| probe.matches(record))
+            .skip(probe.offset)
+            .take(probe.limit)
+            .cloned()
+            .collect(),
+    };
+    let has_more = messages.len() > query.limit;
+    messages.truncate(query.limit);
+    Ok(MessagePage { messages, has_more })
+}
+
+/// Reads of a message one delivery report may make before giving up
+const RECONCILE_ATTEMPTS: usize = 3;
+
+/// Applies a delivery report to the message it's for, returning the updated
+/// record, or `None` when no recorded message has the report's id
+#[instrument(level = "info", skip_all)]
+pub async fn reconcile_delivery(
+    report: &DeliveryReport,
+) -> Result<Option<MessageRecord>, ApiError> {
+    if report.status == DeliveryStatus::Delivered {
+        delivery::record_delivery(&report.message_id);
+    }
+    let storage = match backend() {
+        Some(Ok(storage)) => storage,
+        Some(Err(reason)) => return Err(ApiError::StorageUnavailable { reason }),
+        None => {
+            let mut history = history();
+            let record = history
+                .iter_mut()
+                .find(|record| record.message_ids.contains(&report.message_id));
+            return Ok(record.map(|record| {
+                record.apply_report(report);
+                record.clone()
+            }));
+        }
+    };
+
+    // Providers can report one message's statuses a moment apart, so a
+    // record that changed since it was read is read again
+    for _ in 0..RECONCILE_ATTEMPTS {
+        let Some(previous) = storage
+            .find_message_by_provider_id(&report.message_id)
+            .await
+            .map_err(unavailable)?
+        else {
+            return Ok(None);
+        };
+        let mut record = previous.clone();
+        if !record.apply_report(report) {
+            return Ok(Some(record));
+        }
+        if storage
+            .update_message(&previous, record.clone())
+            .await
+            .map_err(unavailable)?
+        {
+            return Ok(Some(record));
+        }
+        debug!("Message {} changed while reconciling it", record.id);
+    }
+    Err(ApiError::StorageUnavailable {
+        reason: format!(
+            "message with provider id {} kept changing while reconciling it",
+            report.message_id
+        ),
+    })
+}
+
+/// Unavailable-storage error for a failed backend call
+pub fn unavailable(e: io::Error) -> ApiError {
+    error!("Storage failed: {}", e);
+    ApiError::StorageUnavailable {
+        reason: e.to_string(),
+    }
+}
@@ -0,0 +1,718 @@
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use sqlx::types::Json;
+use std::io;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{debug, info};
+
+use super::{MessageQuery, MessageRecord, Storage, StorageFuture};
+use crate::apikeys::ApiKey;
+use crate::autoresponder::Rule;
+use crate::contacts::{Contact, ContactGroup};
+use crate::dlq::DeadLetter;
+use crate::inbound::InboundMessage;
+use crate::jobs::{self, CatchUpPolicy, Claimed, Job};
+use crate::priority::Priority;
+use crate::recipients::StoredRule;
+
+// Whole records are kept as JSON next to the columns queries filter on, so
+// adding a field to a job or dead letter needs no migration
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS scheduler_jobs (
+    id TEXT PRIMARY KEY,
+    priority TEXT NOT NULL,
+    next_run_at TIMESTAMPTZ,
+    job JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS scheduler_jobs_due ON scheduler_jobs (priority, next_run_at);
+CREATE TABLE IF NOT EXISTS scheduler_messages (
+    id TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    record JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS scheduler_messages_created ON scheduler_messages (created_at);
+CREATE INDEX IF NOT EXISTS scheduler_messages_provider_ids
+    ON scheduler_messages USING GIN ((record->'message_ids'));
+CREATE TABLE IF NOT EXISTS scheduler_idempotency (
+    key TEXT PRIMARY KEY,
+    value BYTEA NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS scheduler_dead_letters (
+    id TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    entry JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS scheduler_contact_groups (
+    name TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE IF NOT EXISTS scheduler_contacts (
+    group_name TEXT NOT NULL REFERENCES scheduler_contact_groups (name) ON DELETE CASCADE,
+    phone TEXT NOT NULL,
+    contact JSONB NOT NULL,
+    PRIMARY KEY (group_name, phone)
+);
+CREATE TABLE IF NOT EXISTS scheduler_inbound (
+    id TEXT PRIMARY KEY,
+    received_at TIMESTAMPTZ NOT NULL,
+    message JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS scheduler_inbound_received ON scheduler_inbound (received_at);
+CREATE TABLE IF NOT EXISTS scheduler_autoreplies (
+    keyword TEXT PRIMARY KEY,
+    rule JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS scheduler_api_keys (
+    name TEXT PRIMARY KEY,
+    api_key JSONB NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS scheduler_api_keys_hash ON scheduler_api_keys ((api_key->>'hash'));
+CREATE TABLE IF NOT EXISTS scheduler_number_rules (
+    id TEXT PRIMARY KEY,
+    rule JSONB NOT NULL
+);
+";
+
+/// Storage in a Postgres database such as Neon, from `DATABASE_URL`.
+/// Connections open on first use and the tables are created if missing.
+#[derive(Debug)]
+pub struct PostgresStorage {
+    pool: PgPool,
+    address: String,
+    schema: OnceCell<()>,
+}
+
+impl PostgresStorage {
+    /// Parses the URL and sets up a pool without connecting, so a cold start
+    /// doesn't wait on the database until something needs it
+    pub fn connect(url: &str) -> Result<Self, String> {
+        let options: PgConnectOptions = url.parse().map_err(|e| format!("{}", e))?;
+        let address = format!(
+            "{}:{}/{}",
+            options.get_host(),
+            options.get_port(),
+            options.get_database().unwrap_or_default()
+        );
+        // Serverless instances are many and short-lived, so each keeps few
+        let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(5);
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .acquire_timeout(Duration::from_secs(10))
+            .connect_lazy_with(options);
+        Ok(PostgresStorage {
+            pool,
+            address,
+            schema: OnceCell::new(),
+        })
+    }
+
+    /// Host, port and database, without credentials
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    async fn pool(&self) -> io::Result<&PgPool> {
+        self.schema
+            .get_or_try_init(|| async {
+                sqlx::raw_sql(SCHEMA).execute(&self.pool).await?;
+                info!("Postgres schema ready at: {}", self.address);
+                Ok::<_, sqlx::Error>(())
+            })
+            .await
+            .map_err(db)?;
+        Ok(&self.pool)
+    }
+}
+
+fn db(e: sqlx::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+const UPSERT_JOB: &str = "INSERT INTO scheduler_jobs (id, priority, next_run_at, job) \
+    VALUES ($1, $2, $3, $4) ON CONFLICT (id) DO UPDATE SET priority = EXCLUDED.priority, \
+    next_run_at = EXCLUDED.next_run_at, job = EXCLUDED.job";
+
+impl Storage for PostgresStorage {
+    fn list_jobs(&self) -> StorageFuture<'_, Vec<Job>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<Job>,)> =
+                sqlx::query_as("SELECT job FROM scheduler_jobs ORDER BY id")
+                    .fetch_all(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(job),)| job).collect())
+        })
+    }
+
+    fn get_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<Job>> {
+        Box::pin(async move {
+            let row: Option<(Json<Job>,)> =
+                sqlx::query_as("SELECT job FROM scheduler_jobs WHERE id = $1")
+                    .bind(id)
+                    .fetch_optional(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(row.map(|(Json(job),)| job))
+        })
+    }
+
+    fn put_job(&self, job: Job) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query(UPSERT_JOB)
+                .bind(&job.id)
+                .bind(job.priority.as_str())
+                .bind(job.next_run_at)
+                .bind(Json(&job))
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn delete_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM scheduler_jobs WHERE id = $1")
+                .bind(id)
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn claim_due_jobs(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> StorageFuture<'_, Claimed> {
+        Box::pin(async move {
+            let mut tx = self.pool().await?.begin().await.map_err(db)?;
+            // Rows another instance is claiming are skipped rather than
+            // waited on, so overlapping ticks split the due jobs between them
+            let rows: Vec<(Json<Job>,)> = sqlx::query_as(
+                "SELECT job FROM scheduler_jobs WHERE priority = $1 AND next_run_at <= $2 \
+                 ORDER BY next_run_at LIMIT $3 FOR UPDATE SKIP LOCKED",
+            )
+            .bind(lane.as_str())
+            .bind(now)
+            .bind(limit as i64)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db)?;
+
+            let mut due: Vec<Job> = rows.into_iter().map(|(Json(job),)| job).collect();
+            let before: Vec<Option<DateTime<Utc>>> =
+                due.iter().map(|job| job.next_run_at).collect();
+            let claimed = jobs::claim_in_order(due.iter_mut(), now, window, catch_up, limit);
+            for (job, before) in due.iter().zip(before) {
+                if job.next_run_at == before {
+                    continue;
+                }
+                sqlx::query("UPDATE scheduler_jobs SET next_run_at = $2, job = $3 WHERE id = $1")
+                    .bind(&job.id)
+                    .bind(job.next_run_at)
+                    .bind(Json(job))
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(db)?;
+            }
+            tx.commit().await.map_err(db)?;
+            Ok(claimed)
+        })
+    }
+
+    fn record_message(&self, record: MessageRecord) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO scheduler_messages (id, created_at, record) VALUES ($1, $2, $3)",
+            )
+            .bind(&record.id)
+            .bind(record.created_at)
+            .bind(Json(&record))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn find_messages(&self, query: MessageQuery) -> StorageFuture<'_, Vec<MessageRecord>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<MessageRecord>,)> = sqlx::query_as(
+                "SELECT record FROM scheduler_messages \
+                 WHERE ($1::text IS NULL OR record->>'status' = $1) \
+                 AND ($2::timestamptz IS NULL OR created_at >= $2) \
+                 AND ($3::timestamptz IS NULL OR created_at < $3) \
+                 ORDER BY created_at DESC, id LIMIT $4 OFFSET $5",
+            )
+            .bind(query.status.map(|status| status.as_str()))
+            .bind(query.from)
+            .bind(query.to)
+            .bind(query.limit as i64)
+            .bind(query.offset as i64)
+            .fetch_all(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(record),)| record).collect())
+        })
+    }
+
+    fn find_message_by_provider_id<'a>(
+        &'a self,
+        message_id: &'a str,
+    ) -> StorageFuture<'a, Option<MessageRecord>> {
+        Box::pin(async move {
+            let row: Option<(Json<MessageRecord>,)> = sqlx::query_as(
+                "SELECT record FROM scheduler_messages WHERE record->'message_ids' ? $1 \
+                 ORDER BY created_at DESC LIMIT 1",
+            )
+            .bind(message_id)
+            .fetch_optional(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(row.map(|(Json(record),)| record))
+        })
+    }
+
+    fn update_message<'a>(
+        &'a self,
+        previous: &'a MessageRecord,
+        record: MessageRecord,
+    ) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                "UPDATE scheduler_messages SET record = $2 WHERE id = $1 AND record = $3",
+            )
+            .bind(&record.id)
+            .bind(Json(&record))
+            .bind(Json(previous))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn reserve_key<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> StorageFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            let pool = self.pool().await?;
+            for _ in 0..2 {
+                let now = Utc::now();
+                let expires_at = now + chrono::Duration::from_std(ttl).unwrap_or_default();
+                // Takes the key when it's free or its value has expired
+                let reserved = sqlx::query(
+                    "INSERT INTO scheduler_idempotency (key, value, expires_at) \
+                     VALUES ($1, $2, $3) ON CONFLICT (key) DO UPDATE \
+                     SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at \
+                     WHERE scheduler_idempotency.expires_at <= $4 RETURNING key",
+                )
+                .bind(key)
+                .bind(&value)
+                .bind(expires_at)
+                .bind(now)
+                .fetch_optional(pool)
+                .await
+                .map_err(db)?;
+                if reserved.is_some() {
+                    return Ok(None);
+                }
+                let existing: Option<(Vec<u8>,)> =
+                    sqlx::query_as("SELECT value FROM scheduler_idempotency WHERE key = $1")
+                        .bind(key)
+                        .fetch_optional(pool)
+                        .await
+                        .map_err(db)?;
+                if let Some((existing,)) = existing {
+                    return Ok(Some(existing));
+                }
+                // Deleted between the two queries; try to reserve it again
+                debug!("Idempotency key {} vanished while being reserved", key);
+            }
+            Err(io::Error::other(
+                "idempotency key was released while being reserved",
+            ))
+        })
+    }
+
+    fn set_key<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let expires_at = Utc::now() + chrono::Duration::from_std(ttl).unwrap_or_default();
+            sqlx::query(
+                "INSERT INTO scheduler_idempotency (key, value, expires_at) VALUES ($1, $2, $3) \
+                 ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, \
+                 expires_at = EXCLUDED.expires_at",
+            )
+            .bind(key)
+            .bind(value)
+            .bind(expires_at)
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn delete_key<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM scheduler_idempotency WHERE key = $1")
+                .bind(key)
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn push_dead_letter(&self, entry: DeadLetter) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO scheduler_dead_letters (id, created_at, entry) VALUES ($1, $2, $3)",
+            )
+            .bind(&entry.id)
+            .bind(entry.created_at)
+            .bind(Json(&entry))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn list_dead_letters(&self) -> StorageFuture<'_, Vec<DeadLetter>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<DeadLetter>,)> =
+                sqlx::query_as("SELECT entry FROM scheduler_dead_letters ORDER BY created_at, id")
+                    .fetch_all(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(entry),)| entry).collect())
+        })
+    }
+
+    fn take_dead_letter<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<DeadLetter>> {
+        Box::pin(async move {
+            // Only one of two concurrent replays gets the row back
+            let row: Option<(Json<DeadLetter>,)> =
+                sqlx::query_as("DELETE FROM scheduler_dead_letters WHERE id = $1 RETURNING entry")
+                    .bind(id)
+                    .fetch_optional(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(row.map(|(Json(entry),)| entry))
+        })
+    }
+
+    fn list_groups(&self) -> StorageFuture<'_, Vec<ContactGroup>> {
+        Box::pin(async move {
+            let rows: Vec<(String, DateTime<Utc>, i64)> = sqlx::query_as(
+                "SELECT g.name, g.created_at, COUNT(c.phone) FROM scheduler_contact_groups g \
+                 LEFT JOIN scheduler_contacts c ON c.group_name = g.name \
+                 GROUP BY g.name, g.created_at ORDER BY g.name",
+            )
+            .fetch_all(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(rows
+                .into_iter()
+                .map(|(name, created_at, members)| ContactGroup {
+                    name,
+                    members: members as usize,
+                    created_at,
+                })
+                .collect())
+        })
+    }
+
+    fn create_group(&self, group: ContactGroup) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                "INSERT INTO scheduler_contact_groups (name, created_at) VALUES ($1, $2) \
+                 ON CONFLICT (name) DO NOTHING",
+            )
+            .bind(&group.name)
+            .bind(group.created_at)
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn delete_group<'a>(&'a self, name: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM scheduler_contact_groups WHERE name = $1")
+                .bind(name)
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn group_members<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<Vec<Contact>>> {
+        Box::pin(async move {
+            let pool = self.pool().await?;
+            let exists: Option<(String,)> =
+                sqlx::query_as("SELECT name FROM scheduler_contact_groups WHERE name = $1")
+                    .bind(name)
+                    .fetch_optional(pool)
+                    .await
+                    .map_err(db)?;
+            if exists.is_none() {
+                return Ok(None);
+            }
+            let rows: Vec<(Json<Contact>,)> = sqlx::query_as(
+                "SELECT contact FROM scheduler_contacts WHERE group_name = $1 ORDER BY phone",
+            )
+            .bind(name)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?;
+            Ok(Some(
+                rows.into_iter().map(|(Json(contact),)| contact).collect(),
+            ))
+        })
+    }
+
+    fn add_members<'a>(&'a self, name: &'a str, members: Vec<Contact>) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let mut tx = self.pool().await?.begin().await.map_err(db)?;
+            // Holds off a concurrent delete of the group until these are in
+            let exists: Option<(String,)> = sqlx::query_as(
+                "SELECT name FROM scheduler_contact_groups WHERE name = $1 FOR KEY SHARE",
+            )
+            .bind(name)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(db)?;
+            if exists.is_none() {
+                return Ok(false);
+            }
+            sqlx::query(
+                "INSERT INTO scheduler_contacts (group_name, phone, contact) \
+                 SELECT $1, contact->>'phone', contact FROM jsonb_array_elements($2) AS contact \
+                 ON CONFLICT (group_name, phone) DO UPDATE SET contact = EXCLUDED.contact",
+            )
+            .bind(name)
+            .bind(Json(&members))
+            .execute(&mut *tx)
+            .await
+            .map_err(db)?;
+            tx.commit().await.map_err(db)?;
+            Ok(true)
+        })
+    }
+
+    fn remove_member<'a>(
+        &'a self,
+        name: &'a str,
+        phone: &'a str,
+    ) -> StorageFuture<'a, Option<bool>> {
+        Box::pin(async move {
+            let pool = self.pool().await?;
+            let result =
+                sqlx::query("DELETE FROM scheduler_contacts WHERE group_name = $1 AND phone = $2")
+                    .bind(name)
+                    .bind(phone)
+                    .execute(pool)
+                    .await
+                    .map_err(db)?;
+            if result.rows_affected() > 0 {
+                return Ok(Some(true));
+            }
+            let exists: Option<(String,)> =
+                sqlx::query_as("SELECT name FROM scheduler_contact_groups WHERE name = $1")
+                    .bind(name)
+                    .fetch_optional(pool)
+                    .await
+                    .map_err(db)?;
+            Ok(exists.map(|_| false))
+        })
+    }
+
+    // Providers retry callbacks they think failed, so a repeat of an id
+    // already stored is dropped
+    fn record_inbound(&self, message: InboundMessage) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO scheduler_inbound (id, received_at, message) VALUES ($1, $2, $3) \
+                 ON CONFLICT (id) DO NOTHING",
+            )
+            .bind(&message.id)
+            .bind(message.received_at)
+            .bind(Json(&message))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn recent_inbound(&self, limit: usize) -> StorageFuture<'_, Vec<InboundMessage>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<InboundMessage>,)> = sqlx::query_as(
+                "SELECT message FROM scheduler_inbound ORDER BY received_at DESC, id LIMIT $1",
+            )
+            .bind(limit as i64)
+            .fetch_all(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(message),)| message).collect())
+        })
+    }
+
+    fn list_autoreplies(&self) -> StorageFuture<'_, Vec<Rule>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<Rule>,)> =
+                sqlx::query_as("SELECT rule FROM scheduler_autoreplies ORDER BY keyword")
+                    .fetch_all(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(rule),)| rule).collect())
+        })
+    }
+
+    fn put_autoreply(&self, rule: Rule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            // xmax is only set on a row the upsert updated
+            let (inserted,): (bool,) = sqlx::query_as(
+                "INSERT INTO scheduler_autoreplies (keyword, rule) VALUES ($1, $2) \
+                 ON CONFLICT (keyword) DO UPDATE SET rule = EXCLUDED.rule \
+                 RETURNING xmax = 0",
+            )
+            .bind(&rule.keyword)
+            .bind(Json(&rule))
+            .fetch_one(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(inserted)
+        })
+    }
+
+    fn delete_autoreply<'a>(&'a self, keyword: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM scheduler_autoreplies WHERE keyword = $1")
+                .bind(keyword)
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn list_api_keys(&self) -> StorageFuture<'_, Vec<ApiKey>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<ApiKey>,)> =
+                sqlx::query_as("SELECT api_key FROM scheduler_api_keys ORDER BY name")
+                    .fetch_all(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(key),)| key).collect())
+        })
+    }
+
+    fn find_api_key<'a>(&'a self, hash: &'a str) -> StorageFuture<'a, Option<ApiKey>> {
+        Box::pin(async move {
+            let row: Option<(Json<ApiKey>,)> = sqlx::query_as(
+                "SELECT api_key FROM scheduler_api_keys WHERE api_key->>'hash' = $1",
+            )
+            .bind(hash)
+            .fetch_optional(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(row.map(|(Json(key),)| key))
+        })
+    }
+
+    fn create_api_key(&self, key: ApiKey) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                "INSERT INTO scheduler_api_keys (name, api_key) VALUES ($1, $2) \
+                 ON CONFLICT (name) DO NOTHING",
+            )
+            .bind(&key.name)
+            .bind(Json(&key))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn put_api_key(&self, key: ApiKey) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO scheduler_api_keys (name, api_key) VALUES ($1, $2) \
+                 ON CONFLICT (name) DO UPDATE SET api_key = EXCLUDED.api_key",
+            )
+            .bind(&key.name)
+            .bind(Json(&key))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(())
+        })
+    }
+
+    fn list_number_rules(&self) -> StorageFuture<'_, Vec<StoredRule>> {
+        Box::pin(async move {
+            let rows: Vec<(Json<StoredRule>,)> =
+                sqlx::query_as("SELECT rule FROM scheduler_number_rules ORDER BY id")
+                    .fetch_all(self.pool().await?)
+                    .await
+                    .map_err(db)?;
+            Ok(rows.into_iter().map(|(Json(rule),)| rule).collect())
+        })
+    }
+
+    fn create_number_rule(&self, rule: StoredRule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                "INSERT INTO scheduler_number_rules (id, rule) VALUES ($1, $2) \
+                 ON CONFLICT (id) DO NOTHING",
+            )
+            .bind(rule.id())
+            .bind(Json(&rule))
+            .execute(self.pool().await?)
+            .await
+            .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn delete_number_rule<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM scheduler_number_rules WHERE id = $1")
+                .bind(id)
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn ping(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1")
+                .execute(self.pool().await?)
+                .await
+                .map_err(db)?;
+            Ok(())
+        })
+    }
+}
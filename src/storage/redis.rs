@@ -0,0 +1,779 @@
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use std::io;
+use std::time::Duration;
+use tracing::debug;
+
+use super::{MessageQuery, MessageRecord, Storage, StorageFuture};
+use crate::apikeys::ApiKey;
+use crate::autoresponder::Rule;
+use crate::contacts::{Contact, ContactGroup};
+use crate::dlq::DeadLetter;
+use crate::inbound::InboundMessage;
+use crate::jobs::{self, CatchUpPolicy, Claimed, Job};
+use crate::priority::Priority;
+use crate::recipients::StoredRule;
+use crate::redis::{RedisClient, Reply};
+
+// KEYS: the job hash, then each lane's due set. ARGV: id, body, the KEYS
+// index of the job's lane, its due score or "" when it has no next run, and
+// optionally the body it must still have so two claims can't both write.
+const PUT_JOB_SCRIPT: &str = "\
+if #ARGV >= 5 and redis.call('HGET', KEYS[1], ARGV[1]) ~= ARGV[5] then return 0 end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+for i = 2, #KEYS do redis.call('ZREM', KEYS[i], ARGV[1]) end
+if ARGV[4] ~= '' then redis.call('ZADD', KEYS[tonumber(ARGV[3])], ARGV[4], ARGV[1]) end
+return 1";
+
+// KEYS: the group hash, then the group's member hash. ARGV: the group
+// name, then phone and contact pairs. Writes nothing once the group is gone.
+const ADD_MEMBERS_SCRIPT: &str = "\
+if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then return 0 end
+for i = 2, #ARGV, 2 do redis.call('HSET', KEYS[2], ARGV[i], ARGV[i + 1]) end
+return 1";
+
+// KEYS: the message list. ARGV: the record as read, then its replacement.
+// Replaces nothing if the record has changed or been trimmed since.
+const UPDATE_MESSAGE_SCRIPT: &str = "\
+local index = redis.call('LPOS', KEYS[1], ARGV[1])
+if not index then return 0 end
+redis.call('LSET', KEYS[1], index, ARGV[2])
+return 1";
+
+const DELETE_JOB_SCRIPT: &str = "\
+local removed = redis.call('HDEL', KEYS[1], ARGV[1])
+for i = 2, #KEYS do redis.call('ZREM', KEYS[i], ARGV[1]) end
+return removed";
+
+// KEYS: the key hash, its index by SHA-256 and the index's marker. ARGV:
+// the SHA-256. Keys stored before the index existed are indexed on the
+// first lookup.
+const FIND_API_KEY_SCRIPT: &str = "\
+local name = redis.call('HGET', KEYS[2], ARGV[1])
+if not name and redis.call('SETNX', KEYS[3], '1') == 1 then
+  local keys = redis.call('HGETALL', KEYS[1])
+  for i = 1, #keys, 2 do
+    local hash = cjson.decode(keys[i + 1]).hash
+    redis.call('HSET', KEYS[2], hash, keys[i])
+    if hash == ARGV[1] then name = keys[i] end
+  end
+end
+if not name then return false end
+return redis.call('HGET', KEYS[1], name)";
+
+/// Most sent or received messages kept as history; older ones are trimmed
+const HISTORY_LIMIT: usize = 10_000;
+
+/// Storage in Redis, e.g. Upstash, from `REDIS_URL`. Jobs are JSON in one
+/// hash and indexed by next run in a sorted set per lane, so a tick reads
+/// only what's due; idempotency keys expire on their own.
+#[derive(Debug)]
+pub struct RedisStorage {
+    client: RedisClient,
+    prefix: String,
+}
+
+impl RedisStorage {
+    pub fn connect(url: &str) -> Result<Self, String> {
+        Ok(RedisStorage {
+            client: RedisClient::parse(url)?,
+            prefix: "scheduler:storage:".to_string(),
+        })
+    }
+
+    /// `host:port`, safe to log
+    pub fn address(&self) -> &str {
+        self.client.address()
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    fn members_key(&self, group: &str) -> String {
+        self.key(&format!("contacts:{}", group))
+    }
+
+    async fn group_exists(&self, name: &str) -> io::Result<bool> {
+        let key = self.key("groups");
+        Ok(matches!(
+            self.client
+                .command(&[b"HEXISTS", key.as_bytes(), name.as_bytes()])
+                .await?,
+            Reply::Integer(1)
+        ))
+    }
+
+    async fn index_api_key(&self, api_key: &ApiKey) -> io::Result<()> {
+        let index = self.key("apikeys:hashes");
+        self.client
+            .command(&[
+                b"HSET",
+                index.as_bytes(),
+                api_key.hash.as_bytes(),
+                api_key.name.as_bytes(),
+            ])
+            .await
+            .map(drop)
+    }
+
+    /// The job hash followed by every lane's due set, as the scripts expect
+    fn job_keys(&self) -> Vec<String> {
+        std::iter::once(self.key("jobs"))
+            .chain(
+                Priority::LANES
+                    .iter()
+                    .map(|lane| self.key(&format!("jobs:due:{}", lane))),
+            )
+            .collect()
+    }
+
+    /// Writes the job and its due index, or returns `false` without writing
+    /// if `expected` is given and no longer matches what's stored
+    async fn write_job(&self, job: &Job, expected: Option<&[u8]>) -> io::Result<bool> {
+        let keys = self.job_keys();
+        let body = serde_json::to_vec(job)?;
+        let lane = Priority::LANES
+            .iter()
+            .position(|lane| *lane == job.priority)
+            .unwrap_or_default()
+            + 2;
+        let lane = lane.to_string();
+        let score = job
+            .next_run_at
+            .map(|at| at.timestamp_millis().to_string())
+            .unwrap_or_default();
+        let key_count = keys.len().to_string();
+        let mut args: Vec<&[u8]> = vec![b"EVAL", PUT_JOB_SCRIPT.as_bytes(), key_count.as_bytes()];
+        args.extend(keys.iter().map(|key| key.as_bytes()));
+        args.extend([job.id.as_bytes(), &body, lane.as_bytes(), score.as_bytes()]);
+        if let Some(expected) = expected {
+            args.push(expected);
+        }
+        Ok(matches!(
+            self.client.command(&args).await?,
+            Reply::Integer(1)
+        ))
+    }
+}
+
+fn parse<T: DeserializeOwned>(raw: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(raw).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The bulk strings of an array reply, `None` for nil members
+fn bulks(reply: Reply) -> Vec<Option<Vec<u8>>> {
+    match reply {
+        Reply::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Reply::Bulk(value) => Some(value),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Storage for RedisStorage {
+    fn list_jobs(&self) -> StorageFuture<'_, Vec<Job>> {
+        Box::pin(async move {
+            let key = self.key("jobs");
+            let mut jobs = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<Job>(&raw))
+                .collect::<io::Result<Vec<Job>>>()?;
+            jobs.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok(jobs)
+        })
+    }
+
+    fn get_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<Job>> {
+        Box::pin(async move {
+            let key = self.key("jobs");
+            match self
+                .client
+                .command(&[b"HGET", key.as_bytes(), id.as_bytes()])
+                .await?
+            {
+                Reply::Bulk(raw) => parse(&raw).map(Some),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn put_job(&self, job: Job) -> StorageFuture<'_, ()> {
+        Box::pin(async move { self.write_job(&job, None).await.map(drop) })
+    }
+
+    fn delete_job<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let keys = self.job_keys();
+            let key_count = keys.len().to_string();
+            let mut args: Vec<&[u8]> =
+                vec![b"EVAL", DELETE_JOB_SCRIPT.as_bytes(), key_count.as_bytes()];
+            args.extend(keys.iter().map(|key| key.as_bytes()));
+            args.push(id.as_bytes());
+            Ok(matches!(
+                self.client.command(&args).await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn claim_due_jobs(
+        &self,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+        catch_up: CatchUpPolicy,
+        lane: Priority,
+        limit: usize,
+    ) -> StorageFuture<'_, Claimed> {
+        Box::pin(async move {
+            let mut claimed = Claimed::default();
+            if limit == 0 {
+                return Ok(claimed);
+            }
+            let due_key = self.key(&format!("jobs:due:{}", lane));
+            let max = now.timestamp_millis().to_string();
+            let count = limit.to_string();
+            let ids: Vec<Vec<u8>> = bulks(
+                self.client
+                    .command(&[
+                        b"ZRANGEBYSCORE",
+                        due_key.as_bytes(),
+                        b"-inf",
+                        max.as_bytes(),
+                        b"LIMIT",
+                        b"0",
+                        count.as_bytes(),
+                    ])
+                    .await?,
+            )
+            .into_iter()
+            .flatten()
+            .collect();
+            if ids.is_empty() {
+                return Ok(claimed);
+            }
+
+            let jobs_key = self.key("jobs");
+            let mut args: Vec<&[u8]> = vec![b"HMGET", jobs_key.as_bytes()];
+            args.extend(ids.iter().map(Vec::as_slice));
+            let bodies = bulks(self.client.command(&args).await?);
+
+            for raw in bodies.into_iter().flatten() {
+                if claimed.runs.len() >= limit {
+                    break;
+                }
+                let mut job: Job = parse(&raw)?;
+                let before = job.next_run_at;
+                let job_claimed = jobs::claim_in_order(
+                    std::iter::once(&mut job),
+                    now,
+                    window,
+                    catch_up,
+                    limit - claimed.runs.len(),
+                );
+                if job.next_run_at == before {
+                    continue;
+                }
+                // Another instance claiming the same job changes its body
+                // first, and then this write is refused
+                if !self.write_job(&job, Some(&raw)).await? {
+                    debug!("Job {} was claimed by another instance", job.id);
+                    continue;
+                }
+                claimed.runs.extend(job_claimed.runs);
+                claimed.deferred.extend(job_claimed.deferred);
+            }
+            Ok(claimed)
+        })
+    }
+
+    fn record_message(&self, record: MessageRecord) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let key = self.key("messages");
+            let value = serde_json::to_vec(&record)?;
+            self.client
+                .command(&[b"LPUSH", key.as_bytes(), &value])
+                .await?;
+            let last = (HISTORY_LIMIT - 1).to_string();
+            self.client
+                .command(&[b"LTRIM", key.as_bytes(), b"0", last.as_bytes()])
+                .await
+                .map(drop)
+        })
+    }
+
+    // The list is newest first and capped, so filtering it here reads at
+    // most HISTORY_LIMIT records
+    fn find_messages(&self, query: MessageQuery) -> StorageFuture<'_, Vec<MessageRecord>> {
+        Box::pin(async move {
+            if query.limit == 0 {
+                return Ok(Vec::new());
+            }
+            let key = self.key("messages");
+            let mut matching = Vec::new();
+            let mut skipped = 0;
+            for raw in bulks(
+                self.client
+                    .command(&[b"LRANGE", key.as_bytes(), b"0", b"-1"])
+                    .await?,
+            )
+            .into_iter()
+            .flatten()
+            {
+                let record: MessageRecord = parse(&raw)?;
+                if !query.matches(&record) {
+                    continue;
+                }
+                if skipped < query.offset {
+                    skipped += 1;
+                    continue;
+                }
+                matching.push(record);
+                if matching.len() >= query.limit {
+                    break;
+                }
+            }
+            Ok(matching)
+        })
+    }
+
+    fn find_message_by_provider_id<'a>(
+        &'a self,
+        message_id: &'a str,
+    ) -> StorageFuture<'a, Option<MessageRecord>> {
+        Box::pin(async move {
+            let key = self.key("messages");
+            for raw in bulks(
+                self.client
+                    .command(&[b"LRANGE", key.as_bytes(), b"0", b"-1"])
+                    .await?,
+            )
+            .into_iter()
+            .flatten()
+            {
+                let record: MessageRecord = parse(&raw)?;
+                if record.message_ids.iter().any(|id| id == message_id) {
+                    return Ok(Some(record));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    // Records are matched as the bytes they were stored as, which
+    // serializing what was read from them reproduces
+    fn update_message<'a>(
+        &'a self,
+        previous: &'a MessageRecord,
+        record: MessageRecord,
+    ) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key("messages");
+            let previous = serde_json::to_vec(previous)?;
+            let value = serde_json::to_vec(&record)?;
+            Ok(matches!(
+                self.client
+                    .command(&[
+                        b"EVAL",
+                        UPDATE_MESSAGE_SCRIPT.as_bytes(),
+                        b"1",
+                        key.as_bytes(),
+                        &previous,
+                        &value,
+                    ])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn reserve_key<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> StorageFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            let key = self.key(&format!("idempotency:{}", key));
+            let ttl = ttl.as_millis().max(1).to_string();
+            for _ in 0..2 {
+                let reply = self
+                    .client
+                    .command(&[b"SET", key.as_bytes(), &value, b"NX", b"PX", ttl.as_bytes()])
+                    .await?;
+                if let Reply::Ok = reply {
+                    return Ok(None);
+                }
+                if let Reply::Bulk(existing) =
+                    self.client.command(&[b"GET", key.as_bytes()]).await?
+                {
+                    return Ok(Some(existing));
+                }
+                // Expired between the two commands; try to reserve it again
+            }
+            Err(io::Error::other(
+                "idempotency key expired while being reserved",
+            ))
+        })
+    }
+
+    fn set_key<'a>(&'a self, key: &'a str, value: Vec<u8>, ttl: Duration) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.key(&format!("idempotency:{}", key));
+            let ttl = ttl.as_millis().max(1).to_string();
+            self.client
+                .command(&[b"SET", key.as_bytes(), &value, b"PX", ttl.as_bytes()])
+                .await
+                .map(drop)
+        })
+    }
+
+    fn delete_key<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.key(&format!("idempotency:{}", key));
+            self.client
+                .command(&[b"DEL", key.as_bytes()])
+                .await
+                .map(drop)
+        })
+    }
+
+    fn push_dead_letter(&self, entry: DeadLetter) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let key = self.key("dlq");
+            let value = serde_json::to_vec(&entry)?;
+            self.client
+                .command(&[b"HSET", key.as_bytes(), entry.id.as_bytes(), &value])
+                .await
+                .map(drop)
+        })
+    }
+
+    fn list_dead_letters(&self) -> StorageFuture<'_, Vec<DeadLetter>> {
+        Box::pin(async move {
+            let key = self.key("dlq");
+            let mut entries = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<DeadLetter>(&raw))
+                .collect::<io::Result<Vec<DeadLetter>>>()?;
+            entries.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+            Ok(entries)
+        })
+    }
+
+    fn take_dead_letter<'a>(&'a self, id: &'a str) -> StorageFuture<'a, Option<DeadLetter>> {
+        Box::pin(async move {
+            let key = self.key("dlq");
+            let Reply::Bulk(raw) = self
+                .client
+                .command(&[b"HGET", key.as_bytes(), id.as_bytes()])
+                .await?
+            else {
+                return Ok(None);
+            };
+            // HDEL reports 0 when another replay removed it first
+            match self
+                .client
+                .command(&[b"HDEL", key.as_bytes(), id.as_bytes()])
+                .await?
+            {
+                Reply::Integer(removed) if removed > 0 => parse(&raw).map(Some),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn list_groups(&self) -> StorageFuture<'_, Vec<ContactGroup>> {
+        Box::pin(async move {
+            let key = self.key("groups");
+            let mut groups = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<ContactGroup>(&raw))
+                .collect::<io::Result<Vec<ContactGroup>>>()?;
+            groups.sort_by(|a, b| a.name.cmp(&b.name));
+            for group in &mut groups {
+                let members = self.members_key(&group.name);
+                if let Reply::Integer(count) =
+                    self.client.command(&[b"HLEN", members.as_bytes()]).await?
+                {
+                    group.members = count as usize;
+                }
+            }
+            Ok(groups)
+        })
+    }
+
+    fn create_group(&self, group: ContactGroup) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let key = self.key("groups");
+            let value = serde_json::to_vec(&group)?;
+            Ok(matches!(
+                self.client
+                    .command(&[b"HSETNX", key.as_bytes(), group.name.as_bytes(), &value])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn delete_group<'a>(&'a self, name: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key("groups");
+            // The group goes first so no add can land after its members do
+            let removed = matches!(
+                self.client
+                    .command(&[b"HDEL", key.as_bytes(), name.as_bytes()])
+                    .await?,
+                Reply::Integer(1)
+            );
+            let members = self.members_key(name);
+            self.client.command(&[b"DEL", members.as_bytes()]).await?;
+            Ok(removed)
+        })
+    }
+
+    fn group_members<'a>(&'a self, name: &'a str) -> StorageFuture<'a, Option<Vec<Contact>>> {
+        Box::pin(async move {
+            if !self.group_exists(name).await? {
+                return Ok(None);
+            }
+            let key = self.members_key(name);
+            let mut members = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<Contact>(&raw))
+                .collect::<io::Result<Vec<Contact>>>()?;
+            members.sort_by(|a, b| a.phone.cmp(&b.phone));
+            Ok(Some(members))
+        })
+    }
+
+    fn add_members<'a>(&'a self, name: &'a str, members: Vec<Contact>) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let groups = self.key("groups");
+            let key = self.members_key(name);
+            let values = members
+                .iter()
+                .map(serde_json::to_vec)
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut args: Vec<&[u8]> = vec![
+                b"EVAL",
+                ADD_MEMBERS_SCRIPT.as_bytes(),
+                b"2",
+                groups.as_bytes(),
+                key.as_bytes(),
+                name.as_bytes(),
+            ];
+            for (contact, value) in members.iter().zip(&values) {
+                args.extend([contact.phone.as_bytes(), value.as_slice()]);
+            }
+            Ok(matches!(
+                self.client.command(&args).await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn remove_member<'a>(
+        &'a self,
+        name: &'a str,
+        phone: &'a str,
+    ) -> StorageFuture<'a, Option<bool>> {
+        Box::pin(async move {
+            if !self.group_exists(name).await? {
+                return Ok(None);
+            }
+            let key = self.members_key(name);
+            Ok(Some(matches!(
+                self.client
+                    .command(&[b"HDEL", key.as_bytes(), phone.as_bytes()])
+                    .await?,
+                Reply::Integer(1)
+            )))
+        })
+    }
+
+    // Unlike the other stores this keeps a retried callback twice, as the
+    // list has no index to find an earlier copy by
+    fn record_inbound(&self, message: InboundMessage) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let key = self.key("inbound");
+            let value = serde_json::to_vec(&message)?;
+            self.client
+                .command(&[b"LPUSH", key.as_bytes(), &value])
+                .await?;
+            let last = (HISTORY_LIMIT - 1).to_string();
+            self.client
+                .command(&[b"LTRIM", key.as_bytes(), b"0", last.as_bytes()])
+                .await
+                .map(drop)
+        })
+    }
+
+    fn recent_inbound(&self, limit: usize) -> StorageFuture<'_, Vec<InboundMessage>> {
+        Box::pin(async move {
+            if limit == 0 {
+                return Ok(Vec::new());
+            }
+            let key = self.key("inbound");
+            let last = (limit - 1).to_string();
+            bulks(
+                self.client
+                    .command(&[b"LRANGE", key.as_bytes(), b"0", last.as_bytes()])
+                    .await?,
+            )
+            .into_iter()
+            .flatten()
+            .map(|raw| parse(&raw))
+            .collect()
+        })
+    }
+
+    fn list_autoreplies(&self) -> StorageFuture<'_, Vec<Rule>> {
+        Box::pin(async move {
+            let key = self.key("autoreplies");
+            let mut rules = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<Rule>(&raw))
+                .collect::<io::Result<Vec<_>>>()?;
+            rules.sort_by(|a, b| a.keyword.cmp(&b.keyword));
+            Ok(rules)
+        })
+    }
+
+    fn put_autoreply(&self, rule: Rule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let key = self.key("autoreplies");
+            let value = serde_json::to_vec(&rule)?;
+            Ok(matches!(
+                self.client
+                    .command(&[b"HSET", key.as_bytes(), rule.keyword.as_bytes(), &value])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn delete_autoreply<'a>(&'a self, keyword: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key("autoreplies");
+            Ok(matches!(
+                self.client
+                    .command(&[b"HDEL", key.as_bytes(), keyword.as_bytes()])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn list_api_keys(&self) -> StorageFuture<'_, Vec<ApiKey>> {
+        Box::pin(async move {
+            let key = self.key("apikeys");
+            let mut keys = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<ApiKey>(&raw))
+                .collect::<io::Result<Vec<_>>>()?;
+            keys.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(keys)
+        })
+    }
+
+    fn find_api_key<'a>(&'a self, hash: &'a str) -> StorageFuture<'a, Option<ApiKey>> {
+        Box::pin(async move {
+            let keys = [
+                self.key("apikeys"),
+                self.key("apikeys:hashes"),
+                self.key("apikeys:indexed"),
+            ];
+            let mut args: Vec<&[u8]> = vec![b"EVAL", FIND_API_KEY_SCRIPT.as_bytes(), b"3"];
+            args.extend(keys.iter().map(|key| key.as_bytes()));
+            args.push(hash.as_bytes());
+            match self.client.command(&args).await? {
+                Reply::Bulk(raw) => parse(&raw).map(Some),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn create_api_key(&self, api_key: ApiKey) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let key = self.key("apikeys");
+            let value = serde_json::to_vec(&api_key)?;
+            let created = matches!(
+                self.client
+                    .command(&[b"HSETNX", key.as_bytes(), api_key.name.as_bytes(), &value])
+                    .await?,
+                Reply::Integer(1)
+            );
+            if created {
+                self.index_api_key(&api_key).await?;
+            }
+            Ok(created)
+        })
+    }
+
+    fn put_api_key(&self, api_key: ApiKey) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let key = self.key("apikeys");
+            let value = serde_json::to_vec(&api_key)?;
+            self.client
+                .command(&[b"HSET", key.as_bytes(), api_key.name.as_bytes(), &value])
+                .await?;
+            self.index_api_key(&api_key).await
+        })
+    }
+
+    fn list_number_rules(&self) -> StorageFuture<'_, Vec<StoredRule>> {
+        Box::pin(async move {
+            let key = self.key("numberrules");
+            let mut rules = bulks(self.client.command(&[b"HVALS", key.as_bytes()]).await?)
+                .into_iter()
+                .flatten()
+                .map(|raw| parse::<StoredRule>(&raw))
+                .collect::<io::Result<Vec<_>>>()?;
+            rules.sort_by_key(StoredRule::id);
+            Ok(rules)
+        })
+    }
+
+    fn create_number_rule(&self, rule: StoredRule) -> StorageFuture<'_, bool> {
+        Box::pin(async move {
+            let key = self.key("numberrules");
+            let value = serde_json::to_vec(&rule)?;
+            Ok(matches!(
+                self.client
+                    .command(&[b"HSETNX", key.as_bytes(), rule.id().as_bytes(), &value])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn delete_number_rule<'a>(&'a self, id: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let key = self.key("numberrules");
+            Ok(matches!(
+                self.client
+                    .command(&[b"HDEL", key.as_bytes(), id.as_bytes()])
+                    .await?,
+                Reply::Integer(1)
+            ))
+        })
+    }
+
+    fn ping(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            self.client.command(&[b"PING"]).await?;
+            Ok(())
+        })
+    }
+}
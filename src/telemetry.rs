@@ -0,0 +1,102 @@
+use once_cell::sync::OnceCell;
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider as _,
+};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::time::Instant;
+use tracing::{debug, warn, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::tracecontext::TraceContext;
+
+/// Used when `OTEL_SERVICE_NAME` isn't set
+const SERVICE_NAME: &str = "locci-scheduler";
+
+/// Set once spans are being exported
+static PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+fn env_set(key: &str) -> bool {
+    std::env::var(key).is_ok_and(|value| !value.trim().is_empty())
+}
+
+/// A layer exporting spans over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// (or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`), with `OTEL_EXPORTER_OTLP_HEADERS`
+/// sent along, e.g. `x-honeycomb-team=…`. `None` when no endpoint is set.
+/// It's built before tracing is up, so a failed exporter is returned for
+/// the caller to log once the subscriber is installed.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, Tracer>>, ExporterBuildError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !env_set("OTEL_EXPORTER_OTLP_ENDPOINT") && !env_set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let mut resource = Resource::builder();
+    if !env_set("OTEL_SERVICE_NAME") {
+        resource = resource.with_service_name(SERVICE_NAME);
+    }
+    if let Ok(environment) = std::env::var("VERCEL_ENV") {
+        resource = resource.with_attribute(KeyValue::new("deployment.environment", environment));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer("locci_scheduler_core");
+    if PROVIDER.set(provider).is_err() {
+        return Ok(None);
+    }
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Makes `span` a child of the caller's span, so a trace that came in with
+/// a `traceparent` carries on in the backend. It has to be called before
+/// the span is first entered.
+pub fn continue_trace(span: &Span, context: &TraceContext) {
+    if PROVIDER.get().is_none() {
+        return;
+    }
+    let Some(parent_id) = context.parent_id.as_deref() else {
+        return;
+    };
+    let (Ok(trace_id), Ok(span_id)) = (
+        TraceId::from_hex(&context.trace_id),
+        SpanId::from_hex(parent_id),
+    ) else {
+        return;
+    };
+    let state = context
+        .tracestate
+        .as_deref()
+        .and_then(|state| state.parse::<TraceState>().ok())
+        .unwrap_or_default();
+    let parent = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::new(context.flags),
+        true,
+        state,
+    );
+    let _ = span.set_parent(opentelemetry::Context::new().with_remote_span_context(parent));
+}
+
+/// Sends the spans still batched. Called before each response is returned,
+/// since a serverless function can be frozen as soon as it has answered.
+pub async fn flush() {
+    let Some(provider) = PROVIDER.get() else {
+        return;
+    };
+    let provider = provider.clone();
+    let started = Instant::now();
+    // Flushing blocks until the exporter's thread is done
+    match tokio::task::spawn_blocking(move || provider.force_flush()).await {
+        Ok(Ok(())) => debug!("Flushed spans in {:?}", started.elapsed()),
+        Ok(Err(e)) => warn!("Failed to export spans: {}", e),
+        Err(e) => warn!("Failed to export spans: {}", e),
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Why a template couldn't be rendered for a recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A `{{` with no matching `}}`, at this byte offset
+    Unclosed(usize),
+    /// `{{}}` or `{{ }}`, at this byte offset
+    EmptyName(usize),
+    /// The recipient's vars don't define this name
+    MissingVariable(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(offset) => write!(f, "unclosed {{{{ at offset {}", offset),
+            TemplateError::EmptyName(offset) => {
+                write!(f, "empty variable name at offset {}", offset)
+            }
+            TemplateError::MissingVariable(name) => write!(f, "missing variable {}", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Fills `{{name}}` placeholders from `vars`. Whitespace inside the braces
+/// is ignored; there is no escaping, conditionals or loops.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let offset = template.len() - rest.len() + start;
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or(TemplateError::Unclosed(offset))?;
+        let name = after[..end].trim();
+        if name.is_empty() {
+            return Err(TemplateError::EmptyName(offset));
+        }
+        let value = vars
+            .get(name)
+            .ok_or_else(|| TemplateError::MissingVariable(name.to_string()))?;
+        rendered.push_str(value);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
@@ -0,0 +1,151 @@
+use std::future::Future;
+use tracing::debug;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// Longest `tracestate` passed on; the spec lets anything past it be dropped
+const MAX_TRACESTATE_LEN: usize = 512;
+
+/// A request's place in a W3C Trace Context trace. It continues the trace a
+/// valid `traceparent` header names, or starts one when there's none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits, the same for every service the trace crosses
+    pub trace_id: String,
+    /// This request's span, named as the parent of the calls it makes
+    pub span_id: String,
+    /// The caller's span, when the request carried one
+    pub parent_id: Option<String>,
+    pub flags: u8,
+    /// Vendor data from the caller, passed on untouched
+    pub tracestate: Option<String>,
+}
+
+tokio::task_local! {
+    // Set for each request so calls deep inside it, including bulk sends on
+    // their own tasks, carry its trace
+    static CURRENT: TraceContext;
+}
+
+fn random_hex(digits: usize) -> String {
+    loop {
+        let hex = uuid::Uuid::new_v4().simple().to_string();
+        // All zeros is invalid for both IDs
+        if !hex[..digits].bytes().all(|b| b == b'0') {
+            return hex[..digits].to_string();
+        }
+    }
+}
+
+fn is_hex(value: &str, digits: usize) -> bool {
+    value.len() == digits
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && !value.bytes().all(|b| b == b'0')
+}
+
+/// The trace ID, parent ID and flags of a `traceparent` value, or `None`
+/// when it's malformed. Later versions are read as version 00, as the spec
+/// asks, as long as they start the same way.
+pub fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let value = value.trim();
+    let mut parts = value.splitn(5, '-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let rest = parts.next();
+    let version_ok =
+        version.len() == 2 && version.bytes().all(|b| b.is_ascii_hexdigit()) && version != "ff";
+    let rest_ok = match rest {
+        None => true,
+        Some(_) => version != "00",
+    };
+    if !version_ok || !rest_ok || !is_hex(trace_id, 32) || !is_hex(parent_id, 16) {
+        return None;
+    }
+    if flags.len() != 2 || !flags.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), parent_id.to_string(), flags))
+}
+
+impl TraceContext {
+    /// A new trace, sampled
+    pub fn new() -> Self {
+        TraceContext {
+            trace_id: random_hex(32),
+            span_id: random_hex(16),
+            parent_id: None,
+            flags: 0x01,
+            tracestate: None,
+        }
+    }
+
+    /// Continues the trace in the request's `traceparent`, or starts one
+    /// when it's missing or malformed. `tracestate` is only kept alongside
+    /// a valid `traceparent`.
+    pub fn from_headers(headers: &http::HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let Some(traceparent) = header(TRACEPARENT) else {
+            return Self::new();
+        };
+        let Some((trace_id, parent_id, flags)) = parse_traceparent(traceparent) else {
+            debug!("Ignoring malformed traceparent {:?}", traceparent);
+            return Self::new();
+        };
+        let tracestate = header(TRACESTATE)
+            .map(str::trim)
+            .filter(|state| !state.is_empty() && state.len() <= MAX_TRACESTATE_LEN)
+            .map(str::to_string);
+        TraceContext {
+            trace_id,
+            span_id: random_hex(16),
+            parent_id: Some(parent_id),
+            // Only the sampled bit is defined; the rest aren't passed on
+            flags: flags & 0x01,
+            tracestate,
+        }
+    }
+
+    /// The `traceparent` for calls made on this request's behalf
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Adds `traceparent` and any `tracestate` to an outbound request
+    pub fn inject(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request.header(TRACEPARENT, self.traceparent());
+        match &self.tracestate {
+            Some(state) => request.header(TRACESTATE, state),
+            None => request,
+        }
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `future` as part of `context`'s trace
+pub async fn scope<F: Future>(context: TraceContext, future: F) -> F::Output {
+    CURRENT.scope(context, future).await
+}
+
+/// The trace of the request being handled; outside of one, a new trace
+pub fn current() -> TraceContext {
+    CURRENT.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Adds the current trace to an outbound request, when there is one
+pub fn propagate(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match CURRENT.try_with(Clone::clone) {
+        Ok(context) => context.inject(request),
+        Err(_) => request,
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::alerts;
+use crate::config::Config;
+use crate::heartbeat::{self, Tick};
+use crate::kv;
+use crate::providers::SmsProvider;
+
+/// How long KV remembers that a silence was alerted on
+const ALERTED_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The last tick a silence was alerted on, when KV isn't linked
+static ALERTED: Lazy<Mutex<Option<DateTime<Utc>>>> = Lazy::new(|| Mutex::new(None));
+
+/// When the next tick is due and whether it's late
+#[derive(Debug, Clone, Serialize)]
+pub struct Watch {
+    pub max_gap_mins: u64,
+    pub last_tick: DateTime<Utc>,
+    /// The last tick plus `WATCHDOG_MAX_GAP_MINS`
+    pub expected_by: DateTime<Utc>,
+    pub overdue: bool,
+}
+
+impl Watch {
+    fn new(last: &Tick, max_gap: Duration, now: DateTime<Utc>) -> Self {
+        let expected_by =
+            last.at + chrono::Duration::from_std(max_gap).unwrap_or(chrono::Duration::MAX);
+        Watch {
+            max_gap_mins: max_gap.as_secs() / 60,
+            last_tick: last.at,
+            expected_by,
+            overdue: now > expected_by,
+        }
+    }
+}
+
+/// Takes the one alert for the silence after the tick at `last`, so each
+/// instance polled during it doesn't send its own
+async fn take_alert(last: DateTime<Utc>) -> bool {
+    if let Some(client) = kv::client() {
+        let key = format!("watchdog:alerted:{}", last.timestamp());
+        let result = match client.map_err(io::Error::other) {
+            Ok(client) => client.set_nx(&key, "1", ALERTED_TTL).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(taken) => return taken,
+            Err(e) => warn!("Watchdog alerts unavailable, using memory: {}", e),
+        }
+    }
+    let mut alerted = ALERTED.lock().unwrap_or_else(|e| e.into_inner());
+    if *alerted == Some(last) {
+        return false;
+    }
+    *alerted = Some(last);
+    true
+}
+
+/// Checks that cron ticks are still coming, alerting once on a silence
+/// longer than `WATCHDOG_MAX_GAP_MINS`. A stopped cron invokes nothing, so
+/// this runs on the requests monitors make, to `/status` and `/health`.
+/// `None` when the watchdog is off or no tick has been seen yet.
+pub async fn check(config: &Config, client: &impl SmsProvider) -> Option<Watch> {
+    let max_gap = config.watchdog_max_gap?;
+    let last = heartbeat::last().await?;
+    let now = Utc::now();
+    let watch = Watch::new(&last, max_gap, now);
+    if watch.overdue && take_alert(last.at).await {
+        error!(
+            "No cron tick since {}, over WATCHDOG_MAX_GAP_MINS of {}",
+            last.at, watch.max_gap_mins
+        );
+        alerts::missed_ticks(config, client, last.at, now - last.at, false).await;
+    }
+    Some(watch)
+}
+
+/// Alerts when the tick just recorded came more than `WATCHDOG_MAX_GAP_MINS`
+/// after `previous`, as when the cron was paused or failing and is back
+pub async fn tick_arrived(
+    config: &Config,
+    client: &impl SmsProvider,
+    tick: &Tick,
+    previous: &Tick,
+) {
+    let Some(max_gap) = config.watchdog_max_gap else {
+        return;
+    };
+    let gap = tick.at - previous.at;
+    if gap.to_std().is_ok_and(|gap| gap > max_gap) {
+        warn!(
+            "Cron tick came {} minutes after the last, over WATCHDOG_MAX_GAP_MINS of {}",
+            gap.num_minutes(),
+            max_gap.as_secs() / 60
+        );
+        alerts::missed_ticks(config, client, previous.at, gap, true).await;
+    }
+}
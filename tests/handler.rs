@@ -0,0 +1,565 @@
+//! Drives `api::handler` end to end with `SMS_PROVIDER=mock`. The handler's
+//! config and the mock are shared by every test here, so each test sends to
+//! a number of its own.
+
+#[allow(dead_code)]
+#[path = "../api/handler.rs"]
+mod handler;
+
+use std::sync::Once;
+use std::time::Duration;
+
+use http::StatusCode;
+use locci_scheduler_core::auth;
+use locci_scheduler_core::providers::mock::{MockResponse, MockSmsProvider};
+use serde_json::{json, Value};
+use vercel_runtime::Body;
+
+const ADMIN_KEY: &str = "test-admin";
+const BLOCKED: &str = "254700000999";
+const SIGNING_SECRET: &str = "test-signing-secret";
+
+fn setup() -> MockSmsProvider {
+    static ENV: Once = Once::new();
+    ENV.call_once(|| {
+        std::env::set_var("SMS_PROVIDER", "mock");
+        std::env::set_var("ADMIN_API_KEY", ADMIN_KEY);
+        std::env::set_var("BLOCKED_NUMBERS", format!("+{}", BLOCKED));
+        std::env::set_var("RETRY_MAX_ATTEMPTS", "3");
+        std::env::set_var("RETRY_BACKOFF_MS", "1");
+        std::env::set_var("RETRY_JITTER", "0");
+        // The mock is shared, so one test's scripted failures mustn't
+        // open the circuit on the others
+        std::env::set_var("CIRCUIT_FAILURE_PERCENT", "0");
+        std::env::set_var("REQUEST_SIGNING_SECRET", SIGNING_SECRET);
+        std::env::set_var(
+            "RESPONSE_HEADERS",
+            r#"{"X-Env": "test", "Strict-Transport-Security": "max-age=63072000"}"#,
+        );
+    });
+    MockSmsProvider::shared()
+}
+
+async fn request(method: &str, path: &str, body: Option<Value>) -> http::Response<Body> {
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("https://localhost/api/handler{}", path))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", ADMIN_KEY))
+        .body(match body {
+            Some(body) => Body::Text(body.to_string()),
+            None => Body::Empty,
+        })
+        .expect("request");
+    handler::api::handler(request).await.expect("response")
+}
+
+async fn call(method: &str, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let response = request(method, path, body).await;
+    let status = response.status();
+    let body = match response.into_body() {
+        Body::Text(text) => serde_json::from_str(&text).expect("JSON body"),
+        Body::Binary(bytes) => serde_json::from_slice(&bytes).expect("JSON body"),
+        Body::Empty => Value::Null,
+    };
+    (status, body)
+}
+
+async fn send(phone: &str, extra: Value) -> (StatusCode, Value) {
+    let mut body = json!({ "phone": phone, "message": "Hello from the tests" });
+    if let (Value::Object(body), Value::Object(extra)) = (&mut body, extra) {
+        body.extend(extra);
+    }
+    call("POST", "", Some(body)).await
+}
+
+#[tokio::test]
+async fn health_is_ok() {
+    setup();
+    let (status, body) = call("GET", "/health", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+}
+
+#[tokio::test]
+async fn liveness_readiness_and_provider_checks() {
+    setup();
+    let (status, body) = call("GET", "/healthz", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+
+    let (status, body) = call("GET", "/readyz", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["config"]["ok"], true);
+    assert_eq!(body["checks"]["storage"]["note"], "in memory");
+
+    let (status, body) = call("GET", "/health/provider", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["provider"], "mock");
+}
+
+#[tokio::test]
+async fn status_reports_the_last_tick() {
+    setup();
+    let (status, _) = call("GET", "", None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = call("GET", "/status", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["last_tick"]["at"].is_string(), "{}", body);
+    assert_eq!(body["last_tick"]["killed"], false);
+    assert_eq!(body["dlq_size"], 0);
+    assert_eq!(body["provider"], "mock");
+}
+
+#[tokio::test]
+async fn send_goes_through_the_provider() {
+    let mock = setup();
+    let phone = "254700000101";
+    let (status, body) = send(phone, json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["attempts"], 1);
+    assert_eq!(body["data"]["mock"], true);
+
+    let sends = mock.sends_to(phone);
+    assert_eq!(sends.len(), 1);
+    assert_eq!(sends[0].message, "Hello from the tests");
+    assert!(sends[0].message_id.is_some());
+}
+
+#[tokio::test]
+async fn transient_failures_are_retried() {
+    let mock = setup();
+    let phone = "254700000102";
+    mock.script(
+        phone,
+        [
+            MockResponse::fail("network blip", true),
+            MockResponse::rate_limited(Duration::from_millis(5)),
+        ],
+    );
+    let (status, body) = send(phone, json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["attempts"], 3);
+
+    let sends = mock.sends_to(phone);
+    assert_eq!(sends.len(), 3);
+    assert!(sends[..2].iter().all(|send| send.message_id.is_none()));
+    assert!(sends[2].message_id.is_some());
+}
+
+#[tokio::test]
+async fn permanent_failures_are_not_retried() {
+    let mock = setup();
+    let phone = "254700000103";
+    mock.script(phone, [MockResponse::fail("invalid destination", false)]);
+    let (status, body) = send(phone, json!({})).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", body);
+    assert_eq!(body["code"], "send_failed");
+    assert_eq!(mock.sends_to(phone).len(), 1);
+}
+
+#[tokio::test]
+async fn slow_responses_still_succeed() {
+    let mock = setup();
+    let phone = "254700000104";
+    mock.script(
+        phone,
+        [MockResponse::accept().after(Duration::from_millis(50))],
+    );
+    let (status, body) = send(phone, json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(mock.sends_to(phone).len(), 1);
+}
+
+#[tokio::test]
+async fn dry_runs_send_nothing() {
+    let mock = setup();
+    let phone = "254700000105";
+    let (status, body) = call(
+        "POST",
+        "?dry_run=true",
+        Some(json!({ "phone": phone, "message": "Not really" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["dry_run"], true);
+    assert!(mock.sends_to(phone).is_empty());
+}
+
+#[tokio::test]
+async fn blocked_numbers_are_refused() {
+    let mock = setup();
+    let (status, body) = send(BLOCKED, json!({})).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["code"], "number_blocked");
+    assert!(mock.sends_to(BLOCKED).is_empty());
+}
+
+#[tokio::test]
+async fn invalid_bodies_are_refused() {
+    let mock = setup();
+    let phone = "254700000106";
+    let (status, body) = send(phone, json!({ "sender_id": "" })).await;
+    assert!(status.is_client_error(), "{} {}", status, body);
+    assert!(mock.sends_to(phone).is_empty());
+
+    let (status, body) = send(phone, json!({ "schedule": "not a schedule" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["code"], "invalid_body");
+    assert!(mock.sends_to(phone).is_empty());
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_refused() {
+    let mock = setup();
+    let phone = "254700000108";
+    let message = "x".repeat(2 * 1024 * 1024);
+    let (status, body) = send(phone, json!({ "message": message })).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", body["detail"]);
+    assert_eq!(body["code"], "payload_too_large");
+    assert_eq!(body["limit_bytes"], 1024 * 1024);
+    assert!(mock.sends_to(phone).is_empty());
+}
+
+#[tokio::test]
+async fn form_bodies_are_read_like_json() {
+    let mock = setup();
+    let post = |content_type: &str, body: String| {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("https://localhost/api/handler")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", ADMIN_KEY))
+            .body(Body::Text(body))
+            .expect("request");
+        async { handler::api::handler(request).await.expect("response") }
+    };
+    let phone = "254700000109";
+    let response = post(
+        "application/x-www-form-urlencoded",
+        format!("phone={}&message=Hello+from+a+form", phone),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let sends = mock.sends_to(phone);
+    assert_eq!(sends.len(), 1);
+    assert_eq!(sends[0].message, "Hello from a form");
+
+    let phone = "254700000110";
+    let response = post(
+        "multipart/form-data; boundary=XyZ",
+        format!(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"phone\"\r\n\r\n{}\r\n\
+             --XyZ\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\nHello\r\n\
+             --XyZ\r\nContent-Disposition: form-data; name=\"dry_run\"\r\n\r\ntrue\r\n\
+             --XyZ--\r\n",
+            phone
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(mock.sends_to(phone).is_empty());
+}
+
+#[tokio::test]
+async fn requests_need_a_key() {
+    setup();
+    let request = http::Request::builder()
+        .method("DELETE")
+        .uri("https://localhost/api/handler/optout/254700000111")
+        .body(Body::Empty)
+        .expect("request");
+    let response = handler::api::handler(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn unknown_routes_and_methods() {
+    setup();
+    let (status, _) = call("GET", "/no-such-route", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = call("DELETE", "/health", None).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(body["code"], "method_not_allowed");
+}
+
+#[tokio::test]
+async fn incoming_traces_are_continued() {
+    setup();
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let trace = |traceparent: &str| {
+        let request = http::Request::builder()
+            .uri("https://localhost/api/handler/health")
+            .header("traceparent", traceparent)
+            .body(Body::Empty)
+            .expect("request");
+        async {
+            let response = handler::api::handler(request).await.expect("response");
+            response.headers()["X-Trace-Id"]
+                .to_str()
+                .expect("trace ID")
+                .to_string()
+        }
+    };
+    let continued = trace(&format!("00-{}-00f067aa0ba902b7-01", trace_id)).await;
+    assert_eq!(continued, trace_id);
+
+    let minted = trace("not a traceparent").await;
+    assert_ne!(minted, trace_id);
+    assert_eq!(minted.len(), 32);
+}
+
+#[tokio::test]
+async fn sends_show_up_in_metrics() {
+    setup();
+    let (status, body) = send("254700000107", json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let request = http::Request::builder()
+        .uri("https://localhost/api/handler/metrics")
+        .header("Authorization", format!("Bearer {}", ADMIN_KEY))
+        .body(Body::Empty)
+        .expect("request");
+    let response = handler::api::handler(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = match response.into_body() {
+        Body::Text(text) => text,
+        Body::Binary(bytes) => String::from_utf8(bytes).expect("UTF-8 body"),
+        Body::Empty => String::new(),
+    };
+    assert!(
+        text.contains(r#"scheduler_sms_sends_total{outcome="sent",provider="mock"}"#),
+        "{}",
+        text
+    );
+    assert!(text.contains("scheduler_dlq_depth"), "{}", text);
+}
+
+#[tokio::test]
+async fn jobs_report_their_effective_retry_policy() {
+    setup();
+    let (status, body) = call(
+        "POST",
+        "/jobs",
+        Some(json!({
+            "phone": "254700000112",
+            "message": "Your code",
+            "schedule": "0 9 * * *",
+            "retry_policy": { "max_attempts": 5, "give_up": "dead_letter" },
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(body["job"]["retry_policy"]["max_attempts"], 5);
+    assert_eq!(body["retry_policy"]["max_attempts"], 5);
+    assert_eq!(body["retry_policy"]["give_up"], "dead_letter");
+    // Unset fields come from the global policy
+    assert_eq!(body["retry_policy"]["backoff_ms"], 1);
+
+    let id = body["job"]["id"].as_str().expect("job id");
+    let (status, body) = call("GET", &format!("/jobs/{}", id), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["retry_policy"]["max_attempts"], 5);
+}
+
+#[tokio::test]
+async fn requests_and_responses_round_trip_through_messagepack() {
+    let mock = setup();
+    let (single, bulk) = ("254700000113", "254700000114");
+    let body = json!({
+        "message": "Hello in MessagePack",
+        // Both shapes of the untagged recipient, and a boolean flag
+        "recipients": [single, { "phone": bulk, "message": "Just for you" }],
+        "dry_run": false,
+    });
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("https://localhost/api/handler")
+        .header("Content-Type", "application/msgpack")
+        .header("Accept", "application/json;q=0.5, application/msgpack")
+        .header("Authorization", format!("Bearer {}", ADMIN_KEY))
+        .body(Body::Binary(
+            rmp_serde::to_vec_named(&body).expect("encode"),
+        ))
+        .expect("request");
+    let response = handler::api::handler(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Type"], "application/msgpack");
+    let body: Value = match response.into_body() {
+        Body::Binary(bytes) => rmp_serde::from_slice(&bytes).expect("MessagePack body"),
+        other => panic!("expected a binary body, got {:?}", other),
+    };
+    assert!(body["trace_id"].is_string(), "{}", body);
+    assert_eq!(body["request_info"]["method"], "POST");
+
+    assert_eq!(mock.sends_to(single)[0].message, "Hello in MessagePack");
+    assert_eq!(mock.sends_to(bulk)[0].message, "Just for you");
+}
+
+#[tokio::test]
+async fn non_json_provider_bodies_are_a_bad_gateway() {
+    let mock = setup();
+    let phone = "254700000115";
+    let page = "<html><body><h1>502 Bad Gateway</h1></body></html>";
+    // Retried like any provider trouble, until the attempts run out
+    mock.script(phone, vec![MockResponse::body(page); 3]);
+    let (status, body) = send(phone, json!({})).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", body);
+    assert_eq!(body["code"], "provider_bad_response");
+    assert_eq!(body["raw"], page);
+    assert!(
+        body["parse_error"].as_str().is_some_and(|e| !e.is_empty()),
+        "{}",
+        body
+    );
+    assert_eq!(mock.sends_to(phone).len(), 3);
+}
+
+#[tokio::test]
+async fn configured_headers_are_on_every_response() {
+    setup();
+    let ok = request("GET", "/health", None).await;
+    let refused = request(
+        "POST",
+        "",
+        Some(json!({ "phone": BLOCKED, "message": "Hello from the tests" })),
+    )
+    .await;
+    assert_eq!(ok.status(), StatusCode::OK);
+    assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+    for response in [ok, refused] {
+        let headers = response.headers();
+        assert_eq!(headers["x-env"], "test");
+        assert_eq!(headers["strict-transport-security"], "max-age=63072000");
+    }
+}
+
+#[tokio::test]
+async fn signed_requests_can_carry_an_idempotency_key() {
+    let mock = setup();
+    let phone = "254700000116";
+    let body = json!({ "phone": phone, "message": "Hello from the tests" }).to_string();
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+    let signature = auth::signature(SIGNING_SECRET, &timestamp, body.as_bytes());
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("https://localhost/api/handler")
+        .header("Content-Type", "application/json")
+        .header("X-Timestamp", &timestamp)
+        .header(auth::SIGNATURE_HEADER, &signature)
+        .header("Idempotency-Key", "signed-116")
+        .body(Body::Text(body))
+        .expect("request");
+    let response = handler::api::handler(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(mock.sends_to(phone).len(), 1);
+}
+
+#[tokio::test]
+async fn batch_jobs_are_stored_and_sent_at_their_own_local_time() {
+    let mock = setup();
+    let (eat, wat) = ("254700000117", "254700000118");
+    let (status, body) = call(
+        "POST",
+        "/schedule/batch",
+        Some(json!({
+            "local_time": "2024-09-02T09:00",
+            "jobs": [
+                { "phone": eat, "message": "Habari", "timezone": "Africa/Nairobi" },
+                { "phone": wat, "message": "Good morning", "timezone": "Africa/Lagos" },
+                { "phone": "254700000119", "message": "Hi", "timezone": "Mars/Olympus" },
+            ],
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    let jobs = body["jobs"].as_array().expect("jobs");
+    assert_eq!(jobs.len(), 2, "{}", body);
+    assert!(jobs.iter().all(|job| job["id"].is_string()), "{}", body);
+    assert_ne!(jobs[0]["id"], jobs[1]["id"]);
+    assert_eq!(jobs[0]["fire_at_utc"], "2024-09-02T06:00:00Z");
+    assert_eq!(jobs[1]["fire_at_utc"], "2024-09-02T08:00:00Z");
+    assert_eq!(body["rejected"][0]["index"], 2);
+
+    // Both are long due, so a tick sends them
+    let (status, _) = call("GET", "", None).await;
+    assert_eq!(status, StatusCode::OK);
+    for _ in 0..50 {
+        if !mock.sends_to(wat).is_empty() && !mock.sends_to(eat).is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(mock.sends_to(eat).len(), 1);
+    assert_eq!(mock.sends_to(wat).len(), 1);
+}
+
+#[tokio::test]
+async fn paced_bulk_sends_are_scheduled_apart() {
+    let mock = setup();
+    let phones = ["254700000120", "254700000121", "254700000122"];
+    let (status, body) = send(
+        phones[0],
+        json!({ "recipients": phones, "pace": { "per_second": 0.5 } }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    let data = &body["data"];
+    assert_eq!(data["scheduled"], 3, "{}", body);
+    let send_at: Vec<chrono::DateTime<chrono::Utc>> = data["results"]
+        .as_array()
+        .expect("results")
+        .iter()
+        .map(|result| {
+            assert_eq!(result["status"], "scheduled", "{}", result);
+            result["send_at"]
+                .as_str()
+                .expect("send_at")
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    for pair in send_at.windows(2) {
+        assert_eq!(pair[1] - pair[0], chrono::Duration::seconds(2));
+    }
+    assert_eq!(
+        data["projected_completion_utc"],
+        json!(send_at[2]),
+        "{}",
+        body
+    );
+    // Nothing goes out before its turn
+    assert!(mock.sends_to(phones[2]).is_empty());
+}
+
+#[tokio::test]
+async fn retry_overrides_past_the_limits_are_refused() {
+    let mock = setup();
+    let phone = "254700000123";
+    let (status, body) = send(
+        phone,
+        json!({ "retry_policy": { "max_attempts": 4_000_000_000u32, "backoff_ms": 0 } }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["code"], "invalid_body");
+    assert!(mock.sends_to(phone).is_empty());
+
+    let (status, body) = call(
+        "POST",
+        "/jobs",
+        Some(json!({
+            "phone": phone,
+            "message": "Hello from the tests",
+            "schedule": "0 9 * * *",
+            "retry_policy": { "max_attempts": 50 },
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["code"], "invalid_body");
+}